@@ -0,0 +1,220 @@
+use serde::Serialize;
+
+use crate::boot_sector::VolumeGeometry;
+use crate::cluster_map::ClusterMap;
+use crate::disk_image::ImageSlice;
+use crate::ntfs_logic::{EntrySource, NtfsEntry};
+use crate::stream_reader::read_non_resident;
+
+/// `$Bitmap` lives in MFT record 6.
+const BITMAP_MFT_RECORD: u64 = 6;
+const BITMAP_NAME: &str = "$Bitmap";
+
+/// The volume's cluster allocation bitmap (`$Bitmap`): one bit per cluster, set if the
+/// cluster is in use.
+pub struct ClusterBitmap {
+    bitmap: Vec<u8>,
+    cluster_count: u64,
+}
+
+impl ClusterBitmap {
+    /// Clusters past the end of `bitmap` (e.g., a truncated image) are treated as outside
+    /// the volume.
+    pub fn new(bitmap: Vec<u8>, geometry: &VolumeGeometry) -> Self {
+        let cluster_count = geometry.cluster_count().min(bitmap.len() as u64 * 8);
+        Self {
+            bitmap,
+            cluster_count,
+        }
+    }
+
+    pub fn cluster_count(&self) -> u64 {
+        self.cluster_count
+    }
+
+    fn is_allocated(&self, lcn: u64) -> bool {
+        self.bitmap[(lcn / 8) as usize] & (1 << (lcn % 8)) != 0
+    }
+
+    /// Number of allocated clusters in `[start, end)`, which must be within the volume.
+    pub fn count_allocated(&self, start: u64, end: u64) -> u64 {
+        let mut count = 0;
+        let mut lcn = start;
+
+        while lcn < end && !lcn.is_multiple_of(8) {
+            count += self.is_allocated(lcn) as u64;
+            lcn += 1;
+        }
+        while lcn + 8 <= end {
+            count += self.bitmap[(lcn / 8) as usize].count_ones() as u64;
+            lcn += 8;
+        }
+        while lcn < end {
+            count += self.is_allocated(lcn) as u64;
+            lcn += 1;
+        }
+
+        count
+    }
+}
+
+/// Reads `$Bitmap` through its MFT record.
+pub fn read_bitmap(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: &VolumeGeometry,
+    entries: &[NtfsEntry],
+) -> Option<ClusterBitmap> {
+    let stream = entries
+        .iter()
+        .filter(|e| {
+            e.is_in_use
+                && e.source == EntrySource::Mft
+                && e.mft_record_number == BITMAP_MFT_RECORD
+                && e.filename == BITMAP_NAME
+        })
+        .flat_map(|e| &e.data_streams)
+        .find(|s| s.name.is_none() && !s.resident)?;
+
+    let bitmap = read_non_resident(
+        disk_image_buffer,
+        geometry.cluster_size,
+        stream.data_runs.as_deref()?,
+        stream.size,
+        stream.initialized_size,
+    );
+    Some(ClusterBitmap::new(bitmap, geometry))
+}
+
+/// Reads `$Bitmap` from a known byte offset, for when its MFT record is gone. The bitmap is
+/// assumed to be contiguous, and sized for the whole volume.
+pub fn read_bitmap_at(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: &VolumeGeometry,
+    offset: u64,
+) -> Option<ClusterBitmap> {
+    let volume_clusters = geometry.cluster_count();
+    let start = usize::try_from(offset).ok()?;
+    let bitmap = disk_image_buffer
+        .get_clipped(start, volume_clusters.div_ceil(8) as usize)?
+        .into_owned();
+    Some(ClusterBitmap::new(bitmap, geometry))
+}
+
+/// Whether the clusters of a stream of a deleted file were reused since.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct Recoverability {
+    /// Clusters in the stream's data runs (sparse runs excluded).
+    pub clusters: u64,
+    /// Clusters still free in `$Bitmap`: their content is likely intact.
+    pub clusters_free: u64,
+    /// Clusters allocated again in `$Bitmap`.
+    pub clusters_allocated: u64,
+    /// Clusters claimed by the data runs of files that are in use.
+    pub clusters_claimed_by_live_files: u64,
+    /// Clusters outside the volume (or the bitmap), from corrupt runs.
+    pub clusters_beyond_volume: u64,
+}
+
+/// Fills in `recoverability` for the non-resident streams of every entry that isn't a
+/// file currently in use: deleted files, and earlier versions rebuilt from `$LogFile`.
+pub fn assess_recoverability(
+    entries: &mut [NtfsEntry],
+    bitmap: &ClusterBitmap,
+    cluster_map: &ClusterMap,
+) {
+    let is_live = |e: &NtfsEntry| e.is_in_use && e.source == EntrySource::Mft;
+
+    for entry in entries.iter_mut().filter(|e| !is_live(e)) {
+        for stream in &mut entry.data_streams {
+            let Some(runs) = &stream.data_runs else {
+                continue;
+            };
+
+            let mut recoverability = Recoverability::default();
+            for run in runs {
+                let Some(lcn) = run.cluster_offset else {
+                    continue;
+                };
+                recoverability.clusters += run.cluster_count;
+
+                let start = u64::try_from(lcn).unwrap_or(u64::MAX);
+                let end = start.saturating_add(run.cluster_count);
+                let in_volume_end = end.min(bitmap.cluster_count());
+                if start >= in_volume_end {
+                    recoverability.clusters_beyond_volume += run.cluster_count;
+                    continue;
+                }
+                recoverability.clusters_beyond_volume += end - in_volume_end;
+
+                let allocated = bitmap.count_allocated(start, in_volume_end);
+                recoverability.clusters_allocated += allocated;
+                recoverability.clusters_free += in_volume_end - start - allocated;
+            }
+            // The file's own live version (for `$LogFile` versions) doesn't count.
+            recoverability.clusters_claimed_by_live_files = cluster_map
+                .clusters_claimed_by_live_files(
+                    entry.mft_record_number,
+                    entry.sequence_number,
+                    runs,
+                );
+
+            stream.recoverability = Some(recoverability);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_sector::tests::geometry;
+    use crate::ntfs_logic::tests::{data_fragment, file_name_attribute, mft_record, parse_record};
+
+    /// A bitmap of 512 clusters with clusters 16 to 19 allocated.
+    fn bitmap() -> ClusterBitmap {
+        let mut bitmap = vec![0; 64];
+        bitmap[2] = 0x0F;
+        ClusterBitmap::new(bitmap, &geometry())
+    }
+
+    fn entry(number: u32, flags: u16, runs: &[u8]) -> NtfsEntry {
+        let record = mft_record(
+            1024,
+            number,
+            flags,
+            &[
+                file_name_attribute("a.bin", 5),
+                data_fragment(0, 12 * 4096, runs),
+            ],
+        );
+        parse_record(&record).unwrap()
+    }
+
+    #[test]
+    fn counts_allocated_clusters_across_bytes() {
+        let bitmap = bitmap();
+        assert_eq!(bitmap.cluster_count(), 512);
+        assert_eq!(bitmap.count_allocated(0, 512), 4);
+        assert_eq!(bitmap.count_allocated(18, 40), 2);
+        assert_eq!(bitmap.count_allocated(17, 18), 1);
+    }
+
+    #[test]
+    fn assesses_deleted_streams() {
+        let mut entries = vec![
+            // Deleted: 8 clusters at LCN 16, then 4 at LCN 510 that run past the volume.
+            entry(40, 0, &[0x11, 0x08, 0x10, 0x21, 0x04, 0xEE, 0x01, 0x00]),
+            // In use: 4 clusters at LCN 18.
+            entry(41, 1, &[0x11, 0x04, 0x12, 0x00]),
+        ];
+        let cluster_map = ClusterMap::build(&entries);
+        assess_recoverability(&mut entries, &bitmap(), &cluster_map);
+
+        let deleted = entries[0].data_streams[0].recoverability.clone().unwrap();
+        assert_eq!(deleted.clusters, 12);
+        assert_eq!(deleted.clusters_allocated, 4);
+        assert_eq!(deleted.clusters_free, 6);
+        assert_eq!(deleted.clusters_beyond_volume, 2);
+        assert_eq!(deleted.clusters_claimed_by_live_files, 4);
+        assert!(entries[1].data_streams[0].recoverability.is_none());
+    }
+}
@@ -0,0 +1,270 @@
+use serde::Serialize;
+
+use crate::disk_image::ImageSlice;
+use crate::ntfs_logic::is_valid_record_size;
+
+const NTFS_OEM_ID: &[u8; 8] = b"NTFS    ";
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+/// Which copy of the boot sector the geometry was read from.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BootSectorSource {
+    Primary,
+    Backup,
+}
+
+/// Volume layout, as described by the NTFS boot sector (`$Boot`).
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "kind", rename = "volume_geometry")]
+pub struct VolumeGeometry {
+    pub source: BootSectorSource,
+    /// Byte offset of the boot sector that was parsed.
+    pub boot_sector_offset: u64,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub cluster_size: u64,
+    pub total_sectors: u64,
+    pub mft_lcn: u64,
+    pub mft_mirror_lcn: u64,
+    pub mft_record_size: u64,
+    pub index_record_size: u64,
+    pub volume_serial_number: String,
+}
+
+impl VolumeGeometry {
+    /// Byte offset of `$MFT`, relative to the start of the volume. `None` if it overflows,
+    /// which only a corrupt boot sector can make it do.
+    pub fn mft_offset(&self) -> Option<u64> {
+        self.mft_lcn.checked_mul(self.cluster_size)
+    }
+
+    /// Number of clusters in the volume.
+    pub fn cluster_count(&self) -> u64 {
+        self.total_sectors / self.sectors_per_cluster as u64
+    }
+}
+
+/// Decodes the "clusters per record" encoding used for MFT and index records.
+/// Positive values are a cluster count; negative values mean `2^-value` bytes.
+fn decode_record_size(raw: u8, cluster_size: u64) -> Option<u64> {
+    let value = raw as i8;
+    if value > 0 {
+        Some(value as u64 * cluster_size)
+    } else if (-31..0).contains(&value) {
+        Some(1u64 << -value)
+    } else {
+        None
+    }
+}
+
+/// Parses a boot sector at `offset`. Returns `None` unless it looks like a sane NTFS boot sector.
+pub fn parse_boot_sector(
+    buf: ImageSlice<'_>,
+    offset: usize,
+    source: BootSectorSource,
+) -> Option<VolumeGeometry> {
+    let sector = buf.get(offset..offset.checked_add(512)?)?;
+
+    if &sector[3..11] != NTFS_OEM_ID || sector[510..512] != BOOT_SIGNATURE {
+        return None;
+    }
+
+    let bytes_per_sector = u16::from_le_bytes(sector[11..13].try_into().ok()?) as u32;
+    if !matches!(bytes_per_sector, 256 | 512 | 1024 | 2048 | 4096) {
+        return None;
+    }
+
+    // Values above 0x80 encode 2^(256 - value), used for clusters larger than 64 KiB.
+    let sectors_per_cluster = match sector[13] {
+        raw @ 1..=0x80 if raw.is_power_of_two() => raw as u32,
+        raw @ 0xF4..=0xFF => 1u32 << (256 - raw as u32),
+        _ => return None,
+    };
+
+    let cluster_size = bytes_per_sector as u64 * sectors_per_cluster as u64;
+    let total_sectors = u64::from_le_bytes(sector[40..48].try_into().ok()?);
+    let mft_lcn = u64::from_le_bytes(sector[48..56].try_into().ok()?);
+    let mft_mirror_lcn = u64::from_le_bytes(sector[56..64].try_into().ok()?);
+    let mft_record_size = decode_record_size(sector[64], cluster_size)?;
+    let index_record_size = decode_record_size(sector[68], cluster_size)?;
+    let serial = u64::from_le_bytes(sector[72..80].try_into().ok()?);
+
+    let total_clusters = total_sectors / sectors_per_cluster as u64;
+    if total_sectors == 0 || mft_lcn >= total_clusters || mft_mirror_lcn >= total_clusters {
+        return None;
+    }
+    if !is_valid_record_size(mft_record_size) {
+        return None;
+    }
+    if !index_record_size.is_power_of_two() || !(512..=65536).contains(&index_record_size) {
+        return None;
+    }
+
+    Some(VolumeGeometry {
+        source,
+        boot_sector_offset: offset as u64,
+        bytes_per_sector,
+        sectors_per_cluster,
+        cluster_size,
+        total_sectors,
+        mft_lcn,
+        mft_mirror_lcn,
+        mft_record_size,
+        index_record_size,
+        volume_serial_number: format!("{:016X}", serial),
+    })
+}
+
+/// Where the backup boot sector may be, as `(offset, sector size)`: `total_sectors` sectors
+/// from the start, as the damaged primary still says (the volume may end before `buf`
+/// does), then in the last sector of `buf`.
+fn backup_boot_sector_offsets(buf: ImageSlice<'_>) -> Vec<(usize, usize)> {
+    let mut offsets = Vec::new();
+
+    if let Some(primary) = buf.get(0..512) {
+        let bytes_per_sector = u16::from_le_bytes([primary[11], primary[12]]) as usize;
+        let total_sectors = u64::from_le_bytes(primary[40..48].try_into().unwrap());
+        for sector_size in [512, 4096] {
+            if bytes_per_sector != sector_size && bytes_per_sector != 0 {
+                continue;
+            }
+            let offset = usize::try_from(total_sectors)
+                .ok()
+                .and_then(|sectors| sectors.checked_mul(sector_size));
+            if let Some(offset) = offset.filter(|&offset| offset > 0) {
+                offsets.push((offset, sector_size));
+            }
+        }
+    }
+    for sector_size in [512, 4096] {
+        if let Some(offset) = buf.len().checked_sub(sector_size) {
+            offsets.push((offset, sector_size));
+        }
+    }
+
+    offsets.dedup();
+    offsets
+}
+
+/// Finds the volume geometry of an NTFS volume starting at the beginning of `buf`.
+///
+/// Tries the primary boot sector first, then the backup copy in the last sector of
+/// the volume (where the damaged primary puts it, or at the end of `buf`), for both
+/// 512-byte and 4096-byte sectors.
+pub fn find_volume_geometry(buf: ImageSlice<'_>) -> Option<VolumeGeometry> {
+    if let Some(geometry) = parse_boot_sector(buf, 0, BootSectorSource::Primary) {
+        return Some(geometry);
+    }
+
+    backup_boot_sector_offsets(buf)
+        .into_iter()
+        .find_map(|(offset, sector_size)| {
+            parse_boot_sector(buf, offset, BootSectorSource::Backup)
+                .filter(|g| g.bytes_per_sector as usize == sector_size)
+        })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A boot sector of a volume of `total_sectors` 512-byte sectors with 4 KiB clusters,
+    /// `$MFT` at cluster 4 and 1 KiB records.
+    pub(crate) fn boot_sector(total_sectors: u64) -> Vec<u8> {
+        let mut sector = vec![0; 512];
+        sector[3..11].copy_from_slice(NTFS_OEM_ID);
+        sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        sector[13] = 8;
+        sector[40..48].copy_from_slice(&total_sectors.to_le_bytes());
+        sector[48..56].copy_from_slice(&4u64.to_le_bytes());
+        sector[56..64].copy_from_slice(&2u64.to_le_bytes());
+        // 2^10 bytes per MFT record, 1 cluster per index record.
+        sector[64] = 0xF6;
+        sector[68] = 1;
+        sector[72..80].copy_from_slice(&0x1122_3344_5566_7788u64.to_le_bytes());
+        sector[510..512].copy_from_slice(&BOOT_SIGNATURE);
+        sector
+    }
+
+    /// The geometry of a 512 MiB volume with 4 KiB clusters and 1 KiB MFT records.
+    pub(crate) fn geometry() -> VolumeGeometry {
+        parse_boot_sector(
+            ImageSlice::new(&boot_sector(1 << 20)),
+            0,
+            BootSectorSource::Primary,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_the_primary_boot_sector() {
+        let mut volume = boot_sector(63);
+        volume.resize(64 * 512, 0);
+
+        let geometry = find_volume_geometry(ImageSlice::new(&volume)).unwrap();
+        assert_eq!(geometry.source, BootSectorSource::Primary);
+        assert_eq!(geometry.cluster_size, 4096);
+        assert_eq!(geometry.total_sectors, 63);
+        assert_eq!(geometry.mft_offset(), Some(4 * 4096));
+        assert_eq!(geometry.mft_record_size, 1024);
+        assert_eq!(geometry.index_record_size, 4096);
+        assert_eq!(geometry.volume_serial_number, "1122334455667788");
+    }
+
+    #[test]
+    fn falls_back_to_the_backup_boot_sector() {
+        // The backup is in the last sector of the volume, past `total_sectors`.
+        let mut volume = vec![0; 63 * 512];
+        volume.extend(boot_sector(63));
+
+        let geometry = find_volume_geometry(ImageSlice::new(&volume)).unwrap();
+        assert_eq!(geometry.source, BootSectorSource::Backup);
+        assert_eq!(geometry.boot_sector_offset, 63 * 512);
+    }
+
+    #[test]
+    fn finds_the_backup_boot_sector_before_trailing_space() {
+        // The primary lost its signature, and the volume ends well before the partition.
+        let mut volume = boot_sector(63);
+        volume[510] = 0;
+        volume.resize(63 * 512, 0);
+        volume.extend(boot_sector(63));
+        volume.resize(128 * 512, 0);
+
+        let geometry = find_volume_geometry(ImageSlice::new(&volume)).unwrap();
+        assert_eq!(geometry.source, BootSectorSource::Backup);
+        assert_eq!(geometry.boot_sector_offset, 63 * 512);
+
+        // Without the primary's sector count, only the end of the partition is looked at.
+        volume[..512].fill(0);
+        assert!(find_volume_geometry(ImageSlice::new(&volume)).is_none());
+    }
+
+    #[test]
+    fn rejects_insane_boot_sectors() {
+        let mut sector = boot_sector(63);
+        sector[13] = 3; // Not a power of two
+        assert!(
+            parse_boot_sector(ImageSlice::new(&sector), 0, BootSectorSource::Primary).is_none()
+        );
+
+        let mut sector = boot_sector(63);
+        sector[48..56].copy_from_slice(&100u64.to_le_bytes()); // $MFT past the end
+        assert!(
+            parse_boot_sector(ImageSlice::new(&sector), 0, BootSectorSource::Primary).is_none()
+        );
+
+        let mut sector = boot_sector(63);
+        sector[64] = 0xF3; // 8 KiB MFT records
+        assert!(
+            parse_boot_sector(ImageSlice::new(&sector), 0, BootSectorSource::Primary).is_none()
+        );
+
+        let mut sector = boot_sector(63);
+        sector[510] = 0;
+        assert!(
+            parse_boot_sector(ImageSlice::new(&sector), 0, BootSectorSource::Primary).is_none()
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use serde::Serialize;
+
+use crate::ntfs_logic::{DataRun, EntrySource, NtfsEntry};
+
+/// The stream whose data run claims a range of clusters.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct ClusterOwner {
+    pub mft_record_number: u64,
+    pub sequence_number: u16,
+    pub full_path: Option<String>,
+    pub stream_name: Option<String>,
+    pub vcn: u64, // VCN of the run's first cluster
+    pub is_in_use: bool,
+    pub source: EntrySource,
+}
+
+impl ClusterOwner {
+    /// A file in use, as found in the MFT (not an earlier version rebuilt from `$LogFile`).
+    pub fn is_live(&self) -> bool {
+        self.is_in_use && self.source == EntrySource::Mft
+    }
+
+    fn is_same_file(&self, other: &ClusterOwner) -> bool {
+        self.mft_record_number == other.mft_record_number
+            && self.sequence_number == other.sequence_number
+    }
+}
+
+/// A range of clusters `[start, end)` claimed by one data run.
+#[derive(Debug, Clone)]
+struct Extent {
+    start: u64,
+    end: u64,
+    owner: ClusterOwner,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// Clusters claimed by two files in use: corruption.
+    CrossLink,
+    /// Clusters of a deleted file (or earlier version) that now belong to a file in use.
+    Overwritten,
+    /// Clusters past the end of the volume.
+    BeyondVolume,
+}
+
+/// One line of the conflicts NDJSON.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename = "cluster_conflict")]
+pub struct ClusterConflict {
+    pub conflict: ConflictKind,
+    pub lcn: u64,
+    pub cluster_count: u64,
+    /// For `overwritten`, the deleted file.
+    pub owner: ClusterOwner,
+    /// For `cross_link`, the other claimant; for `overwritten`, the file in use.
+    pub other_owner: Option<ClusterOwner>,
+}
+
+/// The part of a queried byte range that lies in one data run.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename = "owner_match")]
+pub struct OwnerMatch {
+    pub offset: u64, // Byte offset in the volume (in the image, once tagged with a partition)
+    pub partition_index: Option<u32>,
+    pub length: u64,
+    pub lcn: u64,
+    pub owner: ClusterOwner,
+    pub is_live: bool,    // False for deleted files and earlier versions
+    pub file_offset: u64, // Byte offset of `offset` within the stream
+}
+
+/// Which streams claim which clusters, according to their data runs.
+pub struct ClusterMap {
+    /// Sorted by start.
+    extents: Vec<Extent>,
+    /// Largest end among `extents[..=i]`, so lookups know when to stop looking back.
+    max_end: Vec<u64>,
+}
+
+impl ClusterMap {
+    /// Maps the clusters of every non-resident stream of `entries`.
+    pub fn build<'a>(entries: impl IntoIterator<Item = &'a NtfsEntry>) -> Self {
+        let mut extents = Vec::new();
+        for entry in entries {
+            for stream in &entry.data_streams {
+                let mut vcn = stream.lowest_vcn;
+                for run in stream.data_runs.iter().flatten() {
+                    let run_vcn = vcn;
+                    vcn = vcn.saturating_add(run.cluster_count);
+                    let Some(start) = run.cluster_offset.and_then(|lcn| u64::try_from(lcn).ok())
+                    else {
+                        continue;
+                    };
+                    if run.cluster_count == 0 {
+                        continue;
+                    }
+                    extents.push(Extent {
+                        start,
+                        end: start.saturating_add(run.cluster_count),
+                        owner: ClusterOwner {
+                            mft_record_number: entry.mft_record_number,
+                            sequence_number: entry.sequence_number,
+                            full_path: entry.full_path.clone(),
+                            stream_name: stream.name.clone(),
+                            vcn: run_vcn,
+                            is_in_use: entry.is_in_use,
+                            source: entry.source,
+                        },
+                    });
+                }
+            }
+        }
+        extents.sort_by_key(|e| e.start);
+        // The same record can be found twice (e.g., in `$MFTMirr`).
+        extents.dedup_by(|a, b| a.start == b.start && a.end == b.end && a.owner == b.owner);
+
+        let max_end = extents
+            .iter()
+            .scan(0, |max_end, e| {
+                *max_end = e.end.max(*max_end);
+                Some(*max_end)
+            })
+            .collect();
+
+        Self { extents, max_end }
+    }
+
+    /// The extents that overlap `[start, end)`, by start.
+    fn overlapping_extents(&self, start: u64, end: u64) -> Vec<&Extent> {
+        let last = self.extents.partition_point(|e| e.start < end);
+        let mut overlapping = Vec::new();
+
+        for i in (0..last).rev() {
+            if self.max_end[i] <= start {
+                break;
+            }
+            if self.extents[i].end > start {
+                overlapping.push(&self.extents[i]);
+            }
+        }
+
+        overlapping.reverse();
+        overlapping
+    }
+
+    /// The parts of `[start, end)` claimed by runs, with their owners.
+    pub fn overlaps(&self, start: u64, end: u64) -> Vec<(u64, u64, &ClusterOwner)> {
+        self.overlapping_extents(start, end)
+            .into_iter()
+            .map(|e| (e.start.max(start), e.end.min(end), &e.owner))
+            .collect()
+    }
+
+    /// Finds the streams whose data runs cover the bytes `[start, end)` of the volume.
+    pub fn find_owners(&self, start: u64, end: u64, cluster_size: u64) -> Vec<OwnerMatch> {
+        let first_lcn = start / cluster_size;
+        let end_lcn = end.div_ceil(cluster_size);
+
+        self.overlapping_extents(first_lcn, end_lcn)
+            .into_iter()
+            .map(|extent| {
+                let extent_offset = extent.start.saturating_mul(cluster_size);
+                let offset = start.max(extent_offset);
+                let match_end = end.min(extent.end.saturating_mul(cluster_size));
+                let run_offset = offset - extent_offset;
+                OwnerMatch {
+                    offset,
+                    partition_index: None,
+                    length: match_end - offset,
+                    lcn: offset / cluster_size,
+                    file_offset: extent
+                        .owner
+                        .vcn
+                        .saturating_mul(cluster_size)
+                        .saturating_add(run_offset),
+                    is_live: extent.owner.is_live(),
+                    owner: extent.owner.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Number of clusters of `runs` claimed by live files other than the one identified by
+    /// `mft_record_number` and `sequence_number`.
+    pub fn clusters_claimed_by_live_files(
+        &self,
+        mft_record_number: u64,
+        sequence_number: u16,
+        runs: &[DataRun],
+    ) -> u64 {
+        let mut claimed = Vec::new();
+        for run in runs {
+            let Some(start) = run.cluster_offset.and_then(|lcn| u64::try_from(lcn).ok()) else {
+                continue;
+            };
+            let end = start.saturating_add(run.cluster_count);
+            claimed.extend(
+                self.overlaps(start, end)
+                    .into_iter()
+                    .filter(|(_, _, owner)| {
+                        owner.is_live()
+                            && (owner.mft_record_number, owner.sequence_number)
+                                != (mft_record_number, sequence_number)
+                    })
+                    .map(|(start, end, _)| (start, end)),
+            );
+        }
+        count_clusters(claimed)
+    }
+
+    /// Finds cross-linked clusters, deleted files overwritten by live ones, and runs past
+    /// the end of a volume of `volume_clusters` clusters.
+    pub fn conflicts(&self, volume_clusters: u64) -> Vec<ClusterConflict> {
+        let mut conflicts = Vec::new();
+        // Extents that may still overlap the next one.
+        let mut active: Vec<&Extent> = Vec::new();
+
+        for extent in &self.extents {
+            if extent.end > volume_clusters {
+                let lcn = extent.start.max(volume_clusters);
+                conflicts.push(ClusterConflict {
+                    conflict: ConflictKind::BeyondVolume,
+                    lcn,
+                    cluster_count: extent.end - lcn,
+                    owner: extent.owner.clone(),
+                    other_owner: None,
+                });
+            }
+
+            active.retain(|a| a.end > extent.start);
+            for other in &active {
+                let conflict = match (other.owner.is_live(), extent.owner.is_live()) {
+                    (true, true) if !other.owner.is_same_file(&extent.owner) => {
+                        Some((ConflictKind::CrossLink, *other, extent))
+                    }
+                    (false, true) if !other.owner.is_same_file(&extent.owner) => {
+                        Some((ConflictKind::Overwritten, *other, extent))
+                    }
+                    (true, false) if !other.owner.is_same_file(&extent.owner) => {
+                        Some((ConflictKind::Overwritten, extent, *other))
+                    }
+                    // Deleted files often share clusters, earlier versions of a file share
+                    // them with the file, and a file can be listed more than once.
+                    _ => None,
+                };
+                if let Some((conflict, owner, other_owner)) = conflict {
+                    let lcn = extent.start;
+                    conflicts.push(ClusterConflict {
+                        conflict,
+                        lcn,
+                        cluster_count: extent.end.min(other.end) - lcn,
+                        owner: owner.owner.clone(),
+                        other_owner: Some(other_owner.owner.clone()),
+                    });
+                }
+            }
+            active.push(extent);
+        }
+
+        conflicts
+    }
+}
+
+/// Number of clusters covered by `ranges`, counting overlapping ones once.
+fn count_clusters(mut ranges: Vec<(u64, u64)>) -> u64 {
+    ranges.sort_unstable();
+    let mut count = 0;
+    let mut covered_until = 0;
+
+    for (start, end) in ranges {
+        let start = start.max(covered_until);
+        if end > start {
+            count += end - start;
+            covered_until = end;
+        }
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntfs_logic::tests::{data_fragment, file_name_attribute, mft_record, parse_record};
+
+    fn entry(number: u32, flags: u16, runs: &[u8]) -> NtfsEntry {
+        let record = mft_record(
+            1024,
+            number,
+            flags,
+            &[
+                file_name_attribute("a.bin", 5),
+                data_fragment(0, 8 * 4096, runs),
+            ],
+        );
+        parse_record(&record).unwrap()
+    }
+
+    #[test]
+    fn finds_overlapping_extents() {
+        let entries = [
+            // 8 clusters at LCN 16, then 4 at LCN 32.
+            entry(40, 1, &[0x11, 0x08, 0x10, 0x11, 0x04, 0x10, 0x00]),
+            entry(41, 1, &[0x11, 0x04, 0x14, 0x00]),
+        ];
+        let map = ClusterMap::build(&entries);
+
+        let overlaps = map.overlaps(18, 34);
+        let ranges: Vec<_> = overlaps.iter().map(|&(s, e, o)| (s, e, o.vcn)).collect();
+        assert_eq!(ranges, [(18, 24, 0), (20, 24, 0), (32, 34, 8)]);
+        let runs = entries[1].data_streams[0].data_runs.as_deref().unwrap();
+        assert_eq!(map.clusters_claimed_by_live_files(41, 1, runs), 4);
+        assert_eq!(count_clusters(vec![(0, 4), (2, 6), (10, 11)]), 7);
+    }
+
+    #[test]
+    fn maps_a_byte_range_to_file_offsets() {
+        let entries = [
+            // 2 clusters at LCN 16, then 4 at LCN 8 (deleted).
+            entry(40, 0, &[0x11, 0x02, 0x10, 0x11, 0x04, 0xF8, 0x00]),
+        ];
+        let map = ClusterMap::build(&entries);
+
+        let owners = map.find_owners(9 * 4096 + 100, 17 * 4096 + 8, 4096);
+        let summary: Vec<_> = owners
+            .iter()
+            .map(|m| (m.offset, m.length, m.lcn, m.file_offset, m.is_live))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (9 * 4096 + 100, 3 * 4096 - 100, 9, 3 * 4096 + 100, false),
+                (16 * 4096, 4096 + 8, 16, 0, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_cross_links_overwrites_and_runs_past_the_volume() {
+        let entries = [
+            // In use: 8 clusters at LCN 16.
+            entry(40, 1, &[0x11, 0x08, 0x10, 0x00]),
+            // In use: 2 clusters at LCN 22.
+            entry(41, 1, &[0x11, 0x02, 0x16, 0x00]),
+            // Deleted: 4 clusters at LCN 14, then 4 at LCN 62 that run past the volume.
+            entry(42, 0, &[0x11, 0x04, 0x0E, 0x11, 0x04, 0x30, 0x00]),
+        ];
+        let conflicts = ClusterMap::build(&entries).conflicts(64);
+        let summary: Vec<_> = conflicts
+            .iter()
+            .map(|c| {
+                let other = c.other_owner.as_ref().map(|o| o.mft_record_number);
+                (
+                    c.conflict,
+                    c.lcn,
+                    c.cluster_count,
+                    c.owner.mft_record_number,
+                    other,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            summary,
+            [
+                (ConflictKind::Overwritten, 16, 2, 42, Some(40)),
+                (ConflictKind::CrossLink, 22, 2, 40, Some(41)),
+                (ConflictKind::BeyondVolume, 64, 2, 42, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_cross_link_a_file_with_itself() {
+        let entries = [
+            entry(40, 1, &[0x11, 0x08, 0x10, 0x00]),
+            entry(40, 1, &[0x11, 0x08, 0x10, 0x00]),
+        ];
+        assert!(ClusterMap::build(&entries).conflicts(64).is_empty());
+    }
+}
@@ -0,0 +1,402 @@
+use anyhow::{Context, Result};
+use log::{debug, info};
+use memmap2::Mmap;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::VecDeque,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::ewf::{is_ewf_header, read_ewf};
+use crate::vhd::{VHD_COOKIE, read_vhd};
+use crate::vhdx::{VHDX_SIGNATURE, read_vhdx};
+use crate::vmdk::{VMDK_DESCRIPTOR_SIGNATURE, VMDK_SPARSE_MAGIC, read_vmdk};
+
+/// Differencing chains longer than this are taken to be loops.
+pub const MAX_PARENT_DEPTH: usize = 32;
+/// How much of the media [`ImageSlice::sample`] reads at a time.
+const SAMPLE_WINDOW_SIZE: usize = 1024 * 1024;
+
+/// Random access to the media of the input: a raw image, or the disk that a virtual disk or
+/// evidence file describes, decoded as it is read.
+pub trait Media {
+    /// Size of the media in bytes.
+    fn size(&self) -> u64;
+
+    /// Fills `out` with the media from `offset`. Whatever can't be read (unallocated,
+    /// damaged, or past the end) reads as zeros.
+    fn read_at(&self, offset: u64, out: &mut [u8]);
+
+    /// The media's bytes, if they are mapped as is, so that reads can borrow them.
+    fn as_slice(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// A memory-mapped raw image, or the part of a file that holds the media as is.
+pub struct MappedMedia {
+    mmap: Mmap,
+    range: Range<usize>,
+}
+
+impl MappedMedia {
+    /// The bytes `range` of a mapped file, cut short at its end.
+    pub fn new(mmap: Mmap, range: Range<usize>) -> Self {
+        let end = range.end.min(mmap.len());
+        Self {
+            range: range.start.min(end)..end,
+            mmap,
+        }
+    }
+}
+
+impl Media for MappedMedia {
+    fn size(&self) -> u64 {
+        self.range.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, out: &mut [u8]) {
+        copy_from(&self.mmap[self.range.clone()], offset, out);
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(&self.mmap[self.range.clone()])
+    }
+}
+
+/// Media held in memory, such as hand-built sectors.
+impl Media for Vec<u8> {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, out: &mut [u8]) {
+        copy_from(self, offset, out);
+    }
+
+    fn as_slice(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+}
+
+/// Copies `data` from `offset` into `out`, zero-filling past its end.
+pub fn copy_from(data: &[u8], offset: u64, out: &mut [u8]) {
+    let start = usize::try_from(offset)
+        .unwrap_or(usize::MAX)
+        .min(data.len());
+    let length = out.len().min(data.len() - start);
+    out[..length].copy_from_slice(&data[start..start + length]);
+    out[length..].fill(0);
+}
+
+/// Zero-fills the part of a read of `out` at `offset` that is past `size`, and returns the
+/// rest.
+pub fn clip_to_size(size: u64, offset: u64, out: &mut [u8]) -> &mut [u8] {
+    let length = size.saturating_sub(offset).min(out.len() as u64) as usize;
+    let (within, past) = out.split_at_mut(length);
+    past.fill(0);
+    within
+}
+
+/// Reads from the parent of a differencing disk, or zeros if it has none (or it wasn't
+/// found).
+pub fn read_parent(parent: Option<&dyn Media>, offset: u64, out: &mut [u8]) {
+    match parent {
+        Some(parent) => parent.read_at(offset, out),
+        None => out.fill(0),
+    }
+}
+
+/// Splits a read of `out` at `offset` along blocks of `block_size` bytes, and calls
+/// `read_block` with the block index, the offset in the block, and the part of `out`.
+pub fn read_blocks(
+    offset: u64,
+    out: &mut [u8],
+    block_size: u64,
+    mut read_block: impl FnMut(u64, u64, &mut [u8]),
+) {
+    let mut position = offset;
+    let mut out = out;
+    while !out.is_empty() {
+        let in_block = position % block_size;
+        let length = (block_size - in_block).min(out.len() as u64) as usize;
+        let (part, rest) = out.split_at_mut(length);
+        read_block(position / block_size, in_block, part);
+        position = position.saturating_add(length as u64);
+        out = rest;
+    }
+}
+
+/// Recently decoded blocks (compressed chunks or grains), so that nearby reads don't decode
+/// them again. The least recently used block is evicted first.
+pub struct BlockCache {
+    capacity: usize,
+    blocks: RefCell<VecDeque<(u64, Rc<[u8]>)>>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: RefCell::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// The block `index`, decoded with `decode` if it isn't cached.
+    pub fn get(&self, index: u64, decode: impl FnOnce() -> Vec<u8>) -> Rc<[u8]> {
+        let mut blocks = self.blocks.borrow_mut();
+        if let Some(position) = blocks.iter().position(|(i, _)| *i == index) {
+            let block = blocks.remove(position).unwrap();
+            blocks.push_back(block.clone());
+            return block.1;
+        }
+
+        let block: Rc<[u8]> = decode().into();
+        if blocks.len() >= self.capacity {
+            blocks.pop_front();
+        }
+        blocks.push_back((index, block.clone()));
+        block
+    }
+}
+
+/// The media of the input, as the scanner reads it.
+pub struct DiskImage {
+    media: Box<dyn Media>,
+}
+
+impl DiskImage {
+    pub fn new(media: Box<dyn Media>) -> Self {
+        Self { media }
+    }
+
+    /// The bytes `range` of a mapped file.
+    pub fn mapped(mmap: Mmap, range: Range<usize>) -> Self {
+        Self::new(Box::new(MappedMedia::new(mmap, range)))
+    }
+
+    /// The whole media.
+    pub fn slice(&self) -> ImageSlice<'_> {
+        ImageSlice::new(self.media.as_ref())
+    }
+}
+
+/// A part of the media (the whole of it, or one volume), read through [`ImageSlice::get`]
+/// much like a `&[u8]`. Offsets are relative to the start of the slice.
+#[derive(Clone, Copy)]
+pub struct ImageSlice<'a> {
+    media: &'a dyn Media,
+    start: u64,
+    len: u64,
+}
+
+impl<'a> ImageSlice<'a> {
+    pub fn new(media: &'a dyn Media) -> Self {
+        Self {
+            media,
+            start: 0,
+            len: media.size(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// The bytes `range`, borrowed from a mapped image or else read. `None` if `range`
+    /// isn't within the slice, as with `slice::get`.
+    pub fn get(&self, range: Range<usize>) -> Option<Cow<'a, [u8]>> {
+        if range.start > range.end || range.end > self.len() {
+            return None;
+        }
+        let offset = self.start + range.start as u64;
+        if let Some(bytes) = self.media.as_slice() {
+            let start = offset as usize;
+            return Some(Cow::Borrowed(&bytes[start..start + range.len()]));
+        }
+
+        let mut out = vec![0; range.len()];
+        self.media.read_at(offset, &mut out);
+        Some(Cow::Owned(out))
+    }
+
+    /// The `length` bytes at `offset`, or `None` if they aren't all within the slice.
+    pub fn read(&self, offset: u64, length: usize) -> Option<Cow<'a, [u8]>> {
+        let start = usize::try_from(offset).ok()?;
+        self.get(start..start.checked_add(length)?)
+    }
+
+    /// Up to `length` bytes from `start`, cut short at the end of the slice.
+    pub fn get_clipped(&self, start: usize, length: usize) -> Option<Cow<'a, [u8]>> {
+        self.get(start..start.saturating_add(length).min(self.len()))
+    }
+
+    /// The `len` bytes from `start`, cut short at the end of this slice.
+    pub fn slice(&self, start: u64, len: u64) -> ImageSlice<'a> {
+        let start = start.min(self.len);
+        Self {
+            media: self.media,
+            start: self.start + start,
+            len: len.min(self.len - start),
+        }
+    }
+
+    /// The first `N` bytes at every `step`th offset, zero-padded past the end. The slice is
+    /// read a window at a time, so that this stays cheap on media that must be decoded.
+    pub fn sample<const N: usize>(
+        self,
+        step: usize,
+    ) -> impl Iterator<Item = (usize, [u8; N])> + 'a {
+        let image = self;
+        let mut window = Cow::Borrowed(&[][..]);
+        let mut window_start = 0;
+
+        (0..image.len()).step_by(step).map(move |offset| {
+            let end = offset.saturating_add(N).min(image.len());
+            if offset < window_start || end > window_start + window.len() {
+                window_start = offset;
+                window = image
+                    .get_clipped(offset, SAMPLE_WINDOW_SIZE.max(N))
+                    .unwrap_or_default();
+            }
+
+            let mut bytes = [0; N];
+            let available = &window[offset - window_start..end - window_start];
+            bytes[..available.len()].copy_from_slice(available);
+            (offset, bytes)
+        })
+    }
+}
+
+/// Maps a file read-only.
+pub fn map_file(path: &Path) -> Result<Mmap> {
+    let file = File::open(path).with_context(|| format!("Opening {path:?}"))?;
+    // Advisory lock - prevents writes by cooperating processes.
+    // Reduces a risk from unsafe mmap (e.g., if file is shortened or deleted during operation).
+    file.lock_shared()?;
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Finds the parent of a differencing disk from the paths stored in the child (relative to
+/// the child, absolute, or just a file name). An absolute path from another machine is also
+/// looked for next to the child.
+pub fn resolve_parent(child: &Path, hints: &[String]) -> Option<PathBuf> {
+    let directory = child.parent().unwrap_or(Path::new(""));
+    let candidates = hints.iter().flat_map(|hint| {
+        let hint = hint.trim_end_matches('\0').replace('\\', "/");
+        let hint = hint.strip_prefix("./").unwrap_or(&hint).to_string();
+        let file_name = hint.rsplit('/').next().unwrap_or_default().to_string();
+        [directory.join(&hint), directory.join(file_name)]
+    });
+
+    for candidate in candidates {
+        debug!("Looking for the parent disk at {candidate:?}.");
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+/// Opens an image of any supported format. `verify` checks the hashes stored in evidence
+/// files against their media, which takes a full read of it.
+pub fn open_disk_image(path: &str, verify: bool) -> Result<DiskImage> {
+    let mut input_file = File::open(path)?;
+    debug!("Opened input file: {}", path);
+    let path = Path::new(path);
+
+    // Evidence files and virtual disks are decoded as they are read (or, when they store
+    // the media as is, mapped), and read through the same interface as a raw image.
+    let mut header = [0; 512];
+    let header_length = input_file.read(&mut header)?;
+    let header = &header[..header_length];
+    if is_ewf_header(header) {
+        info!("{path:?} is an EWF evidence file. Reading its segments.");
+        return read_ewf(path, verify);
+    }
+    if header.starts_with(VHDX_SIGNATURE) {
+        info!("{path:?} is a VHDX virtual disk.");
+        return read_vhdx(path);
+    }
+    if header.starts_with(VMDK_SPARSE_MAGIC) || header.starts_with(VMDK_DESCRIPTOR_SIGNATURE) {
+        info!("{path:?} is a VMDK virtual disk.");
+        return read_vmdk(path);
+    }
+    // Dynamic VHDs have a copy of the footer at the start; fixed ones only the footer.
+    let mut footer = [0; 512];
+    let length = input_file.seek(SeekFrom::End(0))?;
+    if length >= footer.len() as u64 {
+        input_file.seek(SeekFrom::End(-(footer.len() as i64)))?;
+        input_file.read_exact(&mut footer)?;
+    }
+    if header.starts_with(VHD_COOKIE) || footer.starts_with(VHD_COOKIE) {
+        info!("{path:?} is a VHD virtual disk.");
+        return read_vhd(path);
+    }
+
+    let disk_image_buffer_mmap = map_file(path)?;
+    debug!("Locked input file: {:?}", path);
+
+    // Optimization: Inform the kernel that it's fine to dump old pages after we're past,
+    // and that we'll be requesting forward-looking pages continuously.
+    disk_image_buffer_mmap.advise(memmap2::Advice::Sequential)?;
+
+    let length = disk_image_buffer_mmap.len();
+    Ok(DiskImage::mapped(disk_image_buffer_mmap, 0..length))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Writes `contents` to a file in the temporary directory, named after the test.
+    pub(crate) fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("carrot-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// All the bytes of decoded or mapped media.
+    pub(crate) fn media(image: &DiskImage) -> Vec<u8> {
+        let slice = image.slice();
+        slice.get(0..slice.len()).unwrap().into_owned()
+    }
+
+    #[test]
+    fn maps_a_raw_image_as_is() {
+        let contents: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+        let path = temp_file("raw.img", &contents);
+        let image = open_disk_image(path.to_str().unwrap(), true).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(media(&image), contents);
+    }
+
+    #[test]
+    fn splits_reads_along_blocks() {
+        let mut reads = Vec::new();
+        let mut out = vec![0; 10];
+        read_blocks(6, &mut out, 4, |block, in_block, part| {
+            reads.push((block, in_block, part.len()));
+        });
+        assert_eq!(reads, [(1, 2, 2), (2, 0, 4), (3, 0, 4)]);
+    }
+
+    #[test]
+    fn slices_zero_fill_reads_past_the_end() {
+        let media: Vec<u8> = (0..100).collect();
+        let slice = ImageSlice::new(&media).slice(90, 20);
+        assert_eq!(slice.len(), 10);
+        assert!(slice.get(5..11).is_none());
+        assert_eq!(&slice.get_clipped(8, 4).unwrap()[..], [98, 99]);
+
+        let mut out = [0xFF; 4];
+        media.read_at(98, &mut out);
+        assert_eq!(out, [98, 99, 0, 0]);
+    }
+}
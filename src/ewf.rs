@@ -0,0 +1,840 @@
+use anyhow::{Result, bail};
+use flate2::read::ZlibDecoder;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{debug, info, warn};
+use md5::{Digest, Md5};
+use memmap2::Mmap;
+use sha1::Sha1;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::disk_image::{BlockCache, DiskImage, Media, copy_from, map_file, read_blocks};
+
+// EWF version 1 (`.E01`), as written by EnCase and FTK Imager.
+pub const EWF_SIGNATURE: &[u8; 8] = b"EVF\x09\x0d\x0a\xff\x00";
+// EWF version 2 (`.Ex01`), and logical evidence files (`.L01`, `.Lx01`).
+const EWF2_SIGNATURE: &[u8; 8] = b"EVF2\x0d\x0a\x81\x00";
+const LEF_SIGNATURES: [&[u8; 8]; 2] = [b"LVF\x09\x0d\x0a\xff\x00", b"LEF2\x0d\x0a\x81\x00"];
+
+// Signature, then fields start (1), segment number (u16) and fields end (u16).
+const FILE_HEADER_SIZE: u64 = 13;
+// Type (16 bytes), next section offset, section size, padding and checksum.
+const SECTION_DESCRIPTOR_SIZE: u64 = 76;
+// Entry count, padding, base offset, padding and checksum.
+const TABLE_HEADER_SIZE: usize = 24;
+const TABLE_ENTRY_COMPRESSED: u32 = 0x8000_0000;
+// Uncompressed chunks are followed by their Adler-32.
+const CHUNK_CHECKSUM_SIZE: usize = 4;
+// The E01 volume section; the older SMART one stores a 32-bit sector count.
+const E01_VOLUME_SIZE: usize = 1052;
+
+// EWF2: signature, version (2 bytes), compression method (u16), segment number (u32) and
+// set identifier.
+const EWF2_FILE_HEADER_SIZE: u64 = 32;
+const EWF2_COMPRESSION_BZIP2: u16 = 2;
+// Type, data flags, previous offset, data size, descriptor size, padding size, data hash,
+// padding and checksum. It follows the section's data.
+const EWF2_SECTION_DESCRIPTOR_SIZE: u64 = 64;
+const EWF2_SECTION_DEVICE_INFORMATION: u32 = 0x01;
+const EWF2_SECTION_CASE_DATA: u32 = 0x02;
+const EWF2_SECTION_SECTOR_TABLE: u32 = 0x04;
+const EWF2_SECTION_MD5_HASH: u32 = 0x08;
+const EWF2_SECTION_SHA1_HASH: u32 = 0x09;
+const EWF2_SECTION_NEXT: u32 = 0x0D;
+const EWF2_SECTION_DONE: u32 = 0x0F;
+const EWF2_SECTION_ENCRYPTED: u32 = 0x02;
+// First chunk number (u64), entry count (u32), padding, checksum and padding.
+const EWF2_TABLE_HEADER_SIZE: usize = 32;
+// Chunk offset (u64), size (u32) and flags (u32).
+const EWF2_TABLE_ENTRY_SIZE: usize = 16;
+const EWF2_CHUNK_COMPRESSED: u32 = 0x01;
+const EWF2_CHUNK_PATTERN_FILL: u32 = 0x04;
+// EnCase's default, if the case data doesn't say.
+const EWF2_DEFAULT_SECTORS_PER_CHUNK: u64 = 64;
+// Chunks are 32 KiB unless the examiner chose otherwise; EnCase allows up to 32768 sectors.
+const MAX_CHUNK_SIZE: u64 = 32768 * 4096;
+// Decompressed chunks kept for nearby reads.
+const CACHED_CHUNKS: usize = 64;
+
+/// Media layout, from the `volume` (or `disk`) section.
+#[derive(Debug, Clone, Copy)]
+struct EwfMedia {
+    chunk_count: u32,
+    chunk_size: u64,
+    media_size: u64,
+}
+
+/// How a chunk is stored.
+#[derive(Debug, Clone, Copy)]
+enum ChunkStorage {
+    /// As is, maybe followed by its Adler-32.
+    Stored,
+    Compressed,
+    /// The chunk repeats an 8-byte pattern (EWF2 only), kept in the table entry.
+    Pattern([u8; 8]),
+}
+
+/// Where a chunk is stored.
+#[derive(Debug, Clone, Copy)]
+struct ChunkLocation {
+    segment: usize,
+    offset: u64,
+    size: u64,
+    storage: ChunkStorage,
+}
+
+/// What the sections of a segment set describe.
+#[derive(Default)]
+struct SegmentSet {
+    media: Option<EwfMedia>,
+    chunks: Vec<ChunkLocation>,
+    md5: Option<[u8; 16]>,
+    sha1: Option<[u8; 20]>,
+    /// EWF2 keeps the media layout in text: sector count and size from the device
+    /// information, sectors per chunk from the case data.
+    sector_count: Option<u64>,
+    bytes_per_sector: Option<u64>,
+    sectors_per_chunk: Option<u64>,
+}
+
+/// Whether a file starts like an EWF segment (of any version).
+pub fn is_ewf_header(header: &[u8]) -> bool {
+    header.starts_with(EWF_SIGNATURE)
+        || header.starts_with(EWF2_SIGNATURE)
+        || LEF_SIGNATURES.iter().any(|s| header.starts_with(*s))
+}
+
+/// The `n`th segment's extension (from 1): `E01` to `E99`, then `EAA` to `EZZ`, `FAA`...
+/// For EWF2, `Ex01` to `Ex99`, then `ExAA` to `ExZZ`. The case of the first segment's
+/// extension is kept.
+fn segment_extension(first: &str, n: u32) -> String {
+    let uppercase = first.chars().next().is_some_and(|c| c.is_ascii_uppercase());
+    if first.len() == 4 && first[..2].eq_ignore_ascii_case("ex") {
+        if n <= 99 {
+            return format!("{}{n:02}", &first[..2]);
+        }
+        let index = n - 100;
+        let alphabet = if uppercase { b'A' } else { b'a' };
+        let sub = |offset: u32| (alphabet + (offset % 26) as u8) as char;
+        return format!("{}{}{}", &first[..2], sub(index / 26), sub(index));
+    }
+
+    let base = if uppercase { b'E' } else { b'e' };
+    let letter = |offset: u32| (base + (offset as u8)) as char;
+
+    if n <= 99 {
+        format!("{}{n:02}", letter(0))
+    } else {
+        let index = n - 100;
+        let alphabet = if uppercase { b'A' } else { b'a' };
+        let sub = |offset: u32| (alphabet + (offset % 26) as u8) as char;
+        format!("{}{}{}", letter(index / 676), sub(index / 26), sub(index))
+    }
+}
+
+fn segment_path(first_segment: &Path, n: u32) -> PathBuf {
+    let first = first_segment
+        .extension()
+        .map(|e| e.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    first_segment.with_extension(segment_extension(&first, n))
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn parse_volume(data: &[u8]) -> Option<EwfMedia> {
+    let chunk_count = read_u32(data, 4)?;
+    let sectors_per_chunk = read_u32(data, 8)? as u64;
+    let bytes_per_sector = read_u32(data, 12)? as u64;
+    let sector_count = if data.len() >= E01_VOLUME_SIZE {
+        read_u64(data, 16)?
+    } else {
+        read_u32(data, 16)? as u64
+    };
+
+    Some(EwfMedia {
+        chunk_count,
+        chunk_size: sectors_per_chunk.checked_mul(bytes_per_sector)?,
+        media_size: sector_count.checked_mul(bytes_per_sector)?,
+    })
+}
+
+/// Reads the chunk offsets of a `table` section. The last chunk ends at the first of
+/// `chunks_ends` past its start.
+fn parse_table(
+    data: &[u8],
+    segment: usize,
+    chunks_ends: [u64; 3],
+    chunks: &mut Vec<ChunkLocation>,
+) -> Option<()> {
+    let entry_count = read_u32(data, 0)? as usize;
+    let base_offset = read_u64(data, 8)?;
+    let entries = data.get(TABLE_HEADER_SIZE..TABLE_HEADER_SIZE + entry_count * 4)?;
+
+    let offsets: Vec<(u64, bool)> = entries
+        .chunks_exact(4)
+        .map(|e| {
+            let entry = u32::from_le_bytes(e.try_into().unwrap());
+            let offset = base_offset.checked_add((entry & !TABLE_ENTRY_COMPRESSED) as u64)?;
+            Some((offset, entry & TABLE_ENTRY_COMPRESSED != 0))
+        })
+        .collect::<Option<_>>()?;
+
+    let last_offset = offsets.last().map_or(0, |&(offset, _)| offset);
+    let chunks_end = chunks_ends
+        .into_iter()
+        .find(|&end| end > last_offset)
+        .unwrap_or(last_offset);
+
+    for (i, &(offset, compressed)) in offsets.iter().enumerate() {
+        let end = offsets.get(i + 1).map_or(chunks_end, |&(next, _)| next);
+        chunks.push(ChunkLocation {
+            segment,
+            offset,
+            size: end.saturating_sub(offset),
+            storage: if compressed {
+                ChunkStorage::Compressed
+            } else {
+                ChunkStorage::Stored
+            },
+        });
+    }
+
+    Some(())
+}
+
+/// Follows the chain of sections of one segment file. Returns whether the set goes on
+/// in another segment.
+fn read_sections(segment: &[u8], segment_index: usize, set: &mut SegmentSet) -> bool {
+    let mut offset = FILE_HEADER_SIZE;
+    let mut chunks_end = 0;
+
+    loop {
+        let Some(descriptor) = segment
+            .get(offset as usize..)
+            .and_then(|d| d.get(..SECTION_DESCRIPTOR_SIZE as usize))
+        else {
+            warn!(
+                "EWF segment {} ends without a `done` or `next` section.",
+                segment_index + 1
+            );
+            return false;
+        };
+        let section_type = String::from_utf8_lossy(&descriptor[..16])
+            .trim_end_matches('\0')
+            .to_string();
+        let next = read_u64(descriptor, 16).unwrap_or_default();
+        let size = read_u64(descriptor, 24).unwrap_or_default();
+        let data_start = (offset + SECTION_DESCRIPTOR_SIZE) as usize;
+        let data_end = (offset.saturating_add(size) as usize).min(segment.len());
+        let data = segment.get(data_start..data_end).unwrap_or_default();
+        debug!("EWF section `{section_type}` at offset {offset} ({size} bytes).");
+
+        match section_type.as_str() {
+            "volume" | "disk" if set.media.is_none() => set.media = parse_volume(data),
+            "sectors" => chunks_end = data_end as u64,
+            "table" => {
+                // Chunks are stored in the `sectors` section before the table, or (in older
+                // files) right before the table itself, or after its entries.
+                let chunks_ends = [chunks_end, offset, data_end as u64];
+                if parse_table(data, segment_index, chunks_ends, &mut set.chunks).is_none() {
+                    warn!(
+                        "Invalid EWF table section in segment {}.",
+                        segment_index + 1
+                    );
+                }
+            }
+            "hash" => set.md5 = data.get(..16).and_then(|h| h.try_into().ok()),
+            "digest" => {
+                set.md5 = data.get(..16).and_then(|h| h.try_into().ok());
+                set.sha1 = data.get(16..36).and_then(|h| h.try_into().ok());
+            }
+            "next" => return true,
+            "done" => return false,
+            _ => {}
+        }
+
+        // The last section points to itself.
+        if next <= offset {
+            return false;
+        }
+        offset = next;
+    }
+}
+
+/// The values of an EWF2 device information or case data section: zlib-compressed UTF-16
+/// text, whose third line holds tab-separated keys and fourth line their values.
+fn parse_ewf2_values(data: &[u8]) -> Vec<(String, String)> {
+    let mut text = Vec::new();
+    if ZlibDecoder::new(data).read_to_end(&mut text).is_err() {
+        return Vec::new();
+    }
+    let utf16: Vec<u16> = text
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    let text = String::from_utf16_lossy(&utf16);
+    let mut lines = text.trim_start_matches('\u{feff}').lines();
+
+    match (lines.nth(2), lines.next()) {
+        (Some(keys), Some(values)) => keys
+            .split('\t')
+            .zip(values.split('\t'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Reads the chunk entries of an EWF2 sector table. Chunk offsets are in the segment file.
+fn parse_table_v2(data: &[u8], segment: usize, chunks: &mut Vec<ChunkLocation>) -> Option<()> {
+    let first_chunk = usize::try_from(read_u64(data, 0)?).ok()?;
+    let entry_count = read_u32(data, 8)? as usize;
+    let entries = data.get(
+        EWF2_TABLE_HEADER_SIZE
+            ..EWF2_TABLE_HEADER_SIZE
+                .checked_add(entry_count.checked_mul(EWF2_TABLE_ENTRY_SIZE)?)?,
+    )?;
+
+    // Tables are in chunk order; their entries are appended as in EWF1.
+    if first_chunk != chunks.len() {
+        warn!(
+            "EWF2 sector table of segment {} starts at chunk {first_chunk}, expected {}.",
+            segment + 1,
+            chunks.len()
+        );
+    }
+
+    for entry in entries.chunks_exact(EWF2_TABLE_ENTRY_SIZE) {
+        let offset = read_u64(entry, 0)?;
+        let flags = read_u32(entry, 12)?;
+        let storage = if flags & EWF2_CHUNK_PATTERN_FILL != 0 {
+            ChunkStorage::Pattern(entry[0..8].try_into().unwrap())
+        } else if flags & EWF2_CHUNK_COMPRESSED != 0 {
+            ChunkStorage::Compressed
+        } else {
+            ChunkStorage::Stored
+        };
+        chunks.push(ChunkLocation {
+            segment,
+            offset,
+            size: read_u32(entry, 8)? as u64,
+            storage,
+        });
+    }
+
+    Some(())
+}
+
+/// The EWF2 section descriptor at `offset`, if its checksum matches: its type, data flags,
+/// previous offset, and data size.
+fn read_descriptor_v2(segment: &[u8], offset: u64) -> Option<(u32, u32, u64, u64)> {
+    let start = usize::try_from(offset).ok()?;
+    let descriptor =
+        segment.get(start..start.checked_add(EWF2_SECTION_DESCRIPTOR_SIZE as usize)?)?;
+    if read_u32(descriptor, 60)? != adler32(&descriptor[..60]) {
+        return None;
+    }
+    Some((
+        read_u32(descriptor, 0)?,
+        read_u32(descriptor, 4)?,
+        read_u64(descriptor, 8)?,
+        read_u64(descriptor, 16)?,
+    ))
+}
+
+/// Follows the sections of one EWF2 segment file back from its end (each section's
+/// descriptor follows its data). Returns whether the set goes on in another segment.
+fn read_sections_v2(segment: &[u8], segment_index: usize, set: &mut SegmentSet) -> bool {
+    let mut sections = Vec::new();
+    let mut offset = (segment.len() as u64).checked_sub(EWF2_SECTION_DESCRIPTOR_SIZE);
+
+    // Each step goes back in the file, so the walk ends.
+    while let Some(descriptor_offset) = offset
+        && descriptor_offset >= EWF2_FILE_HEADER_SIZE
+    {
+        let Some((section_type, flags, previous, size)) =
+            read_descriptor_v2(segment, descriptor_offset)
+        else {
+            warn!(
+                "Invalid EWF2 section descriptor at offset {descriptor_offset} of segment {}.",
+                segment_index + 1
+            );
+            break;
+        };
+        let data_start = descriptor_offset.saturating_sub(size);
+        debug!("EWF2 section {section_type:#x} at offset {data_start} ({size} bytes).");
+        sections.push((section_type, flags, data_start..descriptor_offset));
+
+        // The previous offset points to the previous section's descriptor, which usually
+        // ends where this section's data starts.
+        offset = [
+            Some(previous),
+            data_start.checked_sub(EWF2_SECTION_DESCRIPTOR_SIZE),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|&previous| {
+            previous < descriptor_offset && read_descriptor_v2(segment, previous).is_some()
+        });
+        if offset.is_none() && data_start > EWF2_FILE_HEADER_SIZE {
+            warn!(
+                "The EWF2 sections before offset {data_start} of segment {} can't be followed.",
+                segment_index + 1
+            );
+        }
+    }
+
+    let mut has_next = false;
+    for (section_type, flags, range) in sections.into_iter().rev() {
+        let data = &segment[range.start as usize..range.end as usize];
+        if flags & EWF2_SECTION_ENCRYPTED != 0 {
+            warn!("Encrypted EWF2 sections aren't supported. Skipping one.");
+            continue;
+        }
+        match section_type {
+            EWF2_SECTION_DEVICE_INFORMATION | EWF2_SECTION_CASE_DATA => {
+                for (key, value) in parse_ewf2_values(data) {
+                    let value = value.parse().ok();
+                    match key.as_str() {
+                        "ts" if section_type == EWF2_SECTION_DEVICE_INFORMATION => {
+                            set.sector_count = set.sector_count.or(value)
+                        }
+                        "bp" => set.bytes_per_sector = set.bytes_per_sector.or(value),
+                        "sb" => set.sectors_per_chunk = set.sectors_per_chunk.or(value),
+                        _ => {}
+                    }
+                }
+            }
+            EWF2_SECTION_SECTOR_TABLE => {
+                let table = parse_table_v2(data, segment_index, &mut set.chunks);
+                if table.is_none() {
+                    warn!(
+                        "Invalid EWF2 sector table in segment {}.",
+                        segment_index + 1
+                    );
+                }
+            }
+            EWF2_SECTION_MD5_HASH => set.md5 = data.get(..16).and_then(|h| h.try_into().ok()),
+            EWF2_SECTION_SHA1_HASH => set.sha1 = data.get(..20).and_then(|h| h.try_into().ok()),
+            EWF2_SECTION_NEXT => has_next = true,
+            EWF2_SECTION_DONE => has_next = false,
+            _ => {}
+        }
+    }
+
+    has_next
+}
+
+/// The media layout of an EWF2 set, from its device information and case data.
+fn media_v2(set: &SegmentSet) -> Option<EwfMedia> {
+    let bytes_per_sector = set.bytes_per_sector.unwrap_or(512);
+    let sectors_per_chunk = set
+        .sectors_per_chunk
+        .unwrap_or(EWF2_DEFAULT_SECTORS_PER_CHUNK);
+    Some(EwfMedia {
+        chunk_count: u32::try_from(set.chunks.len()).ok()?,
+        chunk_size: sectors_per_chunk.checked_mul(bytes_per_sector)?,
+        media_size: set.sector_count?.checked_mul(bytes_per_sector)?,
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Decompresses (or copies) one chunk into `out`. Returns whether the checksum of a chunk
+/// stored uncompressed matches (zlib checks its own).
+fn read_chunk(segments: &[Mmap], chunk: &ChunkLocation, out: &mut [u8]) -> Option<bool> {
+    if let ChunkStorage::Pattern(pattern) = chunk.storage {
+        for (out, pattern) in out.iter_mut().zip(pattern.iter().cycle()) {
+            *out = *pattern;
+        }
+        return Some(true);
+    }
+
+    let stored = segments
+        .get(chunk.segment)?
+        .get(chunk.offset as usize..chunk.offset.checked_add(chunk.size)? as usize)?;
+
+    if let ChunkStorage::Compressed = chunk.storage {
+        ZlibDecoder::new(stored).read_exact(out).ok()?;
+        return Some(true);
+    }
+
+    let data = stored.get(..out.len())?;
+    out.copy_from_slice(data);
+    let checksum = stored
+        .get(out.len()..out.len() + CHUNK_CHECKSUM_SIZE)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()));
+    Some(checksum.is_none_or(|c| c == adler32(data)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The media of an EWF segment set. Chunks are decompressed as they are read.
+struct EwfImage {
+    segments: Vec<Mmap>,
+    chunks: Vec<ChunkLocation>,
+    media: EwfMedia,
+    cache: BlockCache,
+}
+
+impl EwfImage {
+    /// Length of chunk `index`: the last one may be cut short by the end of the media.
+    fn chunk_length(&self, index: u64) -> usize {
+        let start = index.saturating_mul(self.media.chunk_size);
+        self.media
+            .media_size
+            .saturating_sub(start)
+            .min(self.media.chunk_size) as usize
+    }
+
+    /// Reads chunk `index` into `out`, as [`read_chunk`] does. `None` if it can't be read.
+    fn read_chunk(&self, index: u64, out: &mut [u8]) -> Option<bool> {
+        let chunk = self.chunks.get(usize::try_from(index).ok()?)?;
+        read_chunk(&self.segments, chunk, out)
+    }
+
+    /// Checks the hashes stored in the set against the media, in one pass over its chunks.
+    /// Chunks that can't be read are counted as zeros.
+    fn verify(&self, md5: Option<[u8; 16]>, sha1: Option<[u8; 20]>) {
+        info!(
+            "Verifying the EWF media: reading all {} bytes of it.",
+            self.media.media_size
+        );
+        let progress_bar = ProgressBar::new(self.media.media_size);
+        progress_bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({binary_bytes_per_sec}) - ETA {eta}")
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+        if self.media.media_size < 5_000_000 {
+            // Hide the progress bar on small media, as the scan does.
+            progress_bar.set_draw_target(ProgressDrawTarget::hidden());
+        }
+
+        let mut md5_hasher = Md5::new();
+        let mut sha1_hasher = Sha1::new();
+        let mut failed_chunks = 0;
+        let mut checksum_errors = 0;
+        let mut data = vec![0; self.media.chunk_size as usize];
+
+        let chunk_count = self.media.media_size.div_ceil(self.media.chunk_size);
+        for index in 0..chunk_count {
+            let data = &mut data[..self.chunk_length(index)];
+            match self.read_chunk(index, data) {
+                Some(true) => {}
+                Some(false) => checksum_errors += 1,
+                None => {
+                    data.fill(0);
+                    failed_chunks += 1;
+                }
+            }
+            if md5.is_some() {
+                md5_hasher.update(&*data);
+            }
+            if sha1.is_some() {
+                sha1_hasher.update(&*data);
+            }
+            progress_bar.inc(data.len() as u64);
+        }
+        progress_bar.finish_and_clear();
+        if failed_chunks > 0 {
+            warn!("{failed_chunks} EWF chunks could not be read. They read as zeros.");
+        }
+        if checksum_errors > 0 {
+            warn!("{checksum_errors} EWF chunks don't match their checksum. They are kept as is.");
+        }
+
+        let md5_hash = md5_hasher.finalize();
+        let sha1_hash = sha1_hasher.finalize();
+        for (name, stored, computed) in [
+            ("MD5", md5.map(|h| h.to_vec()), md5_hash.to_vec()),
+            ("SHA1", sha1.map(|h| h.to_vec()), sha1_hash.to_vec()),
+        ] {
+            match stored {
+                Some(stored) if stored == computed => {
+                    info!("EWF {name} verified: {}.", hex(&stored))
+                }
+                Some(stored) => warn!(
+                    "EWF {name} mismatch: stored {}, computed {}.",
+                    hex(&stored),
+                    hex(&computed)
+                ),
+                None => debug!("No {name} stored in the EWF segments."),
+            }
+        }
+    }
+}
+
+impl Media for EwfImage {
+    fn size(&self) -> u64 {
+        self.media.media_size
+    }
+
+    fn read_at(&self, offset: u64, out: &mut [u8]) {
+        read_blocks(
+            offset,
+            out,
+            self.media.chunk_size,
+            |index, in_chunk, out| {
+                let chunk = self.cache.get(index, || {
+                    let mut data = vec![0; self.chunk_length(index)];
+                    if self.read_chunk(index, &mut data).is_none() {
+                        data.fill(0);
+                    }
+                    data
+                });
+                copy_from(&chunk, in_chunk, out);
+            },
+        );
+    }
+}
+
+/// Opens the media of an EWF (`.E01`) or EWF2 (`.Ex01`) segment set, starting from its
+/// first segment.
+///
+/// With `verify`, the hashes stored in the set are checked against the media first; a
+/// mismatch is logged, not an error, so that damaged evidence can still be examined.
+pub fn read_ewf(first_segment: &Path, verify: bool) -> Result<DiskImage> {
+    let mut segments = Vec::new();
+    let mut set = SegmentSet::default();
+    let mut is_ewf2 = false;
+
+    for n in 1.. {
+        let path = if n == 1 {
+            first_segment.to_path_buf()
+        } else {
+            segment_path(first_segment, n)
+        };
+        if n > 1 && !path.exists() {
+            warn!("EWF segment {path:?} is missing. The media will be incomplete.");
+            break;
+        }
+
+        let segment = map_file(&path)?;
+        let segment_is_ewf2 = segment.starts_with(EWF2_SIGNATURE);
+        if !segment_is_ewf2 && !segment.starts_with(EWF_SIGNATURE) {
+            bail!("{path:?} is not an EWF media segment (logical evidence isn't supported).");
+        }
+        if n == 1 {
+            is_ewf2 = segment_is_ewf2;
+        } else if segment_is_ewf2 != is_ewf2 {
+            bail!("{path:?} is not of the same EWF version as the first segment.");
+        }
+        let segment_number = if is_ewf2 {
+            read_u32(&segment, 12)
+        } else {
+            read_u16(&segment, 9).map(u32::from)
+        };
+        let Some(segment_number) = segment_number else {
+            bail!("{path:?} is truncated: it ends inside the EWF file header.");
+        };
+        if segment_number != n {
+            warn!("{path:?} says it is segment {segment_number}, expected {n}.");
+        }
+        if is_ewf2 && read_u16(&segment, 10) == Some(EWF2_COMPRESSION_BZIP2) {
+            bail!("{path:?} is compressed with bzip2, which isn't supported.");
+        }
+
+        let has_next = if is_ewf2 {
+            read_sections_v2(&segment, segments.len(), &mut set)
+        } else {
+            read_sections(&segment, segments.len(), &mut set)
+        };
+        segments.push(segment);
+        if !has_next {
+            break;
+        }
+    }
+
+    let media = if is_ewf2 { media_v2(&set) } else { set.media };
+    let Some(media) = media else {
+        bail!("No volume section (or EWF2 device information) found in the EWF segments.");
+    };
+    if media.media_size == 0 || media.chunk_size == 0 {
+        bail!("The EWF volume section describes empty media.");
+    }
+    if media.chunk_size > MAX_CHUNK_SIZE {
+        bail!("The EWF chunks are too large ({} bytes).", media.chunk_size);
+    }
+    info!(
+        "Reading EWF media: {} bytes in {} chunks, from {} segments.",
+        media.media_size,
+        media.chunk_count,
+        segments.len()
+    );
+    if set.chunks.len() != media.chunk_count as usize {
+        warn!(
+            "The EWF tables list {} chunks, the volume section {}.",
+            set.chunks.len(),
+            media.chunk_count
+        );
+    }
+
+    let image = EwfImage {
+        segments,
+        chunks: set.chunks,
+        media,
+        cache: BlockCache::new(CACHED_CHUNKS),
+    };
+    if verify {
+        image.verify(set.md5, set.sha1);
+    }
+    Ok(DiskImage::new(Box::new(image)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compression, write::ZlibEncoder};
+    use memmap2::MmapMut;
+    use std::io::Write;
+
+    /// Appends a section descriptor and its data, chained to the next section.
+    fn push_section(segment: &mut Vec<u8>, section_type: &str, data: &[u8]) {
+        let offset = segment.len() as u64;
+        let size = SECTION_DESCRIPTOR_SIZE + data.len() as u64;
+        let mut descriptor = vec![0; SECTION_DESCRIPTOR_SIZE as usize];
+        descriptor[..section_type.len()].copy_from_slice(section_type.as_bytes());
+        // The `done` section points to itself.
+        let next = if section_type == "done" {
+            offset
+        } else {
+            offset + size
+        };
+        descriptor[16..24].copy_from_slice(&next.to_le_bytes());
+        descriptor[24..32].copy_from_slice(&size.to_le_bytes());
+        segment.extend_from_slice(&descriptor);
+        segment.extend_from_slice(data);
+    }
+
+    /// A single-segment set of 512-byte chunks: one stored with its Adler-32, one
+    /// zlib-compressed.
+    fn segment(chunks: &[Vec<u8>; 2]) -> Vec<u8> {
+        let mut segment = EWF_SIGNATURE.to_vec();
+        segment.extend_from_slice(&[1, 1, 0, 0, 0]);
+
+        let mut volume = vec![0; 94];
+        volume[4..8].copy_from_slice(&2u32.to_le_bytes());
+        volume[8..12].copy_from_slice(&1u32.to_le_bytes());
+        volume[12..16].copy_from_slice(&512u32.to_le_bytes());
+        volume[16..20].copy_from_slice(&2u32.to_le_bytes());
+        push_section(&mut segment, "volume", &volume);
+
+        let mut sectors = chunks[0].clone();
+        sectors.extend_from_slice(&adler32(&chunks[0]).to_le_bytes());
+        let compressed_offset = sectors.len() as u32;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&chunks[1]).unwrap();
+        sectors.extend_from_slice(&encoder.finish().unwrap());
+        let base_offset = segment.len() as u64 + SECTION_DESCRIPTOR_SIZE;
+        push_section(&mut segment, "sectors", &sectors);
+
+        let mut table = vec![0; TABLE_HEADER_SIZE];
+        table[..4].copy_from_slice(&2u32.to_le_bytes());
+        table[8..16].copy_from_slice(&base_offset.to_le_bytes());
+        table.extend_from_slice(&0u32.to_le_bytes());
+        table.extend_from_slice(&(compressed_offset | TABLE_ENTRY_COMPRESSED).to_le_bytes());
+        push_section(&mut segment, "table", &table);
+        push_section(&mut segment, "done", &[]);
+        segment
+    }
+
+    fn mapped(bytes: &[u8]) -> Mmap {
+        let mut map = MmapMut::map_anon(bytes.len()).unwrap();
+        map.copy_from_slice(bytes);
+        map.make_read_only().unwrap()
+    }
+
+    #[test]
+    fn reads_chunks_through_the_table() {
+        let chunks = [vec![0xAB; 512], (0..512).map(|i| i as u8).collect()];
+        let segment = segment(&chunks);
+        let mut set = SegmentSet::default();
+        assert!(!read_sections(&segment, 0, &mut set));
+
+        let media = set.media.unwrap();
+        assert_eq!((media.chunk_count, media.chunk_size), (2, 512));
+        assert_eq!(media.media_size, 1024);
+        assert_eq!(set.chunks.len(), 2);
+        assert!(matches!(set.chunks[0].storage, ChunkStorage::Stored));
+        assert!(matches!(set.chunks[1].storage, ChunkStorage::Compressed));
+        assert_eq!(set.chunks[0].size, 516);
+
+        let segments = [mapped(&segment)];
+        let mut out = vec![0; 512];
+        for (chunk, expected) in set.chunks.iter().zip(&chunks) {
+            assert_eq!(read_chunk(&segments, chunk, &mut out), Some(true));
+            assert_eq!(&out, expected);
+        }
+    }
+
+    #[test]
+    fn flags_a_stored_chunk_with_a_bad_checksum() {
+        let chunks = [vec![0xAB; 512], vec![0; 512]];
+        let mut segment = segment(&chunks);
+        let mut set = SegmentSet::default();
+        read_sections(&segment, 0, &mut set);
+        segment[set.chunks[0].offset as usize] = 0xAC;
+
+        let mut out = vec![0; 512];
+        assert_eq!(
+            read_chunk(&[mapped(&segment)], &set.chunks[0], &mut out),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn fills_pattern_chunks() {
+        let chunk = ChunkLocation {
+            segment: 0,
+            offset: 0,
+            size: 0,
+            storage: ChunkStorage::Pattern(*b"abcdefgh"),
+        };
+        let mut out = vec![0; 512];
+        assert_eq!(read_chunk(&[], &chunk, &mut out), Some(true));
+        assert_eq!(&out[..12], b"abcdefghabcd");
+    }
+
+    #[test]
+    fn names_segments_past_e99() {
+        assert_eq!(segment_extension("E01", 2), "E02");
+        assert_eq!(segment_extension("e01", 99), "e99");
+        assert_eq!(segment_extension("E01", 100), "EAA");
+        assert_eq!(segment_extension("E01", 127), "EBB");
+        assert_eq!(segment_extension("E01", 776), "FAA");
+    }
+}
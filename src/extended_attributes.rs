@@ -0,0 +1,238 @@
+use serde::Serialize;
+
+// `FILE_FULL_EA_INFORMATION` header: next entry offset, flags, name length, value length.
+const EA_HEADER_SIZE: usize = 8;
+const FILE_NEED_EA: u8 = 0x80;
+
+// Linux metadata stored by WSL on files it creates.
+const LX_UID: &str = "$LXUID";
+const LX_GID: &str = "$LXGID";
+const LX_MOD: &str = "$LXMOD";
+const LX_DEV: &str = "$LXDEV";
+
+const S_IFMT: u32 = 0o170000;
+
+/// An `$EA` entry as stored, before the value is encoded for output.
+struct EaEntry<'a> {
+    name: String,
+    flags: u8,
+    value: &'a [u8],
+}
+
+/// One entry of an `$EA` attribute.
+#[derive(Debug, Serialize, Clone)]
+pub struct ExtendedAttribute {
+    pub name: String,
+    pub need_ea: bool, // The file can't be understood without this EA
+    pub value: String, // Hex
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LinuxFileType {
+    Fifo,
+    CharDevice,
+    Directory,
+    BlockDevice,
+    Regular,
+    Symlink,
+    Socket,
+}
+
+impl LinuxFileType {
+    fn from_mode(mode: u32) -> Option<Self> {
+        match mode & S_IFMT {
+            0o010000 => Some(Self::Fifo),
+            0o020000 => Some(Self::CharDevice),
+            0o040000 => Some(Self::Directory),
+            0o060000 => Some(Self::BlockDevice),
+            0o100000 => Some(Self::Regular),
+            0o120000 => Some(Self::Symlink),
+            0o140000 => Some(Self::Socket),
+            _ => None,
+        }
+    }
+}
+
+/// Linux owner, mode and device numbers of a file created through WSL.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct WslMetadata {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mode: Option<u32>,
+    pub file_type: Option<LinuxFileType>,
+    pub permissions: Option<String>, // As `ls -l` shows them, e.g. `rwxr-xr-x`
+    pub device_major: Option<u32>,   // For character and block devices
+    pub device_minor: Option<u32>,
+}
+
+impl WslMetadata {
+    /// Picks the WSL entries out of a file's extended attributes. Returns `None` if there
+    /// are none.
+    fn from_entries(entries: &[EaEntry]) -> Option<Self> {
+        let mut metadata = Self::default();
+        let mut found = false;
+
+        for entry in entries {
+            let word = |offset: usize| -> Option<u32> {
+                Some(u32::from_le_bytes(
+                    entry.value.get(offset..offset + 4)?.try_into().ok()?,
+                ))
+            };
+            match entry.name.as_str() {
+                LX_UID => metadata.uid = word(0),
+                LX_GID => metadata.gid = word(0),
+                LX_MOD => {
+                    metadata.mode = word(0);
+                    metadata.file_type = metadata.mode.and_then(LinuxFileType::from_mode);
+                    metadata.permissions = metadata.mode.map(permission_string);
+                }
+                LX_DEV => {
+                    metadata.device_major = word(0);
+                    metadata.device_minor = word(4);
+                }
+                _ => continue,
+            }
+            found = true;
+        }
+
+        found.then_some(metadata)
+    }
+}
+
+/// Formats the permission bits of `mode` like `ls -l`, including setuid, setgid and sticky.
+fn permission_string(mode: u32) -> String {
+    let special = [(0o4000, 's', 'S'), (0o2000, 's', 'S'), (0o1000, 't', 'T')];
+    let mut permissions = String::with_capacity(9);
+
+    for (i, (special_bit, set, unset)) in special.into_iter().enumerate() {
+        let bits = (mode >> (6 - 3 * i)) & 0o7;
+        permissions.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        permissions.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        permissions.push(match (bits & 0o1 != 0, mode & special_bit != 0) {
+            (true, true) => set,
+            (false, true) => unset,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+
+    permissions
+}
+
+/// Parses the `FILE_FULL_EA_INFORMATION` entries of an `$EA` attribute.
+fn parse_ea_entries(content: &[u8]) -> Vec<EaEntry<'_>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + EA_HEADER_SIZE <= content.len() {
+        let header = &content[offset..offset + EA_HEADER_SIZE];
+        let next_entry_offset = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let flags = header[4];
+        let name_length = header[5] as usize;
+        let value_length = u16::from_le_bytes([header[6], header[7]]) as usize;
+
+        // The name is NUL-terminated; the value follows.
+        let name_start = offset + EA_HEADER_SIZE;
+        let value_start = name_start + name_length + 1;
+        let Some(value) = content.get(value_start..value_start + value_length) else {
+            break;
+        };
+        if name_length == 0 {
+            break;
+        }
+        let name = String::from_utf8_lossy(&content[name_start..name_start + name_length]);
+        entries.push(EaEntry {
+            name: name.into_owned(),
+            flags,
+            value,
+        });
+
+        if next_entry_offset == 0 {
+            break;
+        }
+        offset += next_entry_offset;
+    }
+
+    entries
+}
+
+/// Decodes an `$EA` attribute into its entries and any WSL metadata.
+pub fn decode_extended_attributes(content: &[u8]) -> (Vec<ExtendedAttribute>, Option<WslMetadata>) {
+    let entries = parse_ea_entries(content);
+    let wsl = WslMetadata::from_entries(&entries);
+    let attributes = entries
+        .into_iter()
+        .map(|entry| ExtendedAttribute {
+            name: entry.name,
+            need_ea: entry.flags & FILE_NEED_EA != 0,
+            value: entry.value.iter().map(|b| format!("{b:02x}")).collect(),
+        })
+        .collect();
+
+    (attributes, wsl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `FILE_FULL_EA_INFORMATION` entry, padded to 4 bytes unless it is the last one.
+    fn ea_entry(name: &str, flags: u8, value: &[u8], last: bool) -> Vec<u8> {
+        let mut entry = vec![0; EA_HEADER_SIZE];
+        entry[4] = flags;
+        entry[5] = name.len() as u8;
+        entry[6..8].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        entry.extend(name.as_bytes());
+        entry.push(0);
+        entry.extend(value);
+        if !last {
+            entry.resize(entry.len().next_multiple_of(4), 0);
+            let next = entry.len() as u32;
+            entry[0..4].copy_from_slice(&next.to_le_bytes());
+        }
+        entry
+    }
+
+    #[test]
+    fn decodes_wsl_metadata() {
+        let content = [
+            ea_entry(LX_UID, 0, &1000u32.to_le_bytes(), false),
+            ea_entry(LX_GID, 0, &100u32.to_le_bytes(), false),
+            // A setuid executable
+            ea_entry(LX_MOD, 0, &0o104755u32.to_le_bytes(), false),
+            ea_entry("user.comment", FILE_NEED_EA, b"hi", true),
+        ]
+        .concat();
+
+        let (attributes, wsl) = decode_extended_attributes(&content);
+        let names: Vec<_> = attributes.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, [LX_UID, LX_GID, LX_MOD, "user.comment"]);
+        assert!(attributes[3].need_ea);
+        assert_eq!(attributes[3].value, "6869");
+
+        let wsl = wsl.unwrap();
+        assert_eq!((wsl.uid, wsl.gid), (Some(1000), Some(100)));
+        assert_eq!(wsl.file_type, Some(LinuxFileType::Regular));
+        assert_eq!(wsl.permissions.as_deref(), Some("rwsr-xr-x"));
+        assert_eq!(wsl.device_major, None);
+    }
+
+    #[test]
+    fn decodes_device_numbers_and_skips_plain_eas() {
+        let content = [
+            ea_entry(LX_MOD, 0, &0o020666u32.to_le_bytes(), false),
+            ea_entry(LX_DEV, 0, &[1, 0, 0, 0, 3, 0, 0, 0], true),
+        ]
+        .concat();
+        let (_, wsl) = decode_extended_attributes(&content);
+        let wsl = wsl.unwrap();
+        assert_eq!(wsl.file_type, Some(LinuxFileType::CharDevice));
+        assert_eq!((wsl.device_major, wsl.device_minor), (Some(1), Some(3)));
+
+        let (attributes, wsl) = decode_extended_attributes(&ea_entry("other", 0, b"x", true));
+        assert_eq!(attributes.len(), 1);
+        assert!(wsl.is_none());
+        assert_eq!(permission_string(0o1777), "rwxrwxrwt");
+    }
+}
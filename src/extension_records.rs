@@ -0,0 +1,244 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ntfs_logic::{AlternateFilename, DataStream, NtfsEntry, dedup_index_entries};
+
+type RecordKey = (u64, u16);
+
+/// Keys under which extension records of `base` may be filed. NTFS increments the
+/// sequence number when a record is freed, so the extensions of a deleted base record
+/// still reference the previous sequence number.
+fn base_keys(base: &NtfsEntry) -> impl Iterator<Item = RecordKey> {
+    let key = (base.mft_record_number, base.sequence_number);
+    let previous =
+        (!base.is_in_use).then(|| (base.mft_record_number, base.sequence_number.wrapping_sub(1)));
+    std::iter::once(key).chain(previous)
+}
+
+/// Joins the fragments of each non-resident stream (split across records by VCN range)
+/// into a single stream. Sizes are only valid in the fragment starting at VCN 0.
+fn merge_stream_fragments(streams: Vec<DataStream>) -> Vec<DataStream> {
+    let mut merged: Vec<DataStream> = Vec::new();
+    let mut fragments: HashMap<Option<String>, Vec<DataStream>> = HashMap::new();
+
+    for stream in streams {
+        if stream.resident {
+            merged.push(stream);
+            continue;
+        }
+        let group = fragments.entry(stream.name.clone()).or_default();
+        if group.is_empty() {
+            // Placeholder that keeps the original stream order.
+            merged.push(stream.clone());
+        }
+        group.push(stream);
+    }
+
+    for stream in merged.iter_mut().filter(|s| !s.resident) {
+        let Some(mut group) = fragments.remove(&stream.name) else {
+            continue;
+        };
+        group.sort_by_key(|f| f.lowest_vcn);
+        // Duplicate copies of the same extension record carry the same fragment.
+        group.dedup_by_key(|f| f.lowest_vcn);
+
+        let mut runs = Vec::new();
+        for fragment in &group {
+            runs.extend(fragment.data_runs.iter().flatten().cloned());
+        }
+
+        *stream = group.swap_remove(0);
+        stream.data_runs = if runs.is_empty() { None } else { Some(runs) };
+    }
+
+    merged
+}
+
+fn merge_into_base(base: &mut NtfsEntry, extensions: &[&NtfsEntry]) {
+    let mut streams = std::mem::take(&mut base.data_streams);
+
+    for extension in extensions {
+        if !extension.filename.is_empty() {
+            if base.filename.is_empty() {
+                base.filename = extension.filename.clone();
+                base.filename_namespace = extension.filename_namespace;
+                base.parent_mft_record = extension.parent_mft_record;
+                base.parent_sequence = extension.parent_sequence;
+                base.allocated_size = extension.allocated_size;
+                base.real_size = extension.real_size;
+                base.fn_timestamps = extension.fn_timestamps.clone();
+                base.fn_file_attributes = extension.fn_file_attributes.clone();
+                base.timestomp_indicators = base.detect_timestomping();
+            } else if let (Some(timestamps), Some(file_attributes)) =
+                (&extension.fn_timestamps, &extension.fn_file_attributes)
+            {
+                base.alternate_filenames.push(AlternateFilename {
+                    name: extension.filename.clone(),
+                    namespace: extension.filename_namespace,
+                    timestamps: timestamps.clone(),
+                    file_attributes: file_attributes.clone(),
+                });
+            }
+        }
+        base.alternate_filenames
+            .extend(extension.alternate_filenames.iter().cloned());
+
+        streams.extend(extension.data_streams.iter().cloned());
+        base.index_entries
+            .extend(extension.index_entries.iter().cloned());
+
+        if base.sddl.is_none() {
+            base.owner_sid = extension.owner_sid.clone();
+            base.sddl = extension.sddl.clone();
+        }
+        if base.object_id.is_none() {
+            base.object_id = extension.object_id.clone();
+        }
+        if base.reparse.is_none() {
+            base.reparse = extension.reparse.clone();
+        }
+        base.has_extended_attributes |= extension.has_extended_attributes;
+        if base.extended_attributes.is_empty() {
+            base.extended_attributes = extension.extended_attributes.clone();
+            base.wsl = extension.wsl.clone();
+        }
+
+        base.extension_records.push(extension.mft_record_number);
+    }
+
+    base.data_streams = merge_stream_fragments(streams);
+    dedup_index_entries(&mut base.index_entries);
+}
+
+/// Second pass over the scanned entries: folds extension records into their base record,
+/// so that attributes (and data run fragments) spread over several records end up in one
+/// entry. Extension records whose base record wasn't found are kept as separate entries.
+pub fn merge_extension_records(entries: Vec<NtfsEntry>) -> Vec<NtfsEntry> {
+    let known_bases: HashSet<RecordKey> = entries
+        .iter()
+        .filter(|e| e.base_mft_record.is_none())
+        .flat_map(base_keys)
+        .collect();
+
+    let (extensions, mut entries): (Vec<NtfsEntry>, Vec<NtfsEntry>) =
+        entries.into_iter().partition(|e| {
+            matches!(
+                (e.base_mft_record, e.base_sequence),
+                (Some(record), Some(sequence)) if known_bases.contains(&(record, sequence))
+            )
+        });
+
+    let mut extensions_by_base: HashMap<RecordKey, Vec<&NtfsEntry>> = HashMap::new();
+    for extension in &extensions {
+        if let (Some(record), Some(sequence)) = (extension.base_mft_record, extension.base_sequence)
+        {
+            extensions_by_base
+                .entry((record, sequence))
+                .or_default()
+                .push(extension);
+        }
+    }
+
+    for base in entries.iter_mut().filter(|e| e.base_mft_record.is_none()) {
+        let mut matched: Vec<&NtfsEntry> = base_keys(base)
+            .filter_map(|key| extensions_by_base.get(&key))
+            .flatten()
+            .copied()
+            .collect();
+        if matched.is_empty() {
+            continue;
+        }
+
+        // The same extension record can be found more than once; prefer the in-use copy.
+        matched.sort_by_key(|e| (e.mft_record_number, !e.is_in_use));
+        matched.dedup_by_key(|e| e.mft_record_number);
+
+        merge_into_base(base, &matched);
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntfs_logic::tests::{
+        data_fragment, file_name_attribute, mft_record, resident_attribute,
+    };
+
+    const ATTR_ATTRIBUTE_LIST: u32 = 0x20;
+
+    fn attribute_list_entry(attribute_type: u32, lowest_vcn: u64, record: u64) -> Vec<u8> {
+        let mut entry = vec![0; 32];
+        entry[0..4].copy_from_slice(&attribute_type.to_le_bytes());
+        entry[4..6].copy_from_slice(&32u16.to_le_bytes());
+        entry[7] = 26;
+        entry[8..16].copy_from_slice(&lowest_vcn.to_le_bytes());
+        entry[16..24].copy_from_slice(&(record | 1 << 48).to_le_bytes());
+        entry
+    }
+
+    fn parse(record: &[u8]) -> NtfsEntry {
+        crate::ntfs_logic::tests::parse_record(record).unwrap()
+    }
+
+    #[test]
+    fn merges_data_run_fragments_from_an_extension_record() {
+        let list = [
+            attribute_list_entry(0x30, 0, 40),
+            attribute_list_entry(0x80, 0, 40),
+            attribute_list_entry(0x80, 2, 41),
+        ]
+        .concat();
+        let base = mft_record(
+            1024,
+            40,
+            1,
+            &[
+                resident_attribute(ATTR_ATTRIBUTE_LIST, "", &list),
+                file_name_attribute("big.bin", 5),
+                // 2 clusters at LCN 16
+                data_fragment(0, 3 * 4096, &[0x11, 0x02, 0x10, 0x00]),
+            ],
+        );
+        // 1 cluster at LCN 32, and a reference back to the base record.
+        let mut extension = mft_record(
+            1024,
+            41,
+            1,
+            &[data_fragment(2, 0, &[0x11, 0x01, 0x20, 0x00])],
+        );
+        extension[32..40].copy_from_slice(&(40u64 | 1 << 48).to_le_bytes());
+
+        let merged = merge_extension_records(vec![parse(&extension), parse(&base)]);
+        assert_eq!(merged.len(), 1);
+        let entry = &merged[0];
+        assert_eq!(entry.filename, "big.bin");
+        assert_eq!(entry.attribute_list.as_ref().unwrap().len(), 3);
+        assert_eq!(entry.extension_records, vec![41]);
+
+        let stream = &entry.data_streams[0];
+        assert_eq!(stream.size, 3 * 4096);
+        let runs: Vec<_> = stream
+            .data_runs
+            .iter()
+            .flatten()
+            .map(|r| (r.cluster_offset, r.cluster_count))
+            .collect();
+        assert_eq!(runs, vec![(Some(16), 2), (Some(32), 1)]);
+    }
+
+    #[test]
+    fn keeps_extension_records_without_a_base() {
+        let mut extension = mft_record(
+            1024,
+            41,
+            1,
+            &[data_fragment(2, 0, &[0x11, 0x01, 0x20, 0x00])],
+        );
+        extension[32..40].copy_from_slice(&(40u64 | 1 << 48).to_le_bytes());
+
+        let merged = merge_extension_records(vec![parse(&extension)]);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].base_mft_record, Some(40));
+    }
+}
@@ -0,0 +1,101 @@
+/// A canonical Huffman code, as used by XPRESS Huffman and LZX.
+///
+/// Codes are decoded one bit length at a time rather than through a lookup table: both
+/// formats rebuild their codes for every chunk or block, so building a table with an entry
+/// for every possible input wouldn't pay for itself.
+pub struct HuffmanCode {
+    /// Number of codes of each bit length (index 0 unused).
+    counts: Vec<u16>,
+    /// Symbols sorted by code length, then by symbol value.
+    symbols: Vec<u16>,
+    max_length: u32,
+}
+
+impl HuffmanCode {
+    /// Builds the code from the bit length of each symbol (0 for unused symbols). Returns
+    /// `None` if the lengths describe more codes than fit (incomplete codes are allowed).
+    pub fn new(lengths: &[u8], max_length: u32) -> Option<Self> {
+        let mut counts = vec![0u16; max_length as usize + 1];
+        for &length in lengths {
+            if length as u32 > max_length {
+                return None;
+            }
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut available: i64 = 1;
+        for &count in &counts[1..] {
+            available = (available << 1) - count as i64;
+            if available < 0 {
+                return None;
+            }
+        }
+
+        let mut symbols = Vec::with_capacity(lengths.len());
+        for length in 1..=max_length as u8 {
+            symbols.extend(
+                (0..lengths.len() as u16).filter(|&symbol| lengths[symbol as usize] == length),
+            );
+        }
+
+        Some(Self {
+            counts,
+            symbols,
+            max_length,
+        })
+    }
+
+    /// Decodes the symbol at the start of `bits`, which holds the next `max_length` bits
+    /// of input (first bit in the most significant position). Returns the symbol and the
+    /// number of bits it used.
+    pub fn decode(&self, bits: u32) -> Option<(u16, u32)> {
+        let mut code: u32 = 0;
+        let mut first: u32 = 0;
+        let mut index: usize = 0;
+
+        for length in 1..=self.max_length {
+            code |= (bits >> (self.max_length - length)) & 1;
+            let count = self.counts[length as usize] as u32;
+            if code < first + count {
+                return Some((self.symbols[index + (code - first) as usize], length));
+            }
+            index += count as usize;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assigns_canonical_codes() {
+        // Shorter codes first, and symbols of the same length in order: 1 = 0, 0 = 10,
+        // 2 = 110, 3 = 111.
+        let code = HuffmanCode::new(&[2, 1, 3, 3], 3).unwrap();
+        assert_eq!(code.decode(0b000), Some((1, 1)));
+        assert_eq!(code.decode(0b011), Some((1, 1)));
+        assert_eq!(code.decode(0b100), Some((0, 2)));
+        assert_eq!(code.decode(0b110), Some((2, 3)));
+        assert_eq!(code.decode(0b111), Some((3, 3)));
+    }
+
+    #[test]
+    fn rejects_oversubscribed_lengths() {
+        assert!(HuffmanCode::new(&[1, 1, 1], 2).is_none());
+        assert!(HuffmanCode::new(&[3], 2).is_none());
+    }
+
+    #[test]
+    fn allows_incomplete_codes() {
+        let code = HuffmanCode::new(&[0, 2, 0, 2], 2).unwrap();
+        assert_eq!(code.decode(0b00), Some((1, 2)));
+        assert_eq!(code.decode(0b01), Some((3, 2)));
+        assert_eq!(code.decode(0b10), None);
+    }
+}
@@ -0,0 +1,451 @@
+use chrono::{DateTime, Datelike, Utc};
+use serde::Serialize;
+
+use crate::disk_image::ImageSlice;
+use crate::ntfs_logic::{
+    FileAttributes, FileNameTimestamps, FixupStatus, apply_fixups, parse_filename_content,
+};
+
+pub const INDX_MAGIC: &[u8; 4] = b"INDX";
+
+/// Name of the filename index of a directory.
+pub const I30_INDEX_NAME: &str = "$I30";
+
+// Index entry flags
+const INDEX_ENTRY_HAS_SUBNODE: u32 = 0x01;
+const INDEX_ENTRY_LAST: u32 = 0x02;
+
+/// Set in the `$FILE_NAME` flags (rather than `FILE_ATTR_DIRECTORY`) for directories.
+const FILE_NAME_FLAG_DIRECTORY: u32 = 0x1000_0000;
+
+// Offset of the index node header in an INDX block.
+const INDX_NODE_HEADER_OFFSET: usize = 0x18;
+
+// Index entry header (16 bytes) plus a `$FILE_NAME` key with an empty name.
+const MIN_FILENAME_ENTRY_SIZE: usize = 16 + 66;
+
+// Bounds for the size of carved INDX blocks (4096 on nearly every volume).
+const MIN_INDEX_BLOCK_SIZE: usize = 512;
+const MAX_INDEX_BLOCK_SIZE: usize = 65536;
+
+// Timestamps outside this range mark a slack candidate as garbage.
+const MIN_PLAUSIBLE_YEAR: i32 = 1980;
+const MAX_PLAUSIBLE_YEAR: i32 = 2100;
+
+/// What became of the child MFT record referenced by an index entry.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexChildStatus {
+    /// The child's MFT record was found, with the same sequence number.
+    Present,
+    /// The child's MFT record was found, but has since been freed.
+    Deleted,
+    /// The child's MFT record number now belongs to a different file.
+    Overwritten,
+    /// The child's MFT record was not found in the image.
+    Missing,
+}
+
+/// One entry of a directory's `$I30` index: a copy of the child's `$FILE_NAME`.
+#[derive(Debug, Serialize, Clone)]
+pub struct IndexEntry {
+    pub mft_record: u64,
+    pub sequence: u16,
+    pub filename: String,
+    pub namespace: u8,
+    pub parent_mft_record: u64,
+    pub parent_sequence: u16,
+    pub is_directory: bool,
+    pub allocated_size: u64,
+    pub real_size: u64,
+    pub timestamps: FileNameTimestamps,
+    pub file_attributes: FileAttributes,
+
+    // Filled in by `paths::resolve_full_paths`
+    pub full_path: Option<String>,
+    pub child_status: Option<IndexChildStatus>,
+}
+
+impl IndexEntry {
+    /// Parses an index entry whose key is a `$FILE_NAME`. Returns `None` for the
+    /// terminating entry, or if the key isn't a valid filename.
+    pub fn parse(entry: &[u8]) -> Option<Self> {
+        let reference = u64::from_le_bytes(entry.get(0..8)?.try_into().ok()?);
+        let key_length = u16::from_le_bytes(entry.get(10..12)?.try_into().ok()?) as usize;
+        let key = entry.get(16..16 + key_length)?;
+        let fname = parse_filename_content(key)?;
+
+        Some(Self {
+            mft_record: reference & 0x0000_FFFF_FFFF_FFFF,
+            sequence: (reference >> 48) as u16,
+            filename: fname.name,
+            namespace: fname.namespace,
+            parent_mft_record: fname.parent_reference & 0x0000_FFFF_FFFF_FFFF,
+            parent_sequence: (fname.parent_reference >> 48) as u16,
+            is_directory: fname.flags & FILE_NAME_FLAG_DIRECTORY != 0,
+            allocated_size: fname.allocated_size,
+            real_size: fname.real_size,
+            timestamps: fname.timestamps,
+            file_attributes: FileAttributes::from_flags(fname.flags),
+            full_path: None,
+            child_status: None,
+        })
+    }
+}
+
+/// Walks the live entries of an index node, parsing each with `parse` (which gets the
+/// entry without its trailing sub-node VCN). `node` starts at the index node header, and
+/// the offsets in the header (and in the result) are relative to it.
+///
+/// Also returns the end of the live entries and of the space allocated to the node; the
+/// gap between the two is slack that may hold entries of deleted files.
+fn walk_index_node<T>(
+    node: &[u8],
+    parse: impl Fn(&[u8]) -> Option<T>,
+) -> (Vec<(usize, T)>, usize, usize) {
+    let mut entries = Vec::new();
+    if node.len() < 16 {
+        return (entries, 0, 0);
+    }
+
+    let entries_offset = u32::from_le_bytes(node[0..4].try_into().unwrap()) as usize;
+    let entries_end = (u32::from_le_bytes(node[4..8].try_into().unwrap()) as usize).min(node.len());
+    let allocated_end =
+        (u32::from_le_bytes(node[8..12].try_into().unwrap()) as usize).min(node.len());
+
+    let mut offset = entries_offset;
+    while offset + 16 <= entries_end {
+        let entry_length =
+            u16::from_le_bytes(node[offset + 8..offset + 10].try_into().unwrap()) as usize;
+        let flags = u32::from_le_bytes(node[offset + 12..offset + 16].try_into().unwrap());
+        if entry_length < 16 || offset + entry_length > entries_end {
+            break;
+        }
+
+        // The last entry carries no key, only (maybe) a sub-node pointer.
+        if flags & INDEX_ENTRY_LAST != 0 {
+            break;
+        }
+
+        let key_end = if flags & INDEX_ENTRY_HAS_SUBNODE != 0 {
+            entry_length.saturating_sub(8)
+        } else {
+            entry_length
+        };
+        if let Some(entry) = parse(&node[offset..offset + key_end]) {
+            entries.push((offset, entry));
+        }
+
+        offset += entry_length;
+    }
+
+    (entries, entries_end, allocated_end)
+}
+
+fn parse_index_node<T>(node: &[u8], parse: impl Fn(&[u8]) -> Option<T>) -> Vec<T> {
+    let (entries, _, _) = walk_index_node(node, parse);
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// Whether an index entry found in slack space looks like a real (if stale) entry rather
+/// than leftover bytes that happen to parse.
+fn is_plausible_slack_entry(entry: &IndexEntry) -> bool {
+    let plausible_time = |t: Option<DateTime<Utc>>| {
+        t.is_some_and(|t| (MIN_PLAUSIBLE_YEAR..=MAX_PLAUSIBLE_YEAR).contains(&t.year()))
+    };
+
+    entry.namespace <= 3
+        && !entry.filename.is_empty()
+        && !entry.filename.chars().any(|c| c.is_control())
+        && entry.parent_mft_record < 1 << 40
+        && entry.real_size <= entry.allocated_size
+        && plausible_time(entry.timestamps.created)
+        && plausible_time(entry.timestamps.modified)
+}
+
+/// Scans the slack of an index node (`node[start..end]`) for remnants of index entries.
+fn carve_index_slack(node: &[u8], start: usize, end: usize) -> Vec<(usize, IndexEntry)> {
+    let mut entries = Vec::new();
+    let mut offset = start.next_multiple_of(8);
+
+    while offset + MIN_FILENAME_ENTRY_SIZE <= end {
+        let entry_length =
+            u16::from_le_bytes(node[offset + 8..offset + 10].try_into().unwrap()) as usize;
+        let parsed = (entry_length >= MIN_FILENAME_ENTRY_SIZE && offset + entry_length <= end)
+            .then(|| IndexEntry::parse(&node[offset..offset + entry_length]))
+            .flatten()
+            .filter(is_plausible_slack_entry);
+
+        match parsed {
+            Some(entry) => {
+                entries.push((offset, entry));
+                offset += entry_length.next_multiple_of(8);
+            }
+            None => offset += 8,
+        }
+    }
+
+    entries
+}
+
+/// An index entry carved from an INDX block found anywhere in the image.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename = "indx_entry")]
+pub struct CarvedIndexEntry {
+    /// Image offset of the INDX block the entry was found in.
+    pub indx_offset: u64,
+    /// Image offset of the entry itself.
+    pub entry_offset: u64,
+    /// Partition the INDX block was found in, for images with a partition table.
+    pub partition_index: Option<u32>,
+    /// VCN of the INDX block within its directory's `$INDEX_ALLOCATION`.
+    pub vcn: u64,
+    /// Found in the unused space after the block's last live entry.
+    pub from_slack: bool,
+    pub fixup_status: FixupStatus,
+    #[serde(flatten)]
+    pub entry: IndexEntry,
+}
+
+/// Reads the block size of a carved INDX block from its index node header.
+fn index_block_size_from_header(header: &[u8]) -> Option<usize> {
+    let field = header.get(INDX_NODE_HEADER_OFFSET + 8..INDX_NODE_HEADER_OFFSET + 12)?;
+    let block_size = u32::from_le_bytes(field.try_into().ok()?) as usize + INDX_NODE_HEADER_OFFSET;
+
+    if block_size.is_power_of_two()
+        && (MIN_INDEX_BLOCK_SIZE..=MAX_INDEX_BLOCK_SIZE).contains(&block_size)
+    {
+        Some(block_size)
+    } else {
+        None
+    }
+}
+
+/// Carves the INDX block at `offset`: both its live entries and those left in its slack.
+/// Without a boot sector (`index_block_size` is `None`), the block size is taken from the
+/// block's own header.
+pub fn carve_indx_block(
+    disk_image_buffer: ImageSlice<'_>,
+    offset: usize,
+    index_block_size: Option<usize>,
+) -> Option<Vec<CarvedIndexEntry>> {
+    let block_size = match index_block_size {
+        Some(block_size) => block_size,
+        None => index_block_size_from_header(
+            &disk_image_buffer.get_clipped(offset, INDX_NODE_HEADER_OFFSET + 12)?,
+        )?,
+    };
+
+    let mut block = disk_image_buffer
+        .get(offset..offset + block_size)?
+        .into_owned();
+    if block.len() < INDX_NODE_HEADER_OFFSET + 16 || &block[0..4] != INDX_MAGIC {
+        return None;
+    }
+    let fixup_status = apply_fixups(&mut block)?;
+    let vcn = u64::from_le_bytes(block[16..24].try_into().ok()?);
+
+    let node = &block[INDX_NODE_HEADER_OFFSET..];
+    let (live, entries_end, allocated_end) = walk_index_node(node, IndexEntry::parse);
+    let slack = carve_index_slack(node, entries_end, allocated_end);
+
+    let carved = live
+        .into_iter()
+        .map(|entry| (entry, false))
+        .chain(slack.into_iter().map(|entry| (entry, true)))
+        .map(|((node_offset, entry), from_slack)| CarvedIndexEntry {
+            indx_offset: offset as u64,
+            entry_offset: (offset + INDX_NODE_HEADER_OFFSET + node_offset) as u64,
+            partition_index: None,
+            vcn,
+            from_slack,
+            fixup_status,
+            entry,
+        })
+        .collect();
+
+    Some(carved)
+}
+
+/// Parses the content of a resident `$INDEX_ROOT` attribute of any index, with `parse`
+/// for the entries. Returns the entries stored directly in the root, and the size of the
+/// INDX blocks in `$INDEX_ALLOCATION`.
+pub fn parse_index_root_with<T>(
+    content: &[u8],
+    parse: impl Fn(&[u8]) -> Option<T>,
+) -> Option<(Vec<T>, u32)> {
+    if content.len() < 32 {
+        return None;
+    }
+
+    let index_block_size = u32::from_le_bytes(content[8..12].try_into().ok()?);
+    Some((parse_index_node(&content[16..], parse), index_block_size))
+}
+
+/// Parses the `$INDEX_ROOT` of a filename index (`$I30`).
+pub fn parse_index_root(content: &[u8]) -> Option<(Vec<IndexEntry>, u32)> {
+    // Only filename indexes (keyed by `$FILE_NAME`, type 0x30) are decoded.
+    let indexed_attr_type = u32::from_le_bytes(content.get(0..4)?.try_into().ok()?);
+    if indexed_attr_type != 0x30 {
+        return None;
+    }
+
+    parse_index_root_with(content, IndexEntry::parse)
+}
+
+/// Parses one INDX block in place (the fixups are applied to `block`).
+fn parse_index_block<T>(
+    block: &mut [u8],
+    parse: impl Fn(&[u8]) -> Option<T>,
+) -> Option<(Vec<T>, FixupStatus)> {
+    if block.len() < INDX_NODE_HEADER_OFFSET + 16 || &block[0..4] != INDX_MAGIC {
+        return None;
+    }
+
+    let fixup_status = apply_fixups(block)?;
+    Some((
+        parse_index_node(&block[INDX_NODE_HEADER_OFFSET..], parse),
+        fixup_status,
+    ))
+}
+
+/// Parses every INDX block of an `$INDEX_ALLOCATION` stream of any index, with `parse`
+/// for the entries.
+pub fn parse_index_allocation_with<T>(
+    data: &mut [u8],
+    index_block_size: usize,
+    parse: impl Fn(&[u8]) -> Option<T> + Copy,
+) -> Vec<T> {
+    if index_block_size == 0 {
+        return Vec::new();
+    }
+
+    data.chunks_exact_mut(index_block_size)
+        .filter_map(|block| parse_index_block(block, parse))
+        .flat_map(|(entries, _)| entries)
+        .collect()
+}
+
+/// Parses the `$INDEX_ALLOCATION` of a filename index (`$I30`).
+pub fn parse_index_allocation(data: &mut [u8], index_block_size: usize) -> Vec<IndexEntry> {
+    parse_index_allocation_with(data, index_block_size, IndexEntry::parse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntfs_logic::tests::file_name_attribute;
+
+    const USN: [u8; 2] = [0x07, 0x00];
+
+    /// An index entry keyed by the `$FILE_NAME` of `name` (record `child`, in directory 5).
+    fn index_entry(child: u64, name: &str) -> Vec<u8> {
+        let key_length = 66 + 2 * name.len();
+        let file_name = file_name_attribute(name, 5);
+        let mut entry = vec![0; 16];
+        entry[0..8].copy_from_slice(&(child | 1 << 48).to_le_bytes());
+        entry[10..12].copy_from_slice(&(key_length as u16).to_le_bytes());
+        entry.extend(&file_name[24..24 + key_length]);
+        // Created and modified in 2019, so that slack entries look plausible.
+        for field in [24, 32] {
+            entry[field..field + 8].copy_from_slice(&132_000_000_001_234_567u64.to_le_bytes());
+        }
+        entry.resize(entry.len().next_multiple_of(8), 0);
+        let entry_length = entry.len() as u16;
+        entry[8..10].copy_from_slice(&entry_length.to_le_bytes());
+        entry
+    }
+
+    fn last_entry() -> Vec<u8> {
+        let mut entry = vec![0; 16];
+        entry[8..10].copy_from_slice(&16u16.to_le_bytes());
+        entry[12..16].copy_from_slice(&INDEX_ENTRY_LAST.to_le_bytes());
+        entry
+    }
+
+    /// An index node header followed by `entries`, which start `entries_offset` bytes
+    /// into the node.
+    fn index_node(entries_offset: usize, entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut node = vec![0; entries_offset];
+        node[0..4].copy_from_slice(&(entries_offset as u32).to_le_bytes());
+        node.extend(entries.concat());
+        let end = node.len() as u32;
+        node[4..8].copy_from_slice(&end.to_le_bytes());
+        node[8..12].copy_from_slice(&end.to_le_bytes());
+        node
+    }
+
+    /// A 4 KiB INDX block holding `entries`, then `slack` after the last one, with the
+    /// sector trailers swapped into the USA.
+    fn indx_block(entries: &[Vec<u8>], slack: &[u8]) -> Vec<u8> {
+        let mut block = vec![0; 4096];
+        // Entries start after the USA, at 0x40 in the block.
+        let mut node = index_node(0x40 - INDX_NODE_HEADER_OFFSET, entries);
+        // The rest of the block is allocated to the node, and may hold slack.
+        node[8..12].copy_from_slice(&(4096 - INDX_NODE_HEADER_OFFSET as u32).to_le_bytes());
+        block[INDX_NODE_HEADER_OFFSET..INDX_NODE_HEADER_OFFSET + node.len()].copy_from_slice(&node);
+        let slack_start = INDX_NODE_HEADER_OFFSET + node.len();
+        block[slack_start..slack_start + slack.len()].copy_from_slice(slack);
+        block[0..4].copy_from_slice(INDX_MAGIC);
+        block[4..6].copy_from_slice(&0x28u16.to_le_bytes());
+        block[6..8].copy_from_slice(&9u16.to_le_bytes());
+        block[0x28..0x2A].copy_from_slice(&USN);
+
+        for sector in 0..8 {
+            let end = sector * 512 + 510;
+            let usa = 0x2A + sector * 2;
+            block.copy_within(end..end + 2, usa);
+            block[end..end + 2].copy_from_slice(&USN);
+        }
+        block
+    }
+
+    #[test]
+    fn parses_the_entries_of_an_index_root() {
+        let mut content = vec![0; 16];
+        content[0..4].copy_from_slice(&0x30u32.to_le_bytes());
+        content[8..12].copy_from_slice(&4096u32.to_le_bytes());
+        content.extend(index_node(16, &[index_entry(40, "a.txt"), last_entry()]));
+
+        let (entries, block_size) = parse_index_root(&content).unwrap();
+        assert_eq!(block_size, 4096);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].mft_record, 40);
+        assert_eq!(entries[0].sequence, 1);
+        assert_eq!(entries[0].filename, "a.txt");
+        assert_eq!(entries[0].parent_mft_record, 5);
+    }
+
+    #[test]
+    fn parses_indx_blocks_after_fixups() {
+        let mut data = indx_block(
+            &[
+                index_entry(40, "a.txt"),
+                index_entry(41, "b.txt"),
+                last_entry(),
+            ],
+            &[],
+        );
+        data.extend(vec![0; 4096]); // An unused block
+
+        let entries = parse_index_allocation(&mut data, 4096);
+        let names: Vec<_> = entries.iter().map(|e| e.filename.as_str()).collect();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn carves_deleted_entries_from_indx_slack() {
+        // A stale entry, then bytes that don't parse as one.
+        let slack = [index_entry(42, "old.txt"), vec![0xFF; 96]].concat();
+        let block = indx_block(&[index_entry(40, "a.txt"), last_entry()], &slack);
+
+        let carved = carve_indx_block(ImageSlice::new(&block), 0, None).unwrap();
+        let found: Vec<_> = carved
+            .iter()
+            .map(|c| (c.entry.filename.as_str(), c.entry.mft_record, c.from_slack))
+            .collect();
+        assert_eq!(found, [("a.txt", 40, false), ("old.txt", 42, true)]);
+        assert_eq!(carved[0].entry_offset, 0x40);
+        assert_eq!(carved[1].fixup_status, FixupStatus::Ok);
+    }
+}
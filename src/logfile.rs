@@ -0,0 +1,816 @@
+use serde::{Serialize, Serializer};
+
+use crate::boot_sector::VolumeGeometry;
+use crate::disk_image::ImageSlice;
+use crate::index::IndexEntry;
+use crate::ntfs_logic::{DataRun, FixupStatus, NtfsEntry, apply_fixups, parse_mapping_pairs};
+use crate::stream_reader::read_non_resident;
+
+const RSTR_MAGIC: &[u8; 4] = b"RSTR";
+const RCRD_MAGIC: &[u8; 4] = b"RCRD";
+
+const LOGFILE_MFT_RECORD: u64 = 2;
+
+// `$LogFile` is 64 MiB by default; anything far bigger is most likely a corrupted record.
+const MAX_LOGFILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Used when neither restart page is readable (the values of every NTFS version so far).
+const DEFAULT_LOG_PAGE_SIZE: usize = 4096;
+const DEFAULT_LOG_PAGE_DATA_OFFSET: usize = 0x40;
+
+// Size of the LFS record header, which precedes the client (NTFS) data of each record.
+const LOG_RECORD_HEADER_SIZE: usize = 0x30;
+// Size of the NTFS log record header, up to the LCN list.
+const NTFS_LOG_RECORD_HEADER_SIZE: usize = 0x20;
+const MAX_CLIENT_DATA_LENGTH: usize = 64 * 1024;
+
+// LFS record types. Restart records are checkpoints, not operations.
+const LOG_RECORD_TYPE_CLIENT: u32 = 1;
+const LOG_RECORD_TYPE_CLIENT_RESTART: u32 = 2;
+
+// Restart area flags
+const RESTART_VOLUME_IS_CLEAN: u16 = 0x02;
+
+// MFT record header fields written by InitializeFileRecordSegment.
+const FILE_RECORD_MAGIC: &[u8; 4] = b"FILE";
+const FILE_RECORD_HEADER_SIZE: usize = 0x30;
+const FILE_RECORD_IN_USE: u16 = 0x01;
+const FILE_RECORD_IS_DIRECTORY: u16 = 0x02;
+
+/// Operation codes of NTFS log records (the redo and undo halves of each record).
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogOperation {
+    Noop,
+    CompensationLogRecord,
+    InitializeFileRecordSegment,
+    DeallocateFileRecordSegment,
+    WriteEndOfFileRecordSegment,
+    CreateAttribute,
+    DeleteAttribute,
+    UpdateResidentValue,
+    UpdateNonresidentValue,
+    UpdateMappingPairs,
+    DeleteDirtyClusters,
+    SetNewAttributeSizes,
+    AddIndexEntryRoot,
+    DeleteIndexEntryRoot,
+    AddIndexEntryAllocation,
+    DeleteIndexEntryAllocation,
+    WriteEndOfIndexBuffer,
+    SetIndexEntryVcnRoot,
+    SetIndexEntryVcnAllocation,
+    UpdateFileNameRoot,
+    UpdateFileNameAllocation,
+    SetBitsInNonresidentBitMap,
+    ClearBitsInNonresidentBitMap,
+    HotFix,
+    EndTopLevelAction,
+    PrepareTransaction,
+    CommitTransaction,
+    ForgetTransaction,
+    OpenNonresidentAttribute,
+    OpenAttributeTableDump,
+    AttributeNamesDump,
+    DirtyPageTableDump,
+    TransactionTableDump,
+    UpdateRecordDataRoot,
+    UpdateRecordDataAllocation,
+    UpdateRelativeDataInIndex,
+    UpdateRelativeDataInIndex2,
+    ZeroEndOfFileRecord,
+    Unknown(u16),
+}
+
+impl LogOperation {
+    fn from_code(code: u16) -> Self {
+        match code {
+            0x00 => Self::Noop,
+            0x01 => Self::CompensationLogRecord,
+            0x02 => Self::InitializeFileRecordSegment,
+            0x03 => Self::DeallocateFileRecordSegment,
+            0x04 => Self::WriteEndOfFileRecordSegment,
+            0x05 => Self::CreateAttribute,
+            0x06 => Self::DeleteAttribute,
+            0x07 => Self::UpdateResidentValue,
+            0x08 => Self::UpdateNonresidentValue,
+            0x09 => Self::UpdateMappingPairs,
+            0x0A => Self::DeleteDirtyClusters,
+            0x0B => Self::SetNewAttributeSizes,
+            0x0C => Self::AddIndexEntryRoot,
+            0x0D => Self::DeleteIndexEntryRoot,
+            0x0E => Self::AddIndexEntryAllocation,
+            0x0F => Self::DeleteIndexEntryAllocation,
+            0x10 => Self::WriteEndOfIndexBuffer,
+            0x11 => Self::SetIndexEntryVcnRoot,
+            0x12 => Self::SetIndexEntryVcnAllocation,
+            0x13 => Self::UpdateFileNameRoot,
+            0x14 => Self::UpdateFileNameAllocation,
+            0x15 => Self::SetBitsInNonresidentBitMap,
+            0x16 => Self::ClearBitsInNonresidentBitMap,
+            0x17 => Self::HotFix,
+            0x18 => Self::EndTopLevelAction,
+            0x19 => Self::PrepareTransaction,
+            0x1A => Self::CommitTransaction,
+            0x1B => Self::ForgetTransaction,
+            0x1C => Self::OpenNonresidentAttribute,
+            0x1D => Self::OpenAttributeTableDump,
+            0x1E => Self::AttributeNamesDump,
+            0x1F => Self::DirtyPageTableDump,
+            0x20 => Self::TransactionTableDump,
+            0x21 => Self::UpdateRecordDataRoot,
+            0x22 => Self::UpdateRecordDataAllocation,
+            0x23 => Self::UpdateRelativeDataInIndex,
+            0x24 => Self::UpdateRelativeDataInIndex2,
+            0x25 => Self::ZeroEndOfFileRecord,
+            other => Self::Unknown(other),
+        }
+    }
+
+    /// Whether the operation modifies an MFT record (as opposed to an INDX block or the
+    /// contents of a non-resident attribute).
+    fn targets_file_record(self) -> bool {
+        matches!(
+            self,
+            Self::InitializeFileRecordSegment
+                | Self::DeallocateFileRecordSegment
+                | Self::WriteEndOfFileRecordSegment
+                | Self::CreateAttribute
+                | Self::DeleteAttribute
+                | Self::UpdateResidentValue
+                | Self::UpdateMappingPairs
+                | Self::SetNewAttributeSizes
+                | Self::AddIndexEntryRoot
+                | Self::DeleteIndexEntryRoot
+                | Self::SetIndexEntryVcnRoot
+                | Self::UpdateFileNameRoot
+                | Self::UpdateRecordDataRoot
+                | Self::ZeroEndOfFileRecord
+        )
+    }
+
+    /// Whether the operation's data is a whole index entry.
+    fn carries_index_entry(self) -> bool {
+        matches!(
+            self,
+            Self::AddIndexEntryRoot
+                | Self::DeleteIndexEntryRoot
+                | Self::AddIndexEntryAllocation
+                | Self::DeleteIndexEntryAllocation
+        )
+    }
+}
+
+/// The redo or undo data of an operation that changes an MFT record, decoded.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LogPayload {
+    /// The header of a whole MFT record (InitializeFileRecordSegment). The undo data of
+    /// DeallocateFileRecordSegment is one too: the record of a file as it was deleted.
+    FileRecord {
+        sequence_number: u16,
+        hard_link_count: u16,
+        is_in_use: bool,
+        is_directory: bool,
+        base_record: u64,
+    },
+    /// Bytes of a resident value written at `attribute_offset` (UpdateResidentValue), when
+    /// they are printable text. Any bytes are in the hex of the redo and undo data.
+    ResidentValue { text: String },
+    /// New sizes of a non-resident attribute (SetNewAttributeSizes).
+    AttributeSizes {
+        allocated_size: u64,
+        initialized_size: u64,
+        data_size: u64,
+        compressed_size: Option<u64>,
+    },
+    /// Data runs written at `attribute_offset` (UpdateMappingPairs). LCNs are relative to
+    /// the run before the first one written, so they are only absolute when the write
+    /// starts the run list.
+    MappingPairs { data_runs: Vec<DataRun> },
+}
+
+impl LogPayload {
+    fn decode(operation: LogOperation, data: &[u8]) -> Option<Self> {
+        let u64_at = |offset: usize| {
+            Some(u64::from_le_bytes(
+                data.get(offset..offset + 8)?.try_into().ok()?,
+            ))
+        };
+
+        match operation {
+            LogOperation::InitializeFileRecordSegment => {
+                let header = data.get(..FILE_RECORD_HEADER_SIZE)?;
+                if &header[0..4] != FILE_RECORD_MAGIC {
+                    return None;
+                }
+                let flags = u16::from_le_bytes([header[22], header[23]]);
+                Some(Self::FileRecord {
+                    sequence_number: u16::from_le_bytes([header[16], header[17]]),
+                    hard_link_count: u16::from_le_bytes([header[18], header[19]]),
+                    is_in_use: flags & FILE_RECORD_IN_USE != 0,
+                    is_directory: flags & FILE_RECORD_IS_DIRECTORY != 0,
+                    base_record: u64_at(32)? & 0x0000_FFFF_FFFF_FFFF,
+                })
+            }
+            LogOperation::UpdateResidentValue => {
+                let text = std::str::from_utf8(data).ok()?;
+                let printable =
+                    !text.is_empty() && text.chars().all(|c| !c.is_control() || c.is_whitespace());
+                printable.then(|| Self::ResidentValue {
+                    text: text.to_string(),
+                })
+            }
+            LogOperation::SetNewAttributeSizes => Some(Self::AttributeSizes {
+                allocated_size: u64_at(0)?,
+                initialized_size: u64_at(8)?,
+                data_size: u64_at(16)?,
+                compressed_size: u64_at(24),
+            }),
+            LogOperation::UpdateMappingPairs => {
+                let data_runs = parse_mapping_pairs(data);
+                (!data_runs.is_empty()).then_some(Self::MappingPairs { data_runs })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Writes bytes as a hex string.
+fn serialize_hex<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = data.iter().map(|b| format!("{b:02x}")).collect();
+    serializer.serialize_str(&hex)
+}
+
+/// A log client registered in a restart area (NTFS is normally the only one).
+#[derive(Debug, Serialize)]
+pub struct LogClient {
+    pub name: String,
+    pub oldest_lsn: u64,
+    pub client_restart_lsn: u64,
+}
+
+/// One of the two `RSTR` pages at the start of `$LogFile`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename = "logfile_restart")]
+pub struct LogRestartArea {
+    pub logfile_offset: u64, // Offset of the page within `$LogFile`
+    pub fixup_status: FixupStatus,
+    pub major_version: i16,
+    pub minor_version: i16,
+    pub chkdsk_lsn: u64,
+    pub system_page_size: u32,
+    pub log_page_size: u32,
+    pub current_lsn: u64,
+    pub volume_is_clean: bool,
+    pub log_page_data_offset: u16,
+    pub file_size: u64,
+    pub clients: Vec<LogClient>,
+}
+
+/// An NTFS operation decoded from a client record of `$LogFile`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename = "logfile_operation")]
+pub struct LogOperationRecord {
+    pub logfile_offset: u64, // Offset of the record header within `$LogFile`
+    pub lsn: u64,
+    pub previous_lsn: u64,
+    pub undo_next_lsn: u64,
+    pub transaction_id: u32,
+    pub redo_operation: LogOperation,
+    pub undo_operation: LogOperation,
+    pub target_attribute: u16,
+    pub target_vcn: u64,
+    pub cluster_block_offset: u16, // In 512-byte units from the start of `target_vcn`
+    pub record_offset: u16,        // Offset of the attribute within the MFT record
+    pub attribute_offset: u16,     // Offset of the change within that attribute
+    pub target_mft_record: Option<u64>,
+    pub lcns: Vec<u64>,
+    pub redo_length: u16,
+    pub undo_length: u16,
+    pub index_entry: Option<IndexEntry>, // For index entry add/delete operations
+    pub redo_payload: Option<LogPayload>,
+    pub undo_payload: Option<LogPayload>,
+    #[serde(serialize_with = "serialize_hex")]
+    pub redo_data: Vec<u8>,
+    #[serde(serialize_with = "serialize_hex")]
+    pub undo_data: Vec<u8>, // Also used to roll records back
+}
+
+/// Everything decoded from `$LogFile`.
+#[derive(Debug, Default)]
+pub struct LogFile {
+    pub restart_areas: Vec<LogRestartArea>,
+    /// Sorted by LSN. Duplicates (from the tail copies of the log pages) are removed.
+    pub operations: Vec<LogOperationRecord>,
+}
+
+fn parse_restart_page(log: &mut [u8], offset: usize) -> Option<LogRestartArea> {
+    let header = log.get(offset..offset + 32)?;
+    if &header[0..4] != RSTR_MAGIC {
+        return None;
+    }
+    let system_page_size = u32::from_le_bytes(header[16..20].try_into().ok()?);
+    if !system_page_size.is_power_of_two() || system_page_size < 512 {
+        return None;
+    }
+
+    let page = log.get_mut(offset..offset + system_page_size as usize)?;
+    let fixup_status = apply_fixups(page)?;
+
+    let chkdsk_lsn = u64::from_le_bytes(page[8..16].try_into().ok()?);
+    let log_page_size = u32::from_le_bytes(page[20..24].try_into().ok()?);
+    let restart_area_offset = u16::from_le_bytes(page[24..26].try_into().ok()?) as usize;
+    let minor_version = i16::from_le_bytes(page[26..28].try_into().ok()?);
+    let major_version = i16::from_le_bytes(page[28..30].try_into().ok()?);
+
+    let area = page.get(restart_area_offset..)?;
+    let current_lsn = u64::from_le_bytes(area.get(0..8)?.try_into().ok()?);
+    let log_clients = u16::from_le_bytes(area.get(8..10)?.try_into().ok()?) as usize;
+    let flags = u16::from_le_bytes(area.get(14..16)?.try_into().ok()?);
+    let client_array_offset = u16::from_le_bytes(area.get(22..24)?.try_into().ok()?) as usize;
+    let file_size = u64::from_le_bytes(area.get(24..32)?.try_into().ok()?);
+    let log_page_data_offset = u16::from_le_bytes(area.get(38..40)?.try_into().ok()?);
+
+    let mut clients = Vec::new();
+    for i in 0..log_clients {
+        // LOG_CLIENT_RECORD: 0xA0 bytes, with a UTF-16 name of up to 64 characters.
+        let Some(client) =
+            area.get(client_array_offset + i * 0xA0..client_array_offset + (i + 1) * 0xA0)
+        else {
+            break;
+        };
+        let name_length = (u32::from_le_bytes(client[28..32].try_into().ok()?) as usize).min(128);
+        let name_utf16: Vec<u16> = client[32..32 + name_length]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        clients.push(LogClient {
+            name: String::from_utf16_lossy(&name_utf16),
+            oldest_lsn: u64::from_le_bytes(client[0..8].try_into().ok()?),
+            client_restart_lsn: u64::from_le_bytes(client[8..16].try_into().ok()?),
+        });
+    }
+
+    Some(LogRestartArea {
+        logfile_offset: offset as u64,
+        fixup_status,
+        major_version,
+        minor_version,
+        chkdsk_lsn,
+        system_page_size,
+        log_page_size,
+        current_lsn,
+        volume_is_clean: flags & RESTART_VOLUME_IS_CLEAN != 0,
+        log_page_data_offset,
+        file_size,
+        clients,
+    })
+}
+
+/// Decodes the client data of an LFS record (an NTFS log record header, redo and undo data).
+fn parse_operation(
+    record: &[u8],
+    logfile_offset: u64,
+    geometry: &VolumeGeometry,
+) -> Option<LogOperationRecord> {
+    let client_data = record.get(LOG_RECORD_HEADER_SIZE..)?;
+    if client_data.len() < NTFS_LOG_RECORD_HEADER_SIZE {
+        return None;
+    }
+
+    let field = |offset: usize| u16::from_le_bytes([client_data[offset], client_data[offset + 1]]);
+    let redo_operation = LogOperation::from_code(field(0));
+    let undo_operation = LogOperation::from_code(field(2));
+    let (redo_offset, redo_length) = (field(4), field(6));
+    let (undo_offset, undo_length) = (field(8), field(10));
+    let target_attribute = field(12);
+    let lcns_to_follow = field(14) as usize;
+    let record_offset = field(16);
+    let attribute_offset = field(18);
+    let cluster_block_offset = field(20);
+    let target_vcn = u64::from_le_bytes(client_data[24..32].try_into().ok()?);
+
+    let lcns = client_data
+        .get(NTFS_LOG_RECORD_HEADER_SIZE..NTFS_LOG_RECORD_HEADER_SIZE + lcns_to_follow * 8)
+        .unwrap_or_default()
+        .chunks_exact(8)
+        .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+
+    let slice = |offset: u16, length: u16| {
+        client_data
+            .get(offset as usize..offset as usize + length as usize)
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default()
+    };
+    let redo_data = slice(redo_offset, redo_length);
+    let undo_data = slice(undo_offset, undo_length);
+
+    // The target of MFT operations is given as a cluster of `$MFT` plus a sector offset.
+    let target_mft_record = (redo_operation.targets_file_record()
+        || undo_operation.targets_file_record())
+    .then(|| {
+        let byte_offset = target_vcn
+            .checked_mul(geometry.cluster_size)?
+            .checked_add(cluster_block_offset as u64 * 512)?;
+        Some(byte_offset / geometry.mft_record_size)
+    })
+    .flatten();
+
+    let index_entry = if redo_operation.carries_index_entry() {
+        IndexEntry::parse(&redo_data)
+    } else if undo_operation.carries_index_entry() {
+        IndexEntry::parse(&undo_data)
+    } else {
+        None
+    };
+
+    Some(LogOperationRecord {
+        logfile_offset,
+        lsn: u64::from_le_bytes(record[0..8].try_into().ok()?),
+        previous_lsn: u64::from_le_bytes(record[8..16].try_into().ok()?),
+        undo_next_lsn: u64::from_le_bytes(record[16..24].try_into().ok()?),
+        transaction_id: u32::from_le_bytes(record[36..40].try_into().ok()?),
+        redo_operation,
+        undo_operation,
+        target_attribute,
+        target_vcn,
+        cluster_block_offset,
+        record_offset,
+        attribute_offset,
+        target_mft_record,
+        lcns,
+        redo_length,
+        undo_length,
+        index_entry,
+        redo_payload: LogPayload::decode(redo_operation, &redo_data),
+        undo_payload: LogPayload::decode(undo_operation, &undo_data),
+        redo_data,
+        undo_data,
+    })
+}
+
+/// A fixed-up `RCRD` page.
+struct LogPage<'a> {
+    offset: usize,
+    data: &'a [u8],
+    last_lsn: u64,
+    next_record_offset: usize,
+}
+
+/// Checks the LFS header of a record found at the current position of a page.
+fn is_valid_record_header(header: &[u8], page_last_lsn: u64) -> bool {
+    let lsn = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let previous_lsn = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let client_data_length = u32::from_le_bytes(header[24..28].try_into().unwrap()) as usize;
+    let record_type = u32::from_le_bytes(header[32..36].try_into().unwrap());
+
+    lsn != 0
+        && previous_lsn < lsn
+        && (page_last_lsn == 0 || lsn <= page_last_lsn)
+        && matches!(
+            record_type,
+            LOG_RECORD_TYPE_CLIENT | LOG_RECORD_TYPE_CLIENT_RESTART
+        )
+        && client_data_length <= MAX_CLIENT_DATA_LENGTH
+}
+
+/// Walks the records of each `RCRD` page, in file order. Records that don't fit in a page
+/// continue in the data area of the following page(s).
+fn parse_log_pages(
+    pages: &[Option<LogPage>],
+    page_size: usize,
+    data_offset: usize,
+    geometry: &VolumeGeometry,
+) -> Vec<LogOperationRecord> {
+    let mut operations = Vec::new();
+    // Bytes at the start of the next page's data area that belong to a spanning record.
+    let mut carry = 0;
+
+    for (page_index, page) in pages.iter().enumerate() {
+        let page_data_size = page_size - data_offset;
+        if carry >= page_data_size {
+            carry -= page_data_size;
+            continue;
+        }
+        let Some(page) = page else {
+            carry = 0;
+            continue;
+        };
+
+        let mut position = data_offset + carry;
+        carry = 0;
+        let end = match page.next_record_offset {
+            0 => page_size,
+            offset => offset.min(page_size),
+        };
+
+        while position + LOG_RECORD_HEADER_SIZE <= page_size && position < end {
+            let header = &page.data[position..position + LOG_RECORD_HEADER_SIZE];
+            if !is_valid_record_header(header, page.last_lsn) {
+                break;
+            }
+            let client_data_length = u32::from_le_bytes(header[24..28].try_into().unwrap());
+            let record_type = u32::from_le_bytes(header[32..36].try_into().unwrap());
+            let record_length =
+                (LOG_RECORD_HEADER_SIZE + client_data_length as usize).next_multiple_of(8);
+
+            let mut record = page.data[position..page_size.min(position + record_length)].to_vec();
+            let mut next_page = page_index + 1;
+            while record.len() < record_length {
+                let missing = record_length - record.len();
+                let continuation = pages
+                    .get(next_page)
+                    .and_then(Option::as_ref)
+                    .map(|p| &p.data[data_offset..page_size]);
+                let Some(continuation) = continuation else {
+                    break;
+                };
+                record.extend_from_slice(&continuation[..missing.min(page_data_size)]);
+                next_page += 1;
+            }
+            let logfile_offset = (page.offset + position) as u64;
+
+            if record.len() == record_length
+                && record_type == LOG_RECORD_TYPE_CLIENT
+                && let Some(operation) = parse_operation(&record, logfile_offset, geometry)
+            {
+                operations.push(operation);
+            }
+
+            if position + record_length > page_size {
+                carry = record_length - (page_size - position);
+                break;
+            }
+            position += record_length;
+        }
+    }
+
+    operations
+}
+
+/// Parses the contents of `$LogFile`: both restart pages, then every `RCRD` page.
+pub fn parse_logfile(mut log: Vec<u8>, geometry: &VolumeGeometry) -> LogFile {
+    let mut restart_areas = Vec::new();
+    if let Some(first) = parse_restart_page(&mut log, 0) {
+        let second_offset = first.system_page_size as usize;
+        restart_areas.push(first);
+        restart_areas.extend(parse_restart_page(&mut log, second_offset));
+    }
+
+    // Go by the most recent restart area.
+    let current = restart_areas.iter().max_by_key(|r| r.current_lsn);
+    let (system_page_size, page_size, data_offset) = match current {
+        Some(r) if r.log_page_size.is_power_of_two() && r.log_page_size >= 512 => (
+            r.system_page_size as usize,
+            r.log_page_size as usize,
+            r.log_page_data_offset as usize,
+        ),
+        _ => (
+            DEFAULT_LOG_PAGE_SIZE,
+            DEFAULT_LOG_PAGE_SIZE,
+            DEFAULT_LOG_PAGE_DATA_OFFSET,
+        ),
+    };
+    let data_offset = if (LOG_RECORD_HEADER_SIZE..page_size).contains(&data_offset) {
+        data_offset
+    } else {
+        DEFAULT_LOG_PAGE_DATA_OFFSET
+    };
+
+    // The log pages follow the two restart pages.
+    let log_start = (2 * system_page_size).min(log.len());
+    let pages: Vec<Option<LogPage>> = log[log_start..]
+        .chunks_exact_mut(page_size)
+        .enumerate()
+        .map(|(i, page)| {
+            if &page[0..4] != RCRD_MAGIC {
+                return None;
+            }
+            apply_fixups(page)?;
+            Some(LogPage {
+                offset: log_start + i * page_size,
+                last_lsn: u64::from_le_bytes(page[8..16].try_into().ok()?),
+                next_record_offset: u16::from_le_bytes(page[24..26].try_into().ok()?) as usize,
+                data: page,
+            })
+        })
+        .collect();
+
+    let mut operations = parse_log_pages(&pages, page_size, data_offset, geometry);
+    operations.sort_by_key(|o| o.lsn);
+    operations.dedup_by_key(|o| o.lsn);
+
+    LogFile {
+        restart_areas,
+        operations,
+    }
+}
+
+/// Finds `$LogFile` (MFT record 2) among the scanned entries and parses it. Returns `None`
+/// if its record wasn't found.
+pub fn read_logfile(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: &VolumeGeometry,
+    entries: &[NtfsEntry],
+) -> Option<LogFile> {
+    let stream = entries
+        .iter()
+        .filter(|e| e.is_in_use && e.mft_record_number == LOGFILE_MFT_RECORD)
+        .flat_map(|e| &e.data_streams)
+        .find(|s| !s.resident && s.name.is_none())?;
+    if stream.size > MAX_LOGFILE_SIZE {
+        return None;
+    }
+
+    let log = read_non_resident(
+        disk_image_buffer,
+        geometry.cluster_size,
+        stream.data_runs.as_deref().unwrap_or_default(),
+        stream.size,
+        stream.initialized_size,
+    );
+    Some(parse_logfile(log, geometry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_sector::tests::geometry;
+    use crate::ntfs_logic::tests::mft_record;
+
+    const PAGE_SIZE: usize = 4096;
+    const USN: [u8; 2] = [0x03, 0x00];
+
+    /// Swaps the sector trailers of a 4 KiB page into its USA at `usa_offset`.
+    fn protect(page: &mut [u8], usa_offset: usize) {
+        page[4..6].copy_from_slice(&(usa_offset as u16).to_le_bytes());
+        page[6..8].copy_from_slice(&9u16.to_le_bytes());
+        page[usa_offset..usa_offset + 2].copy_from_slice(&USN);
+        for sector in 0..8 {
+            let end = sector * 512 + 510;
+            page.copy_within(end..end + 2, usa_offset + 2 + sector * 2);
+            page[end..end + 2].copy_from_slice(&USN);
+        }
+    }
+
+    /// A restart page with one client named "NTFS".
+    fn restart_page(current_lsn: u64) -> Vec<u8> {
+        let mut page = vec![0; PAGE_SIZE];
+        page[0..4].copy_from_slice(RSTR_MAGIC);
+        page[16..20].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        page[20..24].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        page[24..26].copy_from_slice(&0x30u16.to_le_bytes());
+        page[26..28].copy_from_slice(&1i16.to_le_bytes());
+        page[28..30].copy_from_slice(&1i16.to_le_bytes());
+
+        let area = &mut page[0x30..];
+        area[0..8].copy_from_slice(&current_lsn.to_le_bytes());
+        area[8..10].copy_from_slice(&1u16.to_le_bytes());
+        area[14..16].copy_from_slice(&RESTART_VOLUME_IS_CLEAN.to_le_bytes());
+        area[22..24].copy_from_slice(&0x30u16.to_le_bytes());
+        area[24..32].copy_from_slice(&(4 * PAGE_SIZE as u64).to_le_bytes());
+        area[38..40].copy_from_slice(&0x40u16.to_le_bytes());
+        let client = &mut area[0x30..0x30 + 0xA0];
+        client[0..8].copy_from_slice(&(current_lsn - 50).to_le_bytes());
+        client[28..32].copy_from_slice(&8u32.to_le_bytes());
+        for (i, unit) in "NTFS".encode_utf16().enumerate() {
+            client[32 + i * 2..34 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        protect(&mut page, 0x1E);
+        page
+    }
+
+    /// An LFS client record of `length` bytes with an NTFS log record header. The redo
+    /// data is the 8 bytes after the header.
+    fn log_record(lsn: u64, redo: u16, undo: u16, length: usize) -> Vec<u8> {
+        let mut record = vec![0; length];
+        record[0..8].copy_from_slice(&lsn.to_le_bytes());
+        record[8..16].copy_from_slice(&(lsn - 1).to_le_bytes());
+        record[24..28].copy_from_slice(&((length - LOG_RECORD_HEADER_SIZE) as u32).to_le_bytes());
+        record[32..36].copy_from_slice(&LOG_RECORD_TYPE_CLIENT.to_le_bytes());
+        record[36..40].copy_from_slice(&7u32.to_le_bytes());
+
+        let client = &mut record[LOG_RECORD_HEADER_SIZE..];
+        client[0..2].copy_from_slice(&redo.to_le_bytes());
+        client[2..4].copy_from_slice(&undo.to_le_bytes());
+        client[4..6].copy_from_slice(&0x20u16.to_le_bytes());
+        client[6..8].copy_from_slice(&8u16.to_le_bytes());
+        // 2 sectors into VCN 5 of `$MFT`: record 21 with 4 KiB clusters and 1 KiB records.
+        client[20..22].copy_from_slice(&2u16.to_le_bytes());
+        client[24..32].copy_from_slice(&5u64.to_le_bytes());
+        record
+    }
+
+    fn record_page(last_lsn: u64, data: &[u8]) -> Vec<u8> {
+        let mut page = vec![0; PAGE_SIZE];
+        page[0..4].copy_from_slice(RCRD_MAGIC);
+        page[8..16].copy_from_slice(&last_lsn.to_le_bytes());
+        page[0x40..0x40 + data.len()].copy_from_slice(data);
+        protect(&mut page, 0x28);
+        page
+    }
+
+    #[test]
+    fn parses_restart_areas_and_records_spanning_pages() {
+        // Record 102 runs 0x100 bytes into the second page.
+        let first = log_record(101, 0x02, 0x00, 0x58);
+        let spanning = log_record(102, 0x07, 0x07, PAGE_SIZE - 0x98 + 0x100);
+        let last = log_record(103, 0x0B, 0x0B, 0x58);
+        let page_data = PAGE_SIZE - 0x40;
+        let first_page = [&first[..], &spanning[..page_data - first.len()]].concat();
+        let second_page = [&spanning[page_data - first.len()..], &last[..]].concat();
+
+        let log = [
+            restart_page(200),
+            restart_page(150),
+            record_page(102, &first_page),
+            record_page(103, &second_page),
+        ]
+        .concat();
+        let logfile = parse_logfile(log, &geometry());
+
+        assert_eq!(logfile.restart_areas.len(), 2);
+        let restart = &logfile.restart_areas[0];
+        assert_eq!(restart.fixup_status, FixupStatus::Ok);
+        assert_eq!(restart.current_lsn, 200);
+        assert!(restart.volume_is_clean);
+        assert_eq!(restart.clients[0].name, "NTFS");
+        assert_eq!(restart.clients[0].oldest_lsn, 150);
+        assert_eq!(logfile.restart_areas[1].logfile_offset, PAGE_SIZE as u64);
+
+        let found: Vec<_> = logfile
+            .operations
+            .iter()
+            .map(|o| (o.lsn, o.redo_operation, o.logfile_offset))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (101, LogOperation::InitializeFileRecordSegment, 0x2040),
+                (102, LogOperation::UpdateResidentValue, 0x2098),
+                (103, LogOperation::SetNewAttributeSizes, 0x3140),
+            ]
+        );
+        let first = &logfile.operations[0];
+        assert_eq!(first.target_mft_record, Some(21));
+        assert_eq!(first.transaction_id, 7);
+        assert_eq!(first.redo_length, 8);
+        let json = serde_json::to_value(first).unwrap();
+        assert_eq!(json["redo_data"], "0000000000000000");
+        assert_eq!(json["undo_data"], "");
+    }
+
+    #[test]
+    fn decodes_the_payloads_of_mft_record_operations() {
+        let record = mft_record(1024, 40, 0x03, &[]);
+        let Some(LogPayload::FileRecord {
+            sequence_number,
+            is_in_use,
+            is_directory,
+            base_record,
+            ..
+        }) = LogPayload::decode(LogOperation::InitializeFileRecordSegment, &record)
+        else {
+            panic!("not decoded as a file record");
+        };
+        assert_eq!((sequence_number, is_in_use, is_directory), (1, true, true));
+        assert_eq!(base_record, 0);
+
+        let sizes = [8192u64, 5000, 5000].map(u64::to_le_bytes).concat();
+        let Some(LogPayload::AttributeSizes {
+            allocated_size,
+            initialized_size,
+            data_size,
+            compressed_size,
+        }) = LogPayload::decode(LogOperation::SetNewAttributeSizes, &sizes)
+        else {
+            panic!("not decoded as attribute sizes");
+        };
+        assert_eq!(
+            (allocated_size, initialized_size, data_size),
+            (8192, 5000, 5000)
+        );
+        assert_eq!(compressed_size, None);
+
+        let pairs = [0x11, 0x04, 0x10, 0x21, 0x02, 0x00, 0x01, 0x00];
+        let Some(LogPayload::MappingPairs { data_runs }) =
+            LogPayload::decode(LogOperation::UpdateMappingPairs, &pairs)
+        else {
+            panic!("not decoded as mapping pairs");
+        };
+        let runs: Vec<_> = data_runs
+            .iter()
+            .map(|r| (r.cluster_offset, r.cluster_count))
+            .collect();
+        assert_eq!(runs, [(Some(16), 4), (Some(272), 2)]);
+
+        assert!(matches!(
+            LogPayload::decode(LogOperation::UpdateResidentValue, b"new name.txt"),
+            Some(LogPayload::ResidentValue { text }) if text == "new name.txt"
+        ));
+        assert!(LogPayload::decode(LogOperation::UpdateResidentValue, &[0x01, 0xFF]).is_none());
+    }
+}
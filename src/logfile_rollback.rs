@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+
+use crate::boot_sector::VolumeGeometry;
+use crate::disk_image::ImageSlice;
+use crate::logfile::{LogOperation, LogOperationRecord};
+use crate::ntfs_logic::{
+    DataRun, EntrySource, FixupStatus, NtfsEntry, parse_mft_record_bytes, read_mft_record,
+};
+
+const MFT_MFT_RECORD: u64 = 0;
+
+// Offsets in the MFT record header.
+const RECORD_LSN_OFFSET: usize = 8;
+const RECORD_BYTES_IN_USE_OFFSET: usize = 24;
+
+// Offset of the allocated/real/initialized sizes in a non-resident attribute header.
+const ATTRIBUTE_SIZES_OFFSET: usize = 40;
+
+fn read_u32(record: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        record.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn overwrite(record: &mut [u8], offset: usize, data: &[u8]) -> Option<()> {
+    record
+        .get_mut(offset..offset + data.len())?
+        .copy_from_slice(data);
+    Some(())
+}
+
+/// Inserts an attribute at `offset`, moving the following attributes up.
+fn insert_attribute(record: &mut [u8], offset: usize, attribute: &[u8]) -> Option<()> {
+    let bytes_in_use = read_u32(record, RECORD_BYTES_IN_USE_OFFSET)? as usize;
+    let new_bytes_in_use = bytes_in_use + attribute.len();
+    if offset > bytes_in_use || new_bytes_in_use > record.len() {
+        return None;
+    }
+
+    record.copy_within(offset..bytes_in_use, offset + attribute.len());
+    record[offset..offset + attribute.len()].copy_from_slice(attribute);
+    overwrite(
+        record,
+        RECORD_BYTES_IN_USE_OFFSET,
+        &(new_bytes_in_use as u32).to_le_bytes(),
+    )
+}
+
+/// Removes the attribute at `offset`, moving the following attributes down.
+fn remove_attribute(record: &mut [u8], offset: usize) -> Option<()> {
+    let bytes_in_use = read_u32(record, RECORD_BYTES_IN_USE_OFFSET)? as usize;
+    let length = read_u32(record, offset + 4)? as usize;
+    if length == 0 || offset + length > bytes_in_use || bytes_in_use > record.len() {
+        return None;
+    }
+
+    record.copy_within(offset + length..bytes_in_use, offset);
+    record[bytes_in_use - length..bytes_in_use].fill(0);
+    overwrite(
+        record,
+        RECORD_BYTES_IN_USE_OFFSET,
+        &((bytes_in_use - length) as u32).to_le_bytes(),
+    )
+}
+
+/// Byte offset of MFT record `record_number`, following the data runs of `$MFT` itself.
+/// `None` if the runs don't reach it, or it is in a sparse run.
+fn mft_record_offset(
+    mft_runs: &[DataRun],
+    geometry: &VolumeGeometry,
+    record_number: u64,
+) -> Option<u64> {
+    let offset_in_mft = record_number.checked_mul(geometry.mft_record_size)?;
+    let mut run_start = 0u64;
+
+    for run in mft_runs {
+        let run_end =
+            run_start.checked_add(run.cluster_count.checked_mul(geometry.cluster_size)?)?;
+        if offset_in_mft < run_end {
+            let lcn = u64::try_from(run.cluster_offset?).ok()?;
+            return lcn
+                .checked_mul(geometry.cluster_size)?
+                .checked_add(offset_in_mft - run_start);
+        }
+        run_start = run_end;
+    }
+    None
+}
+
+/// Reverts one operation on a record. Returns `false` if the undo half of the operation
+/// isn't one that is replayed (or doesn't fit the record), in which case the record is
+/// left untouched.
+///
+/// Changes that resize a resident value or mapping pairs are replayed in place, without
+/// resizing the attribute.
+fn apply_undo(record: &mut Option<Vec<u8>>, operation: &LogOperationRecord, size: usize) -> bool {
+    let undo = &operation.undo_data;
+    let attribute = operation.record_offset as usize;
+
+    match operation.undo_operation {
+        // The redo half deallocated the record; the undo data is the record as it was.
+        LogOperation::InitializeFileRecordSegment if !undo.is_empty() => {
+            let mut image = undo.clone();
+            image.resize(size, 0);
+            *record = Some(image);
+            true
+        }
+        // The redo half created the record, which didn't exist before.
+        LogOperation::DeallocateFileRecordSegment => {
+            *record = None;
+            false
+        }
+        LogOperation::UpdateResidentValue | LogOperation::UpdateMappingPairs => record
+            .as_mut()
+            .and_then(|r| overwrite(r, attribute + operation.attribute_offset as usize, undo))
+            .is_some(),
+        LogOperation::SetNewAttributeSizes => record
+            .as_mut()
+            .and_then(|r| overwrite(r, attribute + ATTRIBUTE_SIZES_OFFSET, undo))
+            .is_some(),
+        LogOperation::CreateAttribute => record
+            .as_mut()
+            .and_then(|r| insert_attribute(r, attribute, undo))
+            .is_some(),
+        LogOperation::DeleteAttribute => record
+            .as_mut()
+            .and_then(|r| remove_attribute(r, attribute))
+            .is_some(),
+        _ => false,
+    }
+}
+
+/// Rebuilds earlier versions of MFT records by replaying the undo data of `$LogFile`
+/// operations, newest first, against the records found in the image.
+///
+/// A version is emitted after each replayed operation, marked with the LSN of that
+/// operation (it is the record as it was just before it). Operations newer than the
+/// record's own LSN were never written to it and are skipped.
+pub fn roll_back_records(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: &VolumeGeometry,
+    entries: &[NtfsEntry],
+    operations: &[LogOperationRecord],
+) -> Vec<NtfsEntry> {
+    let record_size = geometry.mft_record_size as usize;
+
+    let mut operations_by_record: HashMap<u64, Vec<&LogOperationRecord>> = HashMap::new();
+    for operation in operations {
+        if let Some(record_number) = operation.target_mft_record {
+            operations_by_record
+                .entry(record_number)
+                .or_default()
+                .push(operation);
+        }
+    }
+
+    // Current copy of each targeted record, preferring the one in use.
+    let mut current: HashMap<u64, &NtfsEntry> = HashMap::new();
+    for entry in entries.iter().filter(|e| e.source == EntrySource::Mft) {
+        if operations_by_record.contains_key(&entry.mft_record_number) {
+            current
+                .entry(entry.mft_record_number)
+                .and_modify(|existing| {
+                    if entry.is_in_use && !existing.is_in_use {
+                        *existing = entry;
+                    }
+                })
+                .or_insert(entry);
+        }
+    }
+
+    // Where records without a current copy are, from the data runs of `$MFT`.
+    let mft_runs = entries
+        .iter()
+        .filter(|e| e.source == EntrySource::Mft && e.is_in_use)
+        .filter(|e| e.mft_record_number == MFT_MFT_RECORD)
+        .flat_map(|e| &e.data_streams)
+        .find(|s| !s.resident && s.name.is_none())
+        .and_then(|s| s.data_runs.as_deref())
+        .unwrap_or_default();
+
+    let mut versions = Vec::new();
+    for (record_number, mut record_operations) in operations_by_record {
+        record_operations.sort_by_key(|o| std::cmp::Reverse(o.lsn));
+
+        let mft_offset = match current.get(&record_number) {
+            Some(entry) => Some(entry.mft_offset),
+            None => mft_record_offset(mft_runs, geometry, record_number),
+        };
+        let Some(mft_offset) = mft_offset else {
+            continue;
+        };
+        let (mut record, mut fixup_status) = current
+            .get(&record_number)
+            .and_then(|entry| {
+                read_mft_record(disk_image_buffer, entry.mft_offset as usize, Some(geometry))
+            })
+            .map_or((None, FixupStatus::Ok), |(record, status)| {
+                (Some(record), status)
+            });
+
+        for operation in record_operations {
+            let record_lsn = record
+                .as_deref()
+                .and_then(|r| r.get(RECORD_LSN_OFFSET..RECORD_LSN_OFFSET + 8))
+                .map(|lsn| u64::from_le_bytes(lsn.try_into().unwrap()));
+            if record_lsn.is_some_and(|lsn| operation.lsn > lsn) {
+                continue;
+            }
+            if !apply_undo(&mut record, operation, record_size) {
+                continue;
+            }
+            // A record rebuilt whole from the log no longer has the base read's damage.
+            if operation.undo_operation == LogOperation::InitializeFileRecordSegment {
+                fixup_status = FixupStatus::Ok;
+            }
+
+            let Some(bytes) = &record else {
+                continue;
+            };
+            if let Some(mut entry) = parse_mft_record_bytes(
+                bytes,
+                mft_offset,
+                fixup_status,
+                disk_image_buffer,
+                Some(geometry),
+            ) {
+                entry.source = EntrySource::LogfileRollback;
+                entry.logfile_lsn = Some(operation.lsn);
+                versions.push(entry);
+            }
+        }
+    }
+
+    versions.sort_by_key(|e| (e.mft_record_number, std::cmp::Reverse(e.logfile_lsn)));
+    versions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_sector::tests::geometry;
+    use crate::ntfs_logic::apply_fixups;
+    use crate::ntfs_logic::scan_ntfs_image;
+    use crate::ntfs_logic::tests::{
+        data_fragment, file_name_attribute, mft_record, parse_record, resident_attribute,
+    };
+
+    const ATTR_DATA: u32 = 0x80;
+
+    fn operation(lsn: u64, undo_operation: LogOperation, attribute: usize) -> LogOperationRecord {
+        LogOperationRecord {
+            logfile_offset: 0,
+            lsn,
+            previous_lsn: lsn - 1,
+            undo_next_lsn: 0,
+            transaction_id: 1,
+            redo_operation: LogOperation::Noop,
+            undo_operation,
+            target_attribute: 0,
+            target_vcn: 0,
+            cluster_block_offset: 0,
+            record_offset: attribute as u16,
+            attribute_offset: 0,
+            target_mft_record: Some(40),
+            lcns: Vec::new(),
+            redo_length: 0,
+            undo_length: 0,
+            index_entry: None,
+            redo_payload: None,
+            undo_payload: None,
+            redo_data: Vec::new(),
+            undo_data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn replays_undo_data_newest_first() {
+        let geometry = geometry();
+        let file_name = file_name_attribute("a.txt", 5);
+        let mut record = mft_record(
+            1024,
+            40,
+            1,
+            &[file_name.clone(), resident_attribute(ATTR_DATA, "", b"new")],
+        );
+        record[RECORD_LSN_OFFSET..RECORD_LSN_OFFSET + 8].copy_from_slice(&500u64.to_le_bytes());
+        // Attributes start at 56 in a 1 KiB record.
+        let data_attribute = 56 + file_name.len();
+
+        let mft_offset = (geometry.mft_offset().unwrap() + 40 * 1024) as usize;
+        let mut image = vec![0; mft_offset + 1024];
+        image[mft_offset..].copy_from_slice(&record);
+        // A torn last sector, past the attributes.
+        image[mft_offset + 1022] = 0x99;
+        let entries: Vec<_> = scan_ntfs_image(ImageSlice::new(&image), Some(&geometry)).collect();
+
+        let mut rewrite = operation(400, LogOperation::UpdateResidentValue, data_attribute);
+        // Over the content of `$DATA`, which starts 24 bytes into the attribute.
+        rewrite.attribute_offset = 24;
+        rewrite.undo_data = b"old".to_vec();
+        let operations = [
+            operation(300, LogOperation::DeleteAttribute, data_attribute),
+            rewrite,
+            // Never made it into the record.
+            operation(600, LogOperation::DeleteAttribute, data_attribute),
+        ];
+
+        let versions = roll_back_records(ImageSlice::new(&image), &geometry, &entries, &operations);
+        let found: Vec<_> = versions
+            .iter()
+            .map(|v| {
+                let content = v
+                    .data_streams
+                    .first()
+                    .and_then(|s| s.resident_content.clone());
+                (v.logfile_lsn, content)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [(Some(400), Some(b"old".to_vec())), (Some(300), None)]
+        );
+        assert!(
+            versions
+                .iter()
+                .all(|v| v.source == EntrySource::LogfileRollback)
+        );
+        assert_eq!(versions[0].mft_offset, mft_offset as u64);
+        assert!(
+            versions
+                .iter()
+                .all(|v| v.fixup_status == FixupStatus::MismatchInSector(1))
+        );
+    }
+
+    #[test]
+    fn finds_records_without_a_current_copy_through_the_mft_runs() {
+        let geometry = geometry();
+        // `$MFT`: 8 clusters at LCN 4 (records 0 to 31), then 8 at LCN 100.
+        let mft = mft_record(
+            1024,
+            0,
+            1,
+            &[
+                file_name_attribute("$MFT", 5),
+                data_fragment(0, 16 * 4096, &[0x11, 0x08, 0x04, 0x11, 0x08, 0x60, 0x00]),
+            ],
+        );
+        let entries = [parse_record(&mft).unwrap()];
+
+        let mut deleted = mft_record(1024, 40, 1, &[file_name_attribute("gone.txt", 5)]);
+        apply_fixups(&mut deleted).unwrap();
+        let mut deallocate = operation(300, LogOperation::InitializeFileRecordSegment, 0);
+        deallocate.undo_data = deleted.clone();
+        let mut beyond_mft = operation(200, LogOperation::InitializeFileRecordSegment, 0);
+        beyond_mft.target_mft_record = Some(70);
+        beyond_mft.undo_data = deleted;
+
+        let image = vec![0; 4096];
+        let versions = roll_back_records(
+            ImageSlice::new(&image),
+            &geometry,
+            &entries,
+            &[deallocate, beyond_mft],
+        );
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].filename, "gone.txt");
+        // Record 40 is 2 clusters into the second run.
+        assert_eq!(versions[0].mft_offset, 102 * 4096);
+        assert_eq!(versions[0].fixup_status, FixupStatus::Ok);
+    }
+}
@@ -0,0 +1,270 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::boot_sector::{BootSectorSource, VolumeGeometry, parse_boot_sector};
+use crate::disk_image::ImageSlice;
+use crate::ntfs_logic::{parse_mft_record_bytes, read_mft_record};
+use crate::partitions::Partition;
+
+// Boot sectors are looked for on 512-byte boundaries, which covers 4K-sector disks too.
+const SECTOR_ALIGNMENT: usize = 512;
+const NTFS_OEM_ID: &[u8; 8] = b"NTFS    ";
+const MFT_NAME: &str = "$MFT";
+
+// What each piece of evidence adds to the confidence score (out of 100).
+const SCORE_PRIMARY_BOOT_SECTOR: u32 = 25;
+const SCORE_BACKUP_BOOT_SECTOR: u32 = 25;
+const SCORE_MFT: u32 = 30;
+const SCORE_MFT_MIRROR: u32 = 10;
+const SCORE_FITS_IN_IMAGE: u32 = 10;
+
+/// Candidates below this score aren't scanned as volumes.
+pub const MIN_SCAN_CONFIDENCE: u32 = 50;
+
+/// An NTFS volume found from its boot sectors rather than from a partition table.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "kind", rename = "partition_candidate")]
+pub struct PartitionCandidate {
+    pub offset: u64, // Byte offset in the image
+    pub size: u64,
+    pub geometry: VolumeGeometry,
+    pub has_primary_boot_sector: bool,
+    pub has_backup_boot_sector: bool,
+    /// Record 0 of `$MFT` found where the boot sector says.
+    pub mft_found: bool,
+    pub mft_mirror_found: bool,
+    pub fits_in_image: bool,
+    /// Also listed in the image's partition table.
+    pub in_partition_table: bool,
+    pub confidence: u32, // 0 to 100
+}
+
+impl PartitionCandidate {
+    pub fn end(&self) -> u64 {
+        self.offset.saturating_add(self.size)
+    }
+}
+
+/// Whether the `$MFT` record (record 0) is at `offset` in the volume.
+fn is_mft_record_zero(volume: ImageSlice<'_>, offset: u64, geometry: &VolumeGeometry) -> bool {
+    let Ok(offset) = usize::try_from(offset) else {
+        return false;
+    };
+    read_mft_record(volume, offset, Some(geometry))
+        .and_then(|(record, fixup_status)| {
+            parse_mft_record_bytes(&record, offset as u64, fixup_status, volume, Some(geometry))
+        })
+        .is_some_and(|entry| entry.mft_record_number == 0 && entry.filename == MFT_NAME)
+}
+
+/// Volume start and size implied by a boot sector at `offset`: the primary is in the first
+/// sector of the volume, the backup in the last.
+fn volume_extent(offset: u64, geometry: &VolumeGeometry) -> Option<(u64, u64)> {
+    let bytes_per_sector = geometry.bytes_per_sector as u64;
+    let size = geometry
+        .total_sectors
+        .checked_add(1)?
+        .checked_mul(bytes_per_sector)?;
+    let start = match geometry.source {
+        BootSectorSource::Primary => offset,
+        BootSectorSource::Backup => {
+            offset.checked_sub(geometry.total_sectors.checked_mul(bytes_per_sector)?)?
+        }
+    };
+    Some((start, size))
+}
+
+/// Whether the boot sector at `offset` is a backup: the primary it mirrors is where the
+/// volume would start, or it is the last sector of the image or of a partition.
+fn is_backup_boot_sector(
+    disk_image_buffer: ImageSlice<'_>,
+    offset: u64,
+    geometry: &VolumeGeometry,
+    partitions: &[Partition],
+) -> bool {
+    let bytes_per_sector = geometry.bytes_per_sector as u64;
+    let has_primary = geometry
+        .total_sectors
+        .checked_mul(bytes_per_sector)
+        .and_then(|size| offset.checked_sub(size))
+        .and_then(|start| usize::try_from(start).ok())
+        .and_then(|start| parse_boot_sector(disk_image_buffer, start, BootSectorSource::Primary))
+        .is_some_and(|primary| {
+            primary.total_sectors == geometry.total_sectors
+                && primary.volume_serial_number == geometry.volume_serial_number
+        });
+    let end = offset.saturating_add(bytes_per_sector);
+    has_primary
+        || end == disk_image_buffer.len() as u64
+        || partitions
+            .iter()
+            .any(|p| p.offset.saturating_add(p.size) == end)
+}
+
+/// Looks for NTFS boot sectors (primary or backup) anywhere in the image, and scores the
+/// volumes they describe by checking where they say `$MFT` and `$MFTMirr` are.
+///
+/// Candidates are sorted by decreasing confidence.
+pub fn find_partition_candidates(
+    disk_image_buffer: ImageSlice<'_>,
+    partitions: &[Partition],
+) -> Vec<PartitionCandidate> {
+    // Boot sectors that agree on the volume's start, size and serial number are merged.
+    let mut found: BTreeMap<(u64, u64, String), PartitionCandidate> = BTreeMap::new();
+
+    for (offset, start) in disk_image_buffer.sample::<11>(SECTOR_ALIGNMENT) {
+        if &start[3..11] != NTFS_OEM_ID {
+            continue;
+        }
+        let Some(mut geometry) =
+            parse_boot_sector(disk_image_buffer, offset, BootSectorSource::Primary)
+        else {
+            continue;
+        };
+        // Each boot sector is read as either the primary or the backup, never both.
+        if is_backup_boot_sector(disk_image_buffer, offset as u64, &geometry, partitions) {
+            geometry.source = BootSectorSource::Backup;
+        }
+        let Some((start, size)) = volume_extent(offset as u64, &geometry) else {
+            continue;
+        };
+
+        let key = (start, size, geometry.volume_serial_number.clone());
+        let candidate = found.entry(key).or_insert_with(|| PartitionCandidate {
+            offset: start,
+            size,
+            geometry: geometry.clone(),
+            has_primary_boot_sector: false,
+            has_backup_boot_sector: false,
+            mft_found: false,
+            mft_mirror_found: false,
+            fits_in_image: false,
+            in_partition_table: false,
+            confidence: 0,
+        });
+        match geometry.source {
+            BootSectorSource::Primary => {
+                candidate.has_primary_boot_sector = true;
+                // Report the geometry as read from the primary, when there is one.
+                candidate.geometry = geometry;
+            }
+            BootSectorSource::Backup => candidate.has_backup_boot_sector = true,
+        }
+    }
+
+    let mut candidates: Vec<PartitionCandidate> = found
+        .into_values()
+        .map(|mut candidate| {
+            let image_len = disk_image_buffer.len() as u64;
+            let volume = disk_image_buffer.slice(candidate.offset, candidate.size);
+            let geometry = &candidate.geometry;
+
+            candidate.fits_in_image = candidate.end() <= image_len;
+            candidate.mft_found = geometry
+                .mft_offset()
+                .is_some_and(|offset| is_mft_record_zero(volume, offset, geometry));
+            candidate.mft_mirror_found = geometry
+                .mft_mirror_lcn
+                .checked_mul(geometry.cluster_size)
+                .is_some_and(|offset| is_mft_record_zero(volume, offset, geometry));
+            candidate.in_partition_table = partitions
+                .iter()
+                .any(|p| p.offset == candidate.offset && p.size >= candidate.size);
+            candidate.confidence = [
+                (candidate.has_primary_boot_sector, SCORE_PRIMARY_BOOT_SECTOR),
+                (candidate.has_backup_boot_sector, SCORE_BACKUP_BOOT_SECTOR),
+                (candidate.mft_found, SCORE_MFT),
+                (candidate.mft_mirror_found, SCORE_MFT_MIRROR),
+                (candidate.fits_in_image, SCORE_FITS_IN_IMAGE),
+            ]
+            .into_iter()
+            .filter(|(found, _)| *found)
+            .map(|(_, score)| score)
+            .sum();
+            candidate
+        })
+        .collect();
+
+    candidates.sort_by_key(|c| (std::cmp::Reverse(c.confidence), c.offset));
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_sector::tests::boot_sector;
+    use crate::ntfs_logic::tests::{file_name_attribute, mft_record};
+    use crate::partitions::PartitionScheme;
+
+    const SECTOR: usize = 512;
+
+    /// An image with a 64-sector volume at sector 16, its `$MFT` record 0 at cluster 4 and
+    /// its mirror at cluster 2, and 16 sectors of free space after it.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 96 * SECTOR];
+        let volume = &mut image[16 * SECTOR..80 * SECTOR];
+        volume[..SECTOR].copy_from_slice(&boot_sector(63));
+        volume[63 * SECTOR..].copy_from_slice(&boot_sector(63));
+        let record = mft_record(1024, 0, 1, &[file_name_attribute(MFT_NAME, 5)]);
+        volume[16384..16384 + 1024].copy_from_slice(&record);
+        volume[8192..8192 + 1024].copy_from_slice(&record);
+        image
+    }
+
+    #[test]
+    fn scores_a_volume_found_from_its_boot_sectors() {
+        let candidates = find_partition_candidates(ImageSlice::new(&image()), &[]);
+
+        let best = &candidates[0];
+        assert_eq!((best.offset, best.size), (16 * 512, 64 * 512));
+        assert!(best.has_primary_boot_sector && best.has_backup_boot_sector);
+        assert!(best.mft_found && best.mft_mirror_found && best.fits_in_image);
+        assert!(!best.in_partition_table);
+        assert_eq!(best.confidence, 100);
+        // Neither boot sector is also read the other way round.
+        assert_eq!(candidates.len(), 1);
+    }
+
+    #[test]
+    fn scores_a_lone_backup_boot_sector() {
+        let mut image = image();
+        // Wipe the primary boot sector and the MFT mirror.
+        image[16 * SECTOR..17 * SECTOR].fill(0);
+        image[16 * SECTOR + 8192..16 * SECTOR + 9216].fill(0);
+
+        // Without the primary, the backup is recognized by ending a partition...
+        let partition = Partition {
+            index: 1,
+            scheme: PartitionScheme::Mbr,
+            offset: 16 * 512,
+            size: 64 * 512,
+            partition_type: "07".to_string(),
+            name: None,
+        };
+        let candidates = find_partition_candidates(ImageSlice::new(&image), &[partition]);
+        let best = &candidates[0];
+        assert_eq!((best.offset, best.size), (16 * 512, 64 * 512));
+        assert!(!best.has_primary_boot_sector && best.has_backup_boot_sector);
+        assert!(best.mft_found && !best.mft_mirror_found && best.in_partition_table);
+        assert_eq!(best.confidence, 65);
+
+        // ...or the image.
+        image.truncate(80 * SECTOR);
+        let candidates = find_partition_candidates(ImageSlice::new(&image), &[]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].offset, 16 * 512);
+        assert_eq!(candidates[0].confidence, 65);
+    }
+
+    #[test]
+    fn reads_an_unmatched_boot_sector_as_a_primary() {
+        let mut image = image();
+        image[16 * SECTOR..17 * SECTOR].fill(0);
+
+        let candidates = find_partition_candidates(ImageSlice::new(&image), &[]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].offset, 79 * 512);
+        assert!(candidates[0].has_primary_boot_sector);
+        assert!(candidates[0].confidence < MIN_SCAN_CONFIDENCE);
+    }
+}
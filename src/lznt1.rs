@@ -0,0 +1,145 @@
+/// LZNT1, the compression NTFS applies to compression units of files with the
+/// `compressed` attribute.
+///
+/// The input is a sequence of chunks, each holding up to 4 KiB of output. A chunk starts
+/// with a 16-bit header: bit 15 is set if the chunk is compressed, the low 12 bits are
+/// its size minus one (header excluded). A zero header ends the data.
+const CHUNK_SIZE: usize = 4096;
+const CHUNK_COMPRESSED: u16 = 0x8000;
+const CHUNK_SIZE_MASK: u16 = 0x0FFF;
+
+/// Decompresses one compressed chunk, appending at most `CHUNK_SIZE` bytes to `output`.
+///
+/// Compressed chunks are groups of a flag byte followed by 8 items: a literal byte
+/// (flag bit clear) or a 16-bit back-reference (flag bit set). The split between the
+/// offset and length of a back-reference depends on the position in the chunk: the
+/// further along, the more bits go to the offset.
+fn decompress_chunk(chunk: &[u8], output: &mut Vec<u8>) -> Option<()> {
+    let chunk_start = output.len();
+    let mut position = 0;
+
+    while position < chunk.len() {
+        let flags = chunk[position];
+        position += 1;
+
+        for bit in 0..8 {
+            if position >= chunk.len() {
+                break;
+            }
+            let written = output.len() - chunk_start;
+            if written >= CHUNK_SIZE {
+                return Some(());
+            }
+
+            if flags & (1 << bit) == 0 {
+                output.push(chunk[position]);
+                position += 1;
+                continue;
+            }
+
+            let token = u16::from_le_bytes(chunk.get(position..position + 2)?.try_into().ok()?);
+            position += 2;
+
+            // No back-reference can come before the first byte of the chunk.
+            if written == 0 {
+                return None;
+            }
+            let mut offset_shift = 12;
+            let mut p = written - 1;
+            while p >= 0x10 {
+                offset_shift -= 1;
+                p >>= 1;
+            }
+            let length_mask = 0xFFFF >> (16 - offset_shift);
+            let offset = (token >> offset_shift) as usize + 1;
+            let length = (token & length_mask) as usize + 3;
+
+            if offset > written {
+                return None;
+            }
+            // The source may overlap the bytes being written (runs of repeated bytes), so
+            // copy one byte at a time.
+            let length = length.min(CHUNK_SIZE - written);
+            let source = output.len() - offset;
+            for i in 0..length {
+                output.push(output[source + i]);
+            }
+        }
+    }
+
+    Some(())
+}
+
+/// Decompresses a compression unit into `output_size` bytes. Chunks that decompress to
+/// less than 4 KiB are zero-padded, as is anything after the last chunk (or after
+/// corrupt data).
+pub fn decompress(input: &[u8], output_size: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(output_size);
+    let mut position = 0;
+
+    while output.len() < output_size && position + 2 <= input.len() {
+        let header = u16::from_le_bytes([input[position], input[position + 1]]);
+        if header == 0 {
+            break;
+        }
+        position += 2;
+
+        let chunk_length = (header & CHUNK_SIZE_MASK) as usize + 1;
+        let Some(chunk) = input.get(position..position + chunk_length) else {
+            break;
+        };
+        position += chunk_length;
+
+        let chunk_start = output.len();
+        if header & CHUNK_COMPRESSED != 0 {
+            if decompress_chunk(chunk, &mut output).is_none() {
+                break;
+            }
+        } else {
+            output.extend_from_slice(chunk);
+        }
+        output.resize(chunk_start + CHUNK_SIZE, 0);
+    }
+
+    output.resize(output_size, 0);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The LZNT1 example of MS-XCA: a single compressed chunk.
+    const COMPRESSED: [u8; 59] = [
+        0x38, 0xB0, 0x88, 0x46, 0x23, 0x20, 0x00, 0x20, 0x47, 0x20, 0x41, 0x00, 0x10, 0xA2, 0x47,
+        0x01, 0xA0, 0x45, 0x20, 0x44, 0x00, 0x08, 0x45, 0x01, 0x50, 0x79, 0x00, 0xC0, 0x45, 0x20,
+        0x05, 0x24, 0x13, 0x88, 0x05, 0xB4, 0x02, 0x4A, 0x44, 0xEF, 0x03, 0x58, 0x02, 0x8C, 0x09,
+        0x16, 0x01, 0x48, 0x45, 0x00, 0xBE, 0x00, 0x9E, 0x00, 0x04, 0x01, 0x18, 0x90, 0x00,
+    ];
+    const DECOMPRESSED: &[u8] = b"F# F# G A A G F# E D D E F# F# E E F# F# G A A G F# E D D E F# \
+        E D D E E F# D E F# G F# D E F# G F# E D E A F# F# G A A G F# E D D E F# E D D\0";
+
+    #[test]
+    fn decompresses_ms_xca_example() {
+        let output = decompress(&COMPRESSED, CHUNK_SIZE);
+        assert_eq!(&output[..DECOMPRESSED.len()], DECOMPRESSED);
+        assert!(output[DECOMPRESSED.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn copies_uncompressed_chunks() {
+        // Header 0x3002: uncompressed, 3 bytes; then the end marker.
+        let input = [0x02, 0x30, b'a', b'b', b'c', 0x00, 0x00];
+        let output = decompress(&input, 2 * CHUNK_SIZE);
+        assert_eq!(output.len(), 2 * CHUNK_SIZE);
+        assert_eq!(&output[..3], b"abc");
+        assert!(output[3..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn stops_at_a_back_reference_before_the_chunk() {
+        // A compressed chunk whose first item is a back-reference.
+        let input = [0x02, 0xB0, 0x01, 0x00, 0x00];
+        assert_eq!(decompress(&input, CHUNK_SIZE), vec![0; CHUNK_SIZE]);
+    }
+}
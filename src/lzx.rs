@@ -0,0 +1,344 @@
+/// LZX as WOF (and WIM) uses it: a 32 KiB window, every chunk compressed on its own, and
+/// the x86 call translation (E8) always enabled.
+///
+/// A chunk is a sequence of blocks. Verbatim and aligned-offset blocks carry Huffman codes
+/// for literals/matches (the main code) and match lengths, whose code lengths are sent as
+/// differences from the previous block's, through a small "pretree" code. Uncompressed
+/// blocks hold raw bytes.
+use crate::huffman::HuffmanCode;
+
+const NUM_CHARS: usize = 256;
+const NUM_OFFSET_SLOTS: usize = 30; // For a 32 KiB window
+const NUM_MAIN_SYMBOLS: usize = NUM_CHARS + NUM_OFFSET_SLOTS * 8;
+const NUM_LENGTH_SYMBOLS: usize = 249;
+const NUM_PRETREE_SYMBOLS: usize = 20;
+const NUM_ALIGNED_SYMBOLS: usize = 8;
+const MAX_CODE_LENGTH: u32 = 16;
+const MAX_PRETREE_CODE_LENGTH: u32 = 15;
+const MAX_ALIGNED_CODE_LENGTH: u32 = 7;
+
+const BLOCK_VERBATIM: u32 = 1;
+const BLOCK_ALIGNED: u32 = 2;
+const BLOCK_UNCOMPRESSED: u32 = 3;
+const DEFAULT_BLOCK_SIZE: usize = 32 * 1024;
+
+const NUM_PRIMARY_LENGTHS: usize = 7;
+const MIN_MATCH_LENGTH: usize = 2;
+const NUM_RECENT_OFFSETS: usize = 3;
+const ALIGNED_OFFSET_BITS: u32 = 3;
+
+// The "file size" WIM and WOF use for the E8 translation, whatever the real size.
+const E8_FILE_SIZE: i32 = 12_000_000;
+
+/// 16-bit little-endian words, read most significant bit first. Reads past the end
+/// return zeros, as the last codes of a chunk may be followed by fewer bits than a
+/// lookahead needs.
+struct BitReader<'a> {
+    input: &'a [u8],
+    position: usize,
+    bits: u64,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            position: 0,
+            bits: 0,
+            bit_count: 0,
+        }
+    }
+
+    fn ensure(&mut self, count: u32) {
+        while self.bit_count < count {
+            let word = match self.input.get(self.position..self.position + 2) {
+                Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]) as u64,
+                None => 0,
+            };
+            self.position += 2;
+            self.bits = (self.bits << 16) | word;
+            self.bit_count += 16;
+        }
+    }
+
+    fn peek(&mut self, count: u32) -> u32 {
+        self.ensure(count);
+        ((self.bits >> (self.bit_count - count)) & ((1 << count) - 1)) as u32
+    }
+
+    fn consume(&mut self, count: u32) {
+        self.bit_count -= count;
+        self.bits &= (1 << self.bit_count) - 1;
+    }
+
+    fn read_bits(&mut self, count: u32) -> u32 {
+        let value = self.peek(count);
+        self.consume(count);
+        value
+    }
+
+    fn read_symbol(&mut self, code: &HuffmanCode, max_length: u32) -> Option<u16> {
+        let (symbol, length) = code.decode(self.peek(max_length))?;
+        self.consume(length);
+        Some(symbol)
+    }
+
+    /// Skips to the next 16-bit boundary (a whole word if already there), and hands the
+    /// words that were read ahead back to the input, for reading raw bytes.
+    fn align(&mut self) {
+        self.ensure(1);
+        let padding = match self.bit_count % 16 {
+            0 => 16,
+            partial => partial,
+        };
+        self.consume(padding);
+        self.position -= (self.bit_count / 8) as usize;
+        self.bits = 0;
+        self.bit_count = 0;
+    }
+
+    fn read_bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.input.get(self.position..self.position + count)?;
+        self.position += count;
+        Some(bytes)
+    }
+}
+
+/// Offset slot `slot` covers offsets from its base, plus that many extra bits.
+fn offset_slot_base(slot: usize) -> usize {
+    match slot {
+        0..4 => slot,
+        _ => (2 | (slot & 1)) << (slot / 2 - 1),
+    }
+}
+
+fn offset_slot_extra_bits(slot: usize) -> u32 {
+    match slot {
+        0..4 => 0,
+        _ => (slot / 2 - 1) as u32,
+    }
+}
+
+/// Reads code lengths through the pretree, as changes to the previous `lengths`.
+fn read_code_lengths(reader: &mut BitReader, lengths: &mut [u8]) -> Option<()> {
+    let pretree_lengths: Vec<u8> = (0..NUM_PRETREE_SYMBOLS)
+        .map(|_| reader.read_bits(4) as u8)
+        .collect();
+    let pretree = HuffmanCode::new(&pretree_lengths, MAX_PRETREE_CODE_LENGTH)?;
+    let delta = |previous: u8, symbol: u16| ((previous as u16 + 17 - symbol) % 17) as u8;
+
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = reader.read_symbol(&pretree, MAX_PRETREE_CODE_LENGTH)?;
+        let (run, length) = match symbol {
+            0..17 => (1, delta(lengths[i], symbol)),
+            // Runs of zeros.
+            17 => (4 + reader.read_bits(4) as usize, 0),
+            18 => (20 + reader.read_bits(5) as usize, 0),
+            // A run of the same change.
+            _ => {
+                let run = 4 + reader.read_bits(1) as usize;
+                let symbol = reader.read_symbol(&pretree, MAX_PRETREE_CODE_LENGTH)?;
+                if symbol > 16 {
+                    return None;
+                }
+                (run, delta(lengths[i], symbol))
+            }
+        };
+        let end = (i + run).min(lengths.len());
+        lengths[i..end].fill(length);
+        i = end;
+    }
+
+    Some(())
+}
+
+/// Undoes the E8 translation: the encoder turned the relative targets of x86 `call`
+/// instructions into absolute positions, which compress better.
+fn undo_e8_translation(data: &mut [u8]) {
+    if data.len() <= 10 {
+        return;
+    }
+
+    let mut i = 0;
+    while i < data.len() - 10 {
+        if data[i] != 0xE8 {
+            i += 1;
+            continue;
+        }
+
+        let target = &mut data[i + 1..i + 5];
+        let absolute = i32::from_le_bytes((&*target).try_into().unwrap());
+        let position = i as i32;
+        if (0..E8_FILE_SIZE).contains(&absolute) {
+            target.copy_from_slice(&(absolute - position).to_le_bytes());
+        } else if (-position..0).contains(&absolute) {
+            target.copy_from_slice(&(absolute + E8_FILE_SIZE).to_le_bytes());
+        }
+        i += 5;
+    }
+}
+
+/// Decompresses one chunk into exactly `output_size` bytes. Returns `None` on corrupt data.
+pub fn decompress(input: &[u8], output_size: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(output_size);
+    let mut reader = BitReader::new(input);
+    let mut main_lengths = [0u8; NUM_MAIN_SYMBOLS];
+    let mut length_lengths = [0u8; NUM_LENGTH_SYMBOLS];
+    let mut recent_offsets = [1usize; NUM_RECENT_OFFSETS];
+
+    while output.len() < output_size {
+        let block_type = reader.read_bits(3);
+        let block_size = match reader.read_bits(1) {
+            1 => DEFAULT_BLOCK_SIZE,
+            _ => reader.read_bits(16) as usize,
+        };
+        if block_size == 0 {
+            return None;
+        }
+        // The last block of a short chunk may still claim the default size.
+        let block_size = block_size.min(output_size - output.len());
+        let block_end = output.len() + block_size;
+
+        if block_type == BLOCK_UNCOMPRESSED {
+            reader.align();
+            for offset in &mut recent_offsets {
+                *offset = u32::from_le_bytes(reader.read_bytes(4)?.try_into().ok()?) as usize;
+                if *offset == 0 {
+                    return None;
+                }
+            }
+            output.extend_from_slice(reader.read_bytes(block_size)?);
+            // Blocks are padded to a 16-bit boundary.
+            if block_size % 2 == 1 {
+                reader.read_bytes(1);
+            }
+            continue;
+        }
+
+        let aligned_code = match block_type {
+            BLOCK_ALIGNED => {
+                let lengths: Vec<u8> = (0..NUM_ALIGNED_SYMBOLS)
+                    .map(|_| reader.read_bits(3) as u8)
+                    .collect();
+                Some(HuffmanCode::new(&lengths, MAX_ALIGNED_CODE_LENGTH)?)
+            }
+            BLOCK_VERBATIM => None,
+            _ => return None,
+        };
+        read_code_lengths(&mut reader, &mut main_lengths[..NUM_CHARS])?;
+        read_code_lengths(&mut reader, &mut main_lengths[NUM_CHARS..])?;
+        read_code_lengths(&mut reader, &mut length_lengths)?;
+        let main_code = HuffmanCode::new(&main_lengths, MAX_CODE_LENGTH)?;
+        let length_code = HuffmanCode::new(&length_lengths, MAX_CODE_LENGTH)?;
+
+        while output.len() < block_end {
+            let symbol = reader.read_symbol(&main_code, MAX_CODE_LENGTH)? as usize;
+            if symbol < NUM_CHARS {
+                output.push(symbol as u8);
+                continue;
+            }
+
+            let symbol = symbol - NUM_CHARS;
+            let mut length = symbol & 7;
+            if length == NUM_PRIMARY_LENGTHS {
+                length += reader.read_symbol(&length_code, MAX_CODE_LENGTH)? as usize;
+            }
+            length += MIN_MATCH_LENGTH;
+
+            let slot = symbol >> 3;
+            let offset = if slot < NUM_RECENT_OFFSETS {
+                let offset = recent_offsets[slot];
+                recent_offsets[slot] = recent_offsets[0];
+                offset
+            } else {
+                let extra_bits = offset_slot_extra_bits(slot);
+                let mut offset = offset_slot_base(slot);
+                match &aligned_code {
+                    Some(aligned_code) if extra_bits >= ALIGNED_OFFSET_BITS => {
+                        offset +=
+                            (reader.read_bits(extra_bits - ALIGNED_OFFSET_BITS) as usize) << 3;
+                        offset +=
+                            reader.read_symbol(aligned_code, MAX_ALIGNED_CODE_LENGTH)? as usize;
+                    }
+                    _ => offset += reader.read_bits(extra_bits) as usize,
+                }
+                // Slots 0-2 are the recent offsets, so slot 3 stands for offset 1.
+                let offset = offset - 2;
+                recent_offsets[2] = recent_offsets[1];
+                recent_offsets[1] = recent_offsets[0];
+                offset
+            };
+            recent_offsets[0] = offset;
+
+            if offset > output.len() || length > block_end - output.len() {
+                return None;
+            }
+            // Matches may overlap the bytes being written, so copy one byte at a time.
+            let source = output.len() - offset;
+            for i in 0..length {
+                output.push(output[source + i]);
+            }
+        }
+    }
+
+    undo_e8_translation(&mut output);
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_a_verbatim_block() {
+        // A verbatim block of 12 bytes. The main code gives 3 bits to the literals 00, 20,
+        // 61-63, 90 and E8, and to the match of length 3 from offset slot 4. The literals
+        // 90 E8 20 00 00 00 61 62 63 are followed by a match of 3 bytes at offset 3 (slot 4
+        // and an extra bit of 1), and the call at 1 to the absolute position 0x20 is
+        // translated back to a relative 0x1F.
+        let input = [
+            0x00, 0x20, 0x00, 0xC0, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x22, 0x00, 0xB7, 0x06,
+            0x47, 0xFA, 0xFE, 0x87, 0x60, 0x0C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+            0x1B, 0x44, 0xFF, 0x5F, 0xDB, 0xFF, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x08, 0x40, 0xFF, 0x83, 0xFF, 0xFF, 0xDC, 0xFC, 0x09, 0x40, 0x00, 0xCF,
+        ];
+        assert_eq!(
+            decompress(&input, 12).as_deref(),
+            Some(
+                &[
+                    0x90, 0xE8, 0x1F, 0x00, 0x00, 0x00, 0x61, 0x62, 0x63, 0x61, 0x62, 0x63
+                ][..]
+            )
+        );
+    }
+
+    #[test]
+    fn decompresses_an_uncompressed_block() {
+        // Block type 3 with an explicit size of 24, padding to 16 bits, the three recent
+        // offsets, then the bytes.
+        let mut input = vec![0x01, 0x60, 0x00, 0x80];
+        input.extend([1, 0, 0, 0].repeat(3));
+        let mut data = vec![0x90; 24];
+        // Calls to the absolute positions 0x20 and -4.
+        data[2..7].copy_from_slice(&[0xE8, 0x20, 0x00, 0x00, 0x00]);
+        data[8..13].copy_from_slice(&[0xE8, 0xFC, 0xFF, 0xFF, 0xFF]);
+        input.extend(&data);
+
+        // 0x20 - 2, and -4 + 12,000,000.
+        data[3..7].copy_from_slice(&[0x1E, 0x00, 0x00, 0x00]);
+        data[9..13].copy_from_slice(&[0xFC, 0x1A, 0xB7, 0x00]);
+        assert_eq!(decompress(&input, 24), Some(data));
+    }
+
+    #[test]
+    fn leaves_calls_near_the_end() {
+        // The last 10 bytes aren't translated.
+        let mut data = vec![0; 16];
+        data[6..11].copy_from_slice(&[0xE8, 0x20, 0x00, 0x00, 0x00]);
+        let expected = data.clone();
+        undo_e8_translation(&mut data);
+        assert_eq!(data, expected);
+    }
+}
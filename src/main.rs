@@ -1,69 +1,785 @@
+mod bitmap;
+mod boot_sector;
+mod cluster_map;
+mod disk_image;
+mod ewf;
+mod extended_attributes;
+mod extension_records;
+mod huffman;
+mod index;
+mod logfile;
+mod logfile_rollback;
+mod lost_partitions;
+mod lznt1;
+mod lzx;
 mod ntfs_logic;
+mod partitions;
+mod paths;
+mod recover;
+mod reparse;
+mod security;
+mod stream_reader;
+mod usn_journal;
+mod vhd;
+mod vhdx;
+mod vmdk;
+mod wof;
+mod xpress_huffman;
 
-use anyhow::Result;
-use clap::Parser;
-use log::{debug, info};
-use memmap2::Mmap;
+use anyhow::{Result, bail};
+use clap::{Args, Parser, Subcommand};
+use log::{info, warn};
 use std::{
+    collections::HashSet,
     fs::File,
     io::{BufWriter, Write},
+    path::Path,
 };
 
-use ntfs_logic::scan_ntfs_image;
+use bitmap::{assess_recoverability, read_bitmap, read_bitmap_at};
+use boot_sector::{VolumeGeometry, find_volume_geometry};
+use cluster_map::ClusterMap;
+use disk_image::{DiskImage, ImageSlice, open_disk_image};
+use extension_records::merge_extension_records;
+use logfile::{LogFile, read_logfile};
+use logfile_rollback::roll_back_records;
+use lost_partitions::find_partition_candidates;
+use ntfs_logic::{
+    CarvedRecord, NtfsEntry, ResidentDataEncoding, carve_ntfs_image, scan_ntfs_image,
+};
+use partitions::{Partition, Volume, find_ntfs_volumes, find_partitions};
+use paths::{resolve_carved_index_paths, resolve_full_paths};
+use recover::{ManifestEntry, RecoveryStatus, SkipReason, recover_entry};
+use security::{read_security_descriptors, resolve_security_descriptors};
+use usn_journal::{carve_usn_records, read_usn_journal};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "NTFS filesystem recovery/forensics tool")]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Input disk image (raw)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand, the arguments of `scan`
+    #[command(flatten)]
+    scan: Option<ScanArgs>,
+}
+
+#[derive(Args, Debug)]
+struct ScanArgs {
+    /// Input disk image: raw, E01 or Ex01 (first segment), VHD, VHDX or VMDK
     #[arg(short, long)]
     input: String,
 
     /// Output NDJSON file
     #[arg(short, long)]
     output: String,
+
+    /// Encoding of resident file contents in the output
+    #[arg(long, value_enum, default_value_t = ResidentDataEncoding::Utf8)]
+    resident_data_encoding: ResidentDataEncoding,
+
+    /// Also write the records of the `$UsnJrnl:$J` change journal to this NDJSON file
+    /// (carved from the whole image if the journal itself isn't found)
+    #[arg(long)]
+    usn_journal_output: Option<String>,
+
+    /// Also write the restart areas and operations decoded from `$LogFile` to this
+    /// NDJSON file
+    #[arg(long)]
+    logfile_output: Option<String>,
+
+    /// Also output earlier versions of MFT records, rebuilt from `$LogFile` undo data
+    #[arg(long)]
+    logfile_rollback: bool,
+
+    /// Image offset of the `$Bitmap` content, for when its MFT record can't be found
+    #[arg(long)]
+    bitmap_offset: Option<u64>,
+
+    /// Also write clusters claimed by two files in use, deleted files overwritten by
+    /// files in use, and data runs past the end of the volume to this NDJSON file
+    #[arg(long)]
+    conflicts_output: Option<String>,
+
+    /// Also scan NTFS volumes found from orphan boot sectors, for when the partition
+    /// table is damaged or wiped
+    #[arg(long)]
+    search_lost_partitions: bool,
+
+    /// Don't check the MD5/SHA1 stored in EWF evidence files against the media (which
+    /// takes a full read of it) before scanning
+    #[arg(long)]
+    no_verify: bool,
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Scan an image for MFT records and INDX blocks and write their metadata as NDJSON
+    Scan(ScanArgs),
 
-    let cli = Cli::parse();
+    /// Recover the unnamed $DATA stream of each file found in an image
+    Recover {
+        /// Input disk image: raw, E01 or Ex01 (first segment), VHD, VHDX or VMDK
+        #[arg(short, long)]
+        input: String,
+
+        /// Output directory for recovered files (created if missing)
+        #[arg(short, long)]
+        output_dir: String,
+
+        /// Only recover files whose MFT record is no longer in use
+        #[arg(long)]
+        deleted_only: bool,
+
+        /// Also recover earlier versions of files, rebuilt from `$LogFile` undo data
+        #[arg(long)]
+        logfile_rollback: bool,
+
+        /// Also scan NTFS volumes found from orphan boot sectors, for when the partition
+        /// table is damaged or wiped
+        #[arg(long)]
+        search_lost_partitions: bool,
+    },
+
+    /// Find the files whose data runs cover a byte offset or cluster of an image, and write
+    /// them to stdout as NDJSON
+    Owner {
+        /// Input disk image: raw, E01 or Ex01 (first segment), VHD, VHDX or VMDK
+        #[arg(short, long)]
+        input: String,
+
+        /// Byte offset in the image (of the whole disk, if it has a partition table)
+        #[arg(long, required_unless_present = "cluster", conflicts_with = "cluster")]
+        offset: Option<u64>,
+
+        /// Cluster number (LCN), looked up in every NTFS partition
+        #[arg(long)]
+        cluster: Option<u64>,
+
+        /// Length of the range: bytes with `--offset`, clusters with `--cluster`
+        #[arg(long, default_value_t = 1)]
+        length: u64,
+
+        /// Also look in earlier versions of files, rebuilt from `$LogFile` undo data
+        #[arg(long)]
+        logfile_rollback: bool,
+
+        /// Also scan NTFS volumes found from orphan boot sectors, for when the partition
+        /// table is damaged or wiped
+        #[arg(long)]
+        search_lost_partitions: bool,
+    },
+
+    /// Look for NTFS volumes from their boot sectors (primary or backup), and write the
+    /// candidates to stdout as NDJSON, most likely first
+    FindPartitions {
+        /// Input disk image: raw, E01 or Ex01 (first segment), VHD, VHDX or VMDK
+        #[arg(short, long)]
+        input: String,
+    },
+}
+
+fn load_volume_geometry(disk_image_buffer: ImageSlice<'_>) -> Option<VolumeGeometry> {
+    let geometry = find_volume_geometry(disk_image_buffer);
+    match &geometry {
+        Some(geometry) => info!(
+            "Found NTFS boot sector ({:?}): {} bytes per cluster, MFT at cluster {}.",
+            geometry.source, geometry.cluster_size, geometry.mft_lcn
+        ),
+        None => warn!(
+            "No valid NTFS boot sector found. MFT record sizes will be read from each record header."
+        ),
+    }
+    geometry
+}
+
+fn write_usn_journal(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: Option<&VolumeGeometry>,
+    entries: &[NtfsEntry],
+    partition: Option<&Partition>,
+    usn_writer: &mut impl Write,
+) -> Result<()> {
+    let journal = geometry.and_then(|g| read_usn_journal(disk_image_buffer, g, entries));
+    let mut usn_records = match journal {
+        Some(usn_records) => {
+            info!("Read {} records from $UsnJrnl:$J.", usn_records.len());
+            usn_records
+        }
+        None => {
+            warn!("$UsnJrnl:$J not found. Carving USN records from the whole image instead.");
+            let usn_records = carve_usn_records(disk_image_buffer);
+            info!("Carved {} USN records.", usn_records.len());
+            usn_records
+        }
+    };
+
+    for usn_record in &mut usn_records {
+        if let Some(partition) = partition {
+            partition.tag_usn_record(usn_record);
+        }
+        writeln!(usn_writer, "{}", serde_json::to_string(usn_record)?)?;
+    }
+
+    Ok(())
+}
+
+fn load_logfile(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: Option<&VolumeGeometry>,
+    entries: &[NtfsEntry],
+) -> Option<LogFile> {
+    let Some(logfile) = geometry.and_then(|g| read_logfile(disk_image_buffer, g, entries)) else {
+        warn!("$LogFile not found (or no volume geometry to read it with). Skipping it.");
+        return None;
+    };
+    info!(
+        "Decoded {} operations from $LogFile.",
+        logfile.operations.len()
+    );
+    Some(logfile)
+}
+
+fn write_logfile(logfile: &LogFile, logfile_writer: &mut impl Write) -> Result<()> {
+    for restart_area in &logfile.restart_areas {
+        writeln!(logfile_writer, "{}", serde_json::to_string(restart_area)?)?;
+    }
+    for operation in &logfile.operations {
+        writeln!(logfile_writer, "{}", serde_json::to_string(operation)?)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the earlier versions of MFT records rebuilt from `$LogFile` to `entries`.
+fn add_logfile_rollback_entries(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: Option<&VolumeGeometry>,
+    logfile: Option<&LogFile>,
+    entries: &mut Vec<NtfsEntry>,
+) {
+    let (Some(geometry), Some(logfile)) = (geometry, logfile) else {
+        return;
+    };
+    let versions = roll_back_records(disk_image_buffer, geometry, entries, &logfile.operations);
+    info!(
+        "Rebuilt {} earlier MFT record versions from $LogFile.",
+        versions.len()
+    );
+    entries.extend(versions);
+}
+
+/// Checks the data runs of deleted files (and earlier versions) against `$Bitmap`.
+fn add_recoverability(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: &VolumeGeometry,
+    bitmap_offset: Option<u64>,
+    cluster_map: &ClusterMap,
+    entries: &mut [NtfsEntry],
+) {
+    let bitmap = match bitmap_offset {
+        Some(offset) => read_bitmap_at(disk_image_buffer, geometry, offset),
+        None => read_bitmap(disk_image_buffer, geometry, entries),
+    };
+    let Some(bitmap) = bitmap else {
+        warn!("$Bitmap not found. Skipping the recoverability assessment.");
+        return;
+    };
+    info!(
+        "Read $Bitmap ({} clusters). Assessing the recoverability of deleted files.",
+        bitmap.cluster_count()
+    );
+    assess_recoverability(entries, &bitmap, cluster_map);
+}
+
+fn write_conflicts(
+    geometry: &VolumeGeometry,
+    cluster_map: &ClusterMap,
+    conflicts_writer: &mut impl Write,
+) -> Result<()> {
+    let conflicts = cluster_map.conflicts(geometry.cluster_count());
+    info!("Found {} cluster conflicts.", conflicts.len());
+
+    for conflict in &conflicts {
+        writeln!(conflicts_writer, "{}", serde_json::to_string(conflict)?)?;
+    }
+
+    Ok(())
+}
 
-    let input_file = File::open(&cli.input)?;
-    debug!("Opened input file: {}", &cli.input);
-    // Advisory lock - prevents writes by cooperating processes.
-    // Reduces a risk from unsafe mmap (e.g., if file is shortened or deleted during operation).
-    input_file.lock_shared()?;
-    debug!("Locked input file: {}", &cli.input);
+/// The NDJSON files `scan` writes besides its main output, if asked to.
+struct ExtraOutputs<'a> {
+    usn_journal_output: Option<&'a str>,
+    logfile_output: Option<&'a str>,
+    conflicts_output: Option<&'a str>,
+}
+
+/// The files `scan` writes to, shared by all the volumes of the image.
+struct ScanWriters {
+    output: BufWriter<File>,
+    usn_journal: Option<BufWriter<File>>,
+    logfile: Option<BufWriter<File>>,
+    conflicts: Option<BufWriter<File>>,
+}
+
+impl ScanWriters {
+    fn create(output: &str, extra_outputs: &ExtraOutputs) -> Result<Self> {
+        let create = |path: Option<&str>| -> Result<Option<BufWriter<File>>> {
+            Ok(match path {
+                Some(path) => Some(BufWriter::new(File::create(path)?)),
+                None => None,
+            })
+        };
+        Ok(Self {
+            output: BufWriter::new(File::create(output)?),
+            usn_journal: create(extra_outputs.usn_journal_output)?,
+            logfile: create(extra_outputs.logfile_output)?,
+            conflicts: create(extra_outputs.conflicts_output)?,
+        })
+    }
 
-    let disk_image_buffer_mmap = unsafe { Mmap::map(&input_file)? };
+    /// Writes the partition that the following lines of every output come from.
+    fn write_partition(&mut self, partition: &Partition) -> Result<()> {
+        let json = serde_json::to_string(partition)?;
+        writeln!(self.output, "{json}")?;
+        for writer in [
+            &mut self.usn_journal,
+            &mut self.logfile,
+            &mut self.conflicts,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            writeln!(writer, "{json}")?;
+        }
+        Ok(())
+    }
+}
 
-    // Optimization: Inform the kernel that it's fine to dump old pages after we're past,
-    // and that we'll be requesting forward-looking pages continuously.
-    disk_image_buffer_mmap.advise(memmap2::Advice::Sequential)?;
+fn run_scan(
+    disk_image_buffer_mmap: DiskImage,
+    output: &str,
+    resident_data_encoding: ResidentDataEncoding,
+    extra_outputs: ExtraOutputs,
+    logfile_rollback: bool,
+    bitmap_offset: Option<u64>,
+    search_lost_partitions: bool,
+) -> Result<()> {
+    let mut writers = ScanWriters::create(output, &extra_outputs)?;
 
-    let output_file = File::create(&cli.output)?;
-    let mut output_file_writer = BufWriter::new(output_file);
+    for volume in find_ntfs_volumes(disk_image_buffer_mmap.slice(), search_lost_partitions) {
+        let volume_offset = match &volume.partition {
+            Some(partition) => {
+                info!(
+                    "Scanning partition {} ({} bytes at offset {}).",
+                    partition.index, partition.size, partition.offset
+                );
+                writers.write_partition(partition)?;
+                partition.offset
+            }
+            None => 0,
+        };
+        // The `$Bitmap` offset is given in the image; only the volume holding it uses it.
+        let bitmap_offset = bitmap_offset
+            .and_then(|offset| offset.checked_sub(volume_offset))
+            .filter(|&offset| offset < volume.buffer.len() as u64);
 
-    let mut file_count: u64 = 0;
+        scan_volume(
+            &volume,
+            &mut writers,
+            resident_data_encoding,
+            logfile_rollback,
+            bitmap_offset,
+        )?;
+    }
+
+    writers.output.flush()?;
+    Ok(())
+}
+
+fn scan_volume(
+    volume: &Volume,
+    writers: &mut ScanWriters,
+    resident_data_encoding: ResidentDataEncoding,
+    logfile_rollback: bool,
+    bitmap_offset: Option<u64>,
+) -> Result<()> {
+    let disk_image_buffer = volume.buffer;
+
+    // The volume geometry goes first, so that consumers can turn data runs into byte offsets.
+    let geometry = load_volume_geometry(disk_image_buffer);
+    if let Some(geometry) = &geometry {
+        writeln!(writers.output, "{}", serde_json::to_string(geometry)?)?;
+    }
+
+    let mut entries = Vec::new();
+    let mut carved_index_entries = Vec::new();
 
     info!("Starting to process NTFS image's file entries.");
 
-    for ntfs_output_entry in scan_ntfs_image(&disk_image_buffer_mmap) {
-        let json = serde_json::to_string(&ntfs_output_entry)?;
-        writeln!(output_file_writer, "{json}")?;
-        file_count += 1;
+    for carved_record in carve_ntfs_image(disk_image_buffer, geometry.as_ref()) {
+        let ntfs_output_entry = match carved_record {
+            CarvedRecord::MftEntry(entry) => *entry,
+            CarvedRecord::IndexBlock(index_entries) => {
+                carved_index_entries.extend(index_entries);
+                continue;
+            }
+        };
+        entries.push(ntfs_output_entry);
+        let file_count = entries.len();
 
-        if file_count % 1000 == 0 {
+        if file_count.is_multiple_of(1000) {
+            let mft_offset = entries[file_count - 1].mft_offset;
             info!(
                 "Processed {} file entries. Last file position: {} = {:.3} GiB",
                 file_count,
-                ntfs_output_entry.mft_offset,
-                (ntfs_output_entry.mft_offset as f64 / (1024.0 * 1024.0 * 1024.0))
+                mft_offset,
+                (mft_offset as f64 / (1024.0 * 1024.0 * 1024.0))
             );
         }
     }
 
-    info!("Processed a total of {} file entries.", file_count);
+    info!("Processed a total of {} file entries.", entries.len());
+    info!(
+        "Carved {} index entries from INDX blocks ({} from slack).",
+        carved_index_entries.len(),
+        carved_index_entries.iter().filter(|e| e.from_slack).count()
+    );
+
+    let mut entries = merge_extension_records(entries);
+
+    let logfile = if writers.logfile.is_some() || logfile_rollback {
+        load_logfile(disk_image_buffer, geometry.as_ref(), &entries)
+    } else {
+        None
+    };
+    if logfile_rollback {
+        add_logfile_rollback_entries(
+            disk_image_buffer,
+            geometry.as_ref(),
+            logfile.as_ref(),
+            &mut entries,
+        );
+    }
+
+    if let Some(descriptors) = geometry
+        .as_ref()
+        .and_then(|g| read_security_descriptors(disk_image_buffer, g, &entries))
+    {
+        info!(
+            "Read {} security descriptors from $Secure.",
+            descriptors.len()
+        );
+        resolve_security_descriptors(&mut entries, &descriptors);
+    }
+
+    // Paths can only be resolved once every directory has been seen.
+    resolve_full_paths(&mut entries);
+    resolve_carved_index_paths(&entries, &mut carved_index_entries);
+
+    if let Some(geometry) = &geometry {
+        let cluster_map = ClusterMap::build(&entries);
+        add_recoverability(
+            disk_image_buffer,
+            geometry,
+            bitmap_offset,
+            &cluster_map,
+            &mut entries,
+        );
+        if let Some(conflicts_writer) = &mut writers.conflicts {
+            write_conflicts(geometry, &cluster_map, conflicts_writer)?;
+        }
+    } else if writers.conflicts.is_some() {
+        warn!("No volume geometry. Skipping the cluster conflicts.");
+    }
+
+    if let Some(usn_writer) = &mut writers.usn_journal {
+        write_usn_journal(
+            disk_image_buffer,
+            geometry.as_ref(),
+            &entries,
+            volume.partition.as_ref(),
+            usn_writer,
+        )?;
+    }
+    if let (Some(logfile_writer), Some(logfile)) = (&mut writers.logfile, &logfile) {
+        write_logfile(logfile, logfile_writer)?;
+    }
+
+    // Offsets were relative to the partition until now, for reading from it.
+    if let Some(partition) = &volume.partition {
+        for entry in &mut entries {
+            partition.tag_entry(entry);
+        }
+        for carved_index_entry in &mut carved_index_entries {
+            partition.tag_index_entry(carved_index_entry);
+        }
+    }
+
+    let output_file_writer = &mut writers.output;
+
+    for ntfs_output_entry in &mut entries {
+        for stream in &mut ntfs_output_entry.data_streams {
+            stream.encode_resident_data(resident_data_encoding);
+        }
+        let json = serde_json::to_string(ntfs_output_entry)?;
+        writeln!(output_file_writer, "{json}")?;
+    }
+
+    for carved_index_entry in &carved_index_entries {
+        let json = serde_json::to_string(carved_index_entry)?;
+        writeln!(output_file_writer, "{json}")?;
+    }
 
     Ok(())
 }
+
+/// Scans a volume for its MFT entries, for `recover` and `owner`.
+fn load_volume_entries(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: Option<&VolumeGeometry>,
+    logfile_rollback: bool,
+) -> Vec<NtfsEntry> {
+    // Data runs can be split across extension records, so those must be merged first.
+    let mut entries =
+        merge_extension_records(scan_ntfs_image(disk_image_buffer, geometry).collect());
+    if logfile_rollback {
+        let logfile = load_logfile(disk_image_buffer, geometry, &entries);
+        add_logfile_rollback_entries(disk_image_buffer, geometry, logfile.as_ref(), &mut entries);
+    }
+    entries
+}
+
+fn run_recover(
+    input: &str,
+    output_dir: &str,
+    deleted_only: bool,
+    logfile_rollback: bool,
+    search_lost_partitions: bool,
+) -> Result<()> {
+    let disk_image_buffer_mmap = open_disk_image(input, false)?;
+
+    let output_dir = Path::new(output_dir);
+    std::fs::create_dir_all(output_dir)?;
+
+    let manifest_file = File::create(output_dir.join("manifest.ndjson"))?;
+    let mut manifest_writer = BufWriter::new(manifest_file);
+
+    let mut recovered_names = HashSet::new();
+    let mut written_count: u64 = 0;
+    let mut skipped_count: u64 = 0;
+
+    info!("Starting to recover files from the NTFS image.");
+
+    for volume in find_ntfs_volumes(disk_image_buffer_mmap.slice(), search_lost_partitions) {
+        let geometry = load_volume_geometry(volume.buffer);
+        let mut entries = load_volume_entries(volume.buffer, geometry.as_ref(), logfile_rollback);
+        let cluster_map = ClusterMap::build(&entries);
+        if let Some(partition) = &volume.partition {
+            info!("Recovering files from partition {}.", partition.index);
+            for entry in &mut entries {
+                partition.tag_entry(entry);
+            }
+        }
+
+        for entry in &entries {
+            if deleted_only && entry.is_in_use {
+                continue;
+            }
+
+            let manifest_entry = if recovered_names.insert(recover::output_filename(entry)) {
+                recover_entry(
+                    volume.buffer,
+                    geometry.as_ref(),
+                    &cluster_map,
+                    entry,
+                    output_dir,
+                )?
+            } else {
+                ManifestEntry::skipped(entry, SkipReason::Duplicate)
+            };
+
+            if manifest_entry.status == RecoveryStatus::Skipped {
+                skipped_count += 1;
+            } else {
+                written_count += 1;
+            }
+
+            writeln!(
+                manifest_writer,
+                "{}",
+                serde_json::to_string(&manifest_entry)?
+            )?;
+        }
+    }
+
+    info!(
+        "Recovered {} files, skipped {} entries. See manifest.ndjson for details.",
+        written_count, skipped_count
+    );
+
+    Ok(())
+}
+
+/// Byte range to look up with `owner`, given as bytes or as clusters.
+enum OwnerQuery {
+    Bytes { offset: u64, length: u64 },
+    Clusters { lcn: u64, count: u64 },
+}
+
+fn run_owner(
+    input: &str,
+    query: OwnerQuery,
+    logfile_rollback: bool,
+    search_lost_partitions: bool,
+) -> Result<()> {
+    let disk_image_buffer_mmap = open_disk_image(input, false)?;
+    let mut owners = Vec::new();
+    let mut has_geometry = false;
+
+    // Byte offsets are in the image; cluster numbers are looked up in every volume.
+    for volume in find_ntfs_volumes(disk_image_buffer_mmap.slice(), search_lost_partitions) {
+        let Some(geometry) = load_volume_geometry(volume.buffer) else {
+            continue;
+        };
+        has_geometry = true;
+
+        let volume_offset = volume.partition.as_ref().map_or(0, |p| p.offset);
+        let cluster_size = geometry.cluster_size;
+        let (start, end) = match query {
+            OwnerQuery::Bytes { offset, length } => {
+                let Some(start) = offset
+                    .checked_sub(volume_offset)
+                    .filter(|&start| start < volume.buffer.len() as u64)
+                else {
+                    continue;
+                };
+                (start, start.saturating_add(length.max(1)))
+            }
+            OwnerQuery::Clusters { lcn, count } => (
+                lcn.saturating_mul(cluster_size),
+                lcn.saturating_add(count.max(1))
+                    .saturating_mul(cluster_size),
+            ),
+        };
+
+        let mut entries = load_volume_entries(volume.buffer, Some(&geometry), logfile_rollback);
+        resolve_full_paths(&mut entries);
+        let cluster_map = ClusterMap::build(&entries);
+        for mut owner in cluster_map.find_owners(start, end, cluster_size) {
+            if let Some(partition) = &volume.partition {
+                partition.tag_owner_match(&mut owner);
+            }
+            owners.push(owner);
+        }
+    }
+
+    if !has_geometry {
+        bail!("Data runs can't be mapped to byte offsets without the volume geometry.");
+    }
+    if owners.is_empty() {
+        info!("No data run covers the given range.");
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    for owner in &owners {
+        writeln!(stdout, "{}", serde_json::to_string(owner)?)?;
+    }
+
+    Ok(())
+}
+
+fn run_find_partitions(input: &str) -> Result<()> {
+    let disk_image_buffer_mmap = open_disk_image(input, false)?;
+    let partitions = find_partitions(disk_image_buffer_mmap.slice());
+    let candidates = find_partition_candidates(disk_image_buffer_mmap.slice(), &partitions);
+    info!(
+        "Found {} partition table entries and {} NTFS volume candidates.",
+        partitions.len(),
+        candidates.len()
+    );
+
+    let mut stdout = std::io::stdout().lock();
+    for partition in &partitions {
+        writeln!(stdout, "{}", serde_json::to_string(partition)?)?;
+    }
+    for candidate in &candidates {
+        writeln!(stdout, "{}", serde_json::to_string(candidate)?)?;
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let cli = Cli::parse();
+
+    let command = match (cli.command, cli.scan) {
+        (Some(command), _) => command,
+        // The original invocation, `-i image -o output.ndjson`, still scans.
+        (None, Some(args)) => Command::Scan(args),
+        (None, None) => unreachable!("clap requires the scan arguments without a subcommand"),
+    };
+
+    match command {
+        Command::Scan(ScanArgs {
+            input,
+            output,
+            resident_data_encoding,
+            usn_journal_output,
+            logfile_output,
+            logfile_rollback,
+            bitmap_offset,
+            conflicts_output,
+            search_lost_partitions,
+            no_verify,
+        }) => run_scan(
+            // Only `scan` verifies evidence hashes: the other commands look at a few
+            // records and shouldn't pay for a full read of the media.
+            open_disk_image(&input, !no_verify)?,
+            &output,
+            resident_data_encoding,
+            ExtraOutputs {
+                usn_journal_output: usn_journal_output.as_deref(),
+                logfile_output: logfile_output.as_deref(),
+                conflicts_output: conflicts_output.as_deref(),
+            },
+            logfile_rollback,
+            bitmap_offset,
+            search_lost_partitions,
+        ),
+        Command::Recover {
+            input,
+            output_dir,
+            deleted_only,
+            logfile_rollback,
+            search_lost_partitions,
+        } => run_recover(
+            &input,
+            &output_dir,
+            deleted_only,
+            logfile_rollback,
+            search_lost_partitions,
+        ),
+        Command::Owner {
+            input,
+            offset,
+            cluster,
+            length,
+            logfile_rollback,
+            search_lost_partitions,
+        } => {
+            let query = match (offset, cluster) {
+                (Some(offset), _) => OwnerQuery::Bytes { offset, length },
+                (None, lcn) => OwnerQuery::Clusters {
+                    lcn: lcn.unwrap_or_default(),
+                    count: length,
+                },
+            };
+            run_owner(&input, query, logfile_rollback, search_lost_partitions)
+        }
+        Command::FindPartitions { input } => run_find_partitions(&input),
+    }
+}
@@ -1,15 +1,48 @@
+use base64::prelude::{BASE64_STANDARD, Engine};
 use chrono::{DateTime, TimeZone, Utc};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::warn;
 use serde::Serialize;
 
+use crate::bitmap::Recoverability;
+use crate::boot_sector::VolumeGeometry;
+use crate::disk_image::ImageSlice;
+use crate::extended_attributes::{ExtendedAttribute, WslMetadata, decode_extended_attributes};
+use crate::index::{
+    CarvedIndexEntry, I30_INDEX_NAME, INDX_MAGIC, IndexEntry, carve_indx_block,
+    parse_index_allocation, parse_index_root,
+};
+use crate::paths::PathStatus;
+use crate::reparse::{ReparseInfo, parse_reparse_buffer};
+use crate::security::decode_security_descriptor;
+use crate::stream_reader::read_non_resident;
+
 const MFT_MAGIC: &[u8; 4] = b"FILE";
 
+// Bounds for the MFT record size (1024 on most volumes, 4096 on 4Kn volumes). Windows
+// never formats anything larger, so a bigger size is taken to be corruption. Records are
+// protected by fixups every 512 bytes, whatever their size.
+pub const MIN_RECORD_SIZE: usize = 512;
+pub const MAX_RECORD_SIZE: usize = 4096;
+
+// Upper bounds for non-resident metadata that is read into memory, to avoid huge reads
+// from corrupt headers.
+const MAX_ATTRIBUTE_LIST_SIZE: u64 = 256 * 1024;
+const MAX_INDEX_ALLOCATION_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_SECURITY_DESCRIPTOR_SIZE: u64 = 64 * 1024;
+const MAX_EA_SIZE: u64 = 64 * 1024;
+
 const ATTR_STANDARD_INFORMATION: u32 = 0x10;
+const ATTR_ATTRIBUTE_LIST: u32 = 0x20;
 const ATTR_FILE_NAME: u32 = 0x30;
 const ATTR_OBJECT_ID: u32 = 0x40;
+const ATTR_SECURITY_DESCRIPTOR: u32 = 0x50;
 const ATTR_DATA: u32 = 0x80;
+pub const ATTR_INDEX_ROOT: u32 = 0x90;
+pub const ATTR_INDEX_ALLOCATION: u32 = 0xA0;
 const ATTR_REPARSE_POINT: u32 = 0xC0;
 const ATTR_EA_INFORMATION: u32 = 0xD0;
+const ATTR_EA: u32 = 0xE0;
 const ATTR_END: u32 = 0xFFFFFFFF;
 
 // File attribute flags
@@ -47,7 +80,7 @@ pub struct FileAttributes {
 }
 
 impl FileAttributes {
-    fn from_flags(flags: u32) -> Self {
+    pub fn from_flags(flags: u32) -> Self {
         Self {
             readonly: flags & FILE_ATTR_READONLY != 0,
             hidden: flags & FILE_ATTR_HIDDEN != 0,
@@ -71,40 +104,159 @@ impl FileAttributes {
 pub struct AlternateFilename {
     pub name: String,
     pub namespace: u8,
+    pub timestamps: FileNameTimestamps,
+    pub file_attributes: FileAttributes,
+}
+
+/// Timestamps stored in a `$FILE_NAME` attribute. Unlike the `$STANDARD_INFORMATION` ones,
+/// these can't be changed through the usual Windows APIs, which makes them useful for
+/// spotting timestomping.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct FileNameTimestamps {
+    pub created: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+    pub mft_modified: Option<DateTime<Utc>>,
+    pub accessed: Option<DateTime<Utc>>,
+}
+
+/// Signs that the `$STANDARD_INFORMATION` timestamps were tampered with. The `*BeforeFn`
+/// variants mean an SI timestamp is earlier than the matching `$FILE_NAME` one.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestompIndicator {
+    CreatedBeforeFn,
+    ModifiedBeforeFn,
+    MftModifiedBeforeFn,
+    AccessedBeforeFn,
+    /// Real timestamps have 100ns resolution, so whole seconds suggest they were set by a tool.
+    CreatedWholeSeconds,
+    ModifiedWholeSeconds,
 }
 
 #[derive(Debug, Serialize, Clone)]
 pub struct DataRun {
-    pub cluster_offset: i64,
+    /// Absolute LCN of the run's first cluster. `None` for sparse runs (no clusters on disk).
+    pub cluster_offset: Option<i64>,
     pub cluster_count: u64,
 }
 
+/// How resident data is written into the NDJSON output.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ResidentDataEncoding {
+    Base64,
+    Hex,
+    /// Plain text if the data is valid UTF-8, base64 otherwise.
+    Utf8,
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct DataStream {
     pub name: Option<String>,
     pub resident: bool,
     pub size: u64,
     pub allocated_size: u64,
-    pub resident_data: Option<String>,
+    pub initialized_size: u64,
+    #[serde(skip)]
+    pub lowest_vcn: u64, // First VCN covered by this fragment of a non-resident stream
+    #[serde(skip)]
+    pub resident_content: Option<Vec<u8>>, // Raw bytes of resident data
+    pub resident_data: Option<String>, // Filled in by `encode_resident_data`
+    pub resident_data_encoding: Option<ResidentDataEncoding>,
     pub data_runs: Option<Vec<DataRun>>, // For non-resident data
+    pub compression_unit: Option<u32>,   // Clusters per compression unit, if compressed
+    pub recoverability: Option<Recoverability>, // Filled in by `bitmap::assess_recoverability`
+}
+
+impl DataStream {
+    /// Fills in `resident_data` from the raw resident content, using `encoding`.
+    pub fn encode_resident_data(&mut self, encoding: ResidentDataEncoding) {
+        let Some(content) = &self.resident_content else {
+            return;
+        };
+
+        let (data, encoding) = match encoding {
+            ResidentDataEncoding::Hex => (
+                content.iter().map(|b| format!("{b:02x}")).collect(),
+                ResidentDataEncoding::Hex,
+            ),
+            ResidentDataEncoding::Utf8 if std::str::from_utf8(content).is_ok() => (
+                String::from_utf8_lossy(content).into_owned(),
+                ResidentDataEncoding::Utf8,
+            ),
+            ResidentDataEncoding::Base64 | ResidentDataEncoding::Utf8 => (
+                BASE64_STANDARD.encode(content),
+                ResidentDataEncoding::Base64,
+            ),
+        };
+
+        self.resident_data = Some(data);
+        self.resident_data_encoding = Some(encoding);
+    }
+}
+
+/// One entry of an `$ATTRIBUTE_LIST`: where an attribute (or a fragment of it) lives.
+#[derive(Debug, Serialize, Clone)]
+pub struct AttributeListEntry {
+    pub attribute_type: u32,
+    pub name: Option<String>,
+    pub lowest_vcn: u64,
+    pub mft_record: u64,
+    pub sequence: u16,
+    pub attribute_id: u16,
+}
+
+/// Outcome of applying the update sequence array to an MFT record.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FixupStatus {
+    Ok,
+    /// A single sector (0-based) did not end with the update sequence number.
+    MismatchInSector(usize),
+    /// More than one sector did not match; the record was only partially written.
+    Torn,
+}
+
+/// Where the MFT record behind an entry came from.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntrySource {
+    /// Found as-is in the image.
+    Mft,
+    /// An earlier version, rebuilt by replaying `$LogFile` undo data.
+    LogfileRollback,
 }
 
 #[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename = "mft_entry")]
 pub struct NtfsEntry {
     pub mft_offset: u64,
+    // For images with a partition table: which partition, and the offset within it
+    pub partition_index: Option<u32>,
+    pub partition_relative_offset: Option<u64>,
     pub mft_record_number: u64,
     pub sequence_number: u16,
     pub hardlink_count: u16,
     pub is_in_use: bool,
     pub is_directory: bool,
+    pub fixup_status: FixupStatus,
+    pub record_size: u32,
+    pub source: EntrySource,
+    pub logfile_lsn: Option<u64>, // For `LogfileRollback`: the state before this LSN
 
     // Main filename (Win32/POSIX)
     pub filename: String,
+    #[serde(skip)]
+    pub filename_namespace: u8,
     pub parent_mft_record: u64, // MFT record number of parent directory
     pub parent_sequence: u16,   // Sequence number of parent
     pub allocated_size: u64,
     pub real_size: u64,
 
+    // Full path, filled in by `paths::resolve_full_paths` once all records are scanned
+    pub full_path: Option<String>,
+    pub path_status: Option<PathStatus>,
+
     // Standard Information timestamps
     pub created: Option<DateTime<Utc>>,
     pub modified: Option<DateTime<Utc>>,
@@ -114,9 +266,16 @@ pub struct NtfsEntry {
     // File attributes
     pub file_attributes: Option<FileAttributes>,
 
+    // $FILE_NAME timestamps and flags (of the main filename)
+    pub fn_timestamps: Option<FileNameTimestamps>,
+    pub fn_file_attributes: Option<FileAttributes>,
+    pub timestomp_indicators: Vec<TimestompIndicator>,
+
     // Security and ownership
     pub owner_id: Option<u32>,
     pub security_id: Option<u32>,
+    pub owner_sid: Option<String>, // From `$SECURITY_DESCRIPTOR`, or `$Secure` via `security_id`
+    pub sddl: Option<String>,
     pub usn: Option<u64>,
 
     // Object ID (GUID)
@@ -128,15 +287,76 @@ pub struct NtfsEntry {
     // Data streams (unnamed + named)
     pub data_streams: Vec<DataStream>,
 
+    // Directory contents, from the `$I30` index (`$INDEX_ROOT` + `$INDEX_ALLOCATION`)
+    pub index_entries: Vec<IndexEntry>,
+
     // Reparse point
-    pub reparse_tag: Option<u32>,
-    pub reparse_target: Option<String>,
+    pub reparse: Option<ReparseInfo>,
 
     // Extended attributes
     pub has_extended_attributes: bool,
+    pub extended_attributes: Vec<ExtendedAttribute>, // From `$EA`
+    pub wsl: Option<WslMetadata>,                    // Linux metadata of files created through WSL
+
+    // Attributes spilled over into extension records
+    pub base_mft_record: Option<u64>, // Only set on extension records
+    pub base_sequence: Option<u16>,
+    pub attribute_list: Option<Vec<AttributeListEntry>>,
+    pub extension_records: Vec<u64>, // Filled in by `extension_records::merge_extension_records`
+}
+
+impl NtfsEntry {
+    /// Compares the `$STANDARD_INFORMATION` timestamps against the `$FILE_NAME` ones.
+    pub fn detect_timestomping(&self) -> Vec<TimestompIndicator> {
+        let mut indicators = Vec::new();
+
+        if let Some(fn_times) = &self.fn_timestamps {
+            let pairs = [
+                (
+                    self.created,
+                    fn_times.created,
+                    TimestompIndicator::CreatedBeforeFn,
+                ),
+                (
+                    self.modified,
+                    fn_times.modified,
+                    TimestompIndicator::ModifiedBeforeFn,
+                ),
+                (
+                    self.mft_modified,
+                    fn_times.mft_modified,
+                    TimestompIndicator::MftModifiedBeforeFn,
+                ),
+                (
+                    self.accessed,
+                    fn_times.accessed,
+                    TimestompIndicator::AccessedBeforeFn,
+                ),
+            ];
+            for (si, fn_time, indicator) in pairs {
+                if let (Some(si), Some(fn_time)) = (si, fn_time)
+                    && si < fn_time
+                {
+                    indicators.push(indicator);
+                }
+            }
+        }
+
+        let whole_seconds = [
+            (self.created, TimestompIndicator::CreatedWholeSeconds),
+            (self.modified, TimestompIndicator::ModifiedWholeSeconds),
+        ];
+        for (si, indicator) in whole_seconds {
+            if si.is_some_and(|t| t.timestamp_subsec_nanos() == 0) {
+                indicators.push(indicator);
+            }
+        }
+
+        indicators
+    }
 }
 
-fn parse_attr_header(buf: &[u8], offset: usize) -> Option<(u32, usize, bool, Option<String>)> {
+pub fn parse_attr_header(buf: &[u8], offset: usize) -> Option<(u32, usize, bool, Option<String>)> {
     if offset + 16 > buf.len() {
         return None;
     }
@@ -169,13 +389,15 @@ fn parse_attr_header(buf: &[u8], offset: usize) -> Option<(u32, usize, bool, Opt
     Some((attr_type, length, non_resident, attr_name))
 }
 
-#[derive(Debug)]
-struct FileNameAttr {
-    name: String,
-    parent_reference: u64,
-    namespace: u8,
-    allocated_size: u64,
-    real_size: u64,
+#[derive(Debug, Default)]
+pub struct FileNameAttr {
+    pub name: String,
+    pub parent_reference: u64,
+    pub namespace: u8,
+    pub allocated_size: u64,
+    pub real_size: u64,
+    pub timestamps: FileNameTimestamps,
+    pub flags: u32,
 }
 
 fn parse_filename(attr: &[u8]) -> Option<FileNameAttr> {
@@ -189,11 +411,26 @@ fn parse_filename(attr: &[u8]) -> Option<FileNameAttr> {
         return None;
     }
 
-    let content = &attr[content_offset..];
+    parse_filename_content(&attr[content_offset..])
+}
+
+/// Parses the content of a `$FILE_NAME` attribute. Also used for the keys of `$I30` index
+/// entries, which are copies of the child's `$FILE_NAME`.
+pub fn parse_filename_content(content: &[u8]) -> Option<FileNameAttr> {
+    if content.len() < 66 {
+        return None;
+    }
 
     let parent_reference = u64::from_le_bytes(content[0..8].try_into().ok()?);
     let allocated_size = u64::from_le_bytes(content[40..48].try_into().ok()?);
     let real_size = u64::from_le_bytes(content[48..56].try_into().ok()?);
+    let flags = u32::from_le_bytes(content[56..60].try_into().ok()?);
+    let timestamps = FileNameTimestamps {
+        created: filetime_to_utc(u64::from_le_bytes(content[8..16].try_into().ok()?)),
+        modified: filetime_to_utc(u64::from_le_bytes(content[16..24].try_into().ok()?)),
+        mft_modified: filetime_to_utc(u64::from_le_bytes(content[24..32].try_into().ok()?)),
+        accessed: filetime_to_utc(u64::from_le_bytes(content[32..40].try_into().ok()?)),
+    };
     let namespace = content[65];
     let name_len = content[64] as usize;
     let name_off = 66;
@@ -217,10 +454,12 @@ fn parse_filename(attr: &[u8]) -> Option<FileNameAttr> {
         namespace,
         allocated_size,
         real_size,
+        timestamps,
+        flags,
     })
 }
 
-fn parse_resident_data(attr: &[u8]) -> Option<Vec<u8>> {
+pub fn parse_resident_data(attr: &[u8]) -> Option<Vec<u8>> {
     if attr.len() < 24 {
         return None;
     }
@@ -235,6 +474,139 @@ fn parse_resident_data(attr: &[u8]) -> Option<Vec<u8>> {
     Some(attr[content_offset..content_offset + content_len].to_vec())
 }
 
+fn parse_attribute_list_entries(content: &[u8]) -> Vec<AttributeListEntry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + 26 <= content.len() {
+        let c = &content[offset..];
+        let attribute_type = u32::from_le_bytes(c[0..4].try_into().unwrap());
+        let entry_length = u16::from_le_bytes(c[4..6].try_into().unwrap()) as usize;
+        if attribute_type == 0 || attribute_type == ATTR_END || entry_length < 26 {
+            break;
+        }
+        if entry_length > c.len() {
+            break;
+        }
+
+        let name_length = c[6] as usize;
+        let name_offset = c[7] as usize;
+        let reference = u64::from_le_bytes(c[16..24].try_into().unwrap());
+
+        let name = if name_length > 0 && name_offset + name_length * 2 <= entry_length {
+            let utf16: Vec<u16> = c[name_offset..name_offset + name_length * 2]
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16(&utf16).ok()
+        } else {
+            None
+        };
+
+        entries.push(AttributeListEntry {
+            attribute_type,
+            name,
+            lowest_vcn: u64::from_le_bytes(c[8..16].try_into().unwrap()),
+            mft_record: reference & 0x0000_FFFF_FFFF_FFFF,
+            sequence: (reference >> 48) as u16,
+            attribute_id: u16::from_le_bytes(c[24..26].try_into().unwrap()),
+        });
+
+        offset += entry_length;
+    }
+
+    entries
+}
+
+/// Parses an `$ATTRIBUTE_LIST`. A non-resident list is read through its data runs, which
+/// requires the cluster size.
+fn parse_attribute_list(
+    attr: &[u8],
+    non_resident: bool,
+    disk_image_buffer: ImageSlice<'_>,
+    cluster_size: Option<u64>,
+) -> Option<Vec<AttributeListEntry>> {
+    if !non_resident {
+        return Some(parse_attribute_list_entries(&parse_resident_data(attr)?));
+    }
+
+    if attr.len() < 64 {
+        return None;
+    }
+    let size = u64::from_le_bytes(attr[48..56].try_into().ok()?);
+    let initialized_size = u64::from_le_bytes(attr[56..64].try_into().ok()?);
+    if size > MAX_ATTRIBUTE_LIST_SIZE {
+        return None;
+    }
+
+    let runs = parse_data_runs(attr)?;
+    let content = read_non_resident(
+        disk_image_buffer,
+        cluster_size?,
+        &runs,
+        size,
+        initialized_size,
+    );
+    Some(parse_attribute_list_entries(&content))
+}
+
+/// Reads the content of an attribute that may be resident or not, such as
+/// `$SECURITY_DESCRIPTOR` or `$EA`. Non-resident content over `max_size` is rejected.
+fn read_attribute_content(
+    attr: &[u8],
+    non_resident: bool,
+    disk_image_buffer: ImageSlice<'_>,
+    cluster_size: Option<u64>,
+    max_size: u64,
+) -> Option<Vec<u8>> {
+    if !non_resident {
+        return parse_resident_data(attr);
+    }
+
+    if attr.len() < 64 {
+        return None;
+    }
+    let size = u64::from_le_bytes(attr[48..56].try_into().ok()?);
+    let initialized_size = u64::from_le_bytes(attr[56..64].try_into().ok()?);
+    if size > max_size {
+        return None;
+    }
+
+    let runs = parse_data_runs(attr)?;
+    Some(read_non_resident(
+        disk_image_buffer,
+        cluster_size?,
+        &runs,
+        size,
+        initialized_size,
+    ))
+}
+
+/// Reads the INDX blocks of a non-resident `$INDEX_ALLOCATION` attribute.
+pub fn read_index_allocation_attribute(
+    attr: &[u8],
+    disk_image_buffer: ImageSlice<'_>,
+    cluster_size: u64,
+) -> Option<Vec<u8>> {
+    if attr.len() < 64 {
+        return None;
+    }
+    let size = u64::from_le_bytes(attr[48..56].try_into().ok()?);
+    let initialized_size = u64::from_le_bytes(attr[56..64].try_into().ok()?);
+    if size > MAX_INDEX_ALLOCATION_SIZE {
+        return None;
+    }
+
+    let runs = parse_data_runs(attr)?;
+    Some(read_non_resident(
+        disk_image_buffer,
+        cluster_size,
+        &runs,
+        size,
+        initialized_size,
+    ))
+}
+
 fn parse_data_runs(attr: &[u8]) -> Option<Vec<DataRun>> {
     if attr.len() < 64 {
         return None;
@@ -246,12 +618,18 @@ fn parse_data_runs(attr: &[u8]) -> Option<Vec<DataRun>> {
         return None;
     }
 
+    let runs = parse_mapping_pairs(&attr[data_run_offset..]);
+    if runs.is_empty() { None } else { Some(runs) }
+}
+
+/// Decodes mapping pairs (the encoded data runs) up to their terminating zero byte.
+pub fn parse_mapping_pairs(mapping_pairs: &[u8]) -> Vec<DataRun> {
     let mut runs = Vec::new();
-    let mut offset = data_run_offset;
+    let mut offset = 0;
     let mut current_lcn: i64 = 0; // Logical Cluster Number (cumulative)
 
-    while offset < attr.len() {
-        let header = attr[offset];
+    while offset < mapping_pairs.len() {
+        let header = mapping_pairs[offset];
         if header == 0 {
             break; // End of data runs
         }
@@ -265,39 +643,45 @@ fn parse_data_runs(attr: &[u8]) -> Option<Vec<DataRun>> {
 
         offset += 1;
 
-        if offset + length_bytes + offset_bytes > attr.len() {
+        if offset + length_bytes + offset_bytes > mapping_pairs.len() {
             break;
         }
 
         // Read cluster count (unsigned)
         let mut cluster_count: u64 = 0;
         for i in 0..length_bytes {
-            cluster_count |= (attr[offset + i] as u64) << (i * 8);
+            cluster_count |= (mapping_pairs[offset + i] as u64) << (i * 8);
         }
         offset += length_bytes;
 
         // Read cluster offset (signed, relative to previous LCN)
         let mut cluster_offset: i64 = 0;
         for i in 0..offset_bytes {
-            cluster_offset |= (attr[offset + i] as i64) << (i * 8);
+            cluster_offset |= (mapping_pairs[offset + i] as i64) << (i * 8);
         }
         // Sign extend if necessary
-        if offset_bytes > 0 && (attr[offset + offset_bytes - 1] & 0x80) != 0 {
+        if offset_bytes > 0 && (mapping_pairs[offset + offset_bytes - 1] & 0x80) != 0 {
             for i in offset_bytes..8 {
                 cluster_offset |= 0xFF << (i * 8);
             }
         }
         offset += offset_bytes;
 
-        current_lcn += cluster_offset;
+        // A run without an offset field is sparse, and doesn't move the current LCN.
+        let run_lcn = if offset_bytes == 0 {
+            None
+        } else {
+            current_lcn += cluster_offset;
+            Some(current_lcn)
+        };
 
         runs.push(DataRun {
-            cluster_offset: current_lcn,
+            cluster_offset: run_lcn,
             cluster_count,
         });
     }
 
-    if runs.is_empty() { None } else { Some(runs) }
+    runs
 }
 
 fn parse_data_attribute(
@@ -309,35 +693,53 @@ fn parse_data_attribute(
         if attr.len() < 64 {
             return None;
         }
+        let allocated_size = u64::from_le_bytes(attr[40..48].try_into().ok()?);
         let real_size = u64::from_le_bytes(attr[48..56].try_into().ok()?);
-        let allocated_size = u64::from_le_bytes(attr[56..64].try_into().ok()?);
+        let initialized_size = u64::from_le_bytes(attr[56..64].try_into().ok()?);
+        let lowest_vcn = u64::from_le_bytes(attr[16..24].try_into().ok()?);
         let data_runs = parse_data_runs(attr);
+        // Stored as a power of two; zero means the stream isn't compressed.
+        let compression_unit = match attr[34] {
+            0 => None,
+            shift => 1u32.checked_shl(shift as u32),
+        };
 
         Some(DataStream {
             name: attr_name,
             resident: false,
             size: real_size,
             allocated_size,
+            initialized_size,
+            lowest_vcn,
+            resident_content: None,
             resident_data: None,
+            resident_data_encoding: None,
             data_runs,
+            compression_unit,
+            recoverability: None,
         })
     } else {
         let data = parse_resident_data(attr)?;
         let size = data.len() as u64;
-        let resident_str = String::from_utf8(data).ok();
 
         Some(DataStream {
             name: attr_name,
             resident: true,
             size,
             allocated_size: size,
-            resident_data: resident_str,
+            initialized_size: size,
+            lowest_vcn: 0,
+            resident_content: Some(data),
+            resident_data: None,
+            resident_data_encoding: None,
             data_runs: None,
+            compression_unit: None,
+            recoverability: None,
         })
     }
 }
 
-fn filetime_to_utc(ft: u64) -> Option<DateTime<Utc>> {
+pub fn filetime_to_utc(ft: u64) -> Option<DateTime<Utc>> {
     if ft == 0 {
         return None;
     }
@@ -351,18 +753,18 @@ fn filetime_to_utc(ft: u64) -> Option<DateTime<Utc>> {
     Utc.timestamp_opt(seconds, nanos).single()
 }
 
-fn parse_standard_information(
-    attr: &[u8],
-) -> Option<(
-    DateTime<Utc>,
-    DateTime<Utc>,
-    DateTime<Utc>,
-    DateTime<Utc>,
-    FileAttributes,
-    Option<u32>,
-    Option<u32>,
-    Option<u64>,
-)> {
+struct StandardInformation {
+    created: DateTime<Utc>,
+    modified: DateTime<Utc>,
+    mft_modified: DateTime<Utc>,
+    accessed: DateTime<Utc>,
+    file_attributes: FileAttributes,
+    owner_id: Option<u32>,
+    security_id: Option<u32>,
+    usn: Option<u64>,
+}
+
+fn parse_standard_information(attr: &[u8]) -> Option<StandardInformation> {
     let content_offset = u16::from_le_bytes(attr[20..22].try_into().ok()?) as usize;
 
     if content_offset + 48 > attr.len() {
@@ -398,16 +800,16 @@ fn parse_standard_information(
         None
     };
 
-    Some((
+    Some(StandardInformation {
         created,
         modified,
         mft_modified,
         accessed,
-        file_attrs,
+        file_attributes: file_attrs,
         owner_id,
         security_id,
         usn,
-    ))
+    })
 }
 
 fn parse_object_id(attr: &[u8]) -> Option<String> {
@@ -440,67 +842,158 @@ fn parse_object_id(attr: &[u8]) -> Option<String> {
     ))
 }
 
-fn parse_reparse_point(attr: &[u8]) -> Option<(u32, Option<String>)> {
+fn parse_reparse_point(attr: &[u8]) -> Option<ReparseInfo> {
     let content_offset = u16::from_le_bytes(attr[20..22].try_into().ok()?) as usize;
+    parse_reparse_buffer(attr.get(content_offset..)?)
+}
 
-    if content_offset + 8 > attr.len() {
+/// Applies the update sequence array (USA) of a multi-sector record in place.
+///
+/// The last two bytes of every sector were replaced on disk with the update sequence
+/// number, and the original bytes were stashed in the USA. Returns `None` if the USA
+/// header doesn't describe this record, which usually means a false-positive magic match.
+pub fn apply_fixups(record: &mut [u8]) -> Option<FixupStatus> {
+    if record.len() < 8 {
         return None;
     }
 
-    let content = &attr[content_offset..];
-    let tag = u32::from_le_bytes(content[0..4].try_into().ok()?);
-
-    // Parse symlink/junction target for common reparse points
-    let target = if tag == 0xA000000C || tag == 0xA0000003 {
-        if content.len() >= 20 {
-            let substitute_name_offset =
-                u16::from_le_bytes(content[8..10].try_into().ok()?) as usize;
-            let substitute_name_length =
-                u16::from_le_bytes(content[10..12].try_into().ok()?) as usize;
-
-            let path_buffer_offset = 20;
-            let start = path_buffer_offset + substitute_name_offset;
-            let end = start + substitute_name_length;
-
-            if end <= content.len() {
-                let utf16: Vec<u16> = content[start..end]
-                    .chunks_exact(2)
-                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
-                    .collect();
-                String::from_utf16(&utf16).ok()
-            } else {
-                None
-            }
-        } else {
-            None
+    let usa_offset = u16::from_le_bytes(record[4..6].try_into().ok()?) as usize;
+    let usa_count = u16::from_le_bytes(record[6..8].try_into().ok()?) as usize;
+
+    // One entry for the update sequence number, then one per sector.
+    let sector_count = usa_count.checked_sub(1)?;
+    if sector_count == 0 || !record.len().is_multiple_of(sector_count) {
+        return None;
+    }
+    let stride = record.len() / sector_count;
+    if stride < MIN_RECORD_SIZE || usa_offset < 8 || usa_offset + usa_count * 2 > stride - 2 {
+        return None;
+    }
+
+    let usn = [record[usa_offset], record[usa_offset + 1]];
+    let mut mismatched = Vec::new();
+
+    for sector in 0..sector_count {
+        let trailer = (sector + 1) * stride - 2;
+        if record[trailer..trailer + 2] != usn {
+            mismatched.push(sector);
+            continue;
         }
-    } else {
-        None
-    };
+        let original = usa_offset + 2 + sector * 2;
+        record.copy_within(original..original + 2, trailer);
+    }
 
-    Some((tag, target))
+    Some(match mismatched.as_slice() {
+        [] => FixupStatus::Ok,
+        [sector] => FixupStatus::MismatchInSector(*sector),
+        _ => FixupStatus::Torn,
+    })
 }
 
-fn parse_ntfs_record(
-    disk_image_buffer: &[u8],
-    current_idx: usize,
-    record_size: usize,
-) -> Option<NtfsEntry> {
-    if &disk_image_buffer[current_idx..current_idx + 4] != MFT_MAGIC {
+/// Whether `size` is a plausible MFT record size.
+pub fn is_valid_record_size(size: u64) -> bool {
+    size.is_power_of_two() && (MIN_RECORD_SIZE as u64..=MAX_RECORD_SIZE as u64).contains(&size)
+}
+
+/// Reads the "allocated size of record" field from an MFT record header.
+fn record_size_from_header(header: &[u8]) -> Option<usize> {
+    let field = header.get(28..32)?;
+    let record_size = u32::from_le_bytes(field.try_into().ok()?);
+    is_valid_record_size(record_size as u64).then_some(record_size as usize)
+}
+
+/// Drops repeated index entries (e.g., stale copies of a key in unused INDX blocks).
+pub fn dedup_index_entries(entries: &mut Vec<IndexEntry>) {
+    let mut seen = std::collections::HashSet::new();
+    entries.retain(|e| seen.insert((e.mft_record, e.sequence, e.namespace, e.filename.clone())));
+}
+
+/// Picks the main filename (prefer Win32/POSIX, not DOS) and removes it from `filenames`.
+fn take_main_filename(filenames: &mut Vec<FileNameAttr>) -> Option<FileNameAttr> {
+    if filenames.is_empty() {
         return None;
     }
 
-    if current_idx + record_size > disk_image_buffer.len() {
+    let main_idx = filenames
+        .iter()
+        .position(|f| f.namespace == 1 || f.namespace == 3)
+        .or_else(|| filenames.iter().position(|f| f.namespace == 0))
+        .unwrap_or(0);
+
+    Some(filenames.remove(main_idx))
+}
+
+/// Reads the MFT record at `current_idx` and applies its fixups. Works on a copy, so that
+/// the image itself is never touched.
+pub fn read_mft_record(
+    disk_image_buffer: ImageSlice<'_>,
+    current_idx: usize,
+    geometry: Option<&VolumeGeometry>,
+) -> Option<(Vec<u8>, FixupStatus)> {
+    let header = disk_image_buffer.get_clipped(current_idx, 32)?;
+    if header.get(0..4)? != MFT_MAGIC {
         return None;
     }
 
-    let record = &disk_image_buffer[current_idx..current_idx + record_size];
+    let header_record_size = record_size_from_header(&header);
+    let record_size = match (geometry, header_record_size) {
+        (Some(geometry), Some(size)) if size as u64 != geometry.mft_record_size => {
+            warn!(
+                "MFT record at offset {current_idx} is {size} bytes, the boot sector says {}. \
+                 Using the record's own size.",
+                geometry.mft_record_size
+            );
+            size
+        }
+        (Some(geometry), _) => geometry.mft_record_size as usize,
+        (None, size) => size?,
+    };
+
+    let mut record = disk_image_buffer
+        .get(current_idx..current_idx + record_size)?
+        .into_owned();
+    let fixup_status = apply_fixups(&mut record)?;
+    Some((record, fixup_status))
+}
+
+fn parse_ntfs_record(
+    disk_image_buffer: ImageSlice<'_>,
+    current_idx: usize,
+    geometry: Option<&VolumeGeometry>,
+) -> Option<NtfsEntry> {
+    let (record, fixup_status) = read_mft_record(disk_image_buffer, current_idx, geometry)?;
+    parse_mft_record_bytes(
+        &record,
+        current_idx as u64,
+        fixup_status,
+        disk_image_buffer,
+        geometry,
+    )
+}
+
+/// Parses an MFT record whose fixups have already been applied. Without a boot sector
+/// (`geometry` is `None`), the record size was taken from the record's own header.
+/// `disk_image_buffer` is only used to follow non-resident attribute lists and index
+/// allocations.
+///
+/// Records without a `$FILE_NAME` are only kept if they are extension records, or base
+/// records with an `$ATTRIBUTE_LIST` (their names may live in extension records).
+pub fn parse_mft_record_bytes(
+    record: &[u8],
+    mft_offset: u64,
+    fixup_status: FixupStatus,
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: Option<&VolumeGeometry>,
+) -> Option<NtfsEntry> {
+    let record_size = record.len();
+    let cluster_size = geometry.map(|g| g.cluster_size);
 
     // Parse MFT record header
     let sequence_number = u16::from_le_bytes(record[16..18].try_into().unwrap());
     let hardlink_count = u16::from_le_bytes(record[18..20].try_into().unwrap());
     let first_attr_offset = u16::from_le_bytes(record[20..22].try_into().unwrap()) as usize;
     let flags = u16::from_le_bytes(record[22..24].try_into().unwrap());
+    let base_reference = u64::from_le_bytes(record[32..40].try_into().unwrap());
     let mft_record_number = u32::from_le_bytes(record[44..48].try_into().unwrap()) as u64;
 
     let is_in_use = flags & 0x01 != 0;
@@ -519,9 +1012,15 @@ fn parse_ntfs_record(
     let mut security_id = None;
     let mut usn = None;
     let mut object_id = None;
-    let mut reparse_tag = None;
-    let mut reparse_target = None;
+    let mut reparse = None;
     let mut has_ea = false;
+    let mut extended_attributes = Vec::new();
+    let mut wsl = None;
+    let mut attribute_list = None;
+    let mut security_descriptor = None;
+    let mut index_entries = Vec::new();
+    let mut index_block_size = geometry.map(|g| g.index_record_size as usize);
+    let mut index_allocation_attr = None;
 
     while let Some((attr_type, len, non_resident, attr_name)) = parse_attr_header(record, offset) {
         let attr = &record[offset..offset + len];
@@ -537,56 +1036,91 @@ fn parse_ntfs_record(
                     data_streams.push(stream);
                 }
             }
-            ATTR_STANDARD_INFORMATION => {
-                if created.is_none() {
-                    if let Some((c, m, mm, a, attrs, oid, sid, u)) =
-                        parse_standard_information(attr)
-                    {
-                        created = Some(c);
-                        modified = Some(m);
-                        mft_modified = Some(mm);
-                        accessed = Some(a);
-                        file_attributes = Some(attrs);
-                        owner_id = oid;
-                        security_id = sid;
-                        usn = u;
-                    }
+            ATTR_STANDARD_INFORMATION if created.is_none() => {
+                if let Some(si) = parse_standard_information(attr) {
+                    created = Some(si.created);
+                    modified = Some(si.modified);
+                    mft_modified = Some(si.mft_modified);
+                    accessed = Some(si.accessed);
+                    file_attributes = Some(si.file_attributes);
+                    owner_id = si.owner_id;
+                    security_id = si.security_id;
+                    usn = si.usn;
                 }
             }
-            ATTR_OBJECT_ID => {
-                if object_id.is_none() {
-                    object_id = parse_object_id(attr);
-                }
+            ATTR_OBJECT_ID if object_id.is_none() => {
+                object_id = parse_object_id(attr);
             }
-            ATTR_REPARSE_POINT => {
-                if reparse_tag.is_none() {
-                    if let Some((tag, target)) = parse_reparse_point(attr) {
-                        reparse_tag = Some(tag);
-                        reparse_target = target;
-                    }
-                }
+            ATTR_REPARSE_POINT if reparse.is_none() => {
+                reparse = parse_reparse_point(attr);
             }
             ATTR_EA_INFORMATION => {
                 has_ea = true;
             }
+            ATTR_EA if extended_attributes.is_empty() => {
+                has_ea = true;
+                if let Some(content) = read_attribute_content(
+                    attr,
+                    non_resident,
+                    disk_image_buffer,
+                    cluster_size,
+                    MAX_EA_SIZE,
+                ) {
+                    (extended_attributes, wsl) = decode_extended_attributes(&content);
+                }
+            }
+            ATTR_INDEX_ROOT if attr_name.as_deref() == Some(I30_INDEX_NAME) => {
+                if let Some((entries, block_size)) =
+                    parse_resident_data(attr).and_then(|content| parse_index_root(&content))
+                {
+                    index_entries.extend(entries);
+                    index_block_size = Some(block_size as usize);
+                }
+            }
+            ATTR_INDEX_ALLOCATION
+                if non_resident && attr_name.as_deref() == Some(I30_INDEX_NAME) =>
+            {
+                // Parsed after the loop, once the block size from `$INDEX_ROOT` is known.
+                index_allocation_attr = Some(offset..offset + len);
+            }
+            ATTR_SECURITY_DESCRIPTOR if security_descriptor.is_none() => {
+                // Found on volumes older than NTFS 3.0, which have no `$Secure`.
+                security_descriptor = read_attribute_content(
+                    attr,
+                    non_resident,
+                    disk_image_buffer,
+                    cluster_size,
+                    MAX_SECURITY_DESCRIPTOR_SIZE,
+                )
+                .and_then(|content| decode_security_descriptor(&content));
+            }
+            ATTR_ATTRIBUTE_LIST if attribute_list.is_none() => {
+                attribute_list =
+                    parse_attribute_list(attr, non_resident, disk_image_buffer, cluster_size);
+            }
             _ => {}
         }
 
         offset += len;
     }
 
-    if all_filenames.is_empty() {
-        return None;
+    if let (Some(range), Some(cluster_size), Some(block_size)) =
+        (index_allocation_attr, cluster_size, index_block_size)
+        && let Some(mut data) =
+            read_index_allocation_attribute(&record[range], disk_image_buffer, cluster_size)
+    {
+        index_entries.extend(parse_index_allocation(&mut data, block_size));
     }
+    dedup_index_entries(&mut index_entries);
 
-    // Find main filename (prefer Win32/POSIX, not DOS)
-    let main_idx = all_filenames
-        .iter()
-        .position(|f| f.namespace == 1 || f.namespace == 3)
-        .or_else(|| all_filenames.iter().position(|f| f.namespace == 0))
-        .unwrap_or(0);
+    let is_extension = base_reference != 0;
+    if all_filenames.is_empty() && !is_extension && attribute_list.is_none() {
+        return None;
+    }
 
-    let main = all_filenames.remove(main_idx);
+    let main = take_main_filename(&mut all_filenames);
+    let has_filename = main.is_some();
+    let main = main.unwrap_or_default();
 
     // Extract parent MFT record and sequence from the 48-bit reference
     let parent_mft_record = main.parent_reference & 0x0000_FFFF_FFFF_FFFF;
@@ -598,41 +1132,77 @@ fn parse_ntfs_record(
         .map(|f| AlternateFilename {
             name: f.name,
             namespace: f.namespace,
+            timestamps: f.timestamps,
+            file_attributes: FileAttributes::from_flags(f.flags),
         })
         .collect();
 
-    Some(NtfsEntry {
-        mft_offset: current_idx as u64,
+    let mut entry = NtfsEntry {
+        mft_offset,
+        partition_index: None,
+        partition_relative_offset: None,
         mft_record_number,
         sequence_number,
         hardlink_count,
         is_in_use,
         is_directory,
+        fixup_status,
+        record_size: record_size as u32,
+        source: EntrySource::Mft,
+        logfile_lsn: None,
         filename: main.name,
+        filename_namespace: main.namespace,
         parent_mft_record,
         parent_sequence,
         allocated_size: main.allocated_size,
         real_size: main.real_size,
+        full_path: None,
+        path_status: None,
         created,
         modified,
         mft_modified,
         accessed,
         file_attributes,
+        fn_timestamps: has_filename.then_some(main.timestamps),
+        fn_file_attributes: has_filename.then(|| FileAttributes::from_flags(main.flags)),
+        timestomp_indicators: Vec::new(),
         owner_id,
         security_id,
+        owner_sid: security_descriptor
+            .as_ref()
+            .and_then(|d| d.owner_sid.clone()),
+        sddl: security_descriptor.map(|d| d.sddl),
         usn,
         object_id,
         alternate_filenames,
         data_streams,
-        reparse_tag,
-        reparse_target,
+        index_entries,
+        reparse,
         has_extended_attributes: has_ea,
-    })
+        extended_attributes,
+        wsl,
+        base_mft_record: is_extension.then_some(base_reference & 0x0000_FFFF_FFFF_FFFF),
+        base_sequence: is_extension.then_some((base_reference >> 48) as u16),
+        attribute_list,
+        extension_records: Vec::new(),
+    };
+    entry.timestomp_indicators = entry.detect_timestomping();
+
+    Some(entry)
 }
 
-pub fn scan_ntfs_image(disk_image_buffer: &[u8]) -> impl Iterator<Item = NtfsEntry> + '_ {
-    let record_size = 1024;
+/// A record found by the carver.
+pub enum CarvedRecord {
+    MftEntry(Box<NtfsEntry>),
+    /// The live and slack entries of one INDX block.
+    IndexBlock(Vec<CarvedIndexEntry>),
+}
 
+/// Offsets of the image to try as the start of a record, with a progress bar.
+/// Each offset comes with the first 8 bytes there.
+pub fn scan_offsets<'a>(
+    disk_image_buffer: ImageSlice<'a>,
+) -> impl Iterator<Item = (usize, [u8; 8])> + 'a {
     // Create progress bar.
     let progress_bar = ProgressBar::new(disk_image_buffer.len() as u64);
     progress_bar.set_style(
@@ -646,10 +1216,333 @@ pub fn scan_ntfs_image(disk_image_buffer: &[u8]) -> impl Iterator<Item = NtfsEnt
         progress_bar.set_draw_target(ProgressDrawTarget::hidden());
     }
 
-    (0..disk_image_buffer.len().saturating_sub(4))
-        .step_by(8)
-        .inspect(move |i| {
-            progress_bar.set_position(*i as u64);
-        })
-        .filter_map(move |i| parse_ntfs_record(disk_image_buffer, i, record_size))
+    disk_image_buffer.sample(8).inspect(move |(i, _)| {
+        progress_bar.set_position(*i as u64);
+    })
+}
+
+pub fn scan_ntfs_image<'a>(
+    disk_image_buffer: ImageSlice<'a>,
+    geometry: Option<&'a VolumeGeometry>,
+) -> impl Iterator<Item = NtfsEntry> + 'a {
+    scan_offsets(disk_image_buffer)
+        .filter(|(_, start)| start.starts_with(MFT_MAGIC))
+        .filter_map(move |(i, _)| parse_ntfs_record(disk_image_buffer, i, geometry))
+}
+
+/// Like `scan_ntfs_image`, but also carves INDX blocks in the same pass over the image.
+pub fn carve_ntfs_image<'a>(
+    disk_image_buffer: ImageSlice<'a>,
+    geometry: Option<&'a VolumeGeometry>,
+) -> impl Iterator<Item = CarvedRecord> + 'a {
+    let index_block_size = geometry.map(|g| g.index_record_size as usize);
+
+    scan_offsets(disk_image_buffer).filter_map(move |(i, start)| {
+        match start[0..4].try_into().unwrap() {
+            MFT_MAGIC => parse_ntfs_record(disk_image_buffer, i, geometry)
+                .map(|entry| CarvedRecord::MftEntry(Box::new(entry))),
+            INDX_MAGIC => carve_indx_block(disk_image_buffer, i, index_block_size)
+                .filter(|entries| !entries.is_empty())
+                .map(CarvedRecord::IndexBlock),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::boot_sector::tests::geometry;
+
+    const USN: [u8; 2] = [0x42, 0x00];
+
+    /// A 1024-byte record with its USA at 48, the original sector trailers 0xAAAA and
+    /// 0xBBBB stashed in it, and `USN` at the end of each sector.
+    fn usa_record() -> Vec<u8> {
+        let mut record = vec![0; 1024];
+        record[..4].copy_from_slice(MFT_MAGIC);
+        record[4..8].copy_from_slice(&[48, 0, 3, 0]);
+        record[48..54].copy_from_slice(&[USN[0], USN[1], 0xAA, 0xAA, 0xBB, 0xBB]);
+        record[510..512].copy_from_slice(&USN);
+        record[1022..1024].copy_from_slice(&USN);
+        record
+    }
+
+    #[test]
+    fn restores_sector_trailers() {
+        let mut record = usa_record();
+        assert_eq!(apply_fixups(&mut record), Some(FixupStatus::Ok));
+        assert_eq!(record[510..512], [0xAA, 0xAA]);
+        assert_eq!(record[1022..1024], [0xBB, 0xBB]);
+    }
+
+    #[test]
+    fn reports_a_usa_mismatch() {
+        let mut record = usa_record();
+        record[1022..1024].copy_from_slice(&[0x41, 0x00]);
+        assert_eq!(
+            apply_fixups(&mut record),
+            Some(FixupStatus::MismatchInSector(1))
+        );
+        // The sector that matched is still fixed up, and the other one left as is.
+        assert_eq!(record[510..512], [0xAA, 0xAA]);
+        assert_eq!(record[1022..1024], [0x41, 0x00]);
+
+        let mut record = usa_record();
+        record[510..512].fill(0);
+        record[1022..1024].fill(0);
+        assert_eq!(apply_fixups(&mut record), Some(FixupStatus::Torn));
+    }
+
+    #[test]
+    fn rejects_a_usa_that_does_not_fit() {
+        let mut record = usa_record();
+        // Four sectors of 256 bytes: too small.
+        record[6] = 5;
+        assert_eq!(apply_fixups(&mut record), None);
+        // USA past the end of the first sector.
+        let mut record = usa_record();
+        record[4..6].copy_from_slice(&508u16.to_le_bytes());
+        assert_eq!(apply_fixups(&mut record), None);
+    }
+
+    /// A resident attribute of type `attr_type` named `name`, holding `content`.
+    pub(crate) fn resident_attribute(attr_type: u32, name: &str, content: &[u8]) -> Vec<u8> {
+        let name: Vec<u8> = name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let content_offset = (24 + name.len()).next_multiple_of(8);
+        let mut attr = vec![0; (content_offset + content.len()).next_multiple_of(8)];
+        let length = attr.len() as u32;
+        attr[..4].copy_from_slice(&attr_type.to_le_bytes());
+        attr[4..8].copy_from_slice(&length.to_le_bytes());
+        attr[9] = (name.len() / 2) as u8;
+        attr[10..12].copy_from_slice(&24u16.to_le_bytes());
+        attr[16..20].copy_from_slice(&(content.len() as u32).to_le_bytes());
+        attr[20..22].copy_from_slice(&(content_offset as u16).to_le_bytes());
+        attr[24..24 + name.len()].copy_from_slice(&name);
+        attr[content_offset..content_offset + content.len()].copy_from_slice(content);
+        attr
+    }
+
+    /// A Win32 `$FILE_NAME` attribute for `name` in the directory record `parent`.
+    pub(crate) fn file_name_attribute(name: &str, parent: u64) -> Vec<u8> {
+        let mut content = vec![0; 66];
+        content[..8].copy_from_slice(&(parent | 1 << 48).to_le_bytes());
+        content[64] = name.encode_utf16().count() as u8;
+        content[65] = 1;
+        content.extend(name.encode_utf16().flat_map(u16::to_le_bytes));
+        resident_attribute(ATTR_FILE_NAME, "", &content)
+    }
+
+    /// MFT record `number` of `size` bytes holding `attributes`, with its sector trailers
+    /// swapped into the USA as on disk.
+    pub(crate) fn mft_record(
+        size: usize,
+        number: u32,
+        flags: u16,
+        attributes: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let sectors = size / 512;
+        let first_attribute = (50 + 2 * sectors).next_multiple_of(8);
+        let mut record = vec![0; size];
+        record[..4].copy_from_slice(MFT_MAGIC);
+        record[4..6].copy_from_slice(&48u16.to_le_bytes());
+        record[6..8].copy_from_slice(&(sectors as u16 + 1).to_le_bytes());
+        record[16..18].copy_from_slice(&1u16.to_le_bytes());
+        record[18..20].copy_from_slice(&1u16.to_le_bytes());
+        record[20..22].copy_from_slice(&(first_attribute as u16).to_le_bytes());
+        record[22..24].copy_from_slice(&flags.to_le_bytes());
+        record[28..32].copy_from_slice(&(size as u32).to_le_bytes());
+        record[44..48].copy_from_slice(&number.to_le_bytes());
+
+        let mut offset = first_attribute;
+        for attribute in attributes {
+            record[offset..offset + attribute.len()].copy_from_slice(attribute);
+            offset += attribute.len();
+        }
+        record[offset..offset + 4].copy_from_slice(&ATTR_END.to_le_bytes());
+        record[24..28].copy_from_slice(&(offset as u32 + 8).to_le_bytes());
+
+        record[48..50].copy_from_slice(&USN);
+        for sector in 0..sectors {
+            let trailer = (sector + 1) * 512 - 2;
+            record.copy_within(trailer..trailer + 2, 50 + 2 * sector);
+            record[trailer..trailer + 2].copy_from_slice(&USN);
+        }
+        record
+    }
+
+    /// Parses a record at the start of `record`, sized by its own header.
+    pub(crate) fn parse_record(record: &[u8]) -> Option<NtfsEntry> {
+        let record = record.to_vec();
+        parse_ntfs_record(ImageSlice::new(&record), 0, None)
+    }
+
+    /// Parses a 1 KiB record holding only a `$FILE_NAME`; shared with the other modules' tests.
+    pub(crate) fn parsed_entry(number: u32, flags: u16, name: &str, parent: u64) -> NtfsEntry {
+        let record = mft_record(1024, number, flags, &[file_name_attribute(name, parent)]);
+        parse_record(&record).unwrap()
+    }
+
+    #[test]
+    fn takes_the_record_size_from_the_header() {
+        let record = mft_record(4096, 30, 1, &[file_name_attribute("big.txt", 5)]);
+        let entry = parse_record(&record).unwrap();
+        assert_eq!(entry.record_size, 4096);
+        assert_eq!(entry.mft_record_number, 30);
+        assert_eq!(entry.filename, "big.txt");
+        assert_eq!(entry.fixup_status, FixupStatus::Ok);
+
+        let mut record = mft_record(1024, 30, 1, &[file_name_attribute("big.txt", 5)]);
+        record[28..32].copy_from_slice(&3000u32.to_le_bytes());
+        assert!(parse_record(&record).is_none());
+    }
+
+    #[test]
+    fn prefers_the_record_size_from_the_header_over_the_boot_sector() {
+        // The boot sector says 1 KiB.
+        let geometry = geometry();
+        let record = mft_record(4096, 30, 1, &[file_name_attribute("big.txt", 5)]);
+        let (read, fixup_status) =
+            read_mft_record(ImageSlice::new(&record), 0, Some(&geometry)).unwrap();
+        assert_eq!(read.len(), 4096);
+        assert_eq!(fixup_status, FixupStatus::Ok);
+
+        // A header size out of range is ignored.
+        let mut record = mft_record(1024, 30, 1, &[file_name_attribute("a.txt", 5)]);
+        record[28..32].copy_from_slice(&8192u32.to_le_bytes());
+        let (read, _) = read_mft_record(ImageSlice::new(&record), 0, Some(&geometry)).unwrap();
+        assert_eq!(read.len(), 1024);
+        assert!(parse_record(&record).is_none());
+    }
+
+    /// A non-resident attribute header with the mapping pairs `runs` after it.
+    fn non_resident_attribute(runs: &[u8]) -> Vec<u8> {
+        let mut attr = vec![0; 64];
+        attr[32..34].copy_from_slice(&64u16.to_le_bytes());
+        attr.extend(runs);
+        attr
+    }
+
+    /// A non-resident unnamed `$DATA` fragment starting at `lowest_vcn`, with `runs` after it.
+    pub(crate) fn data_fragment(lowest_vcn: u64, size: u64, runs: &[u8]) -> Vec<u8> {
+        let mut attr = non_resident_attribute(runs);
+        attr.resize(attr.len().next_multiple_of(8), 0);
+        let len = attr.len() as u32;
+        attr[0..4].copy_from_slice(&ATTR_DATA.to_le_bytes());
+        attr[4..8].copy_from_slice(&len.to_le_bytes());
+        attr[8] = 1;
+        attr[16..24].copy_from_slice(&lowest_vcn.to_le_bytes());
+        for field in [40, 48, 56] {
+            attr[field..field + 8].copy_from_slice(&size.to_le_bytes());
+        }
+        attr
+    }
+
+    fn runs(attr: &[u8]) -> Option<Vec<(Option<i64>, u64)>> {
+        let runs = parse_data_runs(attr)?;
+        Some(
+            runs.iter()
+                .map(|run| (run.cluster_offset, run.cluster_count))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn parses_sparse_and_negative_delta_runs() {
+        let attr = non_resident_attribute(&[
+            0x21, 0x01, 0x2C, 0x01, // 1 cluster at LCN 300
+            0x01, 0x02, // 2 sparse clusters
+            0x21, 0x01, 0x6A, 0xFF, // 1 cluster 150 back, at LCN 150
+            0x00,
+        ]);
+        assert_eq!(
+            runs(&attr),
+            Some(vec![(Some(300), 1), (None, 2), (Some(150), 1)])
+        );
+    }
+
+    #[test]
+    fn stops_at_a_truncated_run() {
+        // The second run's offset is cut short by the end of the attribute.
+        let attr = non_resident_attribute(&[0x11, 0x04, 0x10, 0x31, 0x01, 0x00]);
+        assert_eq!(runs(&attr), Some(vec![(Some(16), 4)]));
+        assert_eq!(runs(&non_resident_attribute(&[0x00])), None);
+    }
+
+    #[test]
+    fn encodes_resident_data_as_requested() {
+        let record = mft_record(
+            1024,
+            40,
+            1,
+            &[
+                file_name_attribute("a.bin", 5),
+                resident_attribute(ATTR_DATA, "", &[0xFF, 0x00, b'a']),
+            ],
+        );
+        let entry = parse_record(&record).unwrap();
+        let mut stream = entry.data_streams[0].clone();
+        assert_eq!(
+            stream.resident_content.as_deref(),
+            Some(&[0xFF, 0x00, b'a'][..])
+        );
+
+        // Not valid UTF-8, so `utf8` falls back to base64 and says so.
+        stream.encode_resident_data(ResidentDataEncoding::Utf8);
+        assert_eq!(stream.resident_data.as_deref(), Some("/wBh"));
+        assert_eq!(
+            stream.resident_data_encoding,
+            Some(ResidentDataEncoding::Base64)
+        );
+
+        stream.encode_resident_data(ResidentDataEncoding::Hex);
+        assert_eq!(stream.resident_data.as_deref(), Some("ff0061"));
+
+        stream.resident_content = Some(b"hello".to_vec());
+        stream.encode_resident_data(ResidentDataEncoding::Utf8);
+        assert_eq!(stream.resident_data.as_deref(), Some("hello"));
+        assert_eq!(
+            stream.resident_data_encoding,
+            Some(ResidentDataEncoding::Utf8)
+        );
+    }
+
+    #[test]
+    fn flags_standard_information_older_than_the_file_name() {
+        // Both in 2019; the forged creation time is a year earlier and in whole seconds.
+        let fn_time: u64 = 132_000_000_001_234_567;
+        let forged: u64 = 131_700_000_000_000_000;
+
+        let mut si = vec![0; 72];
+        for (i, time) in [forged, fn_time, fn_time, fn_time].into_iter().enumerate() {
+            si[i * 8..i * 8 + 8].copy_from_slice(&time.to_le_bytes());
+        }
+        let mut file_name = file_name_attribute("a.txt", 5);
+        // `$FILE_NAME` timestamps, right after the parent reference.
+        for i in 0..4 {
+            file_name[32 + i * 8..40 + i * 8].copy_from_slice(&fn_time.to_le_bytes());
+        }
+
+        let record = mft_record(
+            1024,
+            40,
+            1,
+            &[
+                resident_attribute(ATTR_STANDARD_INFORMATION, "", &si),
+                file_name,
+            ],
+        );
+        let entry = parse_record(&record).unwrap();
+        assert_eq!(
+            entry.fn_timestamps.unwrap().created,
+            filetime_to_utc(fn_time)
+        );
+        assert_eq!(
+            entry.timestomp_indicators,
+            vec![
+                TimestompIndicator::CreatedBeforeFn,
+                TimestompIndicator::CreatedWholeSeconds
+            ]
+        );
+    }
 }
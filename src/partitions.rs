@@ -0,0 +1,502 @@
+use log::info;
+use serde::Serialize;
+use std::collections::HashSet;
+
+use crate::boot_sector::{BootSectorSource, find_volume_geometry, parse_boot_sector};
+use crate::cluster_map::OwnerMatch;
+use crate::disk_image::ImageSlice;
+use crate::index::CarvedIndexEntry;
+use crate::lost_partitions::{MIN_SCAN_CONFIDENCE, find_partition_candidates};
+use crate::ntfs_logic::NtfsEntry;
+use crate::usn_journal::UsnRecord;
+
+// Partition tables address 512-byte sectors, except GPT on 4Kn disks.
+const MBR_SECTOR_SIZE: u64 = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// Logical partitions are numbered after the four primary slots, as Linux does.
+const FIRST_LOGICAL_INDEX: u32 = 5;
+// Bounds the EBR chain, which could loop in a corrupt table.
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+const GPT_MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionScheme {
+    Mbr,
+    Gpt,
+    /// Not in the partition table: found from its boot sectors.
+    BootSector,
+}
+
+/// A partition found in the image's MBR or GPT, or from orphan boot sectors.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "kind", rename = "partition")]
+pub struct Partition {
+    /// Numbered from 1, in table order (MBR logical partitions from 5).
+    pub index: u32,
+    pub scheme: PartitionScheme,
+    pub offset: u64, // Byte offset in the image
+    pub size: u64,
+    /// MBR type byte in hex (e.g. `07`), GPT type GUID, or `ntfs` if found from boot sectors.
+    pub partition_type: String,
+    pub name: Option<String>, // GPT only
+}
+
+impl Partition {
+    /// The partition's bytes, cut short if the image is.
+    pub fn slice<'a>(&self, disk_image_buffer: ImageSlice<'a>) -> ImageSlice<'a> {
+        disk_image_buffer.slice(self.offset, self.size)
+    }
+
+    /// Tags an entry scanned from the partition's slice with the partition, and turns its
+    /// offset into an image offset.
+    pub fn tag_entry(&self, entry: &mut NtfsEntry) {
+        entry.partition_index = Some(self.index);
+        entry.partition_relative_offset = Some(entry.mft_offset);
+        entry.mft_offset += self.offset;
+    }
+
+    pub fn tag_index_entry(&self, entry: &mut CarvedIndexEntry) {
+        entry.partition_index = Some(self.index);
+        entry.indx_offset += self.offset;
+        entry.entry_offset += self.offset;
+    }
+
+    pub fn tag_owner_match(&self, owner_match: &mut OwnerMatch) {
+        owner_match.partition_index = Some(self.index);
+        owner_match.offset += self.offset;
+    }
+
+    pub fn tag_usn_record(&self, record: &mut UsnRecord) {
+        record.offset += self.offset;
+    }
+}
+
+/// Part of the image to scan as one NTFS volume.
+pub struct Volume<'a> {
+    /// `None` when the image is scanned as a whole.
+    pub partition: Option<Partition>,
+    pub buffer: ImageSlice<'a>,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Formats a GUID stored in the mixed-endian on-disk layout.
+pub fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+        u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+        u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+        bytes[8],
+        bytes[9],
+        bytes[10..16]
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<String>()
+    )
+}
+
+/// The four entries of an MBR or EBR sector: (type, start sector, sector count).
+fn parse_mbr_entries(sector: &[u8]) -> Option<Vec<(u8, u64, u64)>> {
+    if sector.get(510..512)? != MBR_SIGNATURE {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    for slot in 0..4 {
+        let entry = &sector[MBR_ENTRIES_OFFSET + slot * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        // The boot indicator is either 0x00 or 0x80 in a real partition table.
+        if entry[0] & 0x7F != 0 {
+            return None;
+        }
+        entries.push((
+            entry[4],
+            read_u32(entry, 8)? as u64,
+            read_u32(entry, 12)? as u64,
+        ));
+    }
+
+    Some(entries)
+}
+
+/// Follows the chain of EBRs of an extended partition that starts at sector `extended_start`.
+fn parse_logical_partitions(buf: ImageSlice<'_>, extended_start: u64) -> Vec<Partition> {
+    let mut partitions = Vec::new();
+    let mut visited = HashSet::new();
+    let mut ebr_sector = extended_start;
+
+    while partitions.len() < MAX_LOGICAL_PARTITIONS && visited.insert(ebr_sector) {
+        let Some(entries) = ebr_sector
+            .checked_mul(MBR_SECTOR_SIZE)
+            .and_then(|offset| parse_mbr_entries(&buf.read(offset, 512)?))
+        else {
+            break;
+        };
+
+        // The first entry is the logical partition, relative to this EBR; the second
+        // links to the next EBR, relative to the extended partition.
+        let (partition_type, start, count) = entries[0];
+        if partition_type != 0 && count > 0 {
+            partitions.push(Partition {
+                index: FIRST_LOGICAL_INDEX + partitions.len() as u32,
+                scheme: PartitionScheme::Mbr,
+                offset: (ebr_sector + start) * MBR_SECTOR_SIZE,
+                size: count * MBR_SECTOR_SIZE,
+                partition_type: format!("{partition_type:02x}"),
+                name: None,
+            });
+        }
+
+        let (next_type, next_start, _) = entries[1];
+        if !MBR_TYPES_EXTENDED.contains(&next_type) || next_start == 0 {
+            break;
+        }
+        ebr_sector = extended_start + next_start;
+    }
+
+    partitions
+}
+
+/// Parses the MBR, including the logical partitions of an extended partition. Returns
+/// `None` if there's no MBR, or it's a GPT protective MBR.
+fn parse_mbr(buf: ImageSlice<'_>) -> Option<Vec<Partition>> {
+    let entries = parse_mbr_entries(&buf.get(0..512)?)?;
+    if entries.iter().any(|e| e.0 == MBR_TYPE_GPT_PROTECTIVE) {
+        return None;
+    }
+
+    let mut partitions = Vec::new();
+    let mut logical = Vec::new();
+    for (slot, (partition_type, start, count)) in entries.into_iter().enumerate() {
+        if partition_type == 0 || start == 0 || count == 0 {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&partition_type) {
+            logical.extend(parse_logical_partitions(buf, start));
+            continue;
+        }
+        partitions.push(Partition {
+            index: slot as u32 + 1,
+            scheme: PartitionScheme::Mbr,
+            offset: start * MBR_SECTOR_SIZE,
+            size: count * MBR_SECTOR_SIZE,
+            partition_type: format!("{partition_type:02x}"),
+            name: None,
+        });
+    }
+    partitions.extend(logical);
+
+    Some(partitions)
+}
+
+/// Parses the GPT header at `header_offset` and its partition entries.
+fn parse_gpt_at(
+    buf: ImageSlice<'_>,
+    header_offset: u64,
+    sector_size: u64,
+) -> Option<Vec<Partition>> {
+    let header = buf.read(header_offset, 92)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return None;
+    }
+
+    let entries_lba = read_u64(&header, 72)?;
+    let entry_count = read_u32(&header, 80)? as usize;
+    let entry_size = read_u32(&header, 84)? as usize;
+    if entry_size < GPT_MIN_ENTRY_SIZE || entry_count > GPT_MAX_ENTRIES {
+        return None;
+    }
+
+    let entries_offset = entries_lba.checked_mul(sector_size)?;
+    let mut partitions = Vec::new();
+    for i in 0..entry_count {
+        // Only the fields of the first 128 bytes are read.
+        let Some(entry) = entries_offset
+            .checked_add((i * entry_size) as u64)
+            .and_then(|offset| buf.read(offset, GPT_MIN_ENTRY_SIZE))
+        else {
+            break;
+        };
+        if entry[0..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+
+        let first_lba = read_u64(&entry, 32)?;
+        let last_lba = read_u64(&entry, 40)?;
+        if last_lba < first_lba {
+            continue;
+        }
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+
+        partitions.push(Partition {
+            index: i as u32 + 1,
+            scheme: PartitionScheme::Gpt,
+            offset: first_lba.saturating_mul(sector_size),
+            size: (last_lba - first_lba + 1).saturating_mul(sector_size),
+            partition_type: format_guid(&entry[0..16]),
+            name: Some(String::from_utf16_lossy(&name)),
+        });
+    }
+
+    Some(partitions)
+}
+
+/// Parses the GPT, from the primary header in LBA 1 or else the backup in the last LBA.
+fn parse_gpt(buf: ImageSlice<'_>) -> Option<Vec<Partition>> {
+    GPT_SECTOR_SIZES.into_iter().find_map(|sector_size| {
+        parse_gpt_at(buf, sector_size, sector_size).or_else(|| {
+            let backup = (buf.len() as u64).checked_sub(sector_size)?;
+            parse_gpt_at(buf, backup, sector_size)
+        })
+    })
+}
+
+/// Whether the image starts with an NTFS boot sector, rather than a partition table.
+fn is_volume_image(disk_image_buffer: ImageSlice<'_>) -> bool {
+    parse_boot_sector(disk_image_buffer, 0, BootSectorSource::Primary).is_some()
+}
+
+/// Reads the partition table (GPT or MBR) of a disk image. Returns an empty list if the
+/// image has none, e.g. because it's an image of a single volume.
+pub fn find_partitions(disk_image_buffer: ImageSlice<'_>) -> Vec<Partition> {
+    // The boot sector of a volume image ends with the same signature as an MBR.
+    if is_volume_image(disk_image_buffer) {
+        return Vec::new();
+    }
+
+    parse_gpt(disk_image_buffer)
+        .or_else(|| parse_mbr(disk_image_buffer))
+        .unwrap_or_default()
+}
+
+/// Adds the volumes found from orphan boot sectors that are likely enough to be real, and
+/// that don't overlap the volumes already in `volumes`.
+fn add_lost_volumes<'a>(
+    disk_image_buffer: ImageSlice<'a>,
+    partitions: &[Partition],
+    volumes: &mut Vec<Volume<'a>>,
+) {
+    let mut index = partitions.iter().map(|p| p.index).max().unwrap_or(0) + 1;
+
+    for candidate in find_partition_candidates(disk_image_buffer, partitions) {
+        let overlaps = volumes.iter().flat_map(|v| &v.partition).any(|p| {
+            p.offset < candidate.end() && candidate.offset < p.offset.saturating_add(p.size)
+        });
+        if candidate.confidence < MIN_SCAN_CONFIDENCE || candidate.in_partition_table || overlaps {
+            continue;
+        }
+
+        info!(
+            "Found a lost NTFS partition at offset {} ({} bytes, confidence {}).",
+            candidate.offset, candidate.size, candidate.confidence
+        );
+        let partition = Partition {
+            index,
+            scheme: PartitionScheme::BootSector,
+            offset: candidate.offset,
+            size: candidate.size,
+            partition_type: "ntfs".to_string(),
+            name: None,
+        };
+        index += 1;
+        volumes.push(Volume {
+            buffer: partition.slice(disk_image_buffer),
+            partition: Some(partition),
+        });
+    }
+}
+
+/// Splits the image into the NTFS volumes to scan: its partitions that hold an NTFS boot
+/// sector (and, if `search_lost_partitions`, volumes found from orphan boot sectors), or
+/// the whole image if there are none.
+pub fn find_ntfs_volumes(
+    disk_image_buffer: ImageSlice<'_>,
+    search_lost_partitions: bool,
+) -> Vec<Volume<'_>> {
+    let partitions = find_partitions(disk_image_buffer);
+    let mut volumes: Vec<Volume> = partitions
+        .iter()
+        .map(|partition| Volume {
+            buffer: partition.slice(disk_image_buffer),
+            partition: Some(partition.clone()),
+        })
+        .filter(|volume| find_volume_geometry(volume.buffer).is_some())
+        .collect();
+
+    if search_lost_partitions && !is_volume_image(disk_image_buffer) {
+        add_lost_volumes(disk_image_buffer, &partitions, &mut volumes);
+    }
+
+    if volumes.is_empty() {
+        return vec![Volume {
+            partition: None,
+            buffer: disk_image_buffer,
+        }];
+    }
+    volumes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: usize = 512;
+
+    /// Writes an MBR or EBR entry in the sector at `sector`, and its signature.
+    fn set_mbr_entry(image: &mut [u8], sector: usize, slot: usize, entry: (u8, u32, u32)) {
+        let sector = &mut image[sector * SECTOR..][..SECTOR];
+        let offset = MBR_ENTRIES_OFFSET + slot * MBR_ENTRY_SIZE;
+        sector[offset + 4] = entry.0;
+        sector[offset + 8..offset + 12].copy_from_slice(&entry.1.to_le_bytes());
+        sector[offset + 12..offset + 16].copy_from_slice(&entry.2.to_le_bytes());
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn partitions(image: &Vec<u8>) -> Vec<(u32, u64, u64, String)> {
+        find_partitions(ImageSlice::new(image))
+            .into_iter()
+            .map(|p| (p.index, p.offset, p.size, p.partition_type))
+            .collect()
+    }
+
+    #[test]
+    fn parses_mbr_and_logical_partitions() {
+        let mut image = vec![0; 64 * SECTOR];
+        set_mbr_entry(&mut image, 0, 0, (0x07, 2, 8));
+        set_mbr_entry(&mut image, 0, 1, (0x0F, 16, 32));
+        // Logical partitions are relative to their EBR, and the next EBR to the extended
+        // partition.
+        set_mbr_entry(&mut image, 16, 0, (0x07, 1, 4));
+        set_mbr_entry(&mut image, 16, 1, (0x05, 8, 8));
+        set_mbr_entry(&mut image, 24, 0, (0x83, 2, 4));
+
+        assert_eq!(
+            partitions(&image),
+            [
+                (1, 2 * 512, 8 * 512, "07".to_string()),
+                (5, 17 * 512, 4 * 512, "07".to_string()),
+                (6, 26 * 512, 4 * 512, "83".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_an_ebr_loop() {
+        let mut image = vec![0; 64 * SECTOR];
+        set_mbr_entry(&mut image, 0, 0, (0x05, 16, 32));
+        set_mbr_entry(&mut image, 16, 0, (0x07, 1, 4));
+        set_mbr_entry(&mut image, 16, 1, (0x05, 16, 8));
+        set_mbr_entry(&mut image, 32, 0, (0x07, 1, 4));
+        // Links back to itself.
+        set_mbr_entry(&mut image, 32, 1, (0x05, 16, 8));
+
+        assert_eq!(
+            partitions(&image),
+            [
+                (5, 17 * 512, 4 * 512, "07".to_string()),
+                (6, 33 * 512, 4 * 512, "07".to_string()),
+            ]
+        );
+    }
+
+    /// A GPT header at `header_sector` with 4 entries of 128 bytes from sector 2: a basic
+    /// data partition named "data", an unused entry, and one that ends before it starts.
+    fn set_gpt(image: &mut [u8], header_sector: usize) {
+        let header = &mut image[header_sector * SECTOR..][..SECTOR];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+
+        let entries = &mut image[2 * SECTOR..][..4 * 128];
+        entries[..16].copy_from_slice(&[
+            0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26,
+            0x99, 0xC7,
+        ]);
+        entries[32..40].copy_from_slice(&34u64.to_le_bytes());
+        entries[40..48].copy_from_slice(&41u64.to_le_bytes());
+        for (i, c) in "data".encode_utf16().enumerate() {
+            entries[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entries[256] = 1;
+        entries[256 + 32..256 + 40].copy_from_slice(&41u64.to_le_bytes());
+        entries[256 + 40..256 + 48].copy_from_slice(&34u64.to_le_bytes());
+    }
+
+    #[test]
+    fn parses_gpt_over_its_protective_mbr() {
+        let mut image = vec![0; 64 * SECTOR];
+        set_mbr_entry(&mut image, 0, 0, (MBR_TYPE_GPT_PROTECTIVE, 1, 63));
+        set_gpt(&mut image, 1);
+
+        let found = find_partitions(ImageSlice::new(&image));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].scheme, PartitionScheme::Gpt);
+        assert_eq!(
+            (found[0].index, found[0].offset, found[0].size),
+            (1, 34 * 512, 8 * 512)
+        );
+        assert_eq!(
+            found[0].partition_type,
+            "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"
+        );
+        assert_eq!(found[0].name.as_deref(), Some("data"));
+    }
+
+    #[test]
+    fn falls_back_to_the_backup_gpt_header() {
+        let mut image = vec![0; 64 * SECTOR];
+        set_mbr_entry(&mut image, 0, 0, (MBR_TYPE_GPT_PROTECTIVE, 1, 63));
+        set_gpt(&mut image, 63);
+
+        assert_eq!(
+            partitions(&image),
+            [(
+                1,
+                34 * 512,
+                8 * 512,
+                "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn ignores_the_signature_of_a_volume_boot_sector() {
+        let mut image = vec![0; 64 * SECTOR];
+        image[3..11].copy_from_slice(b"NTFS    ");
+        image[11..13].copy_from_slice(&512u16.to_le_bytes());
+        image[13] = 8;
+        image[40..48].copy_from_slice(&63u64.to_le_bytes());
+        image[48..56].copy_from_slice(&4u64.to_le_bytes());
+        image[56..64].copy_from_slice(&2u64.to_le_bytes());
+        image[64] = 0xF6;
+        image[68] = 1;
+        image[510..512].copy_from_slice(&MBR_SIGNATURE);
+
+        assert!(partitions(&image).is_empty());
+    }
+}
@@ -0,0 +1,290 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::index::{CarvedIndexEntry, IndexChildStatus, IndexEntry};
+use crate::ntfs_logic::NtfsEntry;
+
+const ROOT_MFT_RECORD: u64 = 5;
+const ORPHAN_ROOT: &str = "\\$OrphanFiles";
+
+/// Guards against absurdly deep (most likely corrupted) parent chains.
+const MAX_PATH_DEPTH: usize = 1024;
+
+/// How the `full_path` of an entry was resolved.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PathStatus {
+    /// Every parent up to the root directory was found.
+    Resolved,
+    /// A parent directory's MFT record was not found in the image.
+    ParentMissing,
+    /// A parent's MFT record was found, but its sequence number doesn't match the
+    /// reference, so the record has since been reused for something else.
+    ParentReused,
+    /// The parent chain loops back on itself (corrupted records).
+    Cycle,
+}
+
+#[derive(Debug)]
+struct DirectoryNode<'a> {
+    name: &'a str,
+    parent: (u64, u16),
+    is_in_use: bool,
+}
+
+/// Maps (record number, sequence number) of each directory to its name and parent.
+struct DirectoryMap<'a> {
+    directories: HashMap<(u64, u16), DirectoryNode<'a>>,
+    /// Sequence number and in-use flag of every copy of each record found in the image.
+    known_records: HashMap<u64, Vec<(u16, bool)>>,
+}
+
+impl<'a> DirectoryMap<'a> {
+    fn build(entries: &'a [NtfsEntry]) -> Self {
+        let mut directories: HashMap<(u64, u16), DirectoryNode<'a>> = HashMap::new();
+        let mut known_records: HashMap<u64, Vec<(u16, bool)>> = HashMap::new();
+
+        for entry in entries {
+            known_records
+                .entry(entry.mft_record_number)
+                .or_default()
+                .push((entry.sequence_number, entry.is_in_use));
+            if !entry.is_directory {
+                continue;
+            }
+
+            let key = (entry.mft_record_number, entry.sequence_number);
+            let node = DirectoryNode {
+                name: &entry.filename,
+                parent: (entry.parent_mft_record, entry.parent_sequence),
+                is_in_use: entry.is_in_use,
+            };
+            // The same record can be found more than once (e.g., in `$MFTMirr`, or stale
+            // copies). Prefer the copy that is in use.
+            match directories.get(&key) {
+                Some(existing) if existing.is_in_use || !node.is_in_use => {}
+                _ => {
+                    directories.insert(key, node);
+                }
+            }
+        }
+
+        // Index entries stand in for directories whose own MFT record is gone.
+        for index_entry in entries.iter().flat_map(|e| &e.index_entries) {
+            if !index_entry.is_directory {
+                continue;
+            }
+            directories
+                .entry((index_entry.mft_record, index_entry.sequence))
+                .or_insert(DirectoryNode {
+                    name: &index_entry.filename,
+                    parent: (index_entry.parent_mft_record, index_entry.parent_sequence),
+                    is_in_use: false,
+                });
+        }
+
+        Self {
+            directories,
+            known_records,
+        }
+    }
+
+    fn child_status(&self, index_entry: &IndexEntry) -> IndexChildStatus {
+        let Some(copies) = self.known_records.get(&index_entry.mft_record) else {
+            return IndexChildStatus::Missing;
+        };
+
+        let sequence = index_entry.sequence;
+        if copies.contains(&(sequence, true)) {
+            IndexChildStatus::Present
+        } else if copies.contains(&(sequence, false))
+            || copies.contains(&(sequence.wrapping_add(1), false))
+        {
+            IndexChildStatus::Deleted
+        } else {
+            IndexChildStatus::Overwritten
+        }
+    }
+
+    /// Looks up a parent reference. NTFS increments the sequence number when a record is
+    /// freed, so a deleted directory is also accepted with the next sequence number.
+    fn lookup(&self, reference: (u64, u16)) -> Result<&DirectoryNode<'a>, PathStatus> {
+        let (record, sequence) = reference;
+        if let Some(node) = self.directories.get(&reference) {
+            return Ok(node);
+        }
+        if let Some(node) = self.directories.get(&(record, sequence.wrapping_add(1)))
+            && !node.is_in_use
+        {
+            return Ok(node);
+        }
+
+        if self.known_records.contains_key(&record) {
+            Err(PathStatus::ParentReused)
+        } else {
+            Err(PathStatus::ParentMissing)
+        }
+    }
+
+    fn resolve(&self, entry: &NtfsEntry) -> (String, PathStatus) {
+        self.resolve_name(
+            entry.mft_record_number,
+            &entry.filename,
+            (entry.parent_mft_record, entry.parent_sequence),
+        )
+    }
+
+    fn resolve_name(&self, record: u64, name: &str, parent: (u64, u16)) -> (String, PathStatus) {
+        if record == ROOT_MFT_RECORD {
+            return ("\\".to_string(), PathStatus::Resolved);
+        }
+
+        let mut components = vec![name];
+        let mut visited = HashSet::from([record]);
+        let mut current = parent;
+
+        let status = loop {
+            if current.0 == ROOT_MFT_RECORD {
+                break PathStatus::Resolved;
+            }
+            if !visited.insert(current.0) || visited.len() > MAX_PATH_DEPTH {
+                break PathStatus::Cycle;
+            }
+            match self.lookup(current) {
+                Ok(node) => {
+                    components.push(node.name);
+                    current = node.parent;
+                }
+                Err(status) => break status,
+            }
+        };
+
+        let mut path = match status {
+            PathStatus::Resolved => String::new(),
+            _ => ORPHAN_ROOT.to_string(),
+        };
+        for component in components.iter().rev() {
+            path.push('\\');
+            path.push_str(component);
+        }
+
+        (path, status)
+    }
+}
+
+/// Second pass over the scanned entries: fills in `full_path` and `path_status` by
+/// following the parent references up to the root directory.
+///
+/// Entries whose parent chain is broken get a path under `\$OrphanFiles`, built from
+/// the part of the chain that could be resolved. Directory index entries get the path
+/// of their directory, and the status of the child MFT record they point to.
+pub fn resolve_full_paths(entries: &mut [NtfsEntry]) {
+    let resolved: Vec<(String, PathStatus, Vec<IndexChildStatus>)> = {
+        let directory_map = DirectoryMap::build(entries);
+        entries
+            .iter()
+            .map(|e| {
+                let (path, status) = directory_map.resolve(e);
+                let child_statuses = e
+                    .index_entries
+                    .iter()
+                    .map(|i| directory_map.child_status(i))
+                    .collect();
+                (path, status, child_statuses)
+            })
+            .collect()
+    };
+
+    for (entry, (path, status, child_statuses)) in entries.iter_mut().zip(resolved) {
+        let directory_path = path.trim_end_matches('\\');
+        for (index_entry, child_status) in entry.index_entries.iter_mut().zip(child_statuses) {
+            index_entry.full_path = Some(format!("{}\\{}", directory_path, index_entry.filename));
+            index_entry.child_status = Some(child_status);
+        }
+
+        entry.full_path = Some(path);
+        entry.path_status = Some(status);
+    }
+}
+
+/// Fills in `full_path` and `child_status` of index entries carved from INDX blocks. The
+/// path is built from the parent reference in the entry, as the block itself doesn't
+/// record which directory it belongs to.
+pub fn resolve_carved_index_paths(entries: &[NtfsEntry], carved: &mut [CarvedIndexEntry]) {
+    let directory_map = DirectoryMap::build(entries);
+
+    for carved_entry in carved {
+        let entry = &mut carved_entry.entry;
+        let (path, _) = directory_map.resolve_name(
+            entry.mft_record,
+            &entry.filename,
+            (entry.parent_mft_record, entry.parent_sequence),
+        );
+        entry.full_path = Some(path);
+        entry.child_status = Some(directory_map.child_status(entry));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ntfs_logic::tests::parsed_entry;
+
+    const IN_USE: u16 = 0x01;
+    const DIRECTORY: u16 = 0x03;
+
+    fn resolved(entries: &mut [NtfsEntry]) -> Vec<(String, PathStatus)> {
+        resolve_full_paths(entries);
+        entries
+            .iter()
+            .map(|e| (e.full_path.clone().unwrap(), e.path_status.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn follows_parents_up_to_the_root() {
+        let mut entries = vec![
+            parsed_entry(40, IN_USE, "a.txt", 30),
+            parsed_entry(30, DIRECTORY, "docs", ROOT_MFT_RECORD),
+        ];
+        assert_eq!(
+            resolved(&mut entries)[0],
+            ("\\docs\\a.txt".to_string(), PathStatus::Resolved)
+        );
+    }
+
+    #[test]
+    fn puts_a_parent_cycle_under_the_orphan_root() {
+        // 30 and 31 are each other's parent.
+        let mut entries = vec![
+            parsed_entry(40, IN_USE, "a.txt", 30),
+            parsed_entry(30, DIRECTORY, "x", 31),
+            parsed_entry(31, DIRECTORY, "y", 30),
+        ];
+        assert_eq!(
+            resolved(&mut entries)[0],
+            ("\\$OrphanFiles\\y\\x\\a.txt".to_string(), PathStatus::Cycle)
+        );
+    }
+
+    #[test]
+    fn tells_missing_parents_from_reused_ones() {
+        let mut entries = vec![
+            parsed_entry(40, IN_USE, "a.txt", 30),
+            parsed_entry(41, IN_USE, "b.txt", 31),
+            parsed_entry(31, DIRECTORY, "new", ROOT_MFT_RECORD),
+        ];
+        // Record 31 now holds a later file than the one `b.txt` was created in.
+        entries[2].sequence_number = 3;
+
+        let paths = resolved(&mut entries);
+        assert_eq!(
+            paths[0],
+            (
+                "\\$OrphanFiles\\a.txt".to_string(),
+                PathStatus::ParentMissing
+            )
+        );
+        assert_eq!(paths[1].1, PathStatus::ParentReused);
+    }
+}
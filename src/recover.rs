@@ -0,0 +1,300 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::boot_sector::VolumeGeometry;
+use crate::cluster_map::ClusterMap;
+use crate::disk_image::ImageSlice;
+use crate::extended_attributes::WslMetadata;
+use crate::ntfs_logic::{DataStream, EntrySource, NtfsEntry};
+use crate::stream_reader::{
+    COMPRESSION_UNIT_CLUSTERS, StreamCopyStats, copy_compressed, copy_non_resident,
+};
+use crate::wof::{WOF_STREAM_NAME, decompress_wof};
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryStatus {
+    /// The whole stream was written.
+    Written,
+    /// Some of the stream could not be read (runs missing or past the end of the image).
+    /// The unreadable parts were zero-filled.
+    Partial,
+    Skipped,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    Directory,
+    NoUnnamedDataStream,
+    ResidentDataUnavailable,
+    NoDataRuns,
+    /// Non-resident data can't be located without the cluster size from the boot sector.
+    NoVolumeGeometry,
+    /// The same record was already recovered (e.g., it was also found in `$MFTMirr`).
+    Duplicate,
+    /// A compressed stream whose compression unit isn't the one NTFS writes (likely a
+    /// corrupt record).
+    UnsupportedCompressionUnit,
+}
+
+/// One line of the recovery manifest.
+#[derive(Debug, Serialize)]
+pub struct ManifestEntry {
+    pub mft_offset: u64,
+    pub partition_index: Option<u32>,
+    pub mft_record_number: u64,
+    pub sequence_number: u16,
+    pub filename: String,
+    pub is_in_use: bool,
+    pub status: RecoveryStatus,
+    pub skip_reason: Option<SkipReason>,
+    pub output_path: Option<String>,
+    pub size: u64,
+    pub bytes_written: u64,
+    /// Bytes that were zero-filled because they were sparse or beyond the initialized size.
+    pub bytes_zero_filled: u64,
+    /// Bytes that were zero-filled because they could not be read from the image.
+    pub bytes_missing: u64,
+    /// For deleted files (and earlier versions), clusters of the recovered stream that now
+    /// belong to a file in use: their content is likely that file's.
+    pub clusters_overwritten: Option<u64>,
+    /// Linux owner, mode and device numbers, for files created through WSL.
+    pub wsl: Option<WslMetadata>,
+}
+
+impl ManifestEntry {
+    fn new(entry: &NtfsEntry) -> Self {
+        Self {
+            mft_offset: entry.mft_offset,
+            partition_index: entry.partition_index,
+            mft_record_number: entry.mft_record_number,
+            sequence_number: entry.sequence_number,
+            filename: entry.filename.clone(),
+            is_in_use: entry.is_in_use,
+            status: RecoveryStatus::Skipped,
+            skip_reason: None,
+            output_path: None,
+            size: 0,
+            bytes_written: 0,
+            bytes_zero_filled: 0,
+            bytes_missing: 0,
+            clusters_overwritten: None,
+            wsl: entry.wsl.clone(),
+        }
+    }
+
+    pub fn skipped(entry: &NtfsEntry, reason: SkipReason) -> Self {
+        Self {
+            skip_reason: Some(reason),
+            ..Self::new(entry)
+        }
+    }
+}
+
+/// Replaces characters that are not allowed (or not safe) in file names on common platforms.
+fn sanitize_filename(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    match sanitized.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => sanitized,
+    }
+}
+
+/// Name of the recovered file inside the output directory. The record and sequence numbers
+/// keep files with the same name (e.g., several deleted versions) apart, the LSN earlier
+/// versions rebuilt from `$LogFile` (which keep the record's numbers), and the partition
+/// prefix files from different volumes.
+pub fn output_filename(entry: &NtfsEntry) -> String {
+    let version = match (entry.source, entry.logfile_lsn) {
+        (EntrySource::LogfileRollback, Some(lsn)) => format!("_lsn{lsn}"),
+        _ => String::new(),
+    };
+    let name = format!(
+        "{}_{}{version}_{}",
+        entry.mft_record_number,
+        entry.sequence_number,
+        sanitize_filename(&entry.filename)
+    );
+    match entry.partition_index {
+        Some(index) => format!("p{index}_{name}"),
+        None => name,
+    }
+}
+
+/// Writes the unnamed `$DATA` stream of `entry` into `output_dir`.
+///
+/// Returns the manifest line describing what was written, or why the entry was skipped.
+/// I/O errors on the output side are returned as errors.
+pub fn recover_entry(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: Option<&VolumeGeometry>,
+    cluster_map: &ClusterMap,
+    entry: &NtfsEntry,
+    output_dir: &Path,
+) -> Result<ManifestEntry> {
+    if entry.is_directory {
+        return Ok(ManifestEntry::skipped(entry, SkipReason::Directory));
+    }
+
+    // WOF-compressed files keep their content in a named stream.
+    let unnamed_stream = entry.data_streams.iter().find(|s| s.name.is_none());
+    let wof = entry.reparse.as_ref().and_then(|r| r.wof_algorithm()).zip(
+        entry
+            .data_streams
+            .iter()
+            .find(|s| s.name.as_deref() == Some(WOF_STREAM_NAME)),
+    );
+    let Some(stream) = wof.map(|(_, stream)| stream).or(unnamed_stream) else {
+        return Ok(ManifestEntry::skipped(
+            entry,
+            SkipReason::NoUnnamedDataStream,
+        ));
+    };
+
+    if stream.resident {
+        if stream.resident_content.is_none() && stream.size > 0 {
+            return Ok(ManifestEntry::skipped(
+                entry,
+                SkipReason::ResidentDataUnavailable,
+            ));
+        }
+    } else {
+        if stream.data_runs.is_none() && stream.size > 0 {
+            return Ok(ManifestEntry::skipped(entry, SkipReason::NoDataRuns));
+        }
+        if geometry.is_none() {
+            return Ok(ManifestEntry::skipped(entry, SkipReason::NoVolumeGeometry));
+        }
+        if wof.is_none()
+            && stream
+                .compression_unit
+                .is_some_and(|unit| unit != COMPRESSION_UNIT_CLUSTERS)
+        {
+            return Ok(ManifestEntry::skipped(
+                entry,
+                SkipReason::UnsupportedCompressionUnit,
+            ));
+        }
+    }
+
+    let output_path = output_dir.join(output_filename(entry));
+    let mut writer = BufWriter::new(File::create(&output_path)?);
+
+    let mut manifest = ManifestEntry::new(entry);
+    manifest.output_path = Some(output_path.to_string_lossy().into_owned());
+    manifest.size = stream.size;
+    if !(entry.is_in_use && entry.source == EntrySource::Mft) {
+        manifest.clusters_overwritten = Some(cluster_map.clusters_claimed_by_live_files(
+            entry.mft_record_number,
+            entry.sequence_number,
+            stream.data_runs.as_deref().unwrap_or_default(),
+        ));
+    }
+
+    if let Some((algorithm, _)) = wof {
+        // The unnamed stream holds no data, but its size is the uncompressed size (when
+        // it was kept up to date; otherwise `$FILE_NAME` may have it).
+        let size = unnamed_stream
+            .map(|s| s.size)
+            .filter(|&size| size > 0)
+            .unwrap_or(entry.real_size);
+        let mut compressed = Vec::new();
+        let stats = match (&stream.resident_content, geometry) {
+            (Some(data), _) if stream.resident => {
+                compressed.extend_from_slice(data);
+                StreamCopyStats::default()
+            }
+            (_, Some(geometry)) => copy_non_resident(
+                &mut compressed,
+                disk_image_buffer,
+                geometry.cluster_size,
+                stream.data_runs.as_deref().unwrap_or_default(),
+                stream.size,
+                stream.initialized_size,
+            )?,
+            _ => StreamCopyStats::default(),
+        };
+
+        let (data, failed) = decompress_wof(&compressed, size, algorithm);
+        writer.write_all(&data)?;
+        manifest.size = size;
+        manifest.bytes_missing = failed.max(stats.bytes_missing).min(size);
+        manifest.bytes_written = size - manifest.bytes_missing;
+    } else {
+        copy_stream(
+            &mut writer,
+            disk_image_buffer,
+            geometry,
+            stream,
+            &mut manifest,
+        )?;
+    }
+
+    writer.flush()?;
+
+    manifest.status = if manifest.bytes_missing > 0 {
+        RecoveryStatus::Partial
+    } else {
+        RecoveryStatus::Written
+    };
+
+    Ok(manifest)
+}
+
+/// Writes a stream as stored in the image (resident, plain or LZNT1-compressed).
+fn copy_stream(
+    writer: &mut impl Write,
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: Option<&VolumeGeometry>,
+    stream: &DataStream,
+    manifest: &mut ManifestEntry,
+) -> Result<()> {
+    match (&stream.resident_content, geometry) {
+        (Some(data), _) if stream.resident => {
+            writer.write_all(data)?;
+            manifest.bytes_written = data.len() as u64;
+        }
+        (_, Some(geometry)) if !stream.resident => {
+            let runs = stream.data_runs.as_deref().unwrap_or_default();
+            let stats = match stream.compression_unit {
+                Some(unit_clusters) => copy_compressed(
+                    writer,
+                    disk_image_buffer,
+                    geometry.cluster_size,
+                    runs,
+                    stream.size,
+                    stream.initialized_size,
+                    unit_clusters as u64,
+                )?,
+                None => copy_non_resident(
+                    writer,
+                    disk_image_buffer,
+                    geometry.cluster_size,
+                    runs,
+                    stream.size,
+                    stream.initialized_size,
+                )?,
+            };
+            manifest.bytes_written = stats.bytes_read;
+            manifest.bytes_zero_filled = stats.bytes_zero_filled;
+            manifest.bytes_missing = stats.bytes_missing;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
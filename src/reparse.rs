@@ -0,0 +1,311 @@
+use serde::Serialize;
+
+use crate::wof::{WofAlgorithm, WofProvider, parse_wof_reparse_data};
+
+const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA000_0003;
+const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000_000C;
+const IO_REPARSE_TAG_APPEXECLINK: u32 = 0x8000_001B;
+const IO_REPARSE_TAG_WOF: u32 = 0x8000_0017;
+const IO_REPARSE_TAG_DEDUP: u32 = 0x8000_0013;
+const IO_REPARSE_TAG_ONEDRIVE: u32 = 0x8000_0021;
+const IO_REPARSE_TAG_LX_SYMLINK: u32 = 0xA000_001D;
+const IO_REPARSE_TAG_AF_UNIX: u32 = 0x8000_0023;
+const IO_REPARSE_TAG_LX_FIFO: u32 = 0x8000_0024;
+const IO_REPARSE_TAG_LX_CHR: u32 = 0x8000_0025;
+const IO_REPARSE_TAG_LX_BLK: u32 = 0x8000_0026;
+const IO_REPARSE_TAG_HSM: u32 = 0xC000_0004;
+const IO_REPARSE_TAG_HSM2: u32 = 0x8000_0006;
+
+// `IO_REPARSE_TAG_CLOUD` and its variants `IO_REPARSE_TAG_CLOUD_1` to `_F`, which differ
+// in bits 12-15.
+const IO_REPARSE_TAG_CLOUD: u32 = 0x9000_001A;
+const CLOUD_VARIANT_MASK: u32 = 0x0000_F000;
+
+const SYMLINK_FLAG_RELATIVE: u32 = 0x1;
+
+// Size of the reparse buffer header: tag, data length and a reserved field.
+const REPARSE_HEADER_SIZE: usize = 8;
+
+/// A decoded `$REPARSE_POINT`. Tags that aren't decoded (or don't parse) are kept as
+/// `other`, with their data in hex.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReparseInfo {
+    /// A junction, or a volume mount point.
+    MountPoint {
+        substitute_name: String,
+        print_name: String,
+    },
+    Symlink {
+        substitute_name: String,
+        print_name: String,
+        relative: bool,
+    },
+    /// Launcher for a packaged (Store) app, as found in `%LOCALAPPDATA%\Microsoft\WindowsApps`.
+    AppExecLink {
+        package_id: String,
+        app_user_model_id: String,
+        target_path: String,
+    },
+    /// A file compressed by the Windows Overlay Filter, or backed by a WIM image.
+    Wof {
+        provider: WofProvider,
+        algorithm: Option<WofAlgorithm>, // For the file provider
+    },
+    /// A file whose content was moved into the Data Deduplication chunk store.
+    Dedup {
+        data: String,
+    },
+    /// A cloud files placeholder (OneDrive and other sync providers).
+    CloudFiles {
+        tag: u32,
+        data: String,
+    },
+    /// A symlink created by WSL.
+    LxSymlink {
+        target: String,
+    },
+    /// A Unix domain socket created by WSL or Windows.
+    AfUnix,
+    LxFifo,
+    LxCharDevice,
+    LxBlockDevice,
+    /// A file migrated by a hierarchical storage management product.
+    Hsm {
+        tag: u32,
+        data: String,
+    },
+    Other {
+        tag: u32,
+        data: String,
+    },
+}
+
+impl ReparseInfo {
+    pub fn wof_algorithm(&self) -> Option<WofAlgorithm> {
+        match self {
+            Self::Wof { algorithm, .. } => *algorithm,
+            _ => None,
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn utf16_string(data: &[u8]) -> Option<String> {
+    let utf16: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&utf16).ok()
+}
+
+/// Reads the substitute and print names of a mount point or symlink. Their offsets are
+/// relative to the path buffer, which starts at `path_buffer`.
+fn parse_names(data: &[u8], path_buffer: usize) -> Option<(String, String)> {
+    let field = |offset: usize| -> Option<usize> {
+        Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?) as usize)
+    };
+    let name = |offset: usize, length: usize| -> Option<String> {
+        let start = path_buffer + offset;
+        utf16_string(data.get(start..start + length)?)
+    };
+
+    let substitute_name = name(field(0)?, field(2)?)?;
+    let print_name = name(field(4)?, field(6)?)?;
+    Some((substitute_name, print_name))
+}
+
+/// An AppExecLink holds a version, then NUL-terminated UTF-16 strings.
+fn parse_app_exec_link(data: &[u8]) -> Option<ReparseInfo> {
+    let strings = utf16_string(data.get(4..)?)?;
+    let mut strings = strings.split('\0');
+    Some(ReparseInfo::AppExecLink {
+        package_id: strings.next()?.to_string(),
+        app_user_model_id: strings.next()?.to_string(),
+        target_path: strings.next()?.to_string(),
+    })
+}
+
+fn parse_tag_data(tag: u32, data: &[u8]) -> Option<ReparseInfo> {
+    let info = match tag {
+        IO_REPARSE_TAG_MOUNT_POINT => {
+            let (substitute_name, print_name) = parse_names(data, 8)?;
+            ReparseInfo::MountPoint {
+                substitute_name,
+                print_name,
+            }
+        }
+        IO_REPARSE_TAG_SYMLINK => {
+            let (substitute_name, print_name) = parse_names(data, 12)?;
+            let flags = u32::from_le_bytes(data.get(8..12)?.try_into().ok()?);
+            ReparseInfo::Symlink {
+                substitute_name,
+                print_name,
+                relative: flags & SYMLINK_FLAG_RELATIVE != 0,
+            }
+        }
+        IO_REPARSE_TAG_APPEXECLINK => parse_app_exec_link(data)?,
+        IO_REPARSE_TAG_WOF => {
+            let (provider, algorithm) = parse_wof_reparse_data(data)?;
+            ReparseInfo::Wof {
+                provider,
+                algorithm,
+            }
+        }
+        IO_REPARSE_TAG_DEDUP => ReparseInfo::Dedup { data: hex(data) },
+        IO_REPARSE_TAG_ONEDRIVE => ReparseInfo::CloudFiles {
+            tag,
+            data: hex(data),
+        },
+        _ if tag & !CLOUD_VARIANT_MASK == IO_REPARSE_TAG_CLOUD => ReparseInfo::CloudFiles {
+            tag,
+            data: hex(data),
+        },
+        // The target is stored as UTF-8, after a version number.
+        IO_REPARSE_TAG_LX_SYMLINK => ReparseInfo::LxSymlink {
+            target: String::from_utf8_lossy(data.get(4..)?).into_owned(),
+        },
+        IO_REPARSE_TAG_AF_UNIX => ReparseInfo::AfUnix,
+        IO_REPARSE_TAG_LX_FIFO => ReparseInfo::LxFifo,
+        IO_REPARSE_TAG_LX_CHR => ReparseInfo::LxCharDevice,
+        IO_REPARSE_TAG_LX_BLK => ReparseInfo::LxBlockDevice,
+        IO_REPARSE_TAG_HSM | IO_REPARSE_TAG_HSM2 => ReparseInfo::Hsm {
+            tag,
+            data: hex(data),
+        },
+        _ => return None,
+    };
+    Some(info)
+}
+
+/// Decodes a reparse buffer (the content of a `$REPARSE_POINT` attribute).
+pub fn parse_reparse_buffer(content: &[u8]) -> Option<ReparseInfo> {
+    let tag = u32::from_le_bytes(content.get(0..4)?.try_into().ok()?);
+    let data_length = u16::from_le_bytes(content.get(4..6)?.try_into().ok()?) as usize;
+    let data = content
+        .get(REPARSE_HEADER_SIZE..)?
+        .get(..data_length)
+        .unwrap_or(&content[REPARSE_HEADER_SIZE..]);
+
+    Some(
+        parse_tag_data(tag, data).unwrap_or_else(|| ReparseInfo::Other {
+            tag,
+            data: hex(data),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reparse_buffer(tag: u32, data: &[u8]) -> Vec<u8> {
+        let mut buffer = tag.to_le_bytes().to_vec();
+        buffer.extend((data.len() as u16).to_le_bytes());
+        buffer.extend([0, 0]);
+        buffer.extend(data);
+        buffer
+    }
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    /// Name offsets and lengths, (flags,) then the path buffer with both names.
+    fn link_data(substitute_name: &str, print_name: &str, flags: Option<u32>) -> Vec<u8> {
+        let (substitute_name, print_name) = (utf16(substitute_name), utf16(print_name));
+        let mut data = Vec::new();
+        for value in [
+            0,
+            substitute_name.len(),
+            substitute_name.len(),
+            print_name.len(),
+        ] {
+            data.extend((value as u16).to_le_bytes());
+        }
+        if let Some(flags) = flags {
+            data.extend(flags.to_le_bytes());
+        }
+        data.extend(substitute_name);
+        data.extend(print_name);
+        data
+    }
+
+    #[test]
+    fn decodes_junctions_and_symlinks() {
+        let junction = reparse_buffer(
+            IO_REPARSE_TAG_MOUNT_POINT,
+            &link_data("\\??\\C:\\Users", "C:\\Users", None),
+        );
+        assert_eq!(
+            parse_reparse_buffer(&junction),
+            Some(ReparseInfo::MountPoint {
+                substitute_name: "\\??\\C:\\Users".to_string(),
+                print_name: "C:\\Users".to_string(),
+            })
+        );
+
+        let symlink = reparse_buffer(
+            IO_REPARSE_TAG_SYMLINK,
+            &link_data("..\\a.txt", "..\\a.txt", Some(SYMLINK_FLAG_RELATIVE)),
+        );
+        assert_eq!(
+            parse_reparse_buffer(&symlink),
+            Some(ReparseInfo::Symlink {
+                substitute_name: "..\\a.txt".to_string(),
+                print_name: "..\\a.txt".to_string(),
+                relative: true,
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_wsl_wof_and_cloud_tags() {
+        let lx_symlink = reparse_buffer(IO_REPARSE_TAG_LX_SYMLINK, b"\x02\0\0\0/usr/bin/python3");
+        assert_eq!(
+            parse_reparse_buffer(&lx_symlink),
+            Some(ReparseInfo::LxSymlink {
+                target: "/usr/bin/python3".to_string()
+            })
+        );
+
+        // Version 1, file provider, provider version 1, LZX.
+        let wof_data: Vec<u8> = [1u32, 2, 1, 1]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let wof = parse_reparse_buffer(&reparse_buffer(IO_REPARSE_TAG_WOF, &wof_data)).unwrap();
+        assert_eq!(wof.wof_algorithm(), Some(WofAlgorithm::Lzx));
+
+        // `IO_REPARSE_TAG_CLOUD_3`
+        let cloud = reparse_buffer(0x9000_301A, &[0xAB]);
+        assert_eq!(
+            parse_reparse_buffer(&cloud),
+            Some(ReparseInfo::CloudFiles {
+                tag: 0x9000_301A,
+                data: "ab".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_tags_as_hex() {
+        let unknown = reparse_buffer(0x8000_0099, &[0x01, 0x02]);
+        assert_eq!(
+            parse_reparse_buffer(&unknown),
+            Some(ReparseInfo::Other {
+                tag: 0x8000_0099,
+                data: "0102".to_string()
+            })
+        );
+        // A symlink whose names run past the data is kept too.
+        let broken = reparse_buffer(IO_REPARSE_TAG_SYMLINK, &[0xFF; 12]);
+        assert!(matches!(
+            parse_reparse_buffer(&broken),
+            Some(ReparseInfo::Other { .. })
+        ));
+    }
+}
@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::boot_sector::VolumeGeometry;
+use crate::disk_image::ImageSlice;
+use crate::index::{parse_index_allocation_with, parse_index_root_with};
+use crate::ntfs_logic::{
+    ATTR_INDEX_ALLOCATION, ATTR_INDEX_ROOT, NtfsEntry, parse_attr_header, parse_resident_data,
+    read_index_allocation_attribute, read_mft_record,
+};
+use crate::stream_reader::read_non_resident;
+
+/// `$Secure` is MFT record 9. Its `$SDS` stream holds every security descriptor of the
+/// volume, and its `$SII` index maps security IDs to their place in `$SDS`.
+const SECURE_MFT_RECORD: u64 = 9;
+const SDS_STREAM_NAME: &str = "$SDS";
+const SII_INDEX_NAME: &str = "$SII";
+
+// `$SDS` is usually a few MiB; cap reads from corrupt headers.
+const MAX_SDS_SIZE: u64 = 256 * 1024 * 1024;
+
+// Each `$SDS` entry: hash, security ID, offset of the entry, length (header included).
+const SDS_ENTRY_HEADER_SIZE: usize = 20;
+
+// Security descriptor control flags
+const SE_DACL_PRESENT: u16 = 0x0004;
+const SE_DACL_AUTO_INHERIT_REQ: u16 = 0x0100;
+const SE_DACL_AUTO_INHERITED: u16 = 0x0400;
+const SE_DACL_PROTECTED: u16 = 0x1000;
+
+// Object ACEs carry up to two GUIDs between the access mask and the SID.
+const ACE_OBJECT_TYPE_PRESENT: u32 = 0x1;
+const ACE_INHERITED_OBJECT_TYPE_PRESENT: u32 = 0x2;
+
+/// SID abbreviations used by SDDL for well-known accounts.
+const WELL_KNOWN_SIDS: [(&str, &str); 28] = [
+    ("S-1-1-0", "WD"),
+    ("S-1-3-0", "CO"),
+    ("S-1-3-1", "CG"),
+    ("S-1-5-2", "NU"),
+    ("S-1-5-4", "IU"),
+    ("S-1-5-6", "SU"),
+    ("S-1-5-7", "AN"),
+    ("S-1-5-9", "ED"),
+    ("S-1-5-10", "PS"),
+    ("S-1-5-11", "AU"),
+    ("S-1-5-12", "RC"),
+    ("S-1-5-18", "SY"),
+    ("S-1-5-19", "LS"),
+    ("S-1-5-20", "NS"),
+    ("S-1-5-33", "WR"),
+    ("S-1-5-32-544", "BA"),
+    ("S-1-5-32-545", "BU"),
+    ("S-1-5-32-546", "BG"),
+    ("S-1-5-32-547", "PU"),
+    ("S-1-5-32-548", "AO"),
+    ("S-1-5-32-549", "SO"),
+    ("S-1-5-32-550", "PO"),
+    ("S-1-5-32-551", "BO"),
+    ("S-1-5-32-552", "RE"),
+    ("S-1-5-32-555", "RD"),
+    ("S-1-5-32-556", "NO"),
+    ("S-1-15-2-1", "AC"),
+    ("S-1-16-12288", "HI"),
+];
+
+/// ACE types, by type code. Codes without an SDDL form are skipped.
+const ACE_TYPES: [(u8, &str); 14] = [
+    (0x00, "A"),
+    (0x01, "D"),
+    (0x02, "AU"),
+    (0x03, "AL"),
+    (0x05, "OA"),
+    (0x06, "OD"),
+    (0x07, "OU"),
+    (0x08, "OL"),
+    (0x09, "XA"),
+    (0x0A, "XD"),
+    (0x0B, "ZA"),
+    (0x0D, "XU"),
+    (0x11, "ML"),
+    (0x12, "RA"),
+];
+
+const ACE_FLAGS: [(u8, &str); 7] = [
+    (0x01, "OI"),
+    (0x02, "CI"),
+    (0x04, "NP"),
+    (0x08, "IO"),
+    (0x10, "ID"),
+    (0x40, "SA"),
+    (0x80, "FA"),
+];
+
+/// Access masks with a single SDDL name (tried first).
+const COMPOSITE_RIGHTS: [(u32, &str); 8] = [
+    (0x001F_01FF, "FA"),
+    (0x0012_0089, "FR"),
+    (0x0012_0116, "FW"),
+    (0x0012_00A0, "FX"),
+    (0x1000_0000, "GA"),
+    (0x8000_0000, "GR"),
+    (0x4000_0000, "GW"),
+    (0x2000_0000, "GX"),
+];
+
+/// Individual rights, used when the mask is a combination of them.
+const SIMPLE_RIGHTS: [(u32, &str); 17] = [
+    (0x8000_0000, "GR"),
+    (0x4000_0000, "GW"),
+    (0x2000_0000, "GX"),
+    (0x1000_0000, "GA"),
+    (0x0008_0000, "WO"),
+    (0x0004_0000, "WD"),
+    (0x0002_0000, "RC"),
+    (0x0001_0000, "SD"),
+    (0x0000_0100, "CR"),
+    (0x0000_0080, "LO"),
+    (0x0000_0040, "DT"),
+    (0x0000_0020, "WP"),
+    (0x0000_0010, "RP"),
+    (0x0000_0008, "SW"),
+    (0x0000_0004, "LC"),
+    (0x0000_0002, "DC"),
+    (0x0000_0001, "CC"),
+];
+
+/// Owner and SDDL form of a security descriptor.
+#[derive(Debug, Clone)]
+pub struct DecodedSecurityDescriptor {
+    pub owner_sid: Option<String>,
+    pub sddl: String,
+}
+
+fn parse_sid(data: &[u8]) -> Option<String> {
+    let revision = *data.first()?;
+    let sub_authority_count = *data.get(1)? as usize;
+    let authority = data
+        .get(2..8)?
+        .iter()
+        .fold(0u64, |acc, &b| (acc << 8) | b as u64);
+
+    let mut sid = format!("S-{revision}-{authority}");
+    for i in 0..sub_authority_count {
+        let offset = 8 + i * 4;
+        let sub_authority = u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?);
+        write!(sid, "-{sub_authority}").ok()?;
+    }
+    Some(sid)
+}
+
+fn sddl_sid(sid: &str) -> &str {
+    WELL_KNOWN_SIDS
+        .iter()
+        .find(|(full, _)| *full == sid)
+        .map_or(sid, |(_, abbreviation)| abbreviation)
+}
+
+fn sddl_rights(mask: u32) -> String {
+    if let Some((_, name)) = COMPOSITE_RIGHTS.iter().find(|(bits, _)| *bits == mask) {
+        return name.to_string();
+    }
+
+    let covered = SIMPLE_RIGHTS.iter().fold(0, |acc, (bits, _)| acc | bits);
+    if mask & !covered != 0 {
+        return format!("0x{mask:x}");
+    }
+    SIMPLE_RIGHTS
+        .iter()
+        .filter(|(bits, _)| mask & bits != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+fn format_guid(data: &[u8]) -> Option<String> {
+    let d1 = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let d2 = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?);
+    let d3 = u16::from_le_bytes(data.get(6..8)?.try_into().ok()?);
+    let d4 = data.get(8..16)?;
+    Some(format!(
+        "{d1:08x}-{d2:04x}-{d3:04x}-{:02x}{:02x}-{}",
+        d4[0],
+        d4[1],
+        d4[2..]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    ))
+}
+
+/// Formats one ACE as `(type;flags;rights;object_guid;inherit_object_guid;sid)`.
+fn parse_ace(ace: &[u8]) -> Option<String> {
+    let ace_type = *ace.first()?;
+    let ace_flags = *ace.get(1)?;
+    let type_name = ACE_TYPES.iter().find(|(code, _)| *code == ace_type)?.1;
+    let mask = u32::from_le_bytes(ace.get(4..8)?.try_into().ok()?);
+
+    let is_object_ace = matches!(ace_type, 0x05..=0x08 | 0x0B | 0x0C | 0x0F | 0x10);
+    let (object_guid, inherited_object_guid, sid_offset) = if is_object_ace {
+        let object_flags = u32::from_le_bytes(ace.get(8..12)?.try_into().ok()?);
+        let mut offset = 12;
+        let mut guid = |present: bool| -> Option<String> {
+            if !present {
+                return Some(String::new());
+            }
+            let guid = format_guid(ace.get(offset..offset + 16)?)?;
+            offset += 16;
+            Some(guid)
+        };
+        let object_guid = guid(object_flags & ACE_OBJECT_TYPE_PRESENT != 0)?;
+        let inherited_object_guid = guid(object_flags & ACE_INHERITED_OBJECT_TYPE_PRESENT != 0)?;
+        (object_guid, inherited_object_guid, offset)
+    } else {
+        (String::new(), String::new(), 8)
+    };
+
+    let sid = parse_sid(ace.get(sid_offset..)?)?;
+    let flags: String = ACE_FLAGS
+        .iter()
+        .filter(|(bit, _)| ace_flags & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+
+    Some(format!(
+        "({type_name};{flags};{};{object_guid};{inherited_object_guid};{})",
+        sddl_rights(mask),
+        sddl_sid(&sid)
+    ))
+}
+
+fn parse_acl(acl: &[u8]) -> Option<String> {
+    let ace_count = u16::from_le_bytes(acl.get(4..6)?.try_into().ok()?) as usize;
+    let mut aces = String::new();
+    let mut offset = 8;
+
+    for _ in 0..ace_count {
+        let ace_size =
+            u16::from_le_bytes(acl.get(offset + 2..offset + 4)?.try_into().ok()?) as usize;
+        if ace_size < 8 {
+            break;
+        }
+        if let Some(ace) = acl.get(offset..offset + ace_size).and_then(parse_ace) {
+            aces.push_str(&ace);
+        }
+        offset += ace_size;
+    }
+
+    Some(aces)
+}
+
+/// Decodes a self-relative security descriptor into its owner SID and SDDL
+/// (`O:owner G:group D:dacl`). The SACL is left out.
+pub fn decode_security_descriptor(descriptor: &[u8]) -> Option<DecodedSecurityDescriptor> {
+    if descriptor.len() < 20 || descriptor[0] != 1 {
+        return None;
+    }
+    let control = u16::from_le_bytes(descriptor[2..4].try_into().ok()?);
+    let field = |offset: usize| {
+        u32::from_le_bytes(descriptor[offset..offset + 4].try_into().unwrap()) as usize
+    };
+    let (owner_offset, group_offset, dacl_offset) = (field(4), field(8), field(16));
+
+    let owner_sid = (owner_offset != 0)
+        .then(|| parse_sid(descriptor.get(owner_offset..)?))
+        .flatten();
+    let group_sid = (group_offset != 0)
+        .then(|| parse_sid(descriptor.get(group_offset..)?))
+        .flatten();
+
+    let mut sddl = String::new();
+    if let Some(owner_sid) = &owner_sid {
+        write!(sddl, "O:{}", sddl_sid(owner_sid)).ok()?;
+    }
+    if let Some(group_sid) = &group_sid {
+        write!(sddl, "G:{}", sddl_sid(group_sid)).ok()?;
+    }
+    if control & SE_DACL_PRESENT != 0 {
+        sddl.push_str("D:");
+        if control & SE_DACL_PROTECTED != 0 {
+            sddl.push('P');
+        }
+        if control & SE_DACL_AUTO_INHERIT_REQ != 0 {
+            sddl.push_str("AR");
+        }
+        if control & SE_DACL_AUTO_INHERITED != 0 {
+            sddl.push_str("AI");
+        }
+        match dacl_offset {
+            0 => sddl.push_str("NO_ACCESS_CONTROL"),
+            offset => sddl.push_str(&parse_acl(descriptor.get(offset..)?)?),
+        }
+    }
+
+    Some(DecodedSecurityDescriptor { owner_sid, sddl })
+}
+
+/// An entry of the `$SII` index: where the descriptor of a security ID is in `$SDS`.
+#[derive(Debug, Clone, Copy)]
+struct SecurityIdIndexEntry {
+    security_id: u32,
+    sds_offset: u64,
+    length: u32,
+}
+
+impl SecurityIdIndexEntry {
+    /// Parses an `$SII` index entry: a 4-byte security ID key, and a copy of the `$SDS`
+    /// entry header as data.
+    fn parse(entry: &[u8]) -> Option<Self> {
+        let data_offset = u16::from_le_bytes(entry.get(0..2)?.try_into().ok()?) as usize;
+        let data = entry.get(data_offset..data_offset + SDS_ENTRY_HEADER_SIZE)?;
+
+        Some(Self {
+            security_id: u32::from_le_bytes(data[4..8].try_into().ok()?),
+            sds_offset: u64::from_le_bytes(data[8..16].try_into().ok()?),
+            length: u32::from_le_bytes(data[16..20].try_into().ok()?),
+        })
+    }
+}
+
+/// Reads the `$SII` index from the raw `$Secure` record (it isn't a filename index, so
+/// the scan doesn't decode it).
+fn read_sii_index(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: &VolumeGeometry,
+    secure: &NtfsEntry,
+) -> Vec<SecurityIdIndexEntry> {
+    let Some((record, _)) = read_mft_record(
+        disk_image_buffer,
+        secure.mft_offset as usize,
+        Some(geometry),
+    ) else {
+        return Vec::new();
+    };
+
+    let mut entries = Vec::new();
+    let mut index_block_size = geometry.index_record_size as usize;
+    let mut allocation = None;
+    let mut offset = u16::from_le_bytes([record[20], record[21]]) as usize;
+
+    while let Some((attr_type, len, non_resident, attr_name)) = parse_attr_header(&record, offset) {
+        let attr = &record[offset..offset + len];
+        match attr_type {
+            ATTR_INDEX_ROOT if attr_name.as_deref() == Some(SII_INDEX_NAME) => {
+                if let Some((root_entries, block_size)) = parse_resident_data(attr)
+                    .and_then(|c| parse_index_root_with(&c, SecurityIdIndexEntry::parse))
+                {
+                    entries.extend(root_entries);
+                    index_block_size = block_size as usize;
+                }
+            }
+            ATTR_INDEX_ALLOCATION
+                if non_resident && attr_name.as_deref() == Some(SII_INDEX_NAME) =>
+            {
+                allocation =
+                    read_index_allocation_attribute(attr, disk_image_buffer, geometry.cluster_size);
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+
+    if let Some(mut data) = allocation {
+        entries.extend(parse_index_allocation_with(
+            &mut data,
+            index_block_size,
+            SecurityIdIndexEntry::parse,
+        ));
+    }
+    entries
+}
+
+/// Returns the descriptor of the `$SDS` entry at `offset`, if its header is consistent.
+fn sds_entry(sds: &[u8], offset: usize) -> Option<(u32, &[u8])> {
+    let header = sds.get(offset..offset + SDS_ENTRY_HEADER_SIZE)?;
+    let security_id = u32::from_le_bytes(header[4..8].try_into().ok()?);
+    let entry_offset = u64::from_le_bytes(header[8..16].try_into().ok()?);
+    let length = u32::from_le_bytes(header[16..20].try_into().ok()?) as usize;
+
+    if entry_offset != offset as u64 || length <= SDS_ENTRY_HEADER_SIZE {
+        return None;
+    }
+    let descriptor = sds.get(offset + SDS_ENTRY_HEADER_SIZE..offset + length)?;
+    Some((security_id, descriptor))
+}
+
+/// Decodes every security descriptor of the volume, keyed by security ID.
+///
+/// `$SII` gives the location of each descriptor. `$SDS` is also walked entry by entry,
+/// which catches descriptors whose `$SII` entries are missing. Entries of the mirror copy
+/// (every other 256 KiB block) don't match their own offset, so they are skipped.
+pub fn read_security_descriptors(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: &VolumeGeometry,
+    entries: &[NtfsEntry],
+) -> Option<HashMap<u32, DecodedSecurityDescriptor>> {
+    let secure = entries
+        .iter()
+        .find(|e| e.is_in_use && e.mft_record_number == SECURE_MFT_RECORD)?;
+    let sds_stream = secure
+        .data_streams
+        .iter()
+        .find(|s| !s.resident && s.name.as_deref() == Some(SDS_STREAM_NAME))?;
+    if sds_stream.size > MAX_SDS_SIZE {
+        return None;
+    }
+    let sds = read_non_resident(
+        disk_image_buffer,
+        geometry.cluster_size,
+        sds_stream.data_runs.as_deref().unwrap_or_default(),
+        sds_stream.size,
+        sds_stream.initialized_size,
+    );
+
+    let mut descriptors = HashMap::new();
+    for sii_entry in read_sii_index(disk_image_buffer, geometry, secure) {
+        if let Some((security_id, descriptor)) = sds_entry(&sds, sii_entry.sds_offset as usize)
+            && security_id == sii_entry.security_id
+            && sii_entry.length as usize == descriptor.len() + SDS_ENTRY_HEADER_SIZE
+            && let Some(decoded) = decode_security_descriptor(descriptor)
+        {
+            descriptors.insert(security_id, decoded);
+        }
+    }
+
+    let mut offset = 0;
+    while offset + SDS_ENTRY_HEADER_SIZE <= sds.len() {
+        match sds_entry(&sds, offset) {
+            Some((security_id, descriptor)) => {
+                if !descriptors.contains_key(&security_id)
+                    && let Some(decoded) = decode_security_descriptor(descriptor)
+                {
+                    descriptors.insert(security_id, decoded);
+                }
+                offset += (descriptor.len() + SDS_ENTRY_HEADER_SIZE).next_multiple_of(16);
+            }
+            None => offset += 16,
+        }
+    }
+
+    Some(descriptors)
+}
+
+/// Second pass over the scanned entries: fills in `owner_sid` and `sddl` from `$Secure`
+/// for entries that only carry a `security_id` (those with an inline
+/// `$SECURITY_DESCRIPTOR` already have them).
+pub fn resolve_security_descriptors(
+    entries: &mut [NtfsEntry],
+    descriptors: &HashMap<u32, DecodedSecurityDescriptor>,
+) {
+    for entry in entries.iter_mut().filter(|e| e.sddl.is_none()) {
+        if let Some(decoded) = entry.security_id.and_then(|id| descriptors.get(&id)) {
+            entry.owner_sid = decoded.owner_sid.clone();
+            entry.sddl = Some(decoded.sddl.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sid(authority: u8, sub_authorities: &[u32]) -> Vec<u8> {
+        let mut sid = vec![1, sub_authorities.len() as u8, 0, 0, 0, 0, 0, authority];
+        for sub_authority in sub_authorities {
+            sid.extend(sub_authority.to_le_bytes());
+        }
+        sid
+    }
+
+    fn ace(ace_type: u8, flags: u8, mask: u32, sid: &[u8]) -> Vec<u8> {
+        let mut ace = vec![ace_type, flags, 0, 0];
+        ace.extend(mask.to_le_bytes());
+        ace.extend(sid);
+        let size = ace.len() as u16;
+        ace[2..4].copy_from_slice(&size.to_le_bytes());
+        ace
+    }
+
+    /// A self-relative security descriptor: header, owner, group, then the DACL.
+    fn security_descriptor(control: u16, aces: &[Vec<u8>]) -> Vec<u8> {
+        let owner = sid(5, &[32, 544]);
+        let group = sid(5, &[18]);
+        let mut descriptor = vec![1, 0];
+        descriptor.extend((control | 0x8000).to_le_bytes());
+        let group_offset = 20 + owner.len();
+        let dacl_offset = group_offset + group.len();
+        for offset in [20, group_offset, 0, dacl_offset] {
+            descriptor.extend((offset as u32).to_le_bytes());
+        }
+        descriptor.extend(owner);
+        descriptor.extend(group);
+
+        // ACL header: revision, size, ACE count.
+        let ace_count = aces.len() as u16;
+        let aces = aces.concat();
+        descriptor.extend([2, 0]);
+        descriptor.extend(((8 + aces.len()) as u16).to_le_bytes());
+        descriptor.extend(ace_count.to_le_bytes());
+        descriptor.extend([0, 0]);
+        descriptor.extend(aces);
+        descriptor
+    }
+
+    #[test]
+    fn decodes_a_descriptor_into_sddl() {
+        let descriptor = security_descriptor(
+            SE_DACL_PRESENT | SE_DACL_PROTECTED | SE_DACL_AUTO_INHERITED,
+            &[
+                ace(0x00, 0x03, 0x001F_01FF, &sid(5, &[18])),
+                ace(0x01, 0x10, 0x0012_0089, &sid(5, &[21, 1, 2, 3, 1001])),
+                ace(0x00, 0x00, 0x0000_0003, &sid(1, &[0])),
+            ],
+        );
+
+        let decoded = decode_security_descriptor(&descriptor).unwrap();
+        assert_eq!(decoded.owner_sid.as_deref(), Some("S-1-5-32-544"));
+        assert_eq!(
+            decoded.sddl,
+            "O:BAG:SYD:PAI(A;OICI;FA;;;SY)(D;ID;FR;;;S-1-5-21-1-2-3-1001)(A;;DCCC;;;WD)"
+        );
+    }
+
+    #[test]
+    fn skips_sds_entries_that_do_not_match_their_offset() {
+        let descriptor = security_descriptor(0, &[]);
+        let mut sds = vec![0; 0x40];
+        let mut header = vec![0; SDS_ENTRY_HEADER_SIZE];
+        header[4..8].copy_from_slice(&0x100u32.to_le_bytes());
+        header[8..16].copy_from_slice(&0x40u64.to_le_bytes());
+        let length = (SDS_ENTRY_HEADER_SIZE + descriptor.len()) as u32;
+        header[16..20].copy_from_slice(&length.to_le_bytes());
+        sds.extend(&header);
+        sds.extend(&descriptor);
+
+        let (security_id, found) = sds_entry(&sds, 0x40).unwrap();
+        assert_eq!(security_id, 0x100);
+        assert_eq!(found, descriptor);
+        assert_eq!(decode_security_descriptor(found).unwrap().sddl, "O:BAG:SY");
+
+        // The same entry in the mirror copy, still claiming to be at 0x40.
+        let mirror = [vec![0; 0x40], sds].concat();
+        assert!(sds_entry(&mirror, 0x80).is_none());
+    }
+}
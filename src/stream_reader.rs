@@ -0,0 +1,357 @@
+use std::io::{self, Write};
+
+use crate::disk_image::ImageSlice;
+use crate::lznt1;
+use crate::ntfs_logic::DataRun;
+
+/// The only compression unit NTFS writes: 16 clusters (stored as the shift 4).
+pub const COMPRESSION_UNIT_CLUSTERS: u32 = 16;
+
+static ZERO_CHUNK: [u8; 64 * 1024] = [0; 64 * 1024];
+/// Runs are copied from the image this many bytes at a time.
+const COPY_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Counters describing how a non-resident stream was assembled from its data runs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StreamCopyStats {
+    /// Bytes copied from the image.
+    pub bytes_read: u64,
+    /// Bytes that were zero-filled because they were sparse or beyond the initialized size.
+    pub bytes_zero_filled: u64,
+    /// Bytes that were zero-filled because they could not be read from the image.
+    pub bytes_missing: u64,
+}
+
+pub fn write_zeros(writer: &mut impl Write, mut count: u64) -> io::Result<()> {
+    while count > 0 {
+        let n = count.min(ZERO_CHUNK.len() as u64) as usize;
+        writer.write_all(&ZERO_CHUNK[..n])?;
+        count -= n as u64;
+    }
+    Ok(())
+}
+
+/// Writes a non-resident stream by following its data runs.
+///
+/// Only `size` bytes are written, even though the runs cover the allocated size. Sparse
+/// runs and anything past `initialized_size` are written as zeros.
+pub fn copy_non_resident(
+    writer: &mut impl Write,
+    disk_image_buffer: ImageSlice<'_>,
+    cluster_size: u64,
+    runs: &[DataRun],
+    size: u64,
+    initialized_size: u64,
+) -> io::Result<StreamCopyStats> {
+    let mut stats = StreamCopyStats::default();
+    let valid_size = initialized_size.min(size);
+    let mut position: u64 = 0;
+
+    for run in runs {
+        if position >= size {
+            break;
+        }
+
+        let run_len = run
+            .cluster_count
+            .saturating_mul(cluster_size)
+            .min(size - position);
+        // Portion of this run that holds initialized data, the rest reads as zeros.
+        let data_len = valid_size.saturating_sub(position).min(run_len);
+
+        match run.cluster_offset {
+            Some(lcn) if data_len > 0 => {
+                let start = u64::try_from(lcn)
+                    .ok()
+                    .and_then(|lcn| lcn.checked_mul(cluster_size));
+                let available = start.map_or(0, |start| {
+                    (disk_image_buffer.len() as u64)
+                        .saturating_sub(start)
+                        .min(data_len)
+                });
+                if let Some(start) = start {
+                    let mut copied = 0;
+                    while copied < available {
+                        let length = (available - copied).min(COPY_CHUNK_SIZE);
+                        let chunk = disk_image_buffer
+                            .read(start + copied, length as usize)
+                            .unwrap_or_default();
+                        writer.write_all(&chunk)?;
+                        copied += length;
+                    }
+                    stats.bytes_read += available;
+                }
+                let missing = data_len - available;
+                write_zeros(writer, missing)?;
+                stats.bytes_missing += missing;
+            }
+            _ => {
+                // Sparse run.
+                write_zeros(writer, data_len)?;
+                stats.bytes_zero_filled += data_len;
+            }
+        }
+
+        write_zeros(writer, run_len - data_len)?;
+        stats.bytes_zero_filled += run_len - data_len;
+
+        position += run_len;
+    }
+
+    // Runs that don't cover the whole stream (e.g., the rest is in an extension record).
+    let missing = size - position.min(size);
+    write_zeros(writer, missing)?;
+    stats.bytes_missing += missing;
+
+    Ok(stats)
+}
+
+/// Reads a whole non-resident stream into memory. Meant for metadata streams, so callers
+/// should bound `size` first.
+pub fn read_non_resident(
+    disk_image_buffer: ImageSlice<'_>,
+    cluster_size: u64,
+    runs: &[DataRun],
+    size: u64,
+    initialized_size: u64,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(size as usize);
+    // Writing into a Vec can't fail.
+    let _ = copy_non_resident(
+        &mut data,
+        disk_image_buffer,
+        cluster_size,
+        runs,
+        size,
+        initialized_size,
+    );
+    data
+}
+
+/// Hands out the runs of a stream in pieces of a given number of clusters.
+struct RunCursor<'a> {
+    runs: std::slice::Iter<'a, DataRun>,
+    current: Option<(Option<i64>, u64)>,
+}
+
+impl<'a> RunCursor<'a> {
+    fn new(runs: &'a [DataRun]) -> Self {
+        Self {
+            runs: runs.iter(),
+            current: None,
+        }
+    }
+
+    /// The (LCN, cluster count) pieces covering the next `clusters` clusters. Fewer
+    /// clusters are returned once the runs are exhausted.
+    fn take(&mut self, clusters: u64) -> Vec<(Option<i64>, u64)> {
+        let mut pieces = Vec::new();
+        let mut needed = clusters;
+
+        while needed > 0 {
+            let (lcn, count) = match self.current.take() {
+                Some(current) => current,
+                None => match self.runs.next() {
+                    Some(run) => (run.cluster_offset, run.cluster_count),
+                    None => break,
+                },
+            };
+            let taken = count.min(needed);
+            pieces.push((lcn, taken));
+            needed -= taken;
+            if taken < count {
+                self.current = Some((lcn.map(|lcn| lcn + taken as i64), count - taken));
+            }
+        }
+
+        pieces
+    }
+}
+
+/// Appends `count` clusters starting at `lcn` to `out`. Returns how many of those bytes
+/// were beyond the end of the image (and zero-filled).
+fn read_clusters(
+    disk_image_buffer: ImageSlice<'_>,
+    cluster_size: u64,
+    lcn: i64,
+    count: u64,
+    out: &mut Vec<u8>,
+) -> u64 {
+    let len = count.saturating_mul(cluster_size);
+    let start = u64::try_from(lcn)
+        .ok()
+        .and_then(|lcn| lcn.checked_mul(cluster_size));
+    let available = start.map_or(0, |start| {
+        (disk_image_buffer.len() as u64)
+            .saturating_sub(start)
+            .min(len)
+    });
+
+    if let Some(start) = start
+        && available > 0
+    {
+        out.extend_from_slice(
+            &disk_image_buffer
+                .read(start, available as usize)
+                .unwrap_or_default(),
+        );
+    }
+    out.resize(out.len() + (len - available) as usize, 0);
+    len - available
+}
+
+/// Writes a compressed (LZNT1) non-resident stream, one compression unit at a time.
+///
+/// Each unit of `unit_clusters` clusters is stored in one of three ways: entirely sparse
+/// (all zeros), fully allocated (stored uncompressed), or partly allocated (the allocated
+/// clusters hold the compressed data, the rest of the unit is sparse).
+pub fn copy_compressed(
+    writer: &mut impl Write,
+    disk_image_buffer: ImageSlice<'_>,
+    cluster_size: u64,
+    runs: &[DataRun],
+    size: u64,
+    initialized_size: u64,
+    unit_clusters: u64,
+) -> io::Result<StreamCopyStats> {
+    let mut stats = StreamCopyStats::default();
+    let valid_size = initialized_size.min(size);
+    let unit_size = unit_clusters.saturating_mul(cluster_size);
+    let mut cursor = RunCursor::new(runs);
+    let mut position: u64 = 0;
+
+    while position < size {
+        let pieces = cursor.take(unit_clusters);
+        let unit_len = unit_size.min(size - position);
+        let covered: u64 = pieces.iter().map(|(_, count)| count).sum();
+        let allocated: u64 = pieces
+            .iter()
+            .filter(|(lcn, _)| lcn.is_some())
+            .map(|(_, count)| count)
+            .sum();
+
+        if allocated == 0 {
+            write_zeros(writer, unit_len)?;
+            if covered < unit_clusters {
+                // The runs end before the stream does.
+                stats.bytes_missing += unit_len;
+            } else {
+                stats.bytes_zero_filled += unit_len;
+            }
+            position += unit_len;
+            continue;
+        }
+
+        // Only a unit the runs fully cover can be compressed. Runs that end inside the unit
+        // (a truncated run list) are copied as they are, and the rest counts as missing.
+        let is_compressed = allocated < unit_clusters && covered == unit_clusters;
+
+        let mut raw = Vec::with_capacity((covered * cluster_size) as usize);
+        let mut raw_missing = 0;
+        for (lcn, count) in &pieces {
+            match lcn {
+                Some(lcn) => {
+                    raw_missing +=
+                        read_clusters(disk_image_buffer, cluster_size, *lcn, *count, &mut raw)
+                }
+                None if !is_compressed => {
+                    raw.resize(raw.len() + (count * cluster_size) as usize, 0);
+                }
+                None => {}
+            }
+        }
+
+        let data = if is_compressed {
+            lznt1::decompress(&raw, unit_size as usize)
+        } else {
+            raw
+        };
+
+        let data_len = valid_size.saturating_sub(position).min(unit_len);
+        let available = (data.len() as u64).min(data_len);
+        writer.write_all(&data[..available as usize])?;
+        write_zeros(writer, unit_len - available)?;
+
+        // A compressed unit with missing input can't be trusted at all.
+        let missing = if is_compressed && raw_missing > 0 {
+            data_len
+        } else {
+            raw_missing.min(available) + (data_len - available)
+        };
+        stats.bytes_read += data_len - missing;
+        stats.bytes_missing += missing;
+        stats.bytes_zero_filled += unit_len - data_len;
+
+        position += unit_len;
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLUSTER_SIZE: u64 = 512;
+
+    fn run(cluster_offset: Option<i64>, cluster_count: u64) -> DataRun {
+        DataRun {
+            cluster_offset,
+            cluster_count,
+        }
+    }
+
+    #[test]
+    fn copies_units_whose_runs_end_early_instead_of_decompressing_them() {
+        let mut image = vec![0u8; 8 * CLUSTER_SIZE as usize];
+        image[CLUSTER_SIZE as usize..3 * CLUSTER_SIZE as usize].fill(0xAB);
+        // Two allocated clusters, then the run list stops 14 clusters short of the unit.
+        let runs = [run(Some(1), 2)];
+        let size = 16 * CLUSTER_SIZE;
+
+        let mut out = Vec::new();
+        let stats = copy_compressed(
+            &mut out,
+            ImageSlice::new(&image),
+            CLUSTER_SIZE,
+            &runs,
+            size,
+            size,
+            16,
+        )
+        .unwrap();
+
+        assert_eq!(out.len() as u64, size);
+        assert!(out[..2 * CLUSTER_SIZE as usize].iter().all(|&b| b == 0xAB));
+        assert!(out[2 * CLUSTER_SIZE as usize..].iter().all(|&b| b == 0));
+        assert_eq!(stats.bytes_read, 2 * CLUSTER_SIZE);
+        assert_eq!(stats.bytes_missing, 14 * CLUSTER_SIZE);
+        assert_eq!(stats.bytes_zero_filled, 0);
+    }
+
+    #[test]
+    fn decompresses_units_padded_with_a_sparse_run() {
+        // A single uncompressed LZNT1 chunk: header 0x3FFF (4 KiB of literal data).
+        let mut image = vec![0u8; 16 * CLUSTER_SIZE as usize];
+        image[..2].copy_from_slice(&0x3FFFu16.to_le_bytes());
+        image[2..4098].fill(0x5A);
+        let runs = [run(Some(0), 9), run(None, 7)];
+        let size = 4096;
+
+        let mut out = Vec::new();
+        let stats = copy_compressed(
+            &mut out,
+            ImageSlice::new(&image),
+            CLUSTER_SIZE,
+            &runs,
+            size,
+            size,
+            16,
+        )
+        .unwrap();
+
+        assert_eq!(out, vec![0x5A; 4096]);
+        assert_eq!(stats.bytes_read, 4096);
+        assert_eq!(stats.bytes_missing, 0);
+    }
+}
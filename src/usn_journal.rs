@@ -0,0 +1,385 @@
+use chrono::{DateTime, Datelike, Utc};
+use serde::Serialize;
+
+use crate::boot_sector::VolumeGeometry;
+use crate::disk_image::ImageSlice;
+use crate::ntfs_logic::{FileAttributes, NtfsEntry, filetime_to_utc, scan_offsets};
+
+/// `$UsnJrnl` lives in the `$Extend` directory (MFT record 11).
+const USN_JOURNAL_NAME: &str = "$UsnJrnl";
+const USN_JOURNAL_STREAM: &str = "$J";
+const EXTEND_MFT_RECORD: u64 = 11;
+
+// Size of the fixed part of USN_RECORD_V2 and USN_RECORD_V3 (where the name starts).
+const USN_RECORD_V2_HEADER_SIZE: usize = 60;
+const USN_RECORD_V3_HEADER_SIZE: usize = 76;
+
+// A 255-character name plus the V3 header, rounded up.
+const MAX_USN_RECORD_SIZE: usize = 1024;
+/// The journal is parsed this many bytes at a time.
+const USN_WINDOW_SIZE: usize = 1024 * 1024;
+
+// Carved records with timestamps outside this range are rejected as garbage.
+const MIN_PLAUSIBLE_YEAR: i32 = 1990;
+const MAX_PLAUSIBLE_YEAR: i32 = 2100;
+
+/// Change reasons, in the order of their bits in `USN_RECORD.Reason`.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsnReason {
+    DataOverwrite,
+    DataExtend,
+    DataTruncation,
+    NamedDataOverwrite,
+    NamedDataExtend,
+    NamedDataTruncation,
+    FileCreate,
+    FileDelete,
+    EaChange,
+    SecurityChange,
+    RenameOldName,
+    RenameNewName,
+    IndexableChange,
+    BasicInfoChange,
+    HardLinkChange,
+    CompressionChange,
+    EncryptionChange,
+    ObjectIdChange,
+    ReparsePointChange,
+    StreamChange,
+    TransactedChange,
+    IntegrityChange,
+    DesiredStorageClassChange,
+    Close,
+}
+
+const USN_REASONS: [(u32, UsnReason); 24] = [
+    (0x0000_0001, UsnReason::DataOverwrite),
+    (0x0000_0002, UsnReason::DataExtend),
+    (0x0000_0004, UsnReason::DataTruncation),
+    (0x0000_0010, UsnReason::NamedDataOverwrite),
+    (0x0000_0020, UsnReason::NamedDataExtend),
+    (0x0000_0040, UsnReason::NamedDataTruncation),
+    (0x0000_0100, UsnReason::FileCreate),
+    (0x0000_0200, UsnReason::FileDelete),
+    (0x0000_0400, UsnReason::EaChange),
+    (0x0000_0800, UsnReason::SecurityChange),
+    (0x0000_1000, UsnReason::RenameOldName),
+    (0x0000_2000, UsnReason::RenameNewName),
+    (0x0000_4000, UsnReason::IndexableChange),
+    (0x0000_8000, UsnReason::BasicInfoChange),
+    (0x0001_0000, UsnReason::HardLinkChange),
+    (0x0002_0000, UsnReason::CompressionChange),
+    (0x0004_0000, UsnReason::EncryptionChange),
+    (0x0008_0000, UsnReason::ObjectIdChange),
+    (0x0010_0000, UsnReason::ReparsePointChange),
+    (0x0020_0000, UsnReason::StreamChange),
+    (0x0040_0000, UsnReason::TransactedChange),
+    (0x0080_0000, UsnReason::IntegrityChange),
+    (0x0100_0000, UsnReason::DesiredStorageClassChange),
+    (0x8000_0000, UsnReason::Close),
+];
+
+/// Flags in `USN_RECORD.SourceInfo`: changes made by the system rather than the user.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsnSourceInfo {
+    DataManagement,
+    AuxiliaryData,
+    ReplicationManagement,
+    ClientReplicationManagement,
+}
+
+const USN_SOURCE_INFO: [(u32, UsnSourceInfo); 4] = [
+    (0x1, UsnSourceInfo::DataManagement),
+    (0x2, UsnSourceInfo::AuxiliaryData),
+    (0x4, UsnSourceInfo::ReplicationManagement),
+    (0x8, UsnSourceInfo::ClientReplicationManagement),
+];
+
+/// Where a USN record was read from.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsnRecordSource {
+    /// The `$J` stream of `$Extend\$UsnJrnl`.
+    Journal,
+    /// Carved from anywhere in the image.
+    Carved,
+}
+
+/// One `USN_RECORD_V2`/`USN_RECORD_V3`. `usn` matches `NtfsEntry.usn` for the last change
+/// recorded in a file's `$STANDARD_INFORMATION`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename = "usn_record")]
+pub struct UsnRecord {
+    pub offset: u64, // Offset of the record in the image
+    pub source: UsnRecordSource,
+    pub major_version: u16,
+    pub usn: u64,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub mft_record: u64,
+    pub sequence: u16,
+    pub parent_mft_record: u64,
+    pub parent_sequence: u16,
+    pub filename: String,
+    pub reasons: Vec<UsnReason>,
+    pub source_info: Vec<UsnSourceInfo>,
+    pub security_id: u32,
+    pub file_attributes: FileAttributes,
+}
+
+fn decode_flags<T: Copy>(flags: u32, table: &[(u32, T)]) -> Vec<T> {
+    table
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, value)| *value)
+        .collect()
+}
+
+fn is_plausible_timestamp(timestamp: Option<DateTime<Utc>>) -> bool {
+    timestamp.is_some_and(|t| (MIN_PLAUSIBLE_YEAR..=MAX_PLAUSIBLE_YEAR).contains(&t.year()))
+}
+
+/// Parses the USN record at the start of `data`. Returns the record and its length.
+fn parse_usn_record(
+    data: &[u8],
+    offset: u64,
+    source: UsnRecordSource,
+) -> Option<(UsnRecord, usize)> {
+    let record_length = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+    let major_version = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?);
+    let minor_version = u16::from_le_bytes(data.get(6..8)?.try_into().ok()?);
+
+    // V3 records widen the file references to 128 bits; NTFS only uses the low 64.
+    let header_size = match (major_version, minor_version) {
+        (2, 0) => USN_RECORD_V2_HEADER_SIZE,
+        (3, 0) => USN_RECORD_V3_HEADER_SIZE,
+        _ => return None,
+    };
+    if record_length < header_size
+        || record_length > MAX_USN_RECORD_SIZE
+        || !record_length.is_multiple_of(8)
+    {
+        return None;
+    }
+    let record = data.get(..record_length)?;
+    let (reference_size, fields) = match major_version {
+        2 => (8, 24),
+        _ => (16, 40),
+    };
+
+    let file_reference = u64::from_le_bytes(record[8..16].try_into().ok()?);
+    let parent_reference = u64::from_le_bytes(
+        record[8 + reference_size..16 + reference_size]
+            .try_into()
+            .ok()?,
+    );
+    let usn = u64::from_le_bytes(record[fields..fields + 8].try_into().ok()?);
+    let timestamp = filetime_to_utc(u64::from_le_bytes(
+        record[fields + 8..fields + 16].try_into().ok()?,
+    ));
+    let reason = u32::from_le_bytes(record[fields + 16..fields + 20].try_into().ok()?);
+    let source_info = u32::from_le_bytes(record[fields + 20..fields + 24].try_into().ok()?);
+    let security_id = u32::from_le_bytes(record[fields + 24..fields + 28].try_into().ok()?);
+    let file_attributes = u32::from_le_bytes(record[fields + 28..fields + 32].try_into().ok()?);
+    let name_length =
+        u16::from_le_bytes(record[fields + 32..fields + 34].try_into().ok()?) as usize;
+    let name_offset =
+        u16::from_le_bytes(record[fields + 34..fields + 36].try_into().ok()?) as usize;
+
+    if name_offset != header_size
+        || name_length == 0
+        || !name_length.is_multiple_of(2)
+        || name_offset + name_length > record_length
+        || reason == 0
+        || !is_plausible_timestamp(timestamp)
+    {
+        return None;
+    }
+
+    let name_utf16: Vec<u16> = record[name_offset..name_offset + name_length]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    let filename = String::from_utf16(&name_utf16).ok()?;
+
+    let usn_record = UsnRecord {
+        offset,
+        source,
+        major_version,
+        usn,
+        timestamp,
+        mft_record: file_reference & 0x0000_FFFF_FFFF_FFFF,
+        sequence: (file_reference >> 48) as u16,
+        parent_mft_record: parent_reference & 0x0000_FFFF_FFFF_FFFF,
+        parent_sequence: (parent_reference >> 48) as u16,
+        filename,
+        reasons: decode_flags(reason, &USN_REASONS),
+        source_info: decode_flags(source_info, &USN_SOURCE_INFO),
+        security_id,
+        file_attributes: FileAttributes::from_flags(file_attributes),
+    };
+    Some((usn_record, record_length))
+}
+
+/// Parses the records of a contiguous piece of the journal that starts at image offset
+/// `offset`. Records are 8-byte aligned, and the journal pads its pages with zeros, so
+/// anything unparsable is skipped 8 bytes at a time.
+///
+/// The piece is read a window at a time; each window overlaps the next by the largest
+/// record, so that records starting near its end are whole.
+fn parse_usn_records(data: ImageSlice<'_>, offset: u64, records: &mut Vec<UsnRecord>) {
+    let mut position = 0;
+    while position + USN_RECORD_V2_HEADER_SIZE <= data.len() {
+        let Some(window) = data.get_clipped(position, USN_WINDOW_SIZE + MAX_USN_RECORD_SIZE) else {
+            break;
+        };
+        let mut in_window = 0;
+        while in_window < USN_WINDOW_SIZE && in_window + USN_RECORD_V2_HEADER_SIZE <= window.len() {
+            match parse_usn_record(
+                &window[in_window..],
+                offset + (position + in_window) as u64,
+                UsnRecordSource::Journal,
+            ) {
+                Some((record, length)) => {
+                    records.push(record);
+                    in_window += length;
+                }
+                None => in_window += 8,
+            }
+        }
+        position += in_window;
+    }
+}
+
+/// Finds `$Extend\$UsnJrnl:$J` among the scanned entries and parses its records. Returns
+/// `None` if the journal wasn't found.
+///
+/// `$J` is a sparse stream (old records are deallocated from its start), so only the
+/// allocated runs are read, straight from the image.
+pub fn read_usn_journal(
+    disk_image_buffer: ImageSlice<'_>,
+    geometry: &VolumeGeometry,
+    entries: &[NtfsEntry],
+) -> Option<Vec<UsnRecord>> {
+    let journal_stream = entries
+        .iter()
+        .filter(|e| {
+            e.is_in_use
+                && e.filename == USN_JOURNAL_NAME
+                && e.parent_mft_record == EXTEND_MFT_RECORD
+        })
+        .flat_map(|e| &e.data_streams)
+        .find(|s| !s.resident && s.name.as_deref() == Some(USN_JOURNAL_STREAM))?;
+
+    let cluster_size = geometry.cluster_size;
+    let mut records = Vec::new();
+    let mut position: u64 = 0;
+
+    for run in journal_stream.data_runs.iter().flatten() {
+        if position >= journal_stream.size {
+            break;
+        }
+        let run_len = run
+            .cluster_count
+            .saturating_mul(cluster_size)
+            .min(journal_stream.size - position);
+        position += run_len;
+
+        let Some(start) = run
+            .cluster_offset
+            .and_then(|lcn| u64::try_from(lcn).ok())
+            .and_then(|lcn| lcn.checked_mul(cluster_size))
+        else {
+            continue;
+        };
+        if start >= disk_image_buffer.len() as u64 {
+            continue;
+        }
+        parse_usn_records(disk_image_buffer.slice(start, run_len), start, &mut records);
+    }
+
+    Some(records)
+}
+
+/// Carves USN records from the whole image. Meant as a fallback for when the journal's
+/// MFT record is gone, as it costs a second pass over the image.
+pub fn carve_usn_records(disk_image_buffer: ImageSlice<'_>) -> Vec<UsnRecord> {
+    scan_offsets(disk_image_buffer)
+        .filter(|(_, start)| matches!(start[4..8], [2 | 3, 0, 0, 0]))
+        .filter_map(|(i, _)| {
+            let data = disk_image_buffer.get_clipped(i, MAX_USN_RECORD_SIZE)?;
+            parse_usn_record(&data, i as u64, UsnRecordSource::Carved)
+        })
+        .map(|(record, _)| record)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMESTAMP: u64 = 132_000_000_001_234_567;
+
+    /// A USN record of `major_version` 2 or 3 for `name` (record 40 in directory 5).
+    fn usn_record(major_version: u16, usn: u64, reason: u32, name: &str) -> Vec<u8> {
+        let (header_size, reference_size, fields) = match major_version {
+            2 => (USN_RECORD_V2_HEADER_SIZE, 8, 24),
+            _ => (USN_RECORD_V3_HEADER_SIZE, 16, 40),
+        };
+        let name: Vec<u8> = name.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let length = (header_size + name.len()).next_multiple_of(8);
+
+        let mut record = vec![0; length];
+        record[0..4].copy_from_slice(&(length as u32).to_le_bytes());
+        record[4..6].copy_from_slice(&major_version.to_le_bytes());
+        record[8..16].copy_from_slice(&(40u64 | 2 << 48).to_le_bytes());
+        let parent = 8 + reference_size;
+        record[parent..parent + 8].copy_from_slice(&(5u64 | 5 << 48).to_le_bytes());
+        record[fields..fields + 8].copy_from_slice(&usn.to_le_bytes());
+        record[fields + 8..fields + 16].copy_from_slice(&TIMESTAMP.to_le_bytes());
+        record[fields + 16..fields + 20].copy_from_slice(&reason.to_le_bytes());
+        record[fields + 20..fields + 24].copy_from_slice(&2u32.to_le_bytes());
+        record[fields + 32..fields + 34].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        record[fields + 34..fields + 36].copy_from_slice(&(header_size as u16).to_le_bytes());
+        record[header_size..header_size + name.len()].copy_from_slice(&name);
+        record
+    }
+
+    #[test]
+    fn parses_v2_and_v3_records_between_padding() {
+        let mut journal = usn_record(2, 0x1000, 0x0000_0100, "a.txt");
+        journal.extend([0; 64]); // Page padding
+        journal.extend(usn_record(3, 0x1050, 0x8000_0200, "b.txt"));
+
+        let mut records = Vec::new();
+        parse_usn_records(ImageSlice::new(&journal), 0x10000, &mut records);
+        assert_eq!(records.len(), 2);
+
+        let (v2, v3) = (&records[0], &records[1]);
+        assert_eq!(v2.major_version, 2);
+        assert_eq!((v2.mft_record, v2.sequence), (40, 2));
+        assert_eq!((v2.parent_mft_record, v2.parent_sequence), (5, 5));
+        assert_eq!(v2.usn, 0x1000);
+        assert_eq!(v2.timestamp, filetime_to_utc(TIMESTAMP));
+        assert_eq!(v2.reasons, [UsnReason::FileCreate]);
+        assert_eq!(v2.source_info, [UsnSourceInfo::AuxiliaryData]);
+        assert_eq!(v2.filename, "a.txt");
+
+        assert_eq!(v3.major_version, 3);
+        assert_eq!(v3.offset, 0x10000 + 72 + 64);
+        assert_eq!((v3.mft_record, v3.parent_mft_record), (40, 5));
+        assert_eq!(v3.reasons, [UsnReason::FileDelete, UsnReason::Close]);
+        assert_eq!(v3.filename, "b.txt");
+    }
+
+    #[test]
+    fn rejects_records_without_a_reason_or_with_a_bad_name_offset() {
+        let record = usn_record(2, 0x1000, 0, "a.txt");
+        assert!(parse_usn_record(&record, 0, UsnRecordSource::Carved).is_none());
+
+        let mut record = usn_record(2, 0x1000, 0x0000_0100, "a.txt");
+        record[58..60].copy_from_slice(&64u16.to_le_bytes());
+        assert!(parse_usn_record(&record, 0, UsnRecordSource::Carved).is_none());
+    }
+}
@@ -0,0 +1,372 @@
+use anyhow::{Result, bail};
+use log::{info, warn};
+use memmap2::Mmap;
+use std::path::Path;
+
+use crate::disk_image::{
+    DiskImage, MAX_PARENT_DEPTH, MappedMedia, Media, clip_to_size, copy_from, map_file,
+    read_blocks, read_parent, resolve_parent,
+};
+
+// VHD (Virtual PC and Hyper-V before VHDX). All fields are big-endian.
+pub const VHD_COOKIE: &[u8; 8] = b"conectix";
+const DYNAMIC_HEADER_COOKIE: &[u8; 8] = b"cxsparse";
+const FOOTER_SIZE: usize = 512;
+const FOOTER_CHECKSUM_OFFSET: usize = 64;
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+const DYNAMIC_HEADER_CHECKSUM_OFFSET: usize = 36;
+const SECTOR_SIZE: usize = 512;
+const UNALLOCATED_BLOCK: u32 = 0xFFFF_FFFF;
+
+// The parent's name (UTF-16BE), then eight locators: platform code, data space, data length,
+// reserved and data offset.
+const PARENT_NAME: std::ops::Range<usize> = 64..576;
+const PARENT_LOCATORS_OFFSET: usize = 576;
+const PARENT_LOCATOR_SIZE: usize = 24;
+const PARENT_LOCATOR_COUNT: usize = 8;
+// Relative and absolute Windows paths, in UTF-16LE.
+const PLATFORM_CODES: [&[u8; 4]; 2] = [b"W2ru", b"W2ku"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiskType {
+    Fixed,
+    Dynamic,
+    Differencing,
+}
+
+struct Vhd {
+    file: Mmap,
+    disk_type: DiskType,
+    size: u64,
+    bat_offset: u64,
+    block_count: u32,
+    block_size: u64,
+    parent_hints: Vec<String>,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// One's complement of the sum of the bytes, leaving out the checksum field.
+fn is_checksum_valid(data: &[u8], checksum_offset: usize) -> bool {
+    let sum = data
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(checksum_offset..checksum_offset + 4).contains(i))
+        .fold(0u32, |sum, (_, &b)| sum.wrapping_add(b as u32));
+    read_u32(data, checksum_offset) == Some(!sum)
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| from_bytes([c[0], c[1]]))
+        .take_while(|&u| u != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// The paths to the parent stored in a differencing disk's header, most specific first.
+fn parent_hints(file: &[u8], header: &[u8]) -> Vec<String> {
+    let mut hints = Vec::new();
+    for code in PLATFORM_CODES {
+        for i in 0..PARENT_LOCATOR_COUNT {
+            let locator = PARENT_LOCATORS_OFFSET + i * PARENT_LOCATOR_SIZE;
+            if header.get(locator..locator + 4) != Some(code) {
+                continue;
+            }
+            let length = read_u32(header, locator + 8).unwrap_or_default() as usize;
+            let offset = read_u64(header, locator + 16).unwrap_or_default() as usize;
+            if let Some(data) = file.get(offset..offset.saturating_add(length)) {
+                hints.push(decode_utf16(data, u16::from_le_bytes));
+            }
+        }
+    }
+    hints.push(decode_utf16(&header[PARENT_NAME], u16::from_be_bytes));
+    hints.retain(|hint| !hint.is_empty());
+    hints
+}
+
+impl Vhd {
+    fn open(path: &Path) -> Result<Self> {
+        let file = map_file(path)?;
+        if file.len() < FOOTER_SIZE {
+            bail!("{path:?} is too small to be a VHD.");
+        }
+
+        // Dynamic disks keep a copy of the footer at the start, in case the end is damaged.
+        let footer_offset = file.len() - FOOTER_SIZE;
+        let footer = if file[footer_offset..].starts_with(VHD_COOKIE) {
+            &file[footer_offset..]
+        } else if file.starts_with(VHD_COOKIE) {
+            warn!("The VHD footer of {path:?} is missing. Using its copy at the start.");
+            &file[..FOOTER_SIZE]
+        } else {
+            bail!("{path:?} has no VHD footer.");
+        };
+        if !is_checksum_valid(footer, FOOTER_CHECKSUM_OFFSET) {
+            warn!("The VHD footer of {path:?} doesn't match its checksum.");
+        }
+
+        let disk_type = match read_u32(footer, 60) {
+            Some(2) => DiskType::Fixed,
+            Some(3) => DiskType::Dynamic,
+            Some(4) => DiskType::Differencing,
+            other => bail!("{path:?} has an unknown VHD disk type ({other:?})."),
+        };
+        let size = read_u64(footer, 48).unwrap_or_default();
+        let header_offset = read_u64(footer, 16).unwrap_or(u64::MAX) as usize;
+        let mut vhd = Self {
+            disk_type,
+            size,
+            bat_offset: 0,
+            block_count: 0,
+            block_size: 0,
+            parent_hints: Vec::new(),
+            file,
+        };
+        if disk_type == DiskType::Fixed {
+            return Ok(vhd);
+        }
+
+        let Some(header) = vhd
+            .file
+            .get(header_offset..header_offset.saturating_add(DYNAMIC_HEADER_SIZE))
+            .filter(|h| h.starts_with(DYNAMIC_HEADER_COOKIE))
+        else {
+            bail!("The VHD dynamic disk header of {path:?} is missing.");
+        };
+        if !is_checksum_valid(header, DYNAMIC_HEADER_CHECKSUM_OFFSET) {
+            warn!("The VHD dynamic disk header of {path:?} doesn't match its checksum.");
+        }
+
+        vhd.bat_offset = read_u64(header, 16).unwrap_or_default();
+        vhd.block_count = read_u32(header, 28).unwrap_or_default();
+        vhd.block_size = read_u32(header, 32).unwrap_or_default() as u64;
+        if vhd.block_size == 0 || !vhd.block_size.is_multiple_of(SECTOR_SIZE as u64) {
+            bail!(
+                "{path:?} has an invalid VHD block size ({}).",
+                vhd.block_size
+            );
+        }
+        if disk_type == DiskType::Differencing {
+            vhd.parent_hints = parent_hints(&vhd.file, header);
+        }
+        Ok(vhd)
+    }
+
+    /// The block allocation table entry of `block`: the sector its bitmap starts at.
+    fn block_sector(&self, block: u64) -> Option<u64> {
+        if block >= self.block_count as u64 {
+            return None;
+        }
+        let offset = usize::try_from(self.bat_offset.checked_add(block * 4)?).ok()?;
+        let entry = read_u32(self.file.get(offset..)?, 0)?;
+        (entry != UNALLOCATED_BLOCK).then_some(entry as u64)
+    }
+
+    /// Size of the bitmap of the sectors a block holds, which starts the block, padded to a
+    /// sector.
+    fn bitmap_size(&self) -> u64 {
+        let sectors_per_block = self.block_size / SECTOR_SIZE as u64;
+        sectors_per_block
+            .div_ceil(8)
+            .next_multiple_of(SECTOR_SIZE as u64)
+    }
+}
+
+/// The media of a dynamic or differencing VHD, read through its block allocation table.
+struct VhdMedia {
+    vhd: Vhd,
+    parent: Option<Box<dyn Media>>,
+}
+
+impl VhdMedia {
+    fn read_block(&self, block: u64, in_block: u64, out: &mut [u8]) {
+        let block_offset = block * self.vhd.block_size;
+        let parent = self.parent.as_deref();
+        let Some(bitmap_sector) = self.vhd.block_sector(block) else {
+            // The parent's, or zeros.
+            read_parent(parent, block_offset + in_block, out);
+            return;
+        };
+
+        let bitmap_offset = (bitmap_sector * SECTOR_SIZE as u64) as usize;
+        let bitmap_size = self.vhd.bitmap_size() as usize;
+        let bitmap = self
+            .vhd
+            .file
+            .get(bitmap_offset..bitmap_offset.saturating_add(bitmap_size))
+            .unwrap_or_default();
+        let data = self
+            .vhd
+            .file
+            .get(bitmap_offset.saturating_add(bitmap_size)..)
+            .unwrap_or_default();
+        let data = &data[..data.len().min(self.vhd.block_size as usize)];
+
+        if self.vhd.disk_type == DiskType::Dynamic {
+            copy_from(data, in_block, out);
+            return;
+        }
+        // Sectors whose bit is clear are the parent's.
+        read_blocks(
+            in_block,
+            out,
+            SECTOR_SIZE as u64,
+            |sector, in_sector, out| {
+                let bit = bitmap.get(sector as usize / 8).copied().unwrap_or_default();
+                let offset = sector * SECTOR_SIZE as u64 + in_sector;
+                if bit & (0x80 >> (sector % 8)) != 0 {
+                    copy_from(data, offset, out);
+                } else {
+                    read_parent(parent, block_offset + offset, out);
+                }
+            },
+        );
+    }
+}
+
+impl Media for VhdMedia {
+    fn size(&self) -> u64 {
+        self.vhd.size
+    }
+
+    fn read_at(&self, offset: u64, out: &mut [u8]) {
+        let out = clip_to_size(self.vhd.size, offset, out);
+        read_blocks(offset, out, self.vhd.block_size, |block, in_block, out| {
+            self.read_block(block, in_block, out)
+        });
+    }
+}
+
+/// Opens the media of a VHD, and of its chain of parents if it is a differencing disk.
+fn open_media(path: &Path, depth: usize) -> Result<Box<dyn Media>> {
+    let vhd = Vhd::open(path)?;
+    info!("{path:?}: {:?} VHD of {} bytes.", vhd.disk_type, vhd.size);
+    if vhd.disk_type == DiskType::Fixed {
+        let end = (vhd.file.len() - FOOTER_SIZE).min(vhd.size as usize);
+        return Ok(Box::new(MappedMedia::new(vhd.file, 0..end)));
+    }
+
+    let mut parent = None;
+    if vhd.disk_type == DiskType::Differencing {
+        if depth >= MAX_PARENT_DEPTH {
+            bail!("The VHD differencing chain of {path:?} is too long.");
+        }
+        match resolve_parent(path, &vhd.parent_hints) {
+            Some(parent_path) => {
+                info!("Reading the parent of {path:?}: {parent_path:?}.");
+                let media = open_media(&parent_path, depth + 1)?;
+                if media.size() != vhd.size {
+                    warn!("{parent_path:?} isn't the same size as its child {path:?}.");
+                }
+                parent = Some(media);
+            }
+            None => warn!(
+                "The parent of {path:?} ({:?}) wasn't found. The blocks it holds will read \
+                 as zeros.",
+                vhd.parent_hints
+            ),
+        }
+    }
+
+    let mut allocated_blocks = 0;
+    for block in 0..vhd.block_count as u64 {
+        let Some(bitmap_sector) = vhd.block_sector(block) else {
+            continue;
+        };
+        let bitmap_end = (bitmap_sector * SECTOR_SIZE as u64).saturating_add(vhd.bitmap_size());
+        if bitmap_end > vhd.file.len() as u64 {
+            warn!("Block {block} of {path:?} is past the end of the file.");
+            continue;
+        }
+        allocated_blocks += 1;
+    }
+    info!(
+        "{path:?}: {allocated_blocks} of {} blocks of {} bytes allocated.",
+        vhd.block_count, vhd.block_size
+    );
+    Ok(Box::new(VhdMedia { vhd, parent }))
+}
+
+/// Reads a fixed, dynamic or differencing VHD. The media of a fixed disk is mapped as is;
+/// the others are read through their block allocation table, with unallocated blocks
+/// reading as zeros.
+pub fn read_vhd(path: &Path) -> Result<DiskImage> {
+    Ok(DiskImage::new(open_media(path, 0)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_image::tests::{media, temp_file};
+
+    fn set_checksum(data: &mut [u8], checksum_offset: usize) {
+        let sum = data.iter().fold(0u32, |sum, &b| sum.wrapping_add(b as u32));
+        data[checksum_offset..checksum_offset + 4].copy_from_slice(&(!sum).to_be_bytes());
+    }
+
+    fn footer(disk_type: u32, size: u64) -> Vec<u8> {
+        let mut footer = vec![0; FOOTER_SIZE];
+        footer[..8].copy_from_slice(VHD_COOKIE);
+        footer[16..24].copy_from_slice(&(FOOTER_SIZE as u64).to_be_bytes());
+        footer[48..56].copy_from_slice(&size.to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+        set_checksum(&mut footer, FOOTER_CHECKSUM_OFFSET);
+        footer
+    }
+
+    fn read(name: &str, file: &[u8]) -> Vec<u8> {
+        let path = temp_file(name, file);
+        let media = media(&read_vhd(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        media
+    }
+
+    #[test]
+    fn maps_a_fixed_disk() {
+        let mut file = vec![0xAB; 4096];
+        file.extend_from_slice(&footer(2, 4096));
+
+        assert_eq!(read("fixed.vhd", &file), vec![0xAB; 4096]);
+    }
+
+    #[test]
+    fn reads_the_allocated_blocks_of_a_dynamic_disk() {
+        // Footer copy, dynamic header, BAT (sector 3), then block 1 at sector 4: its sector
+        // bitmap, then 4096 bytes of data.
+        let mut file = footer(3, 3 * 4096);
+        let mut header = vec![0; DYNAMIC_HEADER_SIZE];
+        header[..8].copy_from_slice(DYNAMIC_HEADER_COOKIE);
+        header[16..24].copy_from_slice(&1536u64.to_be_bytes());
+        header[28..32].copy_from_slice(&3u32.to_be_bytes());
+        header[32..36].copy_from_slice(&4096u32.to_be_bytes());
+        set_checksum(&mut header, DYNAMIC_HEADER_CHECKSUM_OFFSET);
+        file.extend_from_slice(&header);
+
+        let mut bat = vec![0; SECTOR_SIZE];
+        for (i, entry) in [UNALLOCATED_BLOCK, 4, UNALLOCATED_BLOCK].iter().enumerate() {
+            bat[i * 4..i * 4 + 4].copy_from_slice(&entry.to_be_bytes());
+        }
+        file.extend_from_slice(&bat);
+        file.extend_from_slice(&[0xFF; SECTOR_SIZE]);
+        file.extend_from_slice(&[0xCD; 4096]);
+        file.extend_from_slice(&footer(3, 3 * 4096));
+
+        let media = read("dynamic.vhd", &file);
+        assert_eq!(media.len(), 3 * 4096);
+        assert!(media[..4096].iter().all(|&b| b == 0));
+        assert!(media[4096..8192].iter().all(|&b| b == 0xCD));
+        assert!(media[8192..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,514 @@
+use anyhow::{Result, bail};
+use log::{info, warn};
+use memmap2::Mmap;
+use std::path::Path;
+
+use crate::disk_image::{
+    DiskImage, MAX_PARENT_DEPTH, Media, clip_to_size, copy_from, map_file, read_blocks,
+    read_parent, resolve_parent,
+};
+use crate::partitions::format_guid;
+
+// VHDX (Hyper-V). All fields are little-endian.
+pub const VHDX_SIGNATURE: &[u8; 8] = b"vhdxfile";
+const HEADER_SIGNATURE: &[u8; 4] = b"head";
+const REGION_TABLE_SIGNATURE: &[u8; 4] = b"regi";
+const METADATA_SIGNATURE: &[u8; 8] = b"metadata";
+// Two copies of each, the one with the highest sequence number being current.
+const HEADER_OFFSETS: [usize; 2] = [64 * 1024, 128 * 1024];
+const HEADER_SIZE: usize = 4 * 1024;
+const REGION_TABLE_OFFSETS: [usize; 2] = [192 * 1024, 256 * 1024];
+const REGION_TABLE_SIZE: usize = 64 * 1024;
+const CHECKSUM_OFFSET: usize = 4;
+
+const BAT_REGION: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08";
+const METADATA_REGION: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E";
+const FILE_PARAMETERS: &str = "CAA16737-FA36-4D43-B3B6-33F0AA44E76B";
+const VIRTUAL_DISK_SIZE: &str = "2FA54224-CD1B-4876-B211-5DBED83BF4B8";
+const LOGICAL_SECTOR_SIZE: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F";
+const PARENT_LOCATOR: &str = "A8D35F2D-B30B-454D-ABF7-D3D84834AB0C";
+const HAS_PARENT: u32 = 0x2;
+// Parent locator keys, most specific first.
+const PARENT_PATH_KEYS: [&str; 3] = ["relative_path", "absolute_win32_path", "volume_path"];
+
+// Payload block states in the BAT; the file offset is in the bits above 20.
+const PAYLOAD_BLOCK_NOT_PRESENT: u64 = 0;
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_BLOCK_PARTIALLY_PRESENT: u64 = 7;
+const SB_BLOCK_PRESENT: u64 = 6;
+const BAT_STATE_MASK: u64 = 0x7;
+const BAT_OFFSET_MASK: u64 = !0xF_FFFF;
+// A sector bitmap block covers this many sectors (1 MiB of bits).
+const SECTORS_PER_BITMAP: u64 = 1 << 23;
+
+struct Vhdx {
+    file: Mmap,
+    size: u64,
+    block_size: u64,
+    logical_sector_size: u64,
+    bat: Vec<u64>,
+    parent_hints: Option<Vec<String>>,
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        buf.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// CRC-32C (Castagnoli) of `data`, with the checksum field taken as zero.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for (i, &byte) in data.iter().enumerate() {
+        let byte = if (CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4).contains(&i) {
+            0
+        } else {
+            byte
+        };
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// The copy of a structure at `offsets` with a valid signature and checksum, and the highest
+/// `sequence` among them.
+fn current_copy<'a>(
+    file: &'a [u8],
+    offsets: [usize; 2],
+    size: usize,
+    signature: &[u8],
+    sequence: impl Fn(&[u8]) -> u64,
+) -> Option<&'a [u8]> {
+    offsets
+        .into_iter()
+        .filter_map(|offset| file.get(offset..offset + size))
+        .filter(|copy| copy.starts_with(signature))
+        .filter(|copy| read_u32(copy, CHECKSUM_OFFSET) == Some(crc32c(copy)))
+        .max_by_key(|copy| sequence(copy))
+}
+
+fn decode_utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// The paths stored in the parent locator metadata item.
+fn parse_parent_locator(locator: &[u8]) -> Vec<String> {
+    let count = read_u16(locator, 18).unwrap_or_default() as usize;
+    let mut entries = Vec::new();
+    for i in 0..count {
+        let entry = 20 + i * 12;
+        let (Some(key_offset), Some(value_offset), Some(key_length), Some(value_length)) = (
+            read_u32(locator, entry),
+            read_u32(locator, entry + 4),
+            read_u16(locator, entry + 8),
+            read_u16(locator, entry + 10),
+        ) else {
+            break;
+        };
+        let text = |offset: u32, length: u16| {
+            let start = offset as usize;
+            locator
+                .get(start..start + length as usize)
+                .map(decode_utf16)
+        };
+        if let (Some(key), Some(value)) = (
+            text(key_offset, key_length),
+            text(value_offset, value_length),
+        ) {
+            entries.push((key, value));
+        }
+    }
+
+    PARENT_PATH_KEYS
+        .iter()
+        .filter_map(|key| entries.iter().find(|(k, _)| k == key))
+        .map(|(_, value)| value.clone())
+        .collect()
+}
+
+impl Vhdx {
+    fn open(path: &Path) -> Result<Self> {
+        let file = map_file(path)?;
+        if !file.starts_with(VHDX_SIGNATURE) {
+            bail!("{path:?} is not a VHDX file.");
+        }
+
+        let Some(header) =
+            current_copy(&file, HEADER_OFFSETS, HEADER_SIZE, HEADER_SIGNATURE, |h| {
+                read_u64(h, 8).unwrap_or_default()
+            })
+        else {
+            bail!("{path:?} has no valid VHDX header.");
+        };
+        if header[48..64].iter().any(|&b| b != 0) {
+            warn!(
+                "{path:?} has a log that wasn't replayed. Its last writes may be missing \
+                 (open it once in Hyper-V to replay them)."
+            );
+        }
+
+        let Some(region_table) = current_copy(
+            &file,
+            REGION_TABLE_OFFSETS,
+            REGION_TABLE_SIZE,
+            REGION_TABLE_SIGNATURE,
+            |_| 0,
+        ) else {
+            bail!("{path:?} has no valid VHDX region table.");
+        };
+        let region_count = read_u32(region_table, 8).unwrap_or_default() as usize;
+        let regions: Vec<(String, &[u8])> = (0..region_count.min(2047))
+            .filter_map(|i| {
+                let entry = region_table.get(16 + i * 32..16 + (i + 1) * 32)?;
+                let offset = read_u64(entry, 16)? as usize;
+                let length = read_u32(entry, 24)? as usize;
+                Some((
+                    format_guid(&entry[..16]),
+                    file.get(offset..offset.checked_add(length)?)?,
+                ))
+            })
+            .collect();
+        let region = |guid: &str| regions.iter().find(|(g, _)| g == guid).map(|(_, r)| *r);
+        let (Some(bat_region), Some(metadata)) = (region(BAT_REGION), region(METADATA_REGION))
+        else {
+            bail!("The BAT or metadata region of {path:?} is missing.");
+        };
+
+        if !metadata.starts_with(METADATA_SIGNATURE) {
+            bail!("{path:?} has an invalid VHDX metadata table.");
+        }
+        let item_count = read_u16(metadata, 10).unwrap_or_default() as usize;
+        let items: Vec<(String, &[u8])> = (0..item_count)
+            .filter_map(|i| {
+                let entry = metadata.get(32 + i * 32..32 + (i + 1) * 32)?;
+                let offset = read_u32(entry, 16)? as usize;
+                let length = read_u32(entry, 20)? as usize;
+                Some((
+                    format_guid(&entry[..16]),
+                    metadata.get(offset..offset.checked_add(length)?)?,
+                ))
+            })
+            .collect();
+        let item = |guid: &str| items.iter().find(|(g, _)| g == guid).map(|(_, i)| *i);
+
+        let file_parameters = item(FILE_PARAMETERS).unwrap_or_default();
+        let block_size = read_u32(file_parameters, 0).unwrap_or_default() as u64;
+        let flags = read_u32(file_parameters, 4).unwrap_or_default();
+        let size = item(VIRTUAL_DISK_SIZE)
+            .and_then(|i| read_u64(i, 0))
+            .unwrap_or_default();
+        let logical_sector_size = item(LOGICAL_SECTOR_SIZE)
+            .and_then(|i| read_u32(i, 0))
+            .unwrap_or_default() as u64;
+        if block_size == 0 || size == 0 || logical_sector_size == 0 {
+            bail!("{path:?} is missing required VHDX metadata.");
+        }
+        if !(SECTORS_PER_BITMAP * logical_sector_size).is_multiple_of(block_size) {
+            bail!("{path:?} has an invalid VHDX block size ({block_size}).");
+        }
+
+        let bat = bat_region
+            .chunks_exact(8)
+            .map(|e| u64::from_le_bytes(e.try_into().unwrap()))
+            .collect();
+        let parent_hints = (flags & HAS_PARENT != 0).then(|| {
+            item(PARENT_LOCATOR)
+                .map(parse_parent_locator)
+                .unwrap_or_default()
+        });
+
+        Ok(Self {
+            file,
+            size,
+            block_size,
+            logical_sector_size,
+            bat,
+            parent_hints,
+        })
+    }
+
+    /// Number of payload blocks in each chunk, which is followed by the BAT entry of its
+    /// sector bitmap block.
+    fn chunk_ratio(&self) -> u64 {
+        SECTORS_PER_BITMAP * self.logical_sector_size / self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.size.div_ceil(self.block_size)
+    }
+
+    fn payload_entry(&self, block: u64) -> u64 {
+        let entry_index = block + block / self.chunk_ratio();
+        self.bat
+            .get(entry_index as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The sector bitmap covering `block`, if its block is present.
+    fn sector_bitmap(&self, block: u64) -> Option<&[u8]> {
+        let chunk_ratio = self.chunk_ratio();
+        let chunk = block / chunk_ratio;
+        let bitmap_index = ((chunk + 1) * (chunk_ratio + 1) - 1) as usize;
+        let bitmap_entry = self.bat.get(bitmap_index).copied().unwrap_or_default();
+        if bitmap_entry & BAT_STATE_MASK != SB_BLOCK_PRESENT {
+            return None;
+        }
+        Some(
+            self.file
+                .get((bitmap_entry & BAT_OFFSET_MASK) as usize..)
+                .unwrap_or_default(),
+        )
+    }
+}
+
+/// The media of a VHDX, read through its block allocation table.
+struct VhdxMedia {
+    vhdx: Vhdx,
+    parent: Option<Box<dyn Media>>,
+}
+
+impl VhdxMedia {
+    fn read_block(&self, block: u64, in_block: u64, out: &mut [u8]) {
+        let vhdx = &self.vhdx;
+        let entry = vhdx.payload_entry(block);
+        let block_offset = block * vhdx.block_size;
+        let parent = self.parent.as_deref();
+        let data = vhdx
+            .file
+            .get((entry & BAT_OFFSET_MASK) as usize..)
+            .map(|d| &d[..d.len().min(vhdx.block_size as usize)])
+            .unwrap_or_default();
+
+        match entry & BAT_STATE_MASK {
+            PAYLOAD_BLOCK_FULLY_PRESENT => copy_from(data, in_block, out),
+            PAYLOAD_BLOCK_PARTIALLY_PRESENT => {
+                let Some(bitmap) = vhdx.sector_bitmap(block) else {
+                    read_parent(parent, block_offset + in_block, out);
+                    return;
+                };
+                let sectors_per_block = vhdx.block_size / vhdx.logical_sector_size;
+                let first_sector = (block % vhdx.chunk_ratio()) * sectors_per_block;
+                // Sectors whose bit is clear are the parent's.
+                read_blocks(
+                    in_block,
+                    out,
+                    vhdx.logical_sector_size,
+                    |i, in_sector, out| {
+                        let sector = first_sector + i;
+                        let bit = bitmap.get(sector as usize / 8).copied().unwrap_or_default();
+                        let offset = i * vhdx.logical_sector_size + in_sector;
+                        if bit & (1 << (sector % 8)) != 0 {
+                            copy_from(data, offset, out);
+                        } else {
+                            read_parent(parent, block_offset + offset, out);
+                        }
+                    },
+                );
+            }
+            // The parent's, in a differencing disk.
+            PAYLOAD_BLOCK_NOT_PRESENT if vhdx.parent_hints.is_some() => {
+                read_parent(parent, block_offset + in_block, out)
+            }
+            // Zero, unmapped or undefined: reads as zeros, even over the parent.
+            _ => out.fill(0),
+        }
+    }
+}
+
+impl Media for VhdxMedia {
+    fn size(&self) -> u64 {
+        self.vhdx.size
+    }
+
+    fn read_at(&self, offset: u64, out: &mut [u8]) {
+        let out = clip_to_size(self.vhdx.size, offset, out);
+        read_blocks(offset, out, self.vhdx.block_size, |block, in_block, out| {
+            self.read_block(block, in_block, out)
+        });
+    }
+}
+
+/// Opens the media of a VHDX, and of its chain of parents if it is a differencing disk.
+fn open_media(path: &Path, depth: usize) -> Result<Box<dyn Media>> {
+    let vhdx = Vhdx::open(path)?;
+    info!(
+        "{path:?}: VHDX disk of {} bytes ({} byte sectors){}.",
+        vhdx.size,
+        vhdx.logical_sector_size,
+        if vhdx.parent_hints.is_some() {
+            ", differencing"
+        } else {
+            ""
+        }
+    );
+    let mut parent = None;
+    if let Some(hints) = &vhdx.parent_hints {
+        if depth >= MAX_PARENT_DEPTH {
+            bail!("The VHDX differencing chain of {path:?} is too long.");
+        }
+        match resolve_parent(path, hints) {
+            Some(parent_path) => {
+                info!("Reading the parent of {path:?}: {parent_path:?}.");
+                let media = open_media(&parent_path, depth + 1)?;
+                if media.size() != vhdx.size {
+                    warn!("{parent_path:?} isn't the same size as its child {path:?}.");
+                }
+                parent = Some(media);
+            }
+            None => warn!(
+                "The parent of {path:?} ({hints:?}) wasn't found. The blocks it holds will \
+                 read as zeros."
+            ),
+        }
+    }
+
+    let block_count = vhdx.block_count();
+    let mut present_blocks = 0;
+    for block in 0..block_count {
+        match vhdx.payload_entry(block) & BAT_STATE_MASK {
+            PAYLOAD_BLOCK_FULLY_PRESENT => present_blocks += 1,
+            PAYLOAD_BLOCK_PARTIALLY_PRESENT if vhdx.sector_bitmap(block).is_none() => {
+                warn!("Block {block} of {path:?} has no sector bitmap. Skipped.")
+            }
+            PAYLOAD_BLOCK_PARTIALLY_PRESENT => present_blocks += 1,
+            _ => {}
+        }
+    }
+    info!(
+        "{path:?}: {present_blocks} of {block_count} blocks of {} bytes present.",
+        vhdx.block_size
+    );
+    Ok(Box::new(VhdxMedia { vhdx, parent }))
+}
+
+/// Reads a VHDX, following its chain of parents if it is a differencing disk. Blocks are
+/// read through the BAT as they are needed, and those that aren't allocated anywhere in the
+/// chain read as zeros.
+pub fn read_vhdx(path: &Path) -> Result<DiskImage> {
+    Ok(DiskImage::new(open_media(path, 0)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_image::tests::{media, temp_file};
+
+    const MIB: usize = 1 << 20;
+    const BAT_OFFSET: usize = 320 * 1024;
+    const METADATA_OFFSET: usize = 384 * 1024;
+
+    /// The on-disk bytes of a GUID, from its string form.
+    fn guid_bytes(guid: &str) -> Vec<u8> {
+        let hex: String = guid.split('-').collect();
+        let byte = |i: usize| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        let mut bytes: Vec<u8> = (0..16).map(byte).collect();
+        bytes[..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes
+    }
+
+    fn set_checksum(structure: &mut [u8]) {
+        let checksum = crc32c(structure);
+        structure[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// A 3 MiB disk of 1 MiB blocks, with only block 1 present (at 1 MiB in the file).
+    fn vhdx(block: &[u8]) -> Vec<u8> {
+        let mut file = vec![0; MIB];
+        file[..8].copy_from_slice(VHDX_SIGNATURE);
+
+        // The second header has the higher sequence number.
+        for (offset, sequence) in HEADER_OFFSETS.into_iter().zip([1u64, 2]) {
+            let header = &mut file[offset..offset + HEADER_SIZE];
+            header[..4].copy_from_slice(HEADER_SIGNATURE);
+            header[8..16].copy_from_slice(&sequence.to_le_bytes());
+            set_checksum(header);
+        }
+
+        let region_table = &mut file[REGION_TABLE_OFFSETS[0]..][..REGION_TABLE_SIZE];
+        region_table[..4].copy_from_slice(REGION_TABLE_SIGNATURE);
+        region_table[8..12].copy_from_slice(&2u32.to_le_bytes());
+        for (i, (guid, offset)) in [(BAT_REGION, BAT_OFFSET), (METADATA_REGION, METADATA_OFFSET)]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = &mut region_table[16 + i * 32..16 + (i + 1) * 32];
+            entry[..16].copy_from_slice(&guid_bytes(guid));
+            entry[16..24].copy_from_slice(&(offset as u64).to_le_bytes());
+            entry[24..28].copy_from_slice(&(64 * 1024u32).to_le_bytes());
+        }
+        set_checksum(region_table);
+
+        let bat_entry = MIB as u64 | PAYLOAD_BLOCK_FULLY_PRESENT;
+        file[BAT_OFFSET + 8..BAT_OFFSET + 16].copy_from_slice(&bat_entry.to_le_bytes());
+
+        let metadata = &mut file[METADATA_OFFSET..][..64 * 1024];
+        metadata[..8].copy_from_slice(METADATA_SIGNATURE);
+        metadata[10..12].copy_from_slice(&3u16.to_le_bytes());
+        let items: [(&str, Vec<u8>); 3] = [
+            (
+                FILE_PARAMETERS,
+                [(MIB as u32).to_le_bytes(), [0; 4]].concat(),
+            ),
+            (VIRTUAL_DISK_SIZE, (3 * MIB as u64).to_le_bytes().to_vec()),
+            (LOGICAL_SECTOR_SIZE, 512u32.to_le_bytes().to_vec()),
+        ];
+        for (i, (guid, data)) in items.iter().enumerate() {
+            let offset = 0x1000 + i * 0x100;
+            let entry = &mut metadata[32 + i * 32..32 + (i + 1) * 32];
+            entry[..16].copy_from_slice(&guid_bytes(guid));
+            entry[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
+            entry[20..24].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            metadata[offset..offset + data.len()].copy_from_slice(data);
+        }
+
+        file.extend_from_slice(block);
+        file
+    }
+
+    #[test]
+    fn reads_the_present_blocks_through_the_bat() {
+        let block: Vec<u8> = (0..MIB).map(|i| (i % 253) as u8).collect();
+        let path = temp_file("disk.vhdx", &vhdx(&block));
+        let media = media(&read_vhdx(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(media.len(), 3 * MIB);
+        assert!(media[..MIB].iter().all(|&b| b == 0));
+        assert_eq!(&media[MIB..2 * MIB], &block[..]);
+        assert!(media[2 * MIB..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn uses_the_header_copy_with_a_valid_checksum() {
+        let mut file = vhdx(&[]);
+        let newest = HEADER_OFFSETS[1];
+        file[newest + 8] = 3;
+
+        let header = current_copy(&file, HEADER_OFFSETS, HEADER_SIZE, HEADER_SIGNATURE, |h| {
+            read_u64(h, 8).unwrap_or_default()
+        });
+        assert_eq!(header.map(|h| read_u64(h, 8)), Some(Some(1)));
+    }
+}
@@ -0,0 +1,583 @@
+use anyhow::{Context, Result, bail};
+use flate2::read::ZlibDecoder;
+use log::{debug, info, warn};
+use memmap2::Mmap;
+use std::{
+    cell::Cell,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::disk_image::{
+    BlockCache, DiskImage, MAX_PARENT_DEPTH, Media, clip_to_size, copy_from, map_file, read_blocks,
+    read_parent, resolve_parent,
+};
+
+// VMDK (VMware). Sparse extents start with a binary header (little-endian), and the disk is
+// described by a text descriptor, embedded in the sparse extent or in its own file.
+pub const VMDK_SPARSE_MAGIC: &[u8; 4] = b"KDMV";
+pub const VMDK_DESCRIPTOR_SIGNATURE: &[u8; 21] = b"# Disk DescriptorFile";
+const SECTOR_SIZE: u64 = 512;
+const SPARSE_HEADER_SIZE: usize = 79;
+// Stream-optimized extents write the grain directory last, and a copy of the header with its
+// offset in a footer, 1024 bytes before the end.
+const GD_AT_END: u64 = u64::MAX;
+const FOOTER_OFFSET_FROM_END: usize = 1024;
+const FLAG_COMPRESSED: u32 = 1 << 16;
+// Compressed grains start with their LBA (u64) and compressed size (u32).
+const GRAIN_MARKER_SIZE: usize = 12;
+// A grain table entry that reads as zeros, even over the parent.
+const ZERO_GRAIN: u32 = 1;
+const NO_PARENT_CID: &str = "ffffffff";
+// Limits from the specification: grains of at most 64 KiB, and 512 entries per grain table.
+const MAX_GRAIN_SIZE: u64 = 128;
+const MAX_GTES_PER_GT: u64 = 512;
+// Decompressed grains kept for nearby reads.
+const CACHED_GRAINS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtentKind {
+    Flat,
+    Sparse,
+    Zero,
+}
+
+#[derive(Debug)]
+struct Extent {
+    kind: ExtentKind,
+    sectors: u64,
+    path: PathBuf,
+    offset: u64, // Byte offset in a flat extent's file
+}
+
+#[derive(Debug)]
+struct Descriptor {
+    extents: Vec<Extent>,
+    parent_hint: Option<String>,
+}
+
+impl Descriptor {
+    /// Size of the disk in bytes, or `None` if the extent sizes overflow.
+    fn size(&self) -> Option<u64> {
+        self.extents.iter().try_fold(0u64, |size, extent| {
+            size.checked_add(extent.sectors.checked_mul(SECTOR_SIZE)?)
+        })
+    }
+}
+
+struct SparseHeader {
+    flags: u32,
+    capacity: u64,   // In sectors
+    grain_size: u64, // In sectors
+    descriptor_offset: u64,
+    descriptor_size: u64,
+    gtes_per_gt: u64,
+    gd_offset: u64,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn parse_sparse_header(buf: &[u8]) -> Option<SparseHeader> {
+    if !buf
+        .get(..SPARSE_HEADER_SIZE)?
+        .starts_with(VMDK_SPARSE_MAGIC)
+    {
+        return None;
+    }
+    Some(SparseHeader {
+        flags: read_u32(buf, 8)?,
+        capacity: read_u64(buf, 12)?,
+        grain_size: read_u64(buf, 20)?,
+        descriptor_offset: read_u64(buf, 28)?,
+        descriptor_size: read_u64(buf, 36)?,
+        gtes_per_gt: read_u32(buf, 44)? as u64,
+        gd_offset: read_u64(buf, 56)?,
+    })
+}
+
+fn sparse_header(file: &[u8], path: &Path) -> Result<SparseHeader> {
+    let Some(mut header) = parse_sparse_header(file) else {
+        bail!("{path:?} has no VMDK sparse extent header.");
+    };
+    if header.gd_offset == GD_AT_END {
+        let footer = file.len().saturating_sub(FOOTER_OFFSET_FROM_END);
+        match parse_sparse_header(&file[footer..]) {
+            Some(footer) => header.gd_offset = footer.gd_offset,
+            None => bail!("The VMDK footer of {path:?} is missing."),
+        }
+    }
+    if !header.grain_size.is_power_of_two()
+        || header.grain_size > MAX_GRAIN_SIZE
+        || !(1..=MAX_GTES_PER_GT).contains(&header.gtes_per_gt)
+    {
+        bail!("{path:?} has an invalid VMDK sparse extent header.");
+    }
+    Ok(header)
+}
+
+/// Parses an extent line: access, size in sectors, type, quoted file name and, for flat
+/// extents, the offset in sectors.
+fn parse_extent(line: &str, directory: &Path) -> Result<Option<Extent>> {
+    let mut fields = line.split_whitespace();
+    let (Some(access), Some(sectors), Some(kind)) = (fields.next(), fields.next(), fields.next())
+    else {
+        return Ok(None);
+    };
+    if !["RW", "RDONLY", "NOACCESS"].contains(&access) {
+        return Ok(None);
+    }
+    let sectors = sectors.parse().context("Invalid VMDK extent size")?;
+    let kind = match kind {
+        "FLAT" | "VMFS" => ExtentKind::Flat,
+        "SPARSE" => ExtentKind::Sparse,
+        "ZERO" => ExtentKind::Zero,
+        other => bail!("VMDK {other} extents aren't supported."),
+    };
+
+    let mut quoted = line.splitn(3, '"').skip(1);
+    let file_name = quoted.next().unwrap_or_default();
+    let offset = quoted
+        .next()
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|offset| offset.parse::<u64>().ok())
+        .unwrap_or_default();
+
+    Ok(Some(Extent {
+        kind,
+        sectors,
+        path: directory.join(file_name),
+        offset: offset * SECTOR_SIZE,
+    }))
+}
+
+fn parse_descriptor(text: &str, path: &Path) -> Result<Descriptor> {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut descriptor = Descriptor {
+        extents: Vec::new(),
+        parent_hint: None,
+    };
+    let mut parent_cid = None;
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim() {
+                "parentCID" => parent_cid = Some(value),
+                "parentFileNameHint" => descriptor.parent_hint = Some(value),
+                _ => {}
+            }
+        } else if let Some(extent) = parse_extent(line, directory)? {
+            descriptor.extents.push(extent);
+        }
+    }
+
+    if parent_cid.is_some_and(|cid| cid == NO_PARENT_CID) {
+        descriptor.parent_hint = None;
+    }
+    Ok(descriptor)
+}
+
+/// Reads the descriptor of a VMDK: a text descriptor file, or the one embedded in a
+/// monolithic sparse extent (the extent then being the file itself).
+fn open(path: &Path) -> Result<Descriptor> {
+    let file = map_file(path)?;
+    if !file.starts_with(VMDK_SPARSE_MAGIC) {
+        let text = String::from_utf8_lossy(&file);
+        return parse_descriptor(&text, path);
+    }
+
+    let header = sparse_header(&file, path)?;
+    let start = (header.descriptor_offset * SECTOR_SIZE) as usize;
+    let end = start.saturating_add((header.descriptor_size * SECTOR_SIZE) as usize);
+    let embedded = file.get(start..end).unwrap_or_default();
+    let embedded = embedded.split(|&b| b == 0).next().unwrap_or_default();
+    let mut descriptor = parse_descriptor(&String::from_utf8_lossy(embedded), path)?;
+    debug!("Embedded VMDK descriptor of {path:?}: {descriptor:?}");
+
+    descriptor.extents = vec![Extent {
+        kind: ExtentKind::Sparse,
+        sectors: header.capacity,
+        path: path.to_path_buf(),
+        offset: 0,
+    }];
+    Ok(descriptor)
+}
+
+/// A sparse extent, read through its grain directory and tables.
+struct SparseExtent {
+    path: PathBuf,
+    file: Mmap,
+    header: SparseHeader,
+    cache: BlockCache,
+    // Whether a grain that couldn't be decompressed was already reported.
+    failed: Cell<bool>,
+}
+
+impl SparseExtent {
+    fn open(path: &Path) -> Result<Self> {
+        let file = map_file(path)?;
+        let header = sparse_header(&file, path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            header,
+            cache: BlockCache::new(CACHED_GRAINS),
+            failed: Cell::new(false),
+        })
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.header.grain_size * SECTOR_SIZE
+    }
+
+    /// The grain table entry of `grain`, or `None` if its grain table isn't allocated.
+    fn grain_entry(&self, grain: u64) -> Option<u32> {
+        let header = &self.header;
+        if grain.checked_mul(header.grain_size)? >= header.capacity {
+            return None;
+        }
+        let gd_offset = header.gd_offset.checked_mul(SECTOR_SIZE)?;
+        let gde_offset = gd_offset.checked_add(grain / header.gtes_per_gt * 4)?;
+        let gde = read_u32(&self.file, usize::try_from(gde_offset).ok()?)?;
+        if gde == 0 {
+            return None;
+        }
+        let gte_offset = gde as u64 * SECTOR_SIZE + grain % header.gtes_per_gt * 4;
+        read_u32(&self.file, usize::try_from(gte_offset).ok()?)
+    }
+
+    fn decompress(&self, stored: &[u8]) -> Vec<u8> {
+        let size = read_u32(stored, 8).unwrap_or_default() as usize;
+        let compressed_data = stored
+            .get(GRAIN_MARKER_SIZE..GRAIN_MARKER_SIZE.saturating_add(size))
+            .unwrap_or_default();
+        let mut grain = Vec::with_capacity(self.grain_bytes() as usize);
+        let decoded = ZlibDecoder::new(compressed_data)
+            .take(self.grain_bytes())
+            .read_to_end(&mut grain);
+        if decoded.is_err() && !self.failed.replace(true) {
+            warn!(
+                "Compressed grains of {:?} could not be decompressed. They read as zeros.",
+                self.path
+            );
+        }
+        grain
+    }
+
+    /// Reads from `offset` in the extent, which is at `extent_start` in the disk.
+    fn read_at(&self, extent_start: u64, offset: u64, out: &mut [u8], parent: Option<&dyn Media>) {
+        let grain_bytes = self.grain_bytes();
+        read_blocks(offset, out, grain_bytes, |grain, in_grain, out| {
+            let disk_offset = extent_start + grain * grain_bytes + in_grain;
+            let gte = match self.grain_entry(grain) {
+                // The parent's, or zeros.
+                None | Some(0) => return read_parent(parent, disk_offset, out),
+                Some(ZERO_GRAIN) => return out.fill(0),
+                Some(gte) => gte,
+            };
+            let stored = self
+                .file
+                .get(gte as usize * SECTOR_SIZE as usize..)
+                .unwrap_or_default();
+            if self.header.flags & FLAG_COMPRESSED != 0 {
+                let data = self.cache.get(grain, || self.decompress(stored));
+                copy_from(&data, in_grain, out);
+            } else {
+                let data = &stored[..stored.len().min(grain_bytes as usize)];
+                copy_from(data, in_grain, out);
+            }
+        });
+    }
+}
+
+enum ExtentReader {
+    Flat { file: Mmap, offset: u64 },
+    Sparse(SparseExtent),
+    Zero,
+}
+
+/// The media of a VMDK, read through its extents, over its parent's for a delta link.
+struct VmdkMedia {
+    // The extents and the offsets they start at, in order.
+    extents: Vec<(u64, ExtentReader)>,
+    size: u64,
+    parent: Option<Box<dyn Media>>,
+}
+
+impl VmdkMedia {
+    fn read_extent(&self, index: usize, offset: u64, out: &mut [u8]) {
+        let (start, extent) = &self.extents[index];
+        let in_extent = offset - start;
+        let parent = self.parent.as_deref();
+        match extent {
+            ExtentReader::Flat {
+                file,
+                offset: file_offset,
+            } => {
+                // Past the end of the file, the parent's.
+                let data = file.get(*file_offset as usize..).unwrap_or_default();
+                let start = usize::try_from(in_extent)
+                    .unwrap_or(usize::MAX)
+                    .min(data.len());
+                let length = out.len().min(data.len() - start);
+                let (within, past) = out.split_at_mut(length);
+                within.copy_from_slice(&data[start..start + length]);
+                read_parent(parent, offset + length as u64, past);
+            }
+            ExtentReader::Sparse(sparse) => sparse.read_at(*start, in_extent, out, parent),
+            ExtentReader::Zero => read_parent(parent, offset, out),
+        }
+    }
+}
+
+impl Media for VmdkMedia {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, out: &mut [u8]) {
+        let mut position = offset;
+        let mut out = clip_to_size(self.size, offset, out);
+        while !out.is_empty() {
+            // The last extent that starts at or before the position, so that empty extents are
+            // skipped.
+            let index = self
+                .extents
+                .partition_point(|(start, _)| *start <= position)
+                - 1;
+            let end = self
+                .extents
+                .get(index + 1)
+                .map_or(self.size, |(start, _)| *start);
+            let length = (end - position).min(out.len() as u64) as usize;
+            let (part, rest) = out.split_at_mut(length);
+            self.read_extent(index, position, part);
+            position += length as u64;
+            out = rest;
+        }
+    }
+}
+
+/// Opens the media of a VMDK, and of its chain of parents if it is a delta link.
+fn open_media(path: &Path, descriptor: Descriptor, depth: usize) -> Result<Box<dyn Media>> {
+    let Some(size) = descriptor.size() else {
+        bail!("The extents of {path:?} are too large.");
+    };
+    let mut parent = None;
+    if let Some(hint) = &descriptor.parent_hint {
+        if depth >= MAX_PARENT_DEPTH {
+            bail!("The VMDK chain of {path:?} is too long.");
+        }
+        match resolve_parent(path, std::slice::from_ref(hint)) {
+            Some(parent_path) => {
+                info!("Reading the parent of {path:?}: {parent_path:?}.");
+                let media = open_media(&parent_path, open(&parent_path)?, depth + 1)?;
+                if media.size() != size {
+                    warn!("{parent_path:?} isn't the same size as its child {path:?}.");
+                }
+                parent = Some(media);
+            }
+            None => warn!(
+                "The parent of {path:?} ({hint:?}) wasn't found. The grains it holds will \
+                 read as zeros."
+            ),
+        }
+    }
+
+    let mut extents = Vec::new();
+    let mut start = 0;
+    for extent in descriptor.extents {
+        let reader = match extent.kind {
+            ExtentKind::Flat => ExtentReader::Flat {
+                file: map_file(&extent.path)?,
+                offset: extent.offset,
+            },
+            ExtentKind::Sparse => {
+                let sparse = SparseExtent::open(&extent.path)?;
+                log_grains(&sparse);
+                ExtentReader::Sparse(sparse)
+            }
+            ExtentKind::Zero => ExtentReader::Zero,
+        };
+        extents.push((start, reader));
+        start += extent.sectors * SECTOR_SIZE;
+    }
+    Ok(Box::new(VmdkMedia {
+        extents,
+        size,
+        parent,
+    }))
+}
+
+fn log_grains(sparse: &SparseExtent) {
+    let grain_count = sparse.header.capacity.div_ceil(sparse.header.grain_size);
+    let allocated_grains = (0..grain_count)
+        .filter(|&grain| !matches!(sparse.grain_entry(grain), None | Some(0 | ZERO_GRAIN)))
+        .count();
+    info!(
+        "{:?}: {allocated_grains} grains of {} bytes allocated{}.",
+        sparse.path,
+        sparse.grain_bytes(),
+        if sparse.header.flags & FLAG_COMPRESSED != 0 {
+            " (compressed)"
+        } else {
+            ""
+        }
+    );
+}
+
+/// Reads a VMDK: a monolithic sparse or stream-optimized file, or a descriptor with its flat
+/// and sparse extents, following the chain of parents of a delta link. A single flat extent is
+/// mapped as is; otherwise grains are read through the grain tables as they are needed, with
+/// those that aren't allocated anywhere in the chain reading as zeros.
+pub fn read_vmdk(path: &Path) -> Result<DiskImage> {
+    let descriptor = open(path)?;
+    let Some(size) = descriptor.size() else {
+        bail!("The extents of {path:?} are too large.");
+    };
+    info!(
+        "VMDK disk of {size} bytes in {} extents{}.",
+        descriptor.extents.len(),
+        if descriptor.parent_hint.is_some() {
+            ", delta link"
+        } else {
+            ""
+        }
+    );
+
+    let single_flat_extent = match &descriptor.extents[..] {
+        [extent] if extent.kind == ExtentKind::Flat && descriptor.parent_hint.is_none() => {
+            Some(extent)
+        }
+        _ => None,
+    };
+    if let Some(extent) = single_flat_extent {
+        let file = map_file(&extent.path)?;
+        let start = extent.offset as usize;
+        return Ok(DiskImage::mapped(
+            file,
+            start..start.saturating_add(size as usize),
+        ));
+    }
+
+    Ok(DiskImage::new(open_media(path, descriptor, 0)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk_image::tests::{media, temp_file};
+    use flate2::{Compression, write::ZlibEncoder};
+    use std::io::Write;
+
+    const SECTOR: usize = SECTOR_SIZE as usize;
+
+    /// A monolithic sparse extent of four 4 KiB grains: its header, embedded descriptor,
+    /// grain directory and single grain table (sectors 0 to 3), then `grains` from sector 4.
+    /// The table maps grain 0 to the first of `grains`, grain 2 to zeros and grain 3 to the
+    /// second.
+    fn sparse_extent(flags: u32, grains: &[Vec<u8>; 2]) -> Vec<u8> {
+        let mut file = vec![0; 4 * SECTOR];
+        file[..4].copy_from_slice(VMDK_SPARSE_MAGIC);
+        file[4..8].copy_from_slice(&1u32.to_le_bytes());
+        file[8..12].copy_from_slice(&flags.to_le_bytes());
+        file[12..20].copy_from_slice(&32u64.to_le_bytes());
+        file[20..28].copy_from_slice(&8u64.to_le_bytes());
+        file[28..36].copy_from_slice(&1u64.to_le_bytes());
+        file[36..44].copy_from_slice(&1u64.to_le_bytes());
+        file[44..48].copy_from_slice(&4u32.to_le_bytes());
+        file[56..64].copy_from_slice(&2u64.to_le_bytes());
+
+        let descriptor = b"# Disk DescriptorFile\nparentCID=ffffffff\nRW 32 SPARSE \"a.vmdk\"\n";
+        file[SECTOR..SECTOR + descriptor.len()].copy_from_slice(descriptor);
+        file[2 * SECTOR..2 * SECTOR + 4].copy_from_slice(&3u32.to_le_bytes());
+
+        let first = 4;
+        let second = first + grains[0].len().div_ceil(SECTOR);
+        for (i, entry) in [first as u32, 0, ZERO_GRAIN, second as u32]
+            .iter()
+            .enumerate()
+        {
+            let offset = 3 * SECTOR + i * 4;
+            file[offset..offset + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+        for grain in grains {
+            file.extend_from_slice(grain);
+            file.resize(file.len().next_multiple_of(SECTOR), 0);
+        }
+        file
+    }
+
+    fn read(name: &str, file: &[u8]) -> Vec<u8> {
+        let path = temp_file(name, file);
+        let media = media(&read_vmdk(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        media
+    }
+
+    #[test]
+    fn reads_the_grains_of_a_sparse_extent() {
+        let file = sparse_extent(0, &[vec![0xAB; 4096], vec![0xCD; 4096]]);
+
+        let media = read("sparse.vmdk", &file);
+        assert_eq!(media.len(), 4 * 4096);
+        assert!(media[..4096].iter().all(|&b| b == 0xAB));
+        assert!(media[4096..3 * 4096].iter().all(|&b| b == 0));
+        assert!(media[3 * 4096..].iter().all(|&b| b == 0xCD));
+    }
+
+    #[test]
+    fn decompresses_stream_optimized_grains() {
+        let grain = |lba: u64, data: &[u8]| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            let compressed = encoder.finish().unwrap();
+            let mut marker = lba.to_le_bytes().to_vec();
+            marker.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            marker.extend_from_slice(&compressed);
+            marker
+        };
+        let second: Vec<u8> = (0..4096).map(|i| (i % 7) as u8).collect();
+        let file = sparse_extent(
+            FLAG_COMPRESSED,
+            &[grain(0, &[0xAB; 4096]), grain(24, &second)],
+        );
+
+        let media = read("compressed.vmdk", &file);
+        assert!(media[..4096].iter().all(|&b| b == 0xAB));
+        assert_eq!(&media[3 * 4096..], &second[..]);
+    }
+
+    #[test]
+    fn parses_extent_lines() {
+        let directory = Path::new("/images");
+        let extent = parse_extent("RW 2048 FLAG \"a-flat.vmdk\" 16", directory).unwrap_err();
+        assert!(extent.to_string().contains("FLAG"));
+
+        let extent = parse_extent("RDONLY 2048 FLAT \"a-flat.vmdk\" 16", directory)
+            .unwrap()
+            .unwrap();
+        assert_eq!(extent.kind, ExtentKind::Flat);
+        assert_eq!(extent.sectors, 2048);
+        assert_eq!(extent.path, directory.join("a-flat.vmdk"));
+        assert_eq!(extent.offset, 16 * 512);
+        assert!(
+            parse_extent("ddb.adapterType = \"lsilogic\"", directory)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,148 @@
+/// Files compressed by the Windows Overlay Filter (WOF), as done by CompactOS and
+/// `compact /exe`.
+///
+/// Such files carry an `IO_REPARSE_TAG_WOF` reparse point naming the compression format,
+/// and their content lives in the `WofCompressedData` named stream: a table with the
+/// offset of every chunk but the first, followed by the chunks. Each chunk is compressed
+/// on its own, or stored as is when compression didn't make it smaller.
+use serde::Serialize;
+
+use crate::{lzx, xpress_huffman};
+
+pub const WOF_STREAM_NAME: &str = "WofCompressedData";
+
+const WOF_CURRENT_VERSION: u32 = 1;
+const WOF_PROVIDER_WIM: u32 = 1;
+const WOF_PROVIDER_FILE: u32 = 2;
+const FILE_PROVIDER_CURRENT_VERSION: u32 = 1;
+
+/// Where the content of a WOF file comes from.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WofProvider {
+    /// A WIM image (WIMBoot), outside the volume.
+    Wim,
+    /// The file's own `WofCompressedData` stream.
+    File,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WofAlgorithm {
+    Xpress4k,
+    Lzx,
+    Xpress8k,
+    Xpress16k,
+}
+
+impl WofAlgorithm {
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(Self::Xpress4k),
+            1 => Some(Self::Lzx),
+            2 => Some(Self::Xpress8k),
+            3 => Some(Self::Xpress16k),
+            _ => None,
+        }
+    }
+
+    pub fn chunk_size(self) -> usize {
+        match self {
+            Self::Xpress4k => 4 * 1024,
+            Self::Lzx => 32 * 1024,
+            Self::Xpress8k => 8 * 1024,
+            Self::Xpress16k => 16 * 1024,
+        }
+    }
+
+    fn decompress_chunk(self, input: &[u8], output_size: usize) -> Option<Vec<u8>> {
+        match self {
+            Self::Lzx => lzx::decompress(input, output_size),
+            _ => xpress_huffman::decompress(input, output_size),
+        }
+    }
+}
+
+/// Parses the data of a WOF reparse point (after the 8-byte reparse header). The
+/// compression format is only known for the file provider.
+pub fn parse_wof_reparse_data(data: &[u8]) -> Option<(WofProvider, Option<WofAlgorithm>)> {
+    let version = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let provider = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?);
+    if version != WOF_CURRENT_VERSION {
+        return None;
+    }
+
+    match provider {
+        WOF_PROVIDER_WIM => Some((WofProvider::Wim, None)),
+        WOF_PROVIDER_FILE => {
+            let provider_version = u32::from_le_bytes(data.get(8..12)?.try_into().ok()?);
+            let algorithm = u32::from_le_bytes(data.get(12..16)?.try_into().ok()?);
+            let algorithm = (provider_version == FILE_PROVIDER_CURRENT_VERSION)
+                .then(|| WofAlgorithm::from_code(algorithm))
+                .flatten();
+            Some((WofProvider::File, algorithm))
+        }
+        _ => None,
+    }
+}
+
+/// Decompresses the `WofCompressedData` stream of a file of `size` bytes.
+///
+/// Returns the content and the number of bytes that couldn't be decompressed (chunks that
+/// are missing or corrupt are zero-filled). Nothing is returned if the chunk table doesn't
+/// fit in the stream.
+pub fn decompress_wof(data: &[u8], size: u64, algorithm: WofAlgorithm) -> (Vec<u8>, u64) {
+    let chunk_size = algorithm.chunk_size();
+    let num_chunks = size.div_ceil(chunk_size as u64) as usize;
+    // Offsets are 64-bit only when the file needs it.
+    let entry_size = if size > u32::MAX as u64 { 8 } else { 4 };
+    let table_size = num_chunks.saturating_sub(1) * entry_size;
+    // Also keeps a corrupt size from turning into a huge allocation.
+    if table_size > data.len() {
+        return (Vec::new(), size);
+    }
+
+    let chunk_offset = |chunk: usize| -> Option<usize> {
+        if chunk == 0 {
+            return Some(table_size);
+        }
+        let entry = data.get((chunk - 1) * entry_size..chunk * entry_size)?;
+        let offset = match entry_size {
+            8 => u64::from_le_bytes(entry.try_into().ok()?),
+            _ => u32::from_le_bytes(entry.try_into().ok()?) as u64,
+        };
+        table_size.checked_add(usize::try_from(offset).ok()?)
+    };
+
+    let mut output = Vec::with_capacity(size as usize);
+    let mut failed = 0;
+
+    for chunk in 0..num_chunks {
+        let output_size = (size as usize - output.len()).min(chunk_size);
+        let end = if chunk + 1 < num_chunks {
+            chunk_offset(chunk + 1)
+        } else {
+            Some(data.len())
+        };
+        let decompressed = chunk_offset(chunk)
+            .zip(end)
+            .and_then(|(start, end)| data.get(start..end))
+            .and_then(|input| {
+                if input.len() == output_size {
+                    Some(input.to_vec())
+                } else {
+                    algorithm.decompress_chunk(input, output_size)
+                }
+            });
+
+        match decompressed {
+            Some(bytes) => output.extend_from_slice(&bytes),
+            None => {
+                output.resize(output.len() + output_size, 0);
+                failed += output_size as u64;
+            }
+        }
+    }
+
+    (output, failed)
+}
@@ -0,0 +1,174 @@
+/// XPRESS Huffman ("LZ77+Huffman" in MS-XCA), used by WOF's XPRESS4K/8K/16K formats.
+///
+/// The output is produced in blocks of 64 KiB. Each block starts with a 256-byte table
+/// holding the 4-bit code lengths of its 512 symbols: 256 literals, then 256 match symbols
+/// combining a length (low 4 bits) with the bit length of the offset (high 4 bits).
+use crate::huffman::HuffmanCode;
+
+const NUM_SYMBOLS: usize = 512;
+const TABLE_SIZE: usize = NUM_SYMBOLS / 2;
+const MAX_CODE_LENGTH: u32 = 15;
+const BLOCK_SIZE: usize = 64 * 1024;
+const MIN_MATCH_LENGTH: usize = 3;
+
+/// The bit stream is made of 16-bit little-endian words, read most significant bit first.
+/// Longer match lengths are stored as whole bytes, interleaved with the words.
+struct BitReader<'a> {
+    input: &'a [u8],
+    position: usize,
+    bits: u32,
+    bit_count: i32, // Bits available beyond the 16 at the top of `bits`
+}
+
+impl<'a> BitReader<'a> {
+    fn new(input: &'a [u8], position: usize) -> Self {
+        let mut reader = Self {
+            input,
+            position,
+            bits: 0,
+            bit_count: 16,
+        };
+        reader.bits = (reader.next_word() << 16) | reader.next_word();
+        reader
+    }
+
+    /// The next 16-bit word. Reads past the end return zeros, as the last codes of a
+    /// block may be followed by fewer than 32 bits of input.
+    fn next_word(&mut self) -> u32 {
+        let word = match self.input.get(self.position..self.position + 2) {
+            Some(bytes) => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+            None => 0,
+        };
+        self.position += 2;
+        word
+    }
+
+    fn peek(&self, count: u32) -> u32 {
+        if count == 0 {
+            0
+        } else {
+            self.bits >> (32 - count)
+        }
+    }
+
+    fn consume(&mut self, count: u32) {
+        self.bits = self.bits.checked_shl(count).unwrap_or(0);
+        self.bit_count -= count as i32;
+        if self.bit_count < 0 {
+            self.bits |= self.next_word() << -self.bit_count;
+            self.bit_count += 16;
+        }
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.input.get(self.position..self.position + N)?;
+        self.position += N;
+        bytes.try_into().ok()
+    }
+}
+
+/// Decompresses `input` into exactly `output_size` bytes. Returns `None` on corrupt data.
+pub fn decompress(input: &[u8], output_size: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(output_size);
+    let mut position = 0;
+
+    while output.len() < output_size {
+        let table = input.get(position..position + TABLE_SIZE)?;
+        let lengths: Vec<u8> = table.iter().flat_map(|&b| [b & 0x0F, b >> 4]).collect();
+        let code = HuffmanCode::new(&lengths, MAX_CODE_LENGTH)?;
+
+        let mut reader = BitReader::new(input, position + TABLE_SIZE);
+        let block_end = (output.len() + BLOCK_SIZE).min(output_size);
+
+        while output.len() < block_end {
+            let (symbol, length) = code.decode(reader.peek(MAX_CODE_LENGTH))?;
+            reader.consume(length);
+
+            if symbol < 256 {
+                output.push(symbol as u8);
+                continue;
+            }
+
+            let symbol = symbol - 256;
+            let offset_bits = (symbol >> 4) as u32;
+            let mut length = (symbol & 0x0F) as usize;
+            if length == 15 {
+                length = reader.read_bytes::<1>()?[0] as usize;
+                if length == 255 {
+                    length = u16::from_le_bytes(reader.read_bytes()?) as usize;
+                    if length == 0 {
+                        length = u32::from_le_bytes(reader.read_bytes()?) as usize;
+                    }
+                    length = length.checked_sub(15)?;
+                }
+                length += 15;
+            }
+            length += MIN_MATCH_LENGTH;
+
+            let offset = (reader.peek(offset_bits) as usize) + (1 << offset_bits);
+            reader.consume(offset_bits);
+            if offset > output.len() {
+                return None;
+            }
+
+            // Matches may overlap the bytes being written, so copy one byte at a time.
+            let length = length.min(output_size - output.len());
+            let source = output.len() - offset;
+            for i in 0..length {
+                output.push(output[source + i]);
+            }
+        }
+
+        position = reader.position;
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A code table with the given code lengths, and 0 for every other symbol.
+    fn table(lengths: &[(usize, u8)]) -> Vec<u8> {
+        let mut table = vec![0; TABLE_SIZE];
+        for &(symbol, length) in lengths {
+            table[symbol / 2] |= length << (4 * (symbol % 2));
+        }
+        table
+    }
+
+    #[test]
+    fn decompresses_ms_xca_example() {
+        // The LZ77+Huffman example of MS-XCA: 'a' to 'v' take 5 bits, 'w' to 'z' and the
+        // end of data symbol (256) 4 bits.
+        let mut input = vec![0; TABLE_SIZE];
+        input[0x30] = 0x50;
+        input[0x31..0x3B].fill(0x55);
+        input[0x3B..0x3E].copy_from_slice(&[0x45, 0x44, 0x04]);
+        input[0x80] = 0x04;
+        input.extend([
+            0xD8, 0x52, 0x3E, 0xD7, 0x94, 0x11, 0x5B, 0xE9, 0x19, 0x5F, 0xF9, 0xD6, 0x7C, 0xDF,
+            0x8D, 0x04, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        assert_eq!(
+            decompress(&input, 26).as_deref(),
+            Some(&b"abcdefghijklmnopqrstuvwxyz"[..])
+        );
+    }
+
+    #[test]
+    fn copies_overlapping_matches() {
+        // 'a' is 0 and the match symbol 256 (length 3, offset 1) is 1: "a" then "aaa".
+        let mut input = table(&[(b'a' as usize, 1), (256, 1)]);
+        input.extend([0x00, 0x40]);
+        assert_eq!(decompress(&input, 4).as_deref(), Some(&b"aaaa"[..]));
+    }
+
+    #[test]
+    fn rejects_a_match_before_the_start() {
+        let mut input = table(&[(b'a' as usize, 1), (256, 1)]);
+        input.extend([0x00, 0x80]);
+        assert_eq!(decompress(&input, 4), None);
+    }
+}